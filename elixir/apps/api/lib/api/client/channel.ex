@@ -185,6 +185,28 @@ defmodule API.Client.Channel do
     end
   end
 
+  # This message is sent by the gateway when the client's access to a resource expired
+  def handle_info(
+        {:access_expired, gateway_id, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "client.access_expired",
+      attributes: %{
+        gateway_id: gateway_id,
+        resource_id: resource_id
+      } do
+      push(socket, "access_expired", %{
+        gateway_id: gateway_id,
+        resource_id: resource_id
+      })
+
+      {:noreply, socket}
+    end
+  end
+
   # This message is sent by the gateway when it is ready to accept the connection from the client
   def handle_info(
         {:connect, socket_ref, resource_id, gateway_public_key, payload,
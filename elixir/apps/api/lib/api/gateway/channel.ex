@@ -391,6 +391,33 @@ defmodule API.Gateway.Channel do
     end
   end
 
+  def handle_in(
+        "access_expired",
+        %{"client_id" => client_id, "resource_id" => resource_id},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "gateway.access_expired",
+      attributes: %{
+        client_id: client_id,
+        resource_id: resource_id
+      } do
+      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
+      :ok =
+        Clients.broadcast_to_client(
+          client_id,
+          {:access_expired, socket.assigns.gateway.id, resource_id,
+           {opentelemetry_ctx, opentelemetry_span_ctx}}
+        )
+
+      {:noreply, socket}
+    end
+  end
+
   def handle_in(
         "metrics",
         %{
@@ -6,7 +6,8 @@ defmodule API.Gateway.Views.Client do
       id: client.id,
       payload: client_payload,
       peer: %{
-        persistent_keepalive: 25,
+        # The gateway uses its own keep-alive unless we override it
+        persistent_keepalive: nil,
         public_key: client.public_key,
         preshared_key: preshared_key,
         ipv4: client.ipv4,
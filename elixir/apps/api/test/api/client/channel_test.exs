@@ -461,6 +461,28 @@ defmodule API.Client.ChannelTest do
     end
   end
 
+  describe "handle_info/2 :access_expired" do
+    test "pushes access_expired message", %{
+      gateway: gateway,
+      dns_resource: resource,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      send(
+        socket.channel_pid,
+        {:access_expired, gateway.id, resource.id, otel_ctx}
+      )
+
+      assert_push "access_expired", payload
+
+      assert payload == %{
+               gateway_id: gateway.id,
+               resource_id: resource.id
+             }
+    end
+  end
+
   describe "handle_info/2 :update_resource" do
     test "pushes message to the socket for authorized clients", %{
       gateway_group: gateway_group,
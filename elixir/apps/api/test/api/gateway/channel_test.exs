@@ -448,7 +448,7 @@ defmodule API.Gateway.ChannelTest do
                peer: %{
                  ipv4: client.ipv4,
                  ipv6: client.ipv6,
-                 persistent_keepalive: 25,
+                 persistent_keepalive: nil,
                  preshared_key: preshared_key,
                  public_key: client.public_key
                },
@@ -563,7 +563,7 @@ defmodule API.Gateway.ChannelTest do
                peer: %{
                  ipv4: client.ipv4,
                  ipv6: client.ipv6,
-                 persistent_keepalive: 25,
+                 persistent_keepalive: nil,
                  preshared_key: preshared_key,
                  public_key: client.public_key
                },
@@ -894,6 +894,31 @@ defmodule API.Gateway.ChannelTest do
     end
   end
 
+  describe "handle_in/3 access_expired" do
+    test "broadcasts :access_expired message to the client", %{
+      client: client,
+      gateway: gateway,
+      resource: resource,
+      subject: subject,
+      socket: socket
+    } do
+      attrs = %{
+        "client_id" => client.id,
+        "resource_id" => resource.id
+      }
+
+      :ok = Domain.Clients.connect_client(client)
+      Domain.PubSub.subscribe(Domain.Tokens.socket_id(subject.token_id))
+
+      push(socket, "access_expired", attrs)
+
+      assert_receive {:access_expired, gateway_id, resource_id, _opentelemetry_ctx}, 200
+
+      assert gateway.id == gateway_id
+      assert resource.id == resource_id
+    end
+  end
+
   describe "handle_in/3 metrics" do
     test "inserts activities", %{
       account: account,
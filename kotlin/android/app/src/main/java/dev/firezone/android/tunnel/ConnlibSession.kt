@@ -10,6 +10,7 @@ object ConnlibSession {
         osVersion: String,
         logDir: String,
         logFilter: String,
+        keepaliveSecs: Int,
         callback: Any,
     ): Long
 
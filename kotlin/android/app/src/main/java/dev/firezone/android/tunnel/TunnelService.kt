@@ -244,6 +244,7 @@ class TunnelService : VpnService() {
                     osVersion = Build.VERSION.RELEASE,
                     logDir = getLogDir(),
                     logFilter = config.logFilter,
+                    keepaliveSecs = KEEPALIVE_SECS,
                     callback = callback,
                 )
 
@@ -389,6 +390,9 @@ class TunnelService : VpnService() {
         private const val SESSION_NAME: String = "Firezone Connection"
         private const val MTU: Int = 1280
 
+        // How often connlib sends a keep-alive to gateways on idle connections, 0 disables them.
+        private const val KEEPALIVE_SECS: Int = 10
+
         private val MANAGED_CONFIGURATIONS = arrayOf("token", "allowedApplications", "disallowedApplications", "deviceName")
 
         // FIXME: Find another way to check if we're running
@@ -5,15 +5,15 @@
 
 use backoff::ExponentialBackoffBuilder;
 use connlib_client_shared::{
-    callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, Error, LoginUrl,
-    LoginUrlError, Session, Tun, V4RouteList, V6RouteList,
+    callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, ConnectionConfig,
+    Error, LoginUrl, LoginUrlError, Session, Tun, V4RouteList, V6RouteList,
 };
 use connlib_shared::get_user_agent;
 use ip_network::{Ipv4Network, Ipv6Network};
 use jni::{
     objects::{GlobalRef, JClass, JObject, JString, JValue},
     strings::JNIString,
-    sys::jlong,
+    sys::{jint, jlong},
     JNIEnv, JavaVM,
 };
 use phoenix_channel::PhoenixChannel;
@@ -330,6 +330,7 @@ fn connect(
     os_version: JString,
     log_dir: JString,
     log_filter: JString,
+    keepalive_secs: jint,
     callback_handler: GlobalRef,
 ) -> Result<SessionWrapper, ConnectError> {
     let api_url = string_from_jstring!(env, api_url);
@@ -370,6 +371,15 @@ fn connect(
         udp_socket_factory: Arc::new(protected_udp_socket_factory(callbacks.clone())),
         private_key,
         callbacks,
+        connection_config: ConnectionConfig {
+            // Like in wireguard, `0` disables keep-alives.
+            keepalive: u64::try_from(keepalive_secs)
+                .ok()
+                .filter(|secs| *secs != 0)
+                .map(Duration::from_secs),
+            ..ConnectionConfig::default()
+        },
+        session_cache: None,
     };
     let portal = PhoenixChannel::connect(
         Secret::new(url),
@@ -404,6 +414,7 @@ pub unsafe extern "system" fn Java_dev_firezone_android_tunnel_ConnlibSession_co
     os_version: JString,
     log_dir: JString,
     log_filter: JString,
+    keepalive_secs: jint,
     callback_handler: JObject,
 ) -> *const SessionWrapper {
     let Ok(callback_handler) = env.new_global_ref(callback_handler) else {
@@ -420,6 +431,7 @@ pub unsafe extern "system" fn Java_dev_firezone_android_tunnel_ConnlibSession_co
             os_version,
             log_dir,
             log_filter,
+            keepalive_secs,
             callback_handler,
         )
     });
@@ -5,8 +5,8 @@ mod make_writer;
 
 use backoff::ExponentialBackoffBuilder;
 use connlib_client_shared::{
-    callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, Error, LoginUrl,
-    Session, Tun, V4RouteList, V6RouteList,
+    callbacks::ResourceDescription, file_logger, keypair, Callbacks, ConnectArgs, ConnectionConfig,
+    Error, LoginUrl, Session, Tun, V4RouteList, V6RouteList,
 };
 use connlib_shared::get_user_agent;
 use ip_network::{Ipv4Network, Ipv6Network};
@@ -46,6 +46,7 @@ mod ffi {
             os_version_override: Option<String>,
             log_dir: String,
             log_filter: String,
+            keepalive_secs: u16,
             callback_handler: CallbackHandler,
         ) -> Result<WrappedSession, String>;
 
@@ -173,6 +174,7 @@ impl WrappedSession {
         os_version_override: Option<String>,
         log_dir: String,
         log_filter: String,
+        keepalive_secs: u16,
         callback_handler: ffi::CallbackHandler,
     ) -> Result<Self, String> {
         let logger = init_logging(log_dir.into(), log_filter).map_err(|e| e.to_string())?;
@@ -203,6 +205,13 @@ impl WrappedSession {
             },
             tcp_socket_factory: Arc::new(socket_factory::tcp),
             udp_socket_factory: Arc::new(socket_factory::udp),
+            connection_config: ConnectionConfig {
+                // Like in wireguard, `0` disables keep-alives.
+                keepalive: (keepalive_secs != 0)
+                    .then(|| Duration::from_secs(keepalive_secs.into())),
+                ..ConnectionConfig::default()
+            },
+            session_cache: None,
         };
         let portal = PhoenixChannel::connect(
             Secret::new(url),
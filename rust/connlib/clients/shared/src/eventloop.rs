@@ -1,22 +1,36 @@
 use crate::{
     messages::{
-        Connect, ConnectionDetails, EgressMessages, GatewayIceCandidates, GatewaysIceCandidates,
-        IngressMessages, InitClient, ReplyMessages,
+        AccessExpired, Connect, ConnectionDetails, EgressMessages, GatewayIceCandidates,
+        GatewaysIceCandidates, IngressMessages, InitClient, ReplyMessages,
     },
-    PHOENIX_TOPIC,
+    session_cache::Snapshot,
+    SessionCache, PHOENIX_TOPIC,
 };
 use anyhow::Result;
 use connlib_shared::{
-    messages::{ConnectionAccepted, GatewayResponse, RelaysPresence, ResourceAccepted, ResourceId},
+    messages::{
+        ConnectionAccepted, DomainResponse, GatewayId, GatewayResponse, RelaysPresence,
+        ResourceAccepted, ResourceId,
+    },
     Callbacks,
 };
-use firezone_tunnel::{ClientTunnel, Tun};
+use firezone_tunnel::{ClientTunnel, ConnectionStats, DnsResourceStats, Tun};
 use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixChannel};
 use std::{
     collections::{HashMap, HashSet},
+    future::Future as _,
+    io,
     net::IpAddr,
+    pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
+use tokio::task::JoinHandle;
+
+/// How often we save the session cache, if enabled and it changed.
+///
+/// Frequent saves mean we remember recently established connections even if we get killed.
+const SAVE_SESSION_CACHE_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct Eventloop<C: Callbacks> {
     tunnel: ClientTunnel,
@@ -26,6 +40,11 @@ pub struct Eventloop<C: Callbacks> {
     rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
 
     connection_intents: SentConnectionIntents,
+
+    session_cache: Option<SessionCache>,
+    save_session_cache_interval: tokio::time::Interval,
+    /// Writing the session cache to disk happens on a blocking thread.
+    saving_session_cache: Option<JoinHandle<io::Result<()>>>,
 }
 
 /// Commands that can be sent to the [`Eventloop`].
@@ -34,6 +53,9 @@ pub enum Command {
     Reconnect,
     SetDns(Vec<IpAddr>),
     SetTun(Tun),
+    SetDnsQueryLog(bool),
+    DnsResourceStats(tokio::sync::oneshot::Sender<HashMap<String, DnsResourceStats>>),
+    ConnectionStats(tokio::sync::oneshot::Sender<HashMap<GatewayId, ConnectionStats>>),
 }
 
 impl<C: Callbacks> Eventloop<C> {
@@ -42,6 +64,7 @@ impl<C: Callbacks> Eventloop<C> {
         callbacks: C,
         portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
         rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
+        session_cache: Option<SessionCache>,
     ) -> Self {
         Self {
             tunnel,
@@ -49,6 +72,9 @@ impl<C: Callbacks> Eventloop<C> {
             connection_intents: SentConnectionIntents::default(),
             rx,
             callbacks,
+            session_cache,
+            save_session_cache_interval: tokio::time::interval(SAVE_SESSION_CACHE_INTERVAL),
+            saving_session_cache: None,
         }
     }
 }
@@ -60,7 +86,11 @@ where
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), phoenix_channel::Error>> {
         loop {
             match self.rx.poll_recv(cx) {
-                Poll::Ready(Some(Command::Stop)) | Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Command::Stop)) | Poll::Ready(None) => {
+                    self.save_session_cache_before_exit();
+
+                    return Poll::Ready(Ok(()));
+                }
                 Poll::Ready(Some(Command::SetDns(dns))) => {
                     self.tunnel.set_new_dns(dns);
 
@@ -70,6 +100,18 @@ where
                     self.tunnel.set_tun(tun);
                     continue;
                 }
+                Poll::Ready(Some(Command::SetDnsQueryLog(enabled))) => {
+                    self.tunnel.set_dns_query_log(enabled);
+                    continue;
+                }
+                Poll::Ready(Some(Command::DnsResourceStats(tx))) => {
+                    let _ = tx.send(self.tunnel.dns_resource_stats());
+                    continue;
+                }
+                Poll::Ready(Some(Command::ConnectionStats(tx))) => {
+                    let _ = tx.send(self.tunnel.connection_stats());
+                    continue;
+                }
                 Poll::Ready(Some(Command::Reconnect)) => {
                     self.portal.reconnect();
                     if let Err(e) = self.tunnel.reset() {
@@ -101,10 +143,73 @@ where
                 Poll::Pending => {}
             }
 
+            if let Some(saving) = self.saving_session_cache.as_mut() {
+                if let Poll::Ready(result) = Pin::new(saving).poll(cx) {
+                    self.saving_session_cache = None;
+                    self.handle_session_cache_saved(result);
+                    continue;
+                }
+            }
+
+            if self.session_cache.is_some()
+                && self.saving_session_cache.is_none()
+                && self.save_session_cache_interval.poll_tick(cx).is_ready()
+            {
+                self.save_session_cache();
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
 
+    fn save_session_cache(&mut self) {
+        let Some(snapshot) = self.session_cache_snapshot() else {
+            return;
+        };
+
+        self.saving_session_cache = Some(tokio::task::spawn_blocking(move || snapshot.write()));
+    }
+
+    /// Saves the session cache one last time.
+    ///
+    /// We are about to exit, so we don't mind blocking here.
+    /// A write that is still in progress on a blocking thread won't overwrite this one because it is older.
+    fn save_session_cache_before_exit(&mut self) {
+        let Some(snapshot) = self.session_cache_snapshot() else {
+            return;
+        };
+
+        if let Err(e) = snapshot.write() {
+            tracing::warn!("Failed to save session cache: {e}");
+        }
+    }
+
+    fn session_cache_snapshot(&mut self) -> Option<Snapshot> {
+        let cache = self.session_cache.as_mut()?;
+        cache.update_gateway_candidates(&self.tunnel);
+
+        cache.snapshot()
+    }
+
+    fn handle_session_cache_saved(
+        &mut self,
+        result: Result<io::Result<()>, tokio::task::JoinError>,
+    ) {
+        let e = match result {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        tracing::warn!("Failed to save session cache: {e}");
+
+        // Try again with the next save.
+        if let Some(cache) = self.session_cache.as_mut() {
+            cache.mark_dirty();
+        }
+    }
+
     fn handle_tunnel_event(&mut self, event: firezone_tunnel::ClientEvent) {
         match event {
             firezone_tunnel::ClientEvent::AddedIceCandidates {
@@ -171,6 +276,9 @@ where
             firezone_tunnel::ClientEvent::TunRoutesUpdated { ip4, ip6 } => {
                 self.callbacks.on_update_routes(ip4, ip6);
             }
+            firezone_tunnel::ClientEvent::DnsQueryLogged { entry } => {
+                self.callbacks.on_dns_query(entry);
+            }
         }
     }
 
@@ -226,7 +334,13 @@ where
 
                 tracing::info!("Firezone Started!");
                 self.tunnel.set_resources(resources);
-                self.tunnel.update_relays(HashSet::default(), relays)
+
+                let stale_relays = self
+                    .session_cache
+                    .as_mut()
+                    .map(|cache| cache.set_relays(&relays))
+                    .unwrap_or_default();
+                self.tunnel.update_relays(stale_relays, relays)
             }
             IngressMessages::ResourceCreatedOrUpdated(resource) => {
                 self.tunnel.add_resource(resource);
@@ -237,9 +351,15 @@ where
             IngressMessages::RelaysPresence(RelaysPresence {
                 disconnected_ids,
                 connected,
-            }) => self
-                .tunnel
-                .update_relays(HashSet::from_iter(disconnected_ids), connected),
+            }) => {
+                let disconnected_ids = HashSet::from_iter(disconnected_ids);
+
+                if let Some(cache) = self.session_cache.as_mut() {
+                    cache.update_relays(&disconnected_ids, &connected);
+                }
+
+                self.tunnel.update_relays(disconnected_ids, connected)
+            }
             IngressMessages::InvalidateIceCandidates(GatewayIceCandidates {
                 gateway_id,
                 candidates,
@@ -248,6 +368,10 @@ where
                     self.tunnel.remove_ice_candidate(gateway_id, candidate)
                 }
             }
+            IngressMessages::AccessExpired(AccessExpired {
+                gateway_id,
+                resource_id,
+            }) => self.tunnel.on_access_expired(gateway_id, resource_id),
         }
     }
 
@@ -255,11 +379,23 @@ where
         match res {
             ReplyMessages::Connect(Connect {
                 gateway_payload:
-                    GatewayResponse::ConnectionAccepted(ConnectionAccepted { ice_parameters, .. }),
+                    GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                        ice_parameters,
+                        domain_response,
+                    }),
                 gateway_public_key,
                 resource_id,
                 ..
             }) => {
+                if let Some(DomainResponse {
+                    domain,
+                    error: Some(error),
+                    ..
+                }) = domain_response
+                {
+                    tracing::warn!(%resource_id, %domain, "Gateway failed to resolve domain: {error}");
+                }
+
                 if let Err(e) = self.tunnel.received_offer_response(
                     resource_id,
                     ice_parameters,
@@ -269,9 +405,15 @@ where
                 }
             }
             ReplyMessages::Connect(Connect {
-                gateway_payload: GatewayResponse::ResourceAccepted(ResourceAccepted { .. }),
+                gateway_payload:
+                    GatewayResponse::ResourceAccepted(ResourceAccepted { domain_response }),
+                resource_id,
                 ..
             }) => {
+                if let Some(error) = domain_response.error {
+                    tracing::warn!(%resource_id, domain = %domain_response.domain, "Gateway failed to resolve domain: {error}");
+                }
+
                 tracing::trace!("Connection response received, ignored as it's deprecated")
             }
             ReplyMessages::ConnectionDetails(ConnectionDetails {
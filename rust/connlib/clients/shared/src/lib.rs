@@ -5,9 +5,13 @@ pub use connlib_shared::{
     callbacks, keypair, Callbacks, Error, LoginUrl, LoginUrlError, StaticSecret,
 };
 pub use eventloop::Eventloop;
-pub use firezone_tunnel::Tun;
+pub use firezone_tunnel::{
+    CandidatePair, CandidateType, ConnectionConfig, ConnectionStats, DnsResourceStats, Tun,
+};
+pub use session_cache::SessionCache;
 pub use tracing_appender::non_blocking::WorkerGuard;
 
+use connlib_shared::messages::GatewayId;
 use eventloop::Command;
 use firezone_tunnel::ClientTunnel;
 use messages::{IngressMessages, ReplyMessages};
@@ -23,6 +27,7 @@ mod eventloop;
 pub mod file_logger;
 mod messages;
 mod serde_routelist;
+mod session_cache;
 
 const PHOENIX_TOPIC: &str = "client";
 
@@ -40,6 +45,12 @@ pub struct ConnectArgs<CB> {
     pub udp_socket_factory: Arc<dyn SocketFactory<tokio::net::UdpSocket>>,
     pub private_key: StaticSecret,
     pub callbacks: CB,
+    /// The idle timeout and keep-alive of our connections to gateways.
+    pub connection_config: ConnectionConfig,
+    /// If set, the tunnel is seeded from this cache and regularly saves its state to it.
+    ///
+    /// `private_key` should be [`SessionCache::private_key`] for the remembered state to be useful.
+    pub session_cache: Option<SessionCache>,
 }
 
 impl Session {
@@ -99,6 +110,33 @@ impl Session {
         let _ = self.channel.send(Command::SetTun(new_tun));
     }
 
+    /// Enables or disables the DNS query log.
+    ///
+    /// If enabled, [`Callbacks::on_dns_query`] is called for every DNS query that connlib answers.
+    pub fn set_dns_query_log(&self, enabled: bool) {
+        let _ = self.channel.send(Command::SetDnsQueryLog(enabled));
+    }
+
+    /// Returns the counters of the DNS queries for each DNS resource, indexed by the resource's address.
+    ///
+    /// Returns `None` if the session has already stopped.
+    pub async fn dns_resource_stats(&self) -> Option<HashMap<String, DnsResourceStats>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.channel.send(Command::DnsResourceStats(tx)).ok()?;
+
+        rx.await.ok()
+    }
+
+    /// Returns the statistics of the connection to each gateway, e.g. its candidate pair, RTT and traffic.
+    ///
+    /// Returns `None` if the session has already stopped.
+    pub async fn connection_stats(&self) -> Option<HashMap<GatewayId, ConnectionStats>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.channel.send(Command::ConnectionStats(tx)).ok()?;
+
+        rx.await.ok()
+    }
+
     /// Disconnect a [`Session`].
     ///
     /// This consumes [`Session`] which cleans up all state associated with it.
@@ -123,16 +161,23 @@ where
         callbacks,
         udp_socket_factory,
         tcp_socket_factory,
+        connection_config,
+        session_cache,
     } = args;
 
-    let tunnel = ClientTunnel::new(
+    let mut tunnel = ClientTunnel::new(
         private_key,
         tcp_socket_factory,
         udp_socket_factory,
         HashMap::from([(portal.server_host().to_owned(), portal.resolved_addresses())]),
     )?;
+    tunnel.set_connection_config(connection_config);
+
+    if let Some(session_cache) = &session_cache {
+        session_cache.seed(&mut tunnel);
+    }
 
-    let mut eventloop = Eventloop::new(tunnel, callbacks, portal, rx);
+    let mut eventloop = Eventloop::new(tunnel, callbacks, portal, rx, session_cache);
 
     std::future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -225,6 +270,8 @@ mod tests {
             ipv4: [100, 71, 96, 96].into(),
             ipv6: [0xfd00, 0x2021, 0x1111, 0x0, 0x0, 0x0, 0x0019, 0x6538].into(),
             upstream_dns,
+            proxy_ip_lease: None,
+            dns_forwarding_rules: vec![],
         };
         tunnel.set_tun(Tun::new().unwrap());
         tunnel.set_new_interface_config(interface).unwrap();
@@ -51,6 +51,9 @@ pub enum IngressMessages {
     IceCandidates(GatewayIceCandidates),
     InvalidateIceCandidates(GatewayIceCandidates),
 
+    /// A gateway no longer grants us access to a resource.
+    AccessExpired(AccessExpired),
+
     ConfigChanged(ConfigUpdate),
 
     RelaysPresence(RelaysPresence),
@@ -72,6 +75,12 @@ pub struct GatewayIceCandidates {
     pub candidates: Vec<String>,
 }
 
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct AccessExpired {
+    pub gateway_id: GatewayId,
+    pub resource_id: ResourceId,
+}
+
 /// The replies that can arrive from the channel by a client
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(untagged)]
@@ -104,7 +113,8 @@ mod test {
     use chrono::DateTime;
     use connlib_shared::messages::{
         client::{ResourceDescriptionCidr, ResourceDescriptionDns, Site},
-        DnsServer, IpDnsServer, Turn,
+        DnsForwardingRule, DnsResolutionError, DnsServer, HttpsDnsServer, IpDnsServer,
+        TlsDnsServer, Turn,
     };
     use phoenix_channel::{OutboundRequestId, PhoenixMessage};
 
@@ -146,6 +156,19 @@ mod test {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn access_expired_message() {
+        let msg = r#"{"event":"access_expired","ref":null,"topic":"client","payload":{"gateway_id":"2b1524e6-239e-4570-bc73-70a188e12101","resource_id":"ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b"}}"#;
+        let expected = IngressMessages::AccessExpired(AccessExpired {
+            gateway_id: "2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap(),
+            resource_id: "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b".parse().unwrap(),
+        });
+
+        let actual = serde_json::from_str::<IngressMessages>(msg).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn connection_ready_deserialization() {
         let message = r#"{
@@ -180,6 +203,34 @@ mod test {
             serde_json::from_str(message).unwrap();
     }
 
+    #[test]
+    fn connection_ready_with_dns_resolution_error() {
+        let message = r#"{
+            "resource_id": "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b",
+            "gateway_public_key": "dvy0IwyxAi+txSbAdT7WKgf7K4TekhKzrnYwt5WfbSM=",
+            "gateway_payload": {
+                "ResourceAccepted":{
+                    "domain_response":{
+                        "address":[],
+                        "domain":"gitlab.mycorp.internal",
+                        "error":"nx_domain"
+                    }
+                }
+            },
+            "persistent_keepalive": 25
+        }"#;
+
+        let connect = serde_json::from_str::<Connect>(message).unwrap();
+
+        let GatewayResponse::ResourceAccepted(accepted) = connect.gateway_payload else {
+            panic!("Unexpected gateway payload")
+        };
+        assert_eq!(
+            accepted.domain_response.error,
+            Some(DnsResolutionError::NxDomain)
+        );
+    }
+
     #[test]
     fn config_updated() {
         let m = PhoenixMessage::new_message(
@@ -191,6 +242,8 @@ mod test {
                     upstream_dns: vec![DnsServer::IpPort(IpDnsServer {
                         address: "1.1.1.1:53".parse().unwrap(),
                     })],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
             }),
             None,
@@ -219,6 +272,107 @@ mod test {
         assert_eq!(m, ingress_message);
     }
 
+    #[test]
+    fn config_updated_with_dns_forwarding_rules() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![DnsForwardingRule {
+                        domain: "*.corp.internal".to_owned(),
+                        server: DnsServer::IpPort(IpDnsServer {
+                            address: "10.0.0.53:53".parse().unwrap(),
+                        }),
+                    }],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "dns_forwarding_rules": [
+                  {
+                    "domain": "*.corp.internal",
+                    "server": {
+                      "protocol": "ip_port",
+                      "address": "10.0.0.53:53"
+                    }
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
+    #[test]
+    fn config_updated_with_encrypted_upstream_dns() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![
+                        DnsServer::Tls(TlsDnsServer {
+                            address: "1.1.1.1:853".parse().unwrap(),
+                            server_name: "one.one.one.one".to_owned(),
+                        }),
+                        DnsServer::Https(HttpsDnsServer {
+                            address: "9.9.9.9:443".parse().unwrap(),
+                            server_name: "dns.quad9.net".to_owned(),
+                        }),
+                    ],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "upstream_dns": [
+                  {
+                    "protocol": "tls",
+                    "address": "1.1.1.1:853",
+                    "server_name": "one.one.one.one"
+                  },
+                  {
+                    "protocol": "https",
+                    "address": "9.9.9.9:443",
+                    "server_name": "dns.quad9.net"
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
     #[test]
     fn init_phoenix_message() {
         let m = PhoenixMessage::new_message(
@@ -228,6 +382,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -253,6 +409,7 @@ mod test {
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
                         address: "gitlab.mycorp.com".to_string(),
+                        excluded_addresses: vec![],
                         name: "gitlab.mycorp.com".to_string(),
                         address_description: Some("dns resource".to_string()),
                         sites: vec![Site {
@@ -263,6 +420,7 @@ mod test {
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dce".parse().unwrap(),
                         address: "github.mycorp.com".to_string(),
+                        excluded_addresses: vec![],
                         name: "github.mycorp.com".to_string(),
                         address_description: None,
                         sites: vec![Site {
@@ -336,6 +494,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -351,6 +511,7 @@ mod test {
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
                         address: "gitlab.mycorp.com".to_string(),
+                        excluded_addresses: vec![],
                         name: "gitlab.mycorp.com".to_string(),
                         address_description: Some("dns resource".to_string()),
                         sites: vec![Site {
@@ -412,6 +573,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -446,6 +609,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -480,6 +645,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -514,6 +681,8 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
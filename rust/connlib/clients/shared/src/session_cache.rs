@@ -0,0 +1,424 @@
+//! An opt-in, on-disk cache for resuming connections quickly after a restart.
+//!
+//! Without it, every restart of a client starts from scratch:
+//! a new private key, no knowledge of how to reach the gateways and no relay allocations until the portal sent us its relays.
+//!
+//! The cache persists:
+//! - the private key of this device
+//! - the candidate of each gateway that our last connection to it was using
+//! - the relays we were using, including their TURN credentials
+//!
+//! Because it contains secrets, the file is only readable by its owner.
+//! We can only ensure that on Unix, hence the cache cannot be enabled on other platforms.
+
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{GatewayId, Key, Relay, RelayId};
+use connlib_shared::{keypair, PublicKey, StaticSecret};
+use firezone_tunnel::ClientTunnel;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub struct SessionCache {
+    path: PathBuf,
+    private_key: StaticSecret,
+    gateway_candidates: HashMap<GatewayId, Vec<String>>,
+    relays: Vec<Relay>,
+
+    /// Whether the cache changed since we took the last [`Snapshot`].
+    dirty: bool,
+    /// The generation of the next [`Snapshot`].
+    generation: u64,
+    /// The generation of the last [`Snapshot`] that was written to disk.
+    ///
+    /// Snapshots are written on a blocking thread and this makes sure an older one never overwrites a newer one.
+    written: Arc<Mutex<u64>>,
+}
+
+impl SessionCache {
+    /// Loads the session cache from the given path.
+    ///
+    /// If the file is missing or cannot be parsed, the cache starts out empty with a freshly generated private key.
+    /// Nothing is written to disk until the session saves the cache for the first time.
+    ///
+    /// Fails on platforms where we cannot restrict access to the file to its owner.
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        if cfg!(not(unix)) {
+            return Err(unsupported());
+        }
+
+        let path = path.into();
+
+        match std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<SessionCacheJson>(&s).map_err(|e| e.to_string()))
+        {
+            Ok(json) => {
+                tracing::debug!(path = %path.display(), num_gateways = %json.gateway_candidates.len(), num_relays = %json.relays.len(), "Loaded session cache");
+
+                Ok(Self {
+                    path,
+                    private_key: StaticSecret::from(json.private_key.0),
+                    gateway_candidates: json.gateway_candidates,
+                    relays: json.relays,
+                    dirty: false,
+                    generation: 1,
+                    written: Arc::default(),
+                })
+            }
+            Err(e) => {
+                tracing::debug!(path = %path.display(), "Starting with empty session cache: {e}");
+
+                let (private_key, _) = keypair();
+
+                Ok(Self {
+                    path,
+                    private_key,
+                    gateway_candidates: HashMap::default(),
+                    relays: Vec::default(),
+                    dirty: true, // We need to persist the new private key.
+                    generation: 1,
+                    written: Arc::default(),
+                })
+            }
+        }
+    }
+
+    pub fn private_key(&self) -> StaticSecret {
+        self.private_key.clone()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.private_key)
+    }
+
+    /// Seeds the tunnel with the remembered gateway candidates and all relays whose credentials are still valid.
+    pub(crate) fn seed(&self, tunnel: &mut ClientTunnel) {
+        for (gateway, candidates) in &self.gateway_candidates {
+            tunnel.remember_gateway_candidates(*gateway, candidates.clone());
+        }
+
+        let relays = self.unexpired_relays(Utc::now());
+
+        if relays.is_empty() {
+            return;
+        }
+
+        tracing::debug!(num_relays = %relays.len(), "Using relays from session cache");
+
+        tunnel.update_relays(HashSet::default(), relays);
+    }
+
+    /// Replaces the cached relays with the ones from the portal.
+    ///
+    /// Returns the IDs of previously cached relays that the portal no longer knows about.
+    /// The tunnel may have allocations on those from [`SessionCache::seed`] and needs to remove them.
+    pub(crate) fn set_relays(&mut self, relays: &[Relay]) -> HashSet<RelayId> {
+        let new = relays.iter().map(relay_id).collect::<HashSet<_>>();
+        let stale = self
+            .relays
+            .iter()
+            .map(relay_id)
+            .filter(|id| !new.contains(id))
+            .collect();
+
+        self.relays = relays.to_vec();
+        self.dirty = true;
+
+        stale
+    }
+
+    /// Applies an update of the relays' presence to the cached relays.
+    pub(crate) fn update_relays(&mut self, to_remove: &HashSet<RelayId>, to_add: &[Relay]) {
+        let updated = to_add.iter().map(relay_id).collect::<HashSet<_>>();
+
+        self.relays.retain(|r| {
+            let id = relay_id(r);
+
+            !to_remove.contains(&id) && !updated.contains(&id)
+        });
+        self.relays.extend_from_slice(to_add);
+        self.dirty = true;
+    }
+
+    /// Remembers the candidates that the tunnel's connections are currently using.
+    ///
+    /// Candidates of gateways we are not connected to right now are kept.
+    pub(crate) fn update_gateway_candidates(&mut self, tunnel: &ClientTunnel) {
+        for (gateway, candidate) in tunnel.gateway_candidates_in_use() {
+            let candidates = vec![candidate];
+
+            if self.gateway_candidates.get(&gateway) == Some(&candidates) {
+                continue;
+            }
+
+            self.gateway_candidates.insert(gateway, candidates);
+            self.dirty = true;
+        }
+    }
+
+    /// Takes a [`Snapshot`] of the cache if it changed since the last one.
+    pub(crate) fn snapshot(&mut self) -> Option<Snapshot> {
+        if !self.dirty {
+            return None;
+        }
+
+        let generation = self.generation;
+        self.generation += 1;
+        self.dirty = false;
+
+        Some(Snapshot {
+            path: self.path.clone(),
+            json: SessionCacheJson {
+                private_key: Key(self.private_key.to_bytes()),
+                gateway_candidates: self.gateway_candidates.clone(),
+                relays: self.relays.clone(),
+            },
+            generation,
+            written: self.written.clone(),
+        })
+    }
+
+    /// Makes sure the next [`SessionCache::snapshot`] is taken, e.g. because writing the last one failed.
+    pub(crate) fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    fn unexpired_relays(&self, now: DateTime<Utc>) -> Vec<Relay> {
+        self.relays
+            .iter()
+            .filter(|r| match r {
+                Relay::Stun(_) => true,
+                Relay::Turn(turn) => turn.expires_at > now,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// The state of a [`SessionCache`] at one point in time, to be written to disk.
+pub(crate) struct Snapshot {
+    path: PathBuf,
+    json: SessionCacheJson,
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl Snapshot {
+    /// Atomically writes the snapshot to disk, unless a newer one was already written.
+    ///
+    /// This does blocking I/O.
+    pub(crate) fn write(self) -> io::Result<()> {
+        let mut written = self
+            .written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if *written >= self.generation {
+            return Ok(());
+        }
+
+        let content = serde_json::to_vec(&self.json)?;
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        create_owner_only(&tmp)?.write_all(&content)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        *written = self.generation;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionCacheJson {
+    private_key: Key,
+    #[serde(default)]
+    gateway_candidates: HashMap<GatewayId, Vec<String>>,
+    #[serde(default)]
+    relays: Vec<Relay>,
+}
+
+fn relay_id(relay: &Relay) -> RelayId {
+    match relay {
+        Relay::Stun(stun) => stun.id,
+        Relay::Turn(turn) => turn.id,
+    }
+}
+
+#[cfg(unix)]
+fn create_owner_only(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    // The mode only applies to newly created files, a leftover file keeps its permissions.
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(file)
+}
+
+/// On non-Unix systems, the file would inherit the permissions of its directory, which may be readable by others.
+#[cfg(not(unix))]
+fn create_owner_only(_: &Path) -> io::Result<std::fs::File> {
+    Err(unsupported())
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The session cache is only supported on Unix",
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::{Stun, Turn};
+
+    #[test]
+    fn saved_cache_can_be_loaded() {
+        let path = std::env::temp_dir()
+            .join(format!("firezone-session-cache-{}", std::process::id()))
+            .join("session.json");
+        let gateway = "f2d1cab1-2a5e-4b4a-8c6a-0a4c0a6e0f7e".parse().unwrap();
+
+        let mut cache = SessionCache::load(&path).unwrap();
+        cache.gateway_candidates.insert(
+            gateway,
+            vec!["candidate:1 1 udp 2130706431 1.1.1.1 80 typ host".to_owned()],
+        );
+        cache.set_relays(&[turn(1, Utc::now())]);
+        cache.snapshot().unwrap().write().unwrap();
+
+        let loaded = SessionCache::load(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(loaded.public_key(), cache.public_key());
+        assert_eq!(loaded.gateway_candidates, cache.gateway_candidates);
+        assert_eq!(loaded.relays.len(), 1);
+    }
+
+    #[test]
+    fn saved_cache_is_only_accessible_by_owner_even_if_stale_tmp_file_exists() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = std::env::temp_dir().join(format!(
+            "firezone-session-cache-mode-{}",
+            std::process::id()
+        ));
+        let path = dir.join("session.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(path.with_extension("tmp"), b"stale").unwrap();
+        std::fs::set_permissions(
+            path.with_extension("tmp"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        let mut cache = SessionCache::load(&path).unwrap();
+        cache.snapshot().unwrap().write().unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn only_takes_snapshot_if_cache_changed() {
+        let mut cache = SessionCache::load(PathBuf::new()).unwrap();
+        assert!(cache.snapshot().is_some(), "new private key must be saved");
+        assert!(cache.snapshot().is_none());
+
+        cache.update_relays(&HashSet::default(), &[stun(1)]);
+
+        assert!(cache.snapshot().is_some());
+    }
+
+    #[test]
+    fn older_snapshot_does_not_overwrite_newer_one() {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "firezone-session-cache-order-{}",
+                std::process::id()
+            ))
+            .join("session.json");
+
+        let mut cache = SessionCache::load(&path).unwrap();
+        let older = cache.snapshot().unwrap();
+        cache.set_relays(&[stun(1)]);
+        let newer = cache.snapshot().unwrap();
+
+        newer.write().unwrap();
+        older.write().unwrap();
+
+        let loaded = SessionCache::load(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(loaded.relays.len(), 1);
+    }
+
+    #[test]
+    fn only_seeds_relays_with_valid_credentials() {
+        let now = Utc::now();
+        let mut cache = SessionCache::load(PathBuf::new()).unwrap();
+
+        cache.set_relays(&[
+            stun(1),
+            turn(2, now + chrono::Duration::hours(1)),
+            turn(3, now - chrono::Duration::hours(1)),
+        ]);
+
+        let relays = cache.unexpired_relays(now);
+
+        assert_eq!(
+            relays.iter().map(relay_id).collect::<Vec<_>>(),
+            vec![relay(1), relay(2)]
+        );
+    }
+
+    #[test]
+    fn returns_stale_relays_when_portal_sends_new_ones() {
+        let mut cache = SessionCache::load(PathBuf::new()).unwrap();
+        cache.set_relays(&[stun(1), stun(2)]);
+
+        let stale = cache.set_relays(&[stun(2), stun(3)]);
+
+        assert_eq!(stale, HashSet::from([relay(1)]));
+    }
+
+    fn stun(id: u8) -> Relay {
+        Relay::Stun(Stun {
+            id: relay(id),
+            addr: "1.1.1.1:3478".parse().unwrap(),
+        })
+    }
+
+    fn turn(id: u8, expires_at: DateTime<Utc>) -> Relay {
+        Relay::Turn(Turn {
+            id: relay(id),
+            expires_at,
+            addr: "1.1.1.1:3478".parse().unwrap(),
+            username: "1719367575:ZQHcVGkdnfgGmcP1".to_owned(),
+            password: "ZWYiBeFHOJyYq0mcwAXjRpcuXIkwPmCtyocO7SD9TOk".to_owned(),
+        })
+    }
+
+    fn relay(id: u8) -> RelayId {
+        format!("00000000-0000-0000-0000-0000000000{id:02}")
+            .parse()
+            .unwrap()
+    }
+}
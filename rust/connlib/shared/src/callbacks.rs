@@ -2,7 +2,8 @@ use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::Debug;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 use crate::messages::client::Site;
 use crate::messages::ResourceId;
@@ -116,6 +117,38 @@ pub struct ResourceDescriptionInternet {
     pub status: Status,
 }
 
+/// A DNS query that connlib answered.
+///
+/// Only emitted if the DNS query log is enabled, see [`Callbacks::on_dns_query`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DnsQueryLogEntry {
+    /// The queried domain.
+    pub name: String,
+    /// The queried record type, e.g. `A` or `SRV`.
+    pub record_type: String,
+    /// The resource the queried domain matched, if any.
+    pub resource: Option<ResourceId>,
+    /// How the query was answered.
+    pub resolution: DnsResolution,
+    /// The RCODE of the response, e.g. `NOERROR` or `NXDOMAIN`.
+    pub response_code: String,
+    /// How long it took to answer the query.
+    pub latency: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DnsResolution {
+    /// connlib answered the query itself, e.g. with the proxy IPs of a resource.
+    Local,
+    /// The query was forwarded to an upstream DNS server.
+    Upstream { server: SocketAddr },
+    /// The query was answered from our cache of responses from upstream DNS servers.
+    Cache,
+    /// The query was forwarded to the gateway of the matched resource.
+    Gateway,
+}
+
 /// Traits that will be used by connlib to callback the client upper layers.
 pub trait Callbacks: Clone + Send + Sync {
     /// Called when the tunnel address is set.
@@ -127,6 +160,9 @@ pub trait Callbacks: Clone + Send + Sync {
     /// Called when the resource list changes.
     fn on_update_resources(&self, _: Vec<ResourceDescription>) {}
 
+    /// Called for every DNS query that connlib answered, if the DNS query log is enabled.
+    fn on_dns_query(&self, _: DnsQueryLogEntry) {}
+
     /// Called when the tunnel is disconnected.
     ///
     /// If the tunnel disconnected due to a fatal error, `error` is the error
@@ -45,6 +45,12 @@ pub enum ConnlibError {
     /// Exhausted nat table
     #[error("exhausted nat")]
     ExhaustedNat,
+    /// The client reached its max. number of NAT sessions.
+    #[error("Too many NAT sessions")]
+    TooManyNatSessions,
+    /// All backends of a load-balanced resource are unhealthy.
+    #[error("No healthy backend")]
+    NoHealthyBackend,
     #[error(transparent)]
     UnsupportedProtocol(ip_packet::UnsupportedProtocol),
     // TODO: we might want to log some extra parameters on these failed translations
@@ -74,6 +80,19 @@ pub enum ConnlibError {
     #[error("Destination not allowed: {dst}")]
     DstNotAllowed { dst: IpAddr },
 
+    #[error("Packet from {src} doesn't belong to a flow opened by the client")]
+    UnsolicitedPacket { src: IpAddr },
+
+    #[error("Rate limit exceeded")]
+    RateLimitExceeded,
+
+    /// The client reached its max. number of open flows.
+    #[error("Too many flows")]
+    TooManyFlows,
+
+    #[error("DNS query not allowed: {name}")]
+    DnsQueryNotAllowed { name: String },
+
     // Error variants for `systemd-resolved` DNS control
     #[error("Failed to control system DNS with `resolvectl`")]
     ResolvectlFailed,
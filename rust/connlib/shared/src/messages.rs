@@ -135,7 +135,9 @@ impl fmt::Display for RelayId {
 /// Represents a wireguard peer.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Peer {
-    /// Keepalive: How often to send a keep alive message.
+    /// Keepalive: How often to send a keep alive message, in seconds.
+    ///
+    /// Only set if an admin overrode the keep-alive, `0` disables it.
     pub persistent_keepalive: Option<u16>,
     /// Peer's public key.
     pub public_key: Key,
@@ -228,6 +230,36 @@ pub struct Offer {
 pub struct DomainResponse {
     pub domain: DomainName,
     pub address: Vec<IpAddr>,
+    /// Set if the gateway failed to resolve [`DomainResponse::domain`].
+    ///
+    /// In that case, [`DomainResponse::address`] is empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<DnsResolutionError>,
+}
+
+/// Why the gateway failed to resolve the domain of a DNS resource.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsResolutionError {
+    /// The domain does not exist.
+    NxDomain,
+    /// The domain exists but has no A or AAAA records.
+    NoRecords,
+    /// The upstream servers did not answer in time.
+    Timeout,
+    /// The upstream servers failed to answer the query.
+    ServerFailure,
+}
+
+impl fmt::Display for DnsResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsResolutionError::NxDomain => write!(f, "domain does not exist"),
+            DnsResolutionError::NoRecords => write!(f, "no A or AAAA records"),
+            DnsResolutionError::Timeout => write!(f, "timed out"),
+            DnsResolutionError::ServerFailure => write!(f, "upstream server failure"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -247,22 +279,26 @@ pub enum GatewayResponse {
     ResourceAccepted(ResourceAccepted),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum DnsServer {
     IpPort(IpDnsServer),
+    /// DNS over TLS, see RFC 7858.
+    Tls(TlsDnsServer),
+    /// DNS over HTTPS, see RFC 8484.
+    Https(HttpsDnsServer),
 }
 
 impl DnsServer {
     pub fn ip(&self) -> IpAddr {
-        match self {
-            DnsServer::IpPort(s) => s.address.ip(),
-        }
+        self.address().ip()
     }
 
     pub fn address(&self) -> SocketAddr {
         match self {
             DnsServer::IpPort(s) => s.address,
+            DnsServer::Tls(s) => s.address,
+            DnsServer::Https(s) => s.address,
         }
     }
 }
@@ -283,6 +319,22 @@ pub struct IpDnsServer {
     pub address: SocketAddr,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct TlsDnsServer {
+    pub address: SocketAddr,
+    /// The name we expect in the server's certificate.
+    pub server_name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct HttpsDnsServer {
+    pub address: SocketAddr,
+    /// The name we expect in the server's certificate.
+    ///
+    /// This is also used as the `Host` of the HTTP requests, the queries are sent to `https://{server_name}/dns-query`.
+    pub server_name: String,
+}
+
 /// Represents a wireguard interface configuration.
 ///
 /// Note that the ips are /32 for ipv4 and /128 for ipv6.
@@ -297,10 +349,33 @@ pub struct Interface {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub upstream_dns: Vec<DnsServer>,
+    /// For how long (in seconds) a proxy IP stays assigned to a DNS resource after it has last been used.
+    ///
+    /// This is also the TTL of the DNS answers for DNS resources.
+    /// If unset, proxy IPs are never recycled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub proxy_ip_lease: Option<u32>,
+    /// DNS servers to use for specific domains instead of `upstream_dns` (or the system's resolvers).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub dns_forwarding_rules: Vec<DnsForwardingRule>,
+}
+
+/// Forwards DNS queries for a domain to a specific DNS server.
+///
+/// If the DNS server is a CIDR resource, the queries are routed through the tunnel and thus resolved within the resource's site.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct DnsForwardingRule {
+    /// The domain this rule applies to.
+    ///
+    /// Supports the same wildcards as the address of a DNS resource, e.g. `*.corp.internal`.
+    pub domain: String,
+    pub server: DnsServer,
 }
 
 /// A single relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Relay {
     /// STUN type of relay
@@ -310,7 +385,7 @@ pub enum Relay {
 }
 
 /// Represent a TURN relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Turn {
     pub id: RelayId,
     //// Expire time of the username/password in unix millisecond timestamp UTC
@@ -326,7 +401,7 @@ pub struct Turn {
 }
 
 /// Stun kind of relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Stun {
     pub id: RelayId,
 
@@ -360,6 +435,7 @@ mod tests {
             id: ResourceId::from_str(uuid).unwrap(),
             name: name.to_string(),
             address: "unused.example.com".to_string(),
+            excluded_addresses: vec![],
             address_description: Some("test description".to_string()),
             sites: vec![Site {
                 name: "test".to_string(),
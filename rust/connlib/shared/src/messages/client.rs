@@ -16,7 +16,14 @@ pub struct ResourceDescriptionDns {
     /// Resource's id.
     pub id: ResourceId,
     /// Internal resource's domain name.
+    ///
+    /// May contain wildcards, e.g. `*.mycorp.com`.
     pub address: String,
+    /// Domains that are not part of this resource even though they match [`ResourceDescriptionDns::address`].
+    ///
+    /// Same syntax as the address, e.g. `public.mycorp.com` or `*.dmz.mycorp.com`.
+    #[serde(default)]
+    pub excluded_addresses: Vec<String>,
     /// Name of the resource.
     ///
     /// Used only for display.
@@ -161,6 +168,7 @@ impl ResourceDescription {
         match (self, other) {
             (ResourceDescription::Dns(dns_a), ResourceDescription::Dns(dns_b)) => {
                 dns_a.address != dns_b.address
+                    || dns_a.excluded_addresses != dns_b.excluded_addresses
             }
             (ResourceDescription::Cidr(cidr_a), ResourceDescription::Cidr(cidr_b)) => {
                 cidr_a.address != cidr_b.address
@@ -1,6 +1,6 @@
 //! Gateway related messages that are needed within connlib
 
-use std::net::IpAddr;
+use std::{net::IpAddr, time::Duration};
 
 use ip_network::IpNetwork;
 use itertools::Itertools;
@@ -23,6 +23,12 @@ pub struct ResourceDescriptionDns {
     pub name: String,
 
     pub filters: Filters,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub port_mappings: Vec<PortMapping>,
+    #[serde(default)]
+    pub load_balancing: Option<LoadBalancing>,
 }
 
 /// Description of a resource that maps to a CIDR.
@@ -38,6 +44,10 @@ pub struct ResourceDescriptionCidr {
     pub name: String,
 
     pub filters: Filters,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub port_mappings: Vec<PortMapping>,
 }
 
 /// Description of a resource that maps to a DNS record which had its domain already resolved.
@@ -52,14 +62,23 @@ pub struct ResolvedResourceDescriptionDns {
     pub name: String,
 
     pub addresses: Vec<IpAddr>,
+    /// How long [`ResolvedResourceDescriptionDns::addresses`] are valid for, as per the TTL of the records.
+    ///
+    /// `None` if unknown, e.g. because resolution failed.
+    pub ttl: Option<Duration>,
 
     pub filters: Filters,
+    pub rate_limit: Option<RateLimit>,
+    pub port_mappings: Vec<PortMapping>,
+    pub load_balancing: Option<LoadBalancing>,
 }
 
 /// Description of an Internet resource.
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct ResourceDescriptionInternet {
     pub id: ResourceId,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -70,14 +89,194 @@ pub enum ResourceDescription<TDNS = ResourceDescriptionDns> {
     Internet(ResourceDescriptionInternet),
 }
 
+/// A token-bucket limit on how much traffic may pass through the gateway.
+///
+/// Either limit may be absent, in which case only the other one applies.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub bytes_per_second: Option<u64>,
+    /// How many bytes may be sent at once after being idle, defaults to [`RateLimit::bytes_per_second`].
+    ///
+    /// The gateway always allows at least one full-size packet, so a tiny limit slows traffic down instead of blocking it.
+    #[serde(default)]
+    pub burst_bytes: Option<u64>,
+    #[serde(default)]
+    pub packets_per_second: Option<u64>,
+    /// How many packets may be sent at once after being idle, defaults to [`RateLimit::packets_per_second`].
+    #[serde(default)]
+    pub burst_packets: Option<u64>,
+}
+
+/// Exposes a port of a resource to clients under a different port.
+///
+/// Clients connect to [`PortMapping::port`] and the gateway forwards the traffic to [`PortMapping::target_port`].
+/// The target port itself is not reachable by clients.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortMapping {
+    pub protocol: PortMappingProtocol,
+    /// The port clients connect to.
+    pub port: u16,
+    /// The port the service is actually listening on.
+    pub target_port: u16,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PortMappingProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Treats the addresses a DNS resource resolves to as a pool of backends.
+///
+/// Instead of mapping each proxy IP of a client to one address, the gateway picks a backend for every new flow.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoadBalancing {
+    pub strategy: LoadBalancingStrategy,
+    /// How to probe the backends, if absent all of them are considered healthy.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    /// Picks the backends in turn.
+    RoundRobin,
+    /// Picks the backend with the fewest sessions of the client.
+    LeastConnections,
+}
+
+/// An active health check of the backends of a resource.
+///
+/// A backend is considered unhealthy after [`HealthCheck::unhealthy_threshold`] consecutive failed probes
+/// and healthy again after [`HealthCheck::healthy_threshold`] consecutive successful ones.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HealthCheck {
+    pub probe: HealthProbe,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl HealthCheck {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// How the gateway checks whether a backend is alive.
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
-pub enum Filter {
-    Udp(PortRange),
-    Tcp(PortRange),
+pub enum HealthProbe {
+    /// Opens a TCP connection to the port.
+    Tcp { port: u16 },
+    /// Sends an ICMP echo request.
     Icmp,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum Filter {
+    Udp(PortFilter),
+    Tcp(PortFilter),
+    Sctp(PortFilter),
+    Icmp(IcmpFilter),
+    Gre(ProtocolFilter),
+    Esp(ProtocolFilter),
+}
+
+impl Filter {
+    pub fn action(&self) -> FilterAction {
+        match self {
+            Filter::Udp(f) | Filter::Tcp(f) | Filter::Sctp(f) => f.action,
+            Filter::Icmp(f) => f.action,
+            Filter::Gre(f) | Filter::Esp(f) => f.action,
+        }
+    }
+}
+
+/// What to do with traffic that matches a filter.
+///
+/// [`FilterAction::Deny`] takes precedence over [`FilterAction::Allow`], regardless of the order of the filters.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Filter for protocols with ports, i.e. TCP, UDP and SCTP.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortFilter {
+    /// The destination ports.
+    #[serde(flatten)]
+    pub ports: PortRange,
+    /// The source ports, any if absent.
+    #[serde(default)]
+    pub source_ports: Option<PortRange>,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+impl From<PortRange> for PortFilter {
+    fn from(ports: PortRange) -> Self {
+        Self {
+            ports,
+            source_ports: None,
+            action: FilterAction::Allow,
+        }
+    }
+}
+
+/// Filter for ICMP and ICMPv6.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct IcmpFilter {
+    /// The message type, any if absent.
+    #[serde(default)]
+    pub icmp_type: Option<IcmpMessageType>,
+    /// The code of the message type, any if absent.
+    ///
+    /// Ignored unless [`IcmpFilter::icmp_type`] is set because codes are specific to a message type.
+    #[serde(default)]
+    pub icmp_code: Option<u8>,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+/// ICMP message types that filters can match on.
+///
+/// These apply to ICMP and ICMPv6 alike, even though their numeric values differ.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum IcmpMessageType {
+    EchoRequest,
+    EchoReply,
+    DestinationUnreachable,
+    PacketTooBig,
+    TimeExceeded,
+    ParameterProblem,
+    Redirect,
+}
+
+/// Filter for protocols without ports, e.g. GRE and ESP.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ProtocolFilter {
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PortRange {
     // TODO: we can use a custom deserializer
@@ -98,10 +297,27 @@ fn max_port() -> u16 {
     u16::MAX
 }
 
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
 impl ResourceDescription<ResourceDescriptionDns> {
     pub fn into_resolved(
         self,
         addresses: Vec<IpAddr>,
+        ttl: Option<Duration>,
     ) -> ResourceDescription<ResolvedResourceDescriptionDns> {
         match self {
             ResourceDescription::Dns(ResourceDescriptionDns {
@@ -109,13 +325,20 @@ impl ResourceDescription<ResourceDescriptionDns> {
                 address,
                 name,
                 filters,
+                rate_limit,
+                port_mappings,
+                load_balancing,
             }) => ResourceDescription::Dns(ResolvedResourceDescriptionDns {
                 id,
                 domain: address,
                 name,
                 addresses,
+                ttl,
 
                 filters,
+                rate_limit,
+                port_mappings,
+                load_balancing,
             }),
             ResourceDescription::Cidr(c) => ResourceDescription::Cidr(c),
             ResourceDescription::Internet(r) => ResourceDescription::Internet(r),
@@ -139,6 +362,29 @@ impl ResourceDescription<ResourceDescriptionDns> {
             ResourceDescription::Internet(_) => Vec::default(),
         }
     }
+
+    pub fn port_mappings(&self) -> Vec<PortMapping> {
+        match self {
+            ResourceDescription::Dns(r) => r.port_mappings.clone(),
+            ResourceDescription::Cidr(r) => r.port_mappings.clone(),
+            ResourceDescription::Internet(_) => Vec::default(),
+        }
+    }
+
+    pub fn load_balancing(&self) -> Option<LoadBalancing> {
+        match self {
+            ResourceDescription::Dns(r) => r.load_balancing,
+            ResourceDescription::Cidr(_) | ResourceDescription::Internet(_) => None,
+        }
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        match self {
+            ResourceDescription::Dns(r) => r.rate_limit,
+            ResourceDescription::Cidr(r) => r.rate_limit,
+            ResourceDescription::Internet(r) => r.rate_limit,
+        }
+    }
 }
 
 impl ResourceDescription<ResolvedResourceDescriptionDns> {
@@ -150,6 +396,14 @@ impl ResourceDescription<ResolvedResourceDescriptionDns> {
         }
     }
 
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        match self {
+            ResourceDescription::Dns(r) => r.rate_limit,
+            ResourceDescription::Cidr(r) => r.rate_limit,
+            ResourceDescription::Internet(r) => r.rate_limit,
+        }
+    }
+
     pub fn id(&self) -> ResourceId {
         match self {
             ResourceDescription::Dns(r) => r.id,
@@ -165,6 +419,21 @@ impl ResourceDescription<ResolvedResourceDescriptionDns> {
             ResourceDescription::Internet(_) => vec![],
         }
     }
+
+    pub fn port_mappings(&self) -> Vec<PortMapping> {
+        match self {
+            ResourceDescription::Dns(r) => r.port_mappings.clone(),
+            ResourceDescription::Cidr(r) => r.port_mappings.clone(),
+            ResourceDescription::Internet(_) => vec![],
+        }
+    }
+
+    pub fn load_balancing(&self) -> Option<LoadBalancing> {
+        match self {
+            ResourceDescription::Dns(r) => r.load_balancing,
+            ResourceDescription::Cidr(_) | ResourceDescription::Internet(_) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -174,10 +443,13 @@ mod tests {
     #[test]
     fn can_deserialize_udp_filter() {
         let msg = r#"{ "protocol": "udp", "port_range_start": 10, "port_range_end": 20 }"#;
-        let expected_filter = Filter::Udp(PortRange {
-            port_range_start: 10,
-            port_range_end: 20,
-        });
+        let expected_filter = Filter::Udp(
+            PortRange {
+                port_range_start: 10,
+                port_range_end: 20,
+            }
+            .into(),
+        );
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -187,10 +459,13 @@ mod tests {
     #[test]
     fn can_deserialize_empty_udp_filter() {
         let msg = r#"{ "protocol": "udp" }"#;
-        let expected_filter = Filter::Udp(PortRange {
-            port_range_start: 0,
-            port_range_end: u16::MAX,
-        });
+        let expected_filter = Filter::Udp(
+            PortRange {
+                port_range_start: 0,
+                port_range_end: u16::MAX,
+            }
+            .into(),
+        );
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -200,10 +475,13 @@ mod tests {
     #[test]
     fn can_deserialize_tcp_filter() {
         let msg = r#"{ "protocol": "tcp", "port_range_start": 10, "port_range_end": 20 }"#;
-        let expected_filter = Filter::Tcp(PortRange {
-            port_range_start: 10,
-            port_range_end: 20,
-        });
+        let expected_filter = Filter::Tcp(
+            PortRange {
+                port_range_start: 10,
+                port_range_end: 20,
+            }
+            .into(),
+        );
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -213,10 +491,13 @@ mod tests {
     #[test]
     fn can_deserialize_empty_tcp_filter() {
         let msg = r#"{ "protocol": "tcp" }"#;
-        let expected_filter = Filter::Tcp(PortRange {
-            port_range_start: 0,
-            port_range_end: u16::MAX,
-        });
+        let expected_filter = Filter::Tcp(
+            PortRange {
+                port_range_start: 0,
+                port_range_end: u16::MAX,
+            }
+            .into(),
+        );
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -226,13 +507,62 @@ mod tests {
     #[test]
     fn can_deserialize_icmp_filter() {
         let msg = r#"{ "protocol": "icmp" }"#;
-        let expected_filter = Filter::Icmp;
+        let expected_filter = Filter::Icmp(IcmpFilter::default());
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_icmp_type_deny_filter() {
+        let msg = r#"{ "protocol": "icmp", "icmp_type": "redirect", "action": "deny" }"#;
+        let expected_filter = Filter::Icmp(IcmpFilter {
+            icmp_type: Some(IcmpMessageType::Redirect),
+            icmp_code: None,
+            action: FilterAction::Deny,
+        });
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_sctp_filter_with_source_ports() {
+        let msg = r#"{ "protocol": "sctp", "port_range_start": 2905, "port_range_end": 2905, "source_ports": { "port_range_start": 1024 } }"#;
+        let expected_filter = Filter::Sctp(PortFilter {
+            ports: PortRange {
+                port_range_start: 2905,
+                port_range_end: 2905,
+            },
+            source_ports: Some(PortRange {
+                port_range_start: 1024,
+                port_range_end: u16::MAX,
+            }),
+            action: FilterAction::Allow,
+        });
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
         assert_eq!(expected_filter, actual_filter);
     }
 
+    #[test]
+    fn can_deserialize_gre_and_esp_filters() {
+        let msg = r#"[{ "protocol": "gre" }, { "protocol": "esp", "action": "deny" }]"#;
+        let expected_filters = vec![
+            Filter::Gre(ProtocolFilter::default()),
+            Filter::Esp(ProtocolFilter {
+                action: FilterAction::Deny,
+            }),
+        ];
+
+        let actual_filters = serde_json::from_str::<Vec<Filter>>(msg).unwrap();
+
+        assert_eq!(expected_filters, actual_filters);
+    }
+
     #[test]
     fn can_deserialize_internet_resource() {
         let resources = r#"[
@@ -262,4 +592,88 @@ mod tests {
 
         serde_json::from_str::<Vec<ResourceDescription>>(resources).unwrap();
     }
+
+    #[test]
+    fn can_deserialize_resource_with_rate_limit() {
+        let resource = r#"{
+            "id": "73037362-715d-4a83-a749-f18eadd970e6",
+            "type": "cidr",
+            "address": "172.172.0.0/16",
+            "name": "172.172.0.0/16",
+            "filters": [],
+            "rate_limit": {
+                "bytes_per_second": 1250000,
+                "packets_per_second": 1000,
+                "burst_packets": 5000
+            }
+        }"#;
+
+        let resource = serde_json::from_str::<ResourceDescription>(resource).unwrap();
+
+        assert_eq!(
+            resource.rate_limit(),
+            Some(RateLimit {
+                bytes_per_second: Some(1_250_000),
+                burst_bytes: None,
+                packets_per_second: Some(1000),
+                burst_packets: Some(5000),
+            })
+        );
+    }
+
+    #[test]
+    fn can_deserialize_resource_with_port_mappings() {
+        let resource = r#"{
+            "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+            "type": "dns",
+            "name": "db.mycorp.com",
+            "address": "db.mycorp.com",
+            "filters": [],
+            "port_mappings": [
+                { "protocol": "tcp", "port": 5432, "target_port": 15432 }
+            ]
+        }"#;
+
+        let resource = serde_json::from_str::<ResourceDescription>(resource).unwrap();
+
+        assert_eq!(
+            resource.port_mappings(),
+            vec![PortMapping {
+                protocol: PortMappingProtocol::Tcp,
+                port: 5432,
+                target_port: 15432,
+            }]
+        );
+    }
+
+    #[test]
+    fn can_deserialize_resource_with_load_balancing() {
+        let resource = r#"{
+            "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+            "type": "dns",
+            "name": "api.mycorp.com",
+            "address": "api.mycorp.com",
+            "filters": [],
+            "load_balancing": {
+                "strategy": "least_connections",
+                "health_check": { "probe": { "protocol": "tcp", "port": 443 }, "interval_secs": 5 }
+            }
+        }"#;
+
+        let resource = serde_json::from_str::<ResourceDescription>(resource).unwrap();
+
+        assert_eq!(
+            resource.load_balancing(),
+            Some(LoadBalancing {
+                strategy: LoadBalancingStrategy::LeastConnections,
+                health_check: Some(HealthCheck {
+                    probe: HealthProbe::Tcp { port: 443 },
+                    interval_secs: 5,
+                    timeout_secs: 2,
+                    unhealthy_threshold: 3,
+                    healthy_threshold: 2,
+                }),
+            })
+        );
+    }
 }
@@ -43,6 +43,7 @@ pub fn dns_resource(
             ResourceDescriptionDns {
                 id,
                 address,
+                excluded_addresses: vec![],
                 name,
                 sites,
                 address_description,
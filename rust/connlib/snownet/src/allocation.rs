@@ -1,6 +1,6 @@
 use crate::{
     backoff::{self, ExponentialBackoff},
-    node::{CandidateEvent, Transmit},
+    node::{CandidateEvent, Transmit, Transport},
     ringbuffer::RingBuffer,
     utils::earliest,
 };
@@ -34,6 +34,14 @@ use tracing::{field, Span};
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// For how long we wait for a response to our BINDING requests before we try the next [`Transport`].
+///
+/// Networks that block UDP typically drop the packets silently, thus we would otherwise only notice once all retransmissions timed out.
+const TRANSPORT_FALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The port relays listen on for TURN over TLS, the relay hardcodes it as well.
+const TURN_TLS_PORT: u16 = 443;
+
 /// Represents a TURN allocation that refreshes itself.
 ///
 /// Allocations have a lifetime and need to be continuously refreshed to stay active.
@@ -50,6 +58,15 @@ pub struct Allocation {
     /// Whatever comes back first, wins.
     active_socket: Option<SocketAddr>,
 
+    /// How we talk to the relay.
+    ///
+    /// We start with UDP and fall back to TCP and then TLS if the relay doesn't respond to our BINDING requests until we have an allocation.
+    transport: Transport,
+    /// When we started to wait for a response to our BINDING requests.
+    binding_requests_sent_at: Instant,
+    /// Whether we fall back to stream-based transports, only disabled in tests.
+    stream_fallback: bool,
+
     /// If present, the IPv4 address the relay observed for us.
     ip4_srflx_candidate: Option<Candidate>,
     /// If present, the IPv6 address the relay observed for us.
@@ -194,6 +211,9 @@ impl Allocation {
         let mut allocation = Self {
             server,
             active_socket: None,
+            transport: Transport::Udp,
+            binding_requests_sent_at: now,
+            stream_fallback: true,
             ip4_srflx_candidate: Default::default(),
             ip6_srflx_candidate: Default::default(),
             ip4_allocation: Default::default(),
@@ -257,6 +277,7 @@ impl Allocation {
 
         // Server isn't the same, let's pick a new socket.
         self.active_socket = None;
+        self.transport = Transport::Udp;
         self.send_binding_requests();
     }
 
@@ -276,6 +297,7 @@ impl Allocation {
             tracing::debug!("Attempting to make a new allocation");
 
             self.active_socket = None;
+            self.transport = Transport::Udp;
             self.send_binding_requests();
             return;
         }
@@ -303,7 +325,7 @@ impl Allocation {
 
         self.update_now(now);
 
-        if !self.server.matches(from) {
+        if !self.matches(from) {
             return false;
         }
 
@@ -428,8 +450,12 @@ impl Allocation {
                     SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
                 };
 
-                let maybe_candidate = message.attributes().find_map(|a| srflx_candidate(local, a));
-                update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events);
+                // Over TCP, the relay observes the address of our TCP connection which is useless as a candidate.
+                if self.transport == Transport::Udp {
+                    let maybe_candidate =
+                        message.attributes().find_map(|a| srflx_candidate(local, a));
+                    update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events);
+                }
 
                 self.log_update(now);
 
@@ -446,7 +472,7 @@ impl Allocation {
                 // If the socket isn't set yet, use the `original_dst` as the primary socket.
                 self.active_socket = Some(original_dst);
 
-                tracing::debug!(active_socket = %original_dst, "Updating active socket");
+                tracing::debug!(active_socket = %original_dst, transport = ?self.transport, "Updating active socket");
 
                 if self.has_allocation() {
                     self.authenticate_and_queue(make_refresh_request(), None);
@@ -536,7 +562,7 @@ impl Allocation {
         packet: &'p [u8],
         now: Instant,
     ) -> Option<(SocketAddr, &'p [u8], Socket)> {
-        if !self.server.matches(from) {
+        if !self.matches(from) {
             tracing::trace!(?self.server, "Packet is not for this allocation");
 
             return None;
@@ -570,6 +596,17 @@ impl Allocation {
             self.invalidate_allocation();
         }
 
+        if self.transport_fallback_at().is_some_and(|at| now >= at) {
+            if let Some(fallback) = self.transport.fallback() {
+                tracing::info!(transport = ?self.transport, ?fallback, "Relay did not respond, falling back to other transport");
+
+                self.transport = fallback;
+                self.sent_requests
+                    .retain(|_, (_, r, _, _, _)| r.method() != BINDING);
+                self.send_binding_requests();
+            }
+        }
+
         while let Some(timed_out_request) =
             self.sent_requests
                 .iter()
@@ -645,7 +682,7 @@ impl Allocation {
             earliest_timeout = earliest(earliest_timeout, Some(*sent_at + *backoff));
         }
 
-        earliest_timeout
+        earliest(earliest_timeout, self.transport_fallback_at())
     }
 
     #[tracing::instrument(level = "debug", skip(self, now), fields(active_socket = ?self.active_socket))]
@@ -708,8 +745,9 @@ impl Allocation {
 
         Some(Transmit {
             src: None,
-            dst: self.active_socket?,
+            dst: self.transport_dst(self.active_socket?),
             payload: Cow::Borrowed(&buffer[..total_length]),
+            transport: self.transport,
         })
     }
 
@@ -724,8 +762,9 @@ impl Allocation {
 
         Some(Transmit {
             src: None,
-            dst: self.active_socket?,
+            dst: self.transport_dst(self.active_socket?),
             payload: Cow::Owned(channel_data),
+            transport: self.transport,
         })
     }
 
@@ -810,6 +849,29 @@ impl Allocation {
         self.server
     }
 
+    /// Whether a packet from the given address comes from the relay of this allocation.
+    pub fn matches(&self, from: SocketAddr) -> bool {
+        let matches_v4 = self
+            .server
+            .as_v4()
+            .is_some_and(|v4| self.transport_dst(SocketAddr::V4(*v4)) == from);
+        let matches_v6 = self
+            .server
+            .as_v6()
+            .is_some_and(|v6| self.transport_dst(SocketAddr::V6(*v6)) == from);
+
+        matches_v4 || matches_v6
+    }
+
+    /// Whether we talk to the relay at the given address via TCP or TLS.
+    pub fn is_stream_from(&self, from: SocketAddr) -> bool {
+        self.transport != Transport::Udp && self.matches(from)
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
     pub fn ip4_socket(&self) -> Option<Socket> {
         let address = self.ip4_allocation.as_ref().map(|c| c.addr())?;
 
@@ -885,6 +947,8 @@ impl Allocation {
     }
 
     fn send_binding_requests(&mut self) {
+        self.binding_requests_sent_at = self.last_now;
+
         if let Some(v4) = self.server.as_v4() {
             self.queue((*v4).into(), make_binding_request(), None);
         }
@@ -941,13 +1005,35 @@ impl Allocation {
             .insert(id, (dst, message.clone(), self.last_now, duration, backoff));
         self.buffered_transmits.push_back(Transmit {
             src: None,
-            dst,
+            dst: self.transport_dst(dst),
             payload: encode(message).into(),
+            transport: self.transport,
         });
 
         true
     }
 
+    /// When we give up on the current [`Transport`] if the relay doesn't respond.
+    ///
+    /// Once we have an allocation, the relay is reachable and we stick with the current [`Transport`] until we need to make a new allocation.
+    fn transport_fallback_at(&self) -> Option<Instant> {
+        if !self.stream_fallback || self.active_socket.is_some() || self.has_allocation() {
+            return None;
+        }
+
+        self.transport.fallback()?;
+
+        Some(self.binding_requests_sent_at + TRANSPORT_FALLBACK_TIMEOUT)
+    }
+
+    /// The address we need to send to in order to reach the given socket of the relay via the current [`Transport`].
+    fn transport_dst(&self, server: SocketAddr) -> SocketAddr {
+        match self.transport {
+            Transport::Udp | Transport::Tcp => server,
+            Transport::Tls => SocketAddr::new(server.ip(), TURN_TLS_PORT),
+        }
+    }
+
     fn update_now(&mut self, now: Instant) {
         if now <= self.last_now {
             return;
@@ -2431,6 +2517,76 @@ mod tests {
         );
     }
 
+    #[test]
+    fn falls_back_to_tcp_and_then_tls_if_relay_does_not_respond() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start).with_stream_fallback();
+
+        assert_eq!(
+            allocation.drain_transmits().last().unwrap().transport,
+            Transport::Udp
+        );
+
+        allocation.handle_timeout(start + TRANSPORT_FALLBACK_TIMEOUT);
+        let transmit = allocation.drain_transmits().pop().unwrap();
+        assert_eq!(transmit.transport, Transport::Tcp);
+        assert_eq!(transmit.dst, SocketAddr::V4(RELAY_V4));
+
+        allocation.handle_timeout(start + TRANSPORT_FALLBACK_TIMEOUT * 2);
+        let transmit = allocation.drain_transmits().pop().unwrap();
+        assert_eq!(transmit.transport, Transport::Tls);
+        assert_eq!(
+            transmit.dst,
+            SocketAddr::new(IpAddr::V4(*RELAY_V4.ip()), 443)
+        );
+    }
+
+    #[test]
+    fn allocates_via_tls_after_falling_back() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start).with_stream_fallback();
+        allocation.handle_timeout(start + TRANSPORT_FALLBACK_TIMEOUT);
+        allocation.handle_timeout(start + TRANSPORT_FALLBACK_TIMEOUT * 2);
+        let relay_tls = SocketAddr::new(IpAddr::V4(*RELAY_V4.ip()), 443);
+
+        let binding = decode(&allocation.drain_transmits().pop().unwrap().payload)
+            .unwrap()
+            .unwrap();
+        let handled = allocation.handle_input(
+            relay_tls,
+            PEER1,
+            &binding_response(&binding, PEER1),
+            start + TRANSPORT_FALLBACK_TIMEOUT * 2,
+        );
+
+        assert!(handled);
+        assert!(allocation.is_stream_from(relay_tls));
+        assert_eq!(allocation.poll_event(), None, "no srflx candidate via TLS");
+
+        let transmit = allocation.poll_transmit().unwrap();
+        assert_eq!(transmit.transport, Transport::Tls);
+        assert_eq!(transmit.dst, relay_tls);
+        assert_eq!(
+            decode(&transmit.payload).unwrap().unwrap().method(),
+            ALLOCATE
+        );
+    }
+
+    #[test]
+    fn does_not_fall_back_once_relay_responded() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start)
+            .with_stream_fallback()
+            .with_binding_response(PEER1);
+
+        allocation.handle_timeout(start + TRANSPORT_FALLBACK_TIMEOUT);
+
+        assert!(allocation
+            .drain_transmits()
+            .iter()
+            .all(|t| t.transport == Transport::Udp));
+    }
+
     fn ch(peer: SocketAddr, now: Instant) -> Channel {
         Channel {
             peer,
@@ -2549,6 +2705,7 @@ mod tests {
                 Realm::new("firezone".to_owned()).unwrap(),
                 start,
             )
+            .without_stream_fallback()
         }
 
         fn for_test_dual(start: Instant) -> Self {
@@ -2562,6 +2719,24 @@ mod tests {
                 Realm::new("firezone".to_owned()).unwrap(),
                 start,
             )
+            .without_stream_fallback()
+        }
+
+        /// Most tests are concerned with the protocol itself, falling back to other transports would get in the way.
+        fn without_stream_fallback(mut self) -> Self {
+            self.stream_fallback = false;
+
+            self
+        }
+
+        fn with_stream_fallback(mut self) -> Self {
+            self.stream_fallback = true;
+
+            self
+        }
+
+        fn drain_transmits(&mut self) -> Vec<Transmit<'static>> {
+            std::iter::from_fn(|| self.poll_transmit()).collect()
         }
 
         fn with_binding_response(mut self, srflx_addr: SocketAddr) -> Self {
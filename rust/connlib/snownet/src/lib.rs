@@ -7,11 +7,12 @@ mod index;
 mod node;
 mod ringbuffer;
 mod stats;
+pub mod stream;
 mod utils;
 
 pub use allocation::RelaySocket;
 pub use node::{
-    Answer, Client, ClientNode, Credentials, Error, Event, Node, Offer, Server, ServerNode,
-    Transmit, HANDSHAKE_TIMEOUT,
+    Answer, Client, ClientNode, ConnectionConfig, Credentials, Error, Event, Node, Offer, Server,
+    ServerNode, Transmit, Transport, HANDSHAKE_TIMEOUT,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use stats::{CandidatePair, CandidateType, ConnectionStats, HumanBytes, NodeStats};
@@ -1,7 +1,7 @@
 use crate::allocation::{Allocation, RelaySocket, Socket};
 use crate::index::IndexLfsr;
 use crate::ringbuffer::RingBuffer;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::stats::{CandidatePair, CandidateType, ConnectionStats, NodeStats};
 use crate::utils::earliest;
 use boringtun::noise::errors::WireGuardError;
 use boringtun::noise::{Tunn, TunnResult};
@@ -39,6 +39,13 @@ const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
 /// How long we will at most wait for an [`Answer`] from the remote.
 pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
+/// How long we wait before we re-check the direct candidate pairs of a relayed connection for the first time.
+///
+/// Each subsequent check doubles this interval, up to [`MAX_DIRECT_PATH_CHECK_INTERVAL`].
+const DIRECT_PATH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const MAX_DIRECT_PATH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 
 /// Manages a set of wireguard connections for a server.
@@ -94,6 +101,13 @@ pub struct Node<T, TId, RId> {
     connections: Connections<TId, RId>,
     pending_events: VecDeque<Event<TId>>,
 
+    /// The [`ConnectionConfig`] of new connections, unless overridden via [`Node::override_connection_config`].
+    connection_config: ConnectionConfig,
+    connection_config_overrides: HashMap<TId, ConnectionConfig>,
+
+    /// Remote candidates that worked in a previous session, see [`Node::remember_remote_candidates`].
+    remembered_remote_candidates: HashMap<TId, Vec<Candidate>>,
+
     buffer: Box<[u8; MAX_UDP_SIZE]>,
 
     stats: NodeStats,
@@ -139,10 +153,31 @@ where
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             allocations: HashMap::default(),
             connections: Default::default(),
+            connection_config: ConnectionConfig::default(),
+            connection_config_overrides: HashMap::default(),
+            remembered_remote_candidates: HashMap::default(),
             stats: Default::default(),
         }
     }
 
+    /// Sets the [`ConnectionConfig`] for all connections created from now on.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.connection_config = config;
+    }
+
+    /// Overrides the [`ConnectionConfig`] for the next connection created with the given ID.
+    ///
+    /// The idle timeout also applies to an existing connection with this ID.
+    /// Its keep-alive cannot be changed because it is part of the wireguard session.
+    /// The override is forgotten once the connection with this ID is closed or failed.
+    pub fn override_connection_config(&mut self, cid: TId, config: ConnectionConfig) {
+        if let Some(connection) = self.connections.established.get_mut(&cid) {
+            connection.idle_timeout = config.idle_timeout;
+        }
+
+        self.connection_config_overrides.insert(cid, config);
+    }
+
     /// Resets this [`Node`].
     ///
     /// # Implementation note
@@ -172,6 +207,7 @@ where
 
         self.host_candidates.clear();
         self.connections.clear();
+        self.connection_config_overrides.clear();
         self.buffered_transmits.clear();
 
         tracing::debug!(%num_connections, "Closed all connections as part of reconnecting");
@@ -192,6 +228,47 @@ where
         (self.stats, self.connections.stats())
     }
 
+    /// The remote candidate that each connected connection is currently sending to.
+    ///
+    /// Upper layers can persist these and pass them to [`Node::remember_remote_candidates`] after a restart.
+    /// Peer-reflexive candidates are omitted because they are only meaningful within the ICE session that discovered them.
+    pub fn remote_candidates_in_use(&self) -> impl Iterator<Item = (TId, String)> + '_ {
+        self.connections.iter_established().filter_map(|(id, c)| {
+            let candidate = c.remote_candidate_in_use()?;
+
+            Some((id, candidate.to_sdp_string()))
+        })
+    }
+
+    /// Remembers remote candidates that worked for this connection in a previous session.
+    ///
+    /// Once a new connection with this ID has completed signalling, these candidates are added right away.
+    /// ICE can thus test them without waiting for the remote's candidates to be trickled through the signalling channel.
+    /// Candidates that are no longer valid simply fail their checks.
+    pub fn remember_remote_candidates(
+        &mut self,
+        cid: TId,
+        candidates: impl IntoIterator<Item = String>,
+    ) {
+        let candidates = candidates
+            .into_iter()
+            .filter_map(|c| match Candidate::from_sdp_string(&c) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    tracing::debug!(%cid, "Failed to parse remembered candidate: {e}");
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            self.remembered_remote_candidates.remove(&cid);
+            return;
+        }
+
+        self.remembered_remote_candidates.insert(cid, candidates);
+    }
+
     /// Add an address as a `host` candidate.
     ///
     /// For most network topologies, [`snownet`](crate) will automatically discover host candidates via the traffic to the configured STUN and TURN servers.
@@ -231,6 +308,10 @@ where
             }
         };
 
+        self.add_parsed_remote_candidate(cid, candidate, now);
+    }
+
+    fn add_parsed_remote_candidate(&mut self, cid: TId, candidate: Candidate, now: Instant) {
         if let Some(agent) = self.connections.agent_mut(cid) {
             agent.add_remote_candidate(candidate.clone());
         }
@@ -302,7 +383,10 @@ where
         now: Instant,
         buffer: &'s mut [u8],
     ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
-        self.add_local_as_host_candidate(local)?;
+        // Packets from a relay that we talk to via TCP or TLS are received on a TCP socket, which is not a host candidate.
+        if !self.allocations.values().any(|a| a.is_stream_from(from)) {
+            self.add_local_as_host_candidate(local)?;
+        }
 
         let (from, packet, relayed) = match self.allocations_try_handle(from, local, packet, now) {
             ControlFlow::Continue(c) => c,
@@ -370,6 +454,7 @@ where
                     src: Some(source),
                     dst: remote,
                     payload: Cow::Borrowed(packet),
+                    transport: Transport::Udp,
                 }))
             }
             PeerSocket::Relay { relay, dest: peer } => {
@@ -437,7 +522,13 @@ where
         self.bindings_and_allocations_drain_events();
 
         for (id, connection) in self.connections.iter_established_mut() {
-            connection.handle_timeout(id, now, &mut self.allocations, &mut self.buffered_transmits);
+            connection.handle_timeout(
+                id,
+                now,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                &mut self.pending_events,
+            );
         }
 
         for (id, connection) in self.connections.initial.iter_mut() {
@@ -465,6 +556,10 @@ where
                 None => true,
             });
         self.connections.gc(&mut self.pending_events);
+
+        let connections = &self.connections;
+        self.connection_config_overrides
+            .retain(|cid, _| connections.contains(cid));
     }
 
     /// Returns buffered data that needs to be sent on the socket.
@@ -549,28 +644,28 @@ where
         mut agent: IceAgent,
         remote: PublicKey,
         key: [u8; 32],
+        config: ConnectionConfig,
         intent_sent_at: Instant,
         now: Instant,
     ) -> Connection<RId> {
         agent.handle_timeout(now);
 
-        /// We set a Wireguard keep-alive to ensure the WG session doesn't timeout on an idle connection.
-        ///
-        /// Without such a timeout, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
-        const WG_KEEP_ALIVE: Option<u16> = Some(10);
-
         Connection {
             agent,
             tunnel: Tunn::new(
                 self.private_key.clone(),
                 remote,
                 Some(key),
-                WG_KEEP_ALIVE,
+                config.keepalive_secs(),
                 self.index.next(),
                 Some(self.rate_limiter.clone()),
             ),
+            idle_timeout: config.idle_timeout,
             next_timer_update: now,
+            next_direct_path_check: None,
+            direct_path_check_interval: DIRECT_PATH_CHECK_INTERVAL,
             stats: Default::default(),
+            time_since_last_handshake: None,
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             intent_sent_at,
             signalling_completed_at: now,
@@ -629,10 +724,7 @@ where
         match packet.first().copied() {
             // STUN method range
             Some(0..=3) => {
-                let Some(allocation) = self
-                    .allocations
-                    .values_mut()
-                    .find(|a| a.server().matches(from))
+                let Some(allocation) = self.allocations.values_mut().find(|a| a.matches(from))
                 else {
                     // False-positive, continue processing packet elsewhere
                     return ControlFlow::Continue((from, packet, None));
@@ -649,10 +741,7 @@ where
             }
             // Channel data number range
             Some(64..=79) => {
-                let Some(allocation) = self
-                    .allocations
-                    .values_mut()
-                    .find(|a| a.server().matches(from))
+                let Some(allocation) = self.allocations.values_mut().find(|a| a.matches(from))
                 else {
                     // False-positive, continue processing packet elsewhere
                     return ControlFlow::Continue((from, packet, None));
@@ -855,10 +944,12 @@ where
 
         self.seed_agent_with_local_candidates(cid, &mut agent);
 
+        let config = self.connection_config_for(cid);
         let connection = self.init_connection(
             agent,
             remote,
             *initial.session_key.expose_secret(),
+            config,
             initial.intent_sent_at,
             now,
         );
@@ -869,6 +960,14 @@ where
         tracing::info!(?duration_since_intent, remote = %hex::encode(remote.as_bytes()), "Signalling protocol completed");
 
         debug_assert!(existing.is_none());
+
+        if let Some(remembered) = self.remembered_remote_candidates.get(&cid).cloned() {
+            tracing::debug!(num_candidates = %remembered.len(), "Adding remembered remote candidates");
+
+            for candidate in remembered {
+                self.add_parsed_remote_candidate(cid, candidate, now);
+            }
+        }
     }
 }
 
@@ -916,10 +1015,12 @@ where
 
         self.seed_agent_with_local_candidates(cid, &mut agent);
 
+        let config = self.connection_config_for(cid);
         let connection = self.init_connection(
             agent,
             remote,
             *offer.session_key.expose_secret(),
+            config,
             now, // Technically, this isn't fully correct because gateways don't send intents so we just use the current time.
             now,
         );
@@ -938,6 +1039,13 @@ where
     TId: Eq + Hash + Copy + fmt::Display,
     RId: Copy + Eq + Hash + PartialEq + fmt::Debug + fmt::Display,
 {
+    fn connection_config_for(&self, cid: TId) -> ConnectionConfig {
+        self.connection_config_overrides
+            .get(&cid)
+            .copied()
+            .unwrap_or(self.connection_config)
+    }
+
     fn seed_agent_with_local_candidates(&mut self, connection: TId, agent: &mut IceAgent) {
         for candidate in self.host_candidates.iter().cloned() {
             add_local_candidate(connection, agent, candidate, &mut self.pending_events);
@@ -1003,7 +1111,7 @@ where
     }
 
     fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1048,6 +1156,10 @@ where
         self.established.clear();
     }
 
+    fn contains(&self, id: &TId) -> bool {
+        self.initial.contains_key(id) || self.established.contains_key(id)
+    }
+
     fn iter_ids(&self) -> impl Iterator<Item = TId> + '_ {
         self.initial.keys().chain(self.established.keys()).copied()
     }
@@ -1177,6 +1289,36 @@ pub struct Credentials {
     pub password: String,
 }
 
+/// How long a connection may be idle and whether we keep it alive in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// After how long without any traffic in either direction we close a connection.
+    pub idle_timeout: Duration,
+    /// How often wireguard sends a keep-alive if there is no other traffic, `None` to not send any.
+    ///
+    /// Without keep-alives, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
+    /// On the other hand, each keep-alive wakes up the device, which drains the battery of mobile devices.
+    /// The resolution is whole seconds.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(5 * 60),
+            keepalive: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    fn keepalive_secs(&self) -> Option<u16> {
+        let secs = self.keepalive?.as_secs();
+
+        Some(secs.clamp(1, u16::MAX as u64) as u16)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Event<TId> {
     /// We created a new candidate for this connection and ask to signal it to the remote party.
@@ -1197,6 +1339,14 @@ pub enum Event<TId> {
 
     ConnectionEstablished(TId),
 
+    /// The path of an established connection changed, e.g. from a relay to a direct one.
+    ///
+    /// The wireguard session is not affected by this, packets simply take the new path.
+    ConnectionPathChanged {
+        connection: TId,
+        candidate_pair: CandidatePair,
+    },
+
     /// We failed to establish a connection.
     ///
     /// All state associated with the connection has been cleared.
@@ -1219,6 +1369,38 @@ pub struct Transmit<'a> {
     pub dst: SocketAddr,
     /// The data that should be sent.
     pub payload: Cow<'a, [u8]>,
+    /// How the packet should be sent.
+    ///
+    /// Only packets to a relay may use a stream-based transport, see [`Transport`].
+    pub transport: Transport,
+}
+
+/// The transport over which a [`Transmit`] needs to be sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    #[default]
+    Udp,
+    /// TURN over TCP, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+    ///
+    /// The connection should be established on demand, i.e. when the first packet is sent to a relay.
+    /// All messages must be framed as described in [`stream`](crate::stream).
+    Tcp,
+    /// TURN over TLS over TCP, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+    ///
+    /// Like [`Transport::Tcp`] but wrapped in TLS.
+    /// Unlike the other transports, relays listen for TLS on port 443 because it is the least likely to be blocked.
+    Tls,
+}
+
+impl Transport {
+    /// The transport to try next if a relay is unreachable via this one.
+    pub(crate) fn fallback(&self) -> Option<Transport> {
+        match self {
+            Transport::Udp => Some(Transport::Tcp),
+            Transport::Tcp => Some(Transport::Tls),
+            Transport::Tls => None,
+        }
+    }
 }
 
 impl<'a> fmt::Debug for Transmit<'a> {
@@ -1227,6 +1409,7 @@ impl<'a> fmt::Debug for Transmit<'a> {
             .field("src", &self.src)
             .field("dst", &self.dst)
             .field("len", &self.payload.len())
+            .field("transport", &self.transport)
             .finish()
     }
 }
@@ -1237,6 +1420,7 @@ impl<'a> Transmit<'a> {
             src: self.src,
             dst: self.dst,
             payload: Cow::Owned(self.payload.into_owned()),
+            transport: self.transport,
         }
     }
 }
@@ -1292,11 +1476,21 @@ struct Connection<RId> {
 
     tunnel: Tunn,
     remote_pub_key: PublicKey,
+    /// After how long without any traffic we close this connection.
+    idle_timeout: Duration,
     next_timer_update: Instant,
 
     state: ConnectionState<RId>,
 
+    /// When we re-check the direct candidate pairs next, `None` unless we are talking to the peer via a relay.
+    next_direct_path_check: Option<Instant>,
+    direct_path_check_interval: Duration,
+
     stats: ConnectionStats,
+    /// What [`Tunn::time_since_last_handshake`] returned the last time we checked.
+    ///
+    /// This only ever grows until the next handshake completes, which is how we count handshakes.
+    time_since_last_handshake: Option<Duration>,
     intent_sent_at: Instant,
     signalling_completed_at: Instant,
 
@@ -1392,6 +1586,119 @@ where
         self.tunnel.time_since_last_handshake().is_some()
     }
 
+    fn stats(&self) -> ConnectionStats {
+        let relayed = matches!(
+            self.state,
+            ConnectionState::Connected {
+                peer_socket: PeerSocket::Relay { .. },
+                ..
+            }
+        );
+        let (_, _, _, _, rtt_millis) = self.tunnel.stats();
+
+        ConnectionStats {
+            relayed,
+            rtt: rtt_millis.map(|ms| Duration::from_millis(ms as u64)),
+            ..self.stats
+        }
+    }
+
+    fn update_handshake_stats(&mut self, now: Instant) {
+        let time_since_last_handshake = self.tunnel.time_since_last_handshake();
+
+        let completed_handshake = match (self.time_since_last_handshake, time_since_last_handshake)
+        {
+            (None, Some(_)) => true,
+            (Some(previous), Some(current)) => current < previous,
+            (_, None) => false,
+        };
+
+        if completed_handshake {
+            self.stats.handshakes += 1;
+            self.stats.last_handshake_at = Some(now);
+        }
+
+        self.time_since_last_handshake = time_since_last_handshake;
+    }
+
+    /// Schedules the next check of the direct candidate pairs if we are talking to the peer via a relay.
+    fn schedule_direct_path_check(&mut self, now: Instant) {
+        match self.socket() {
+            Some(PeerSocket::Relay { .. }) => {
+                self.next_direct_path_check = Some(now + self.direct_path_check_interval);
+            }
+            Some(PeerSocket::Direct { .. }) | None => {
+                self.next_direct_path_check = None;
+                self.direct_path_check_interval = DIRECT_PATH_CHECK_INTERVAL;
+            }
+        }
+    }
+
+    /// Re-checks the candidate pairs that would allow us to talk to the peer directly.
+    ///
+    /// A relayed pair may get nominated because the direct ones failed their checks before the NATs on both sides were punched, e.g. because candidates arrived late.
+    /// ICE doesn't re-check failed pairs on its own, thus we re-add the remote's host and server-reflexive candidates which creates fresh pairs for them.
+    /// Once one of them succeeds, ICE nominates it because it has a higher priority than the relayed pair and we switch over to it.
+    fn recheck_direct_candidates(&mut self, now: Instant) {
+        let Some(PeerSocket::Relay { dest, .. }) = self.socket() else {
+            self.next_direct_path_check = None;
+            return;
+        };
+
+        let candidates = self
+            .agent
+            .remote_candidates()
+            .iter()
+            .filter(|c| {
+                matches!(
+                    c.kind(),
+                    CandidateKind::Host | CandidateKind::ServerReflexive
+                )
+            })
+            .filter(|c| c.addr() != dest) // Don't disturb the pair we are currently using.
+            .cloned()
+            .collect::<Vec<_>>();
+
+        tracing::debug!(num_candidates = %candidates.len(), "Re-checking direct candidate pairs");
+
+        for candidate in candidates {
+            self.agent.invalidate_candidate(&candidate);
+            self.agent.add_remote_candidate(candidate);
+        }
+
+        self.direct_path_check_interval =
+            (self.direct_path_check_interval * 2).min(MAX_DIRECT_PATH_CHECK_INTERVAL);
+        self.next_direct_path_check = Some(now + self.direct_path_check_interval);
+    }
+
+    /// Determines the candidate pair of the socket that ICE nominated.
+    fn candidate_pair(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        relayed: bool,
+    ) -> CandidatePair {
+        let local = if relayed {
+            CandidateType::Relayed
+        } else {
+            self.agent
+                .local_candidates()
+                .iter()
+                .find(|c| c.addr() == source)
+                .map(|c| CandidateType::from(c.kind()))
+                .unwrap_or(CandidateType::Host)
+        };
+        let remote = self
+            .agent
+            .remote_candidates()
+            .iter()
+            .find(|c| c.addr() == destination)
+            .map(|c| CandidateType::from(c.kind()))
+            .unwrap_or(CandidateType::PeerReflexive); // ICE learns peer-reflexive candidates from the STUN requests it receives.
+
+        CandidatePair { local, remote }
+    }
+
     fn duration_since_intent(&self, now: Instant) -> Duration {
         now.duration_since(self.intent_sent_at)
     }
@@ -1405,7 +1712,13 @@ where
 
         earliest(
             Some(idle_timeout),
-            earliest(agent_timeout, earliest(next_wg_timer, candidate_timeout)),
+            earliest(
+                agent_timeout,
+                earliest(
+                    next_wg_timer,
+                    earliest(candidate_timeout, self.next_direct_path_check),
+                ),
+            ),
         )
     }
 
@@ -1418,9 +1731,7 @@ where
     }
 
     fn idle_timeout(&self) -> Instant {
-        const MAX_IDLE: Duration = Duration::from_secs(5 * 60);
-
-        self.last_incoming.max(self.last_outgoing) + MAX_IDLE
+        self.last_incoming.max(self.last_outgoing) + self.idle_timeout
     }
 
     #[tracing::instrument(level = "info", skip_all, fields(%cid))]
@@ -1430,11 +1741,20 @@ where
         now: Instant,
         allocations: &mut HashMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        events: &mut VecDeque<Event<TId>>,
     ) where
         TId: fmt::Display + Copy,
         RId: Copy + fmt::Display,
     {
         self.agent.handle_timeout(now);
+        self.update_handshake_stats(now);
+
+        if self
+            .next_direct_path_check
+            .is_some_and(|check| now >= check)
+        {
+            self.recheck_direct_candidates(now);
+        }
 
         if self
             .candidate_timeout()
@@ -1512,6 +1832,11 @@ where
                             source,
                             dest: destination,
                         });
+                    let candidate_pair = self.candidate_pair(
+                        source,
+                        destination,
+                        matches!(remote_socket, PeerSocket::Relay { .. }),
+                    );
 
                     let old = match mem::replace(&mut self.state, ConnectionState::Failed) {
                         ConnectionState::Connecting {
@@ -1525,6 +1850,7 @@ where
                                 peer_socket: remote_socket,
                                 possible_sockets,
                             };
+                            self.stats.time_to_connect = Some(self.duration_since_intent(now));
 
                             None
                         }
@@ -1547,13 +1873,20 @@ where
                                 peer_socket: remote_socket,
                                 possible_sockets,
                             };
+                            events.push_back(Event::ConnectionPathChanged {
+                                connection: cid,
+                                candidate_pair,
+                            });
 
                             Some(peer_socket)
                         }
                         ConnectionState::Idle | ConnectionState::Failed => continue, // Failed and idle connections are cleaned up, don't bother handling events.
                     };
 
-                    tracing::info!(?old, new = ?remote_socket, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
+                    tracing::info!(?old, new = ?remote_socket, ?candidate_pair, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
+
+                    self.stats.candidate_pair = Some(candidate_pair);
+                    self.schedule_direct_path_check(now);
 
                     self.force_handshake(allocations, transmits, now);
                 }
@@ -1579,6 +1912,7 @@ where
                     src: Some(source),
                     dst,
                     payload: Cow::Owned(packet.into()),
+                    transport: Transport::Udp,
                 });
                 continue;
             };
@@ -1612,6 +1946,8 @@ where
         };
 
         self.last_outgoing = now;
+        self.stats.packets_out += 1;
+        self.stats.bytes_out += len;
 
         Ok(Some(&buffer[..len]))
     }
@@ -1698,8 +2034,12 @@ where
 
         if control_flow.is_continue() {
             self.last_incoming = now;
+            self.stats.packets_in += 1;
+            self.stats.bytes_in += packet.len();
         }
 
+        self.update_handshake_stats(now);
+
         control_flow
     }
 
@@ -1740,6 +2080,16 @@ where
         }
     }
 
+    fn remote_candidate_in_use(&self) -> Option<&Candidate> {
+        let (PeerSocket::Direct { dest, .. } | PeerSocket::Relay { dest, .. }) = self.socket()?;
+
+        self.agent
+            .remote_candidates()
+            .iter()
+            .filter(|c| c.kind() != CandidateKind::PeerReflexive)
+            .find(|c| c.addr() == dest)
+    }
+
     fn is_failed(&self) -> bool {
         matches!(self.state, ConnectionState::Failed)
     }
@@ -1767,6 +2117,7 @@ where
             src: Some(source),
             dst: remote,
             payload: Cow::Owned(message.into()),
+            transport: Transport::Udp,
         },
         PeerSocket::Relay { relay, dest: peer } => {
             encode_as_channel_data(relay, peer, message, allocations, now).ok()?
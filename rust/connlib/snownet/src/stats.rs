@@ -1,4 +1,6 @@
 use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+use str0m::CandidateKind;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -12,6 +14,52 @@ pub struct ConnectionStats {
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+    /// Whether the socket we nominated for this connection is on a relay.
+    pub relayed: bool,
+    /// The candidate pair that ICE nominated, `None` until it did.
+    pub candidate_pair: Option<CandidatePair>,
+    /// The round-trip time to the peer, as measured by wireguard during the last handshake.
+    pub rtt: Option<Duration>,
+    /// How many wireguard data packets we sent to the peer.
+    pub packets_out: u64,
+    /// How many bytes of wireguard data packets we sent to the peer, i.e. including wireguard's overhead.
+    pub bytes_out: HumanBytes,
+    /// How many wireguard data packets we received from the peer.
+    pub packets_in: u64,
+    /// How many bytes of wireguard data packets we received from the peer, i.e. including wireguard's overhead.
+    pub bytes_in: HumanBytes,
+    /// How many wireguard handshakes completed on this connection.
+    pub handshakes: u32,
+    /// When the last wireguard handshake completed.
+    pub last_handshake_at: Option<Instant>,
+    /// How long it took from sending the connection intent until ICE nominated a candidate pair.
+    pub time_to_connect: Option<Duration>,
+}
+
+/// The candidate pair of a connection, i.e. the kind of path our packets take to the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidatePair {
+    pub local: CandidateType,
+    pub remote: CandidateType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl From<CandidateKind> for CandidateType {
+    fn from(kind: CandidateKind) -> Self {
+        match kind {
+            CandidateKind::Host => CandidateType::Host,
+            CandidateKind::ServerReflexive => CandidateType::ServerReflexive,
+            CandidateKind::PeerReflexive => CandidateType::PeerReflexive,
+            CandidateKind::Relayed => CandidateType::Relayed,
+        }
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -0,0 +1,142 @@
+//! Framing of TURN messages on stream-based transports, i.e. TCP and TLS.
+//!
+//! STUN messages and channel-data messages carry their own length, thus they can be sent back-to-back on a stream.
+//! The only difference to UDP is that channel-data messages must be padded to a multiple of 4 bytes.
+//!
+//! See <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+
+use bytes::{Buf as _, Bytes, BytesMut};
+use std::io;
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Returns the padding that needs to be sent after the given message on a stream.
+pub fn padding(message: &[u8]) -> &'static [u8] {
+    const ZEROS: [u8; 3] = [0; 3];
+
+    if !is_channel_data(message) {
+        return &[];
+    }
+
+    let padding = (4 - message.len() % 4) % 4;
+
+    &ZEROS[..padding]
+}
+
+/// Splits the bytes read from a stream into individual STUN and channel-data messages.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buffer: BytesMut,
+}
+
+impl Decoder {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete message, without any padding.
+    ///
+    /// Fails if the stream doesn't contain STUN or channel-data messages.
+    /// The stream can't be recovered from that and should be closed.
+    pub fn decode(&mut self) -> io::Result<Option<Bytes>> {
+        let Some((message_len, frame_len)) = frame_len(&self.buffer)? else {
+            return Ok(None);
+        };
+
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let message = self.buffer.split_to(message_len).freeze();
+        self.buffer.advance(frame_len - message_len);
+
+        Ok(Some(message))
+    }
+}
+
+/// Computes the length of the message at the start of `buffer` and the length of its frame, i.e. including the padding.
+fn frame_len(buffer: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let Some(first) = buffer.first() else {
+        return Ok(None);
+    };
+    let Some(length) = buffer.get(2..4) else {
+        return Ok(None);
+    };
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+    // The first two bits distinguish STUN from channel-data messages, see <https://www.rfc-editor.org/rfc/rfc7983#section-7>.
+    match first >> 6 {
+        0b00 => {
+            let message_len = STUN_HEADER_LEN + length;
+
+            Ok(Some((message_len, message_len)))
+        }
+        0b01 => {
+            let message_len = CHANNEL_DATA_HEADER_LEN + length;
+
+            Ok(Some((message_len, message_len.next_multiple_of(4))))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "neither a STUN nor a channel-data message",
+        )),
+    }
+}
+
+fn is_channel_data(message: &[u8]) -> bool {
+    message.first().is_some_and(|b| b >> 6 == 0b01)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_channel_data_to_multiple_of_4() {
+        let message = crate::channel_data::encode(0x4000, b"hello");
+
+        assert_eq!(padding(&message), &[0, 0, 0]);
+        assert_eq!(padding(&crate::channel_data::encode(0x4000, b"helo")), &[]);
+    }
+
+    #[test]
+    fn does_not_pad_stun_messages() {
+        let message = [0u8; STUN_HEADER_LEN + 1];
+
+        assert_eq!(padding(&message), &[]);
+    }
+
+    #[test]
+    fn decodes_messages_split_across_reads() {
+        let channel_data = crate::channel_data::encode(0x4000, b"hello");
+        let mut stun = vec![0u8; STUN_HEADER_LEN + 4];
+        stun[3] = 4;
+
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&channel_data);
+        stream.extend_from_slice(padding(&channel_data));
+        stream.extend_from_slice(&stun);
+
+        let mut decoder = Decoder::default();
+        let mut messages = Vec::new();
+
+        for chunk in stream.chunks(3) {
+            decoder.push(chunk);
+
+            while let Some(message) = decoder.decode().unwrap() {
+                messages.push(message);
+            }
+        }
+
+        assert_eq!(messages, vec![Bytes::from(channel_data), Bytes::from(stun)]);
+    }
+
+    #[test]
+    fn fails_on_other_data() {
+        let mut decoder = Decoder::default();
+        decoder.push(&[0xFF; 8]);
+
+        assert!(decoder.decode().is_err());
+    }
+}
@@ -2,7 +2,10 @@ use boringtun::x25519::StaticSecret;
 use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerSocket};
 use ip_packet::*;
 use rand::rngs::OsRng;
-use snownet::{Answer, Client, ClientNode, Event, Node, RelaySocket, Server, ServerNode, Transmit};
+use snownet::{
+    Answer, CandidatePair, CandidateType, Client, ClientNode, ConnectionConfig, ConnectionStats,
+    Event, Node, RelaySocket, Server, ServerNode, Transmit,
+};
 use std::{
     collections::{HashSet, VecDeque},
     iter,
@@ -136,6 +139,258 @@ fn idle_connection_is_closed_after_5_minutes() {
         .contains(&(Event::ConnectionClosed(1), clock.now)));
 }
 
+#[test]
+fn reports_stats_of_direct_connection() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let (alice, bob) = alice_and_bob();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let firewall = Firewall::default();
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+
+    let alice_stats = alice.connection_stats();
+    let bob_stats = bob.connection_stats();
+
+    assert_eq!(
+        alice_stats.candidate_pair,
+        Some(CandidatePair {
+            local: CandidateType::Host,
+            remote: CandidateType::Host
+        })
+    );
+    assert!(!alice_stats.relayed);
+    assert!(alice_stats.time_to_connect.is_some());
+    assert!(alice_stats.handshakes >= 1);
+    assert!(alice_stats.last_handshake_at.is_some());
+    assert_eq!(alice_stats.packets_out, 1);
+    assert_eq!(bob_stats.packets_in, 1);
+    assert_eq!(alice_stats.bytes_out.0, bob_stats.bytes_in.0);
+}
+
+#[test]
+fn upgrades_relayed_connection_once_direct_path_is_available() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let (alice, bob) = alice_and_bob();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let firewall = Firewall::default()
+        .with_block_rule(&alice, &bob)
+        .with_block_rule(&bob, &alice);
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    assert!(alice.connection_stats().relayed);
+
+    // The direct path becomes available, e.g. because the NAT got punched.
+    let firewall = Firewall::default();
+    let start = clock.now;
+
+    while alice.connection_stats().relayed && clock.elapsed(start) < Duration::from_secs(30) {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    assert!(!alice.connection_stats().relayed);
+    assert!(alice
+        .events
+        .iter()
+        .any(|(e, _)| matches!(e, Event::ConnectionPathChanged { connection: 1, .. })));
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+    progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    assert_eq!(bob.packets_from(ip("9.9.9.9")).count(), 1);
+}
+
+#[test]
+fn idle_connection_is_closed_after_configured_idle_timeout() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let (mut alice, bob) = alice_and_bob();
+    alice.set_connection_config(ConnectionConfig {
+        idle_timeout: Duration::from_secs(60),
+        keepalive: None,
+    });
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(debug_span!("Alice"), alice, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let firewall = Firewall::default();
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    alice.ping(ip("9.9.9.9"), ip("8.8.8.8"), &bob, clock.now);
+
+    let start = clock.now;
+
+    while clock.elapsed(start) <= Duration::from_secs(60) {
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    assert!(alice
+        .events
+        .iter()
+        .any(|(e, _)| e == &Event::ConnectionClosed(1)));
+    assert!(!bob
+        .events
+        .iter()
+        .any(|(e, _)| e == &Event::ConnectionClosed(1)));
+}
+
+#[test]
+fn reconnects_with_remembered_candidates_after_restart() {
+    let _guard = setup_tracing();
+    let mut clock = Clock::new();
+
+    let alice_key = StaticSecret::random_from_rng(rand::thread_rng());
+    let (_, bob) = alice_and_bob();
+
+    let mut relays = [(
+        1,
+        TestRelay::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478),
+            debug_span!("Roger"),
+        ),
+    )];
+    let mut alice = TestNode::new(
+        debug_span!("Alice"),
+        ClientNode::new(alice_key.clone()),
+        "1.1.1.1:80",
+    )
+    .with_relays("alice", HashSet::default(), &mut relays, clock.now);
+    let mut bob = TestNode::new(debug_span!("Bob"), bob, "2.2.2.2:80").with_relays(
+        "bob",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+    let firewall = Firewall::default();
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+
+    let remembered = alice
+        .node
+        .remote_candidates_in_use()
+        .map(|(_, candidate)| candidate)
+        .collect::<Vec<_>>();
+
+    assert_eq!(remembered.len(), 1);
+    assert_eq!(
+        Candidate::from_sdp_string(&remembered[0]).unwrap().addr(),
+        s("2.2.2.2:80")
+    );
+
+    let mut restarted = ClientNode::new(alice_key);
+    restarted.remember_remote_candidates(1, remembered);
+    let mut alice = TestNode::new(debug_span!("Alice"), restarted, "1.1.1.1:80").with_relays(
+        "alice",
+        HashSet::default(),
+        &mut relays,
+        clock.now,
+    );
+
+    handshake(&mut alice, &mut bob, &clock);
+
+    loop {
+        if alice.is_connected_to(&bob) && bob.is_connected_to(&alice) {
+            break;
+        }
+
+        progress(&mut alice, &mut bob, &mut relays, &firewall, &mut clock);
+    }
+}
+
 #[test]
 fn connection_times_out_after_20_seconds() {
     let (mut alice, _) = alice_and_bob();
@@ -631,6 +886,14 @@ impl<R> TestNode<R> {
         self.transmits.push_back(transmit);
     }
 
+    /// The stats of the only connection of this node.
+    fn connection_stats(&self) -> ConnectionStats {
+        let (_, mut connections) = self.node.stats();
+        let (_, stats) = connections.next().expect("node to have a connection");
+
+        stats
+    }
+
     fn packets_from(&self, src: IpAddr) -> impl Iterator<Item = &IpPacket<'static>> {
         self.received_packets
             .iter()
@@ -671,7 +934,8 @@ impl<R> TestNode<R> {
                     .in_scope(|| other.node.remove_remote_candidate(connection, candidate)),
                 Event::ConnectionEstablished(_)
                 | Event::ConnectionFailed(_)
-                | Event::ConnectionClosed(_) => {}
+                | Event::ConnectionClosed(_)
+                | Event::ConnectionPathChanged { .. } => {}
             };
         }
     }
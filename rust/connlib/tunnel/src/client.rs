@@ -1,10 +1,11 @@
+use crate::dns::query_log::{DnsResourceStats, QueryLog};
 use crate::dns::StubResolver;
 use crate::io::DnsQueryError;
 use crate::peer_store::PeerStore;
 use crate::{dns, dns::DnsQuery};
 use anyhow::Context;
 use bimap::BiMap;
-use connlib_shared::callbacks::Status;
+use connlib_shared::callbacks::{DnsResolution, Status};
 use connlib_shared::error::ConnlibError as Error;
 use connlib_shared::messages::client::{Site, SiteId};
 use connlib_shared::messages::ResolveRequest;
@@ -25,9 +26,10 @@ use crate::utils::{self, earliest, turn};
 use crate::{ClientEvent, ClientTunnel, Tun};
 use core::fmt;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{ClientNode, RelaySocket};
+use snownet::{ClientNode, ConnectionConfig, ConnectionStats, RelaySocket};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
@@ -38,8 +40,8 @@ pub(crate) const IPV4_RESOURCES: &str = "100.96.0.0/11";
 pub(crate) const IPV6_RESOURCES: &str = "fd00:2021:1111:8000::/107";
 
 const DNS_PORT: u16 = 53;
-const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
-const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
+pub(crate) const DNS_SENTINELS_V4: &str = "100.100.111.0/24";
+pub(crate) const DNS_SENTINELS_V6: &str = "fd00:2021:1111:8000:100:100:111:0/120";
 
 // The max time a dns request can be configured to live in resolvconf
 // is 30 seconds. See resolvconf(5) timeout.
@@ -116,7 +118,7 @@ impl ClientTunnel {
         }
 
         self.io
-            .set_upstream_dns_servers(self.role_state.dns_mapping());
+            .set_upstream_dns_servers(self.role_state.upstream_dns_servers());
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
@@ -128,16 +130,57 @@ impl ClientTunnel {
 
         if dns_changed {
             self.io
-                .set_upstream_dns_servers(self.role_state.dns_mapping());
+                .set_upstream_dns_servers(self.role_state.upstream_dns_servers());
         }
 
         Ok(())
     }
 
+    /// Enables or disables emitting [`ClientEvent::DnsQueryLogged`] for every DNS query we answer.
+    pub fn set_dns_query_log(&mut self, enabled: bool) {
+        self.role_state.set_dns_query_log(enabled);
+    }
+
+    /// Counters of the DNS queries for each DNS resource, indexed by the resource's address.
+    pub fn dns_resource_stats(&self) -> HashMap<String, DnsResourceStats> {
+        self.role_state.dns_resource_stats()
+    }
+
+    /// Configures the idle timeout and keep-alive of new connections to gateways.
+    ///
+    /// For example, mobile clients may want to disable keep-alives to save battery.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.role_state.set_connection_config(config);
+    }
+
+    /// Statistics of the connection to each gateway, e.g. its RTT and whether it is relayed.
+    pub fn connection_stats(&self) -> HashMap<GatewayId, ConnectionStats> {
+        self.role_state.connection_stats()
+    }
+
+    /// The candidate of each gateway that our connection to it is currently using.
+    ///
+    /// Persist these and pass them to [`ClientTunnel::remember_gateway_candidates`] to speed up connecting after a restart.
+    pub fn gateway_candidates_in_use(&self) -> HashMap<GatewayId, String> {
+        self.role_state.gateway_candidates_in_use()
+    }
+
+    /// Remembers candidates of a gateway from a previous session.
+    ///
+    /// They are tried as soon as the gateway answers our next connection request, before its candidates arrive via the portal.
+    pub fn remember_gateway_candidates(&mut self, gateway: GatewayId, candidates: Vec<String>) {
+        self.role_state
+            .remember_gateway_candidates(gateway, candidates);
+    }
+
     pub fn cleanup_connection(&mut self, id: ResourceId) {
         self.role_state.on_connection_failed(id);
     }
 
+    pub fn on_access_expired(&mut self, gateway_id: GatewayId, resource: ResourceId) {
+        self.role_state.on_access_expired(gateway_id, resource);
+    }
+
     pub fn set_resource_offline(&mut self, id: ResourceId) {
         self.role_state.set_resource_offline(id);
 
@@ -247,18 +290,29 @@ pub struct ClientState {
 
     /// Maps from connlib-assigned IP of a DNS server back to the originally configured system DNS resolver.
     dns_mapping: BiMap<IpAddr, DnsServer>,
-    /// DNS queries that had their destination IP mangled because the servers is a CIDR resource.
+    /// DNS queries that had their destination mangled because the servers is a CIDR resource.
+    ///
+    /// Indexed by the DNS server and the query ID, the [`SocketAddr`] is the sentinel the query was originally sent to, the [`dns::Transport`] is how the query reached us and the [`Instant`] tracks when the DNS query expires.
+    mangled_dns_queries: HashMap<SocketAddr, HashMap<u16, (SocketAddr, dns::Transport, Instant)>>,
+    /// DNS queries for non-address records of DNS resources that we forwarded to a gateway.
     ///
     /// The [`Instant`] tracks when the DNS query expires.
-    mangled_dns_queries: HashMap<u16, Instant>,
+    forwarded_dns_queries: HashMap<ForwardedDnsQuery, (dns::Transport, Instant)>,
     /// Manages internal dns records and emits forwarding event when not internally handled
     stub_resolver: StubResolver,
+    /// Terminates TCP connections to our sentinel DNS servers.
+    tcp_dns_server: dns::tcp::Server,
+    /// Responses to DNS queries that we forwarded to upstream DNS servers.
+    dns_cache: dns::cache::ResponseCache,
+    /// Tracks how we answered DNS queries for the DNS query log and the per-resource counters.
+    dns_query_log: QueryLog,
 
     /// Configuration of the TUN device, when it is up.
     interface_config: Option<InterfaceConfig>,
 
     buffered_events: VecDeque<ClientEvent>,
     buffered_packets: VecDeque<IpPacket<'static>>,
+    buffered_transmits: VecDeque<snownet::Transmit<'static>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -289,7 +343,12 @@ impl ClientState {
             sites_status: Default::default(),
             gateways_site: Default::default(),
             mangled_dns_queries: Default::default(),
+            forwarded_dns_queries: Default::default(),
             stub_resolver: StubResolver::new(known_hosts),
+            tcp_dns_server: Default::default(),
+            dns_cache: Default::default(),
+            dns_query_log: Default::default(),
+            buffered_transmits: Default::default(),
         }
     }
 
@@ -396,7 +455,7 @@ impl ClientState {
         packet: MutableIpPacket<'_>,
         now: Instant,
     ) -> Option<snownet::Transmit<'s>> {
-        let (packet, dst) = match self.handle_dns(packet) {
+        let (packet, dst) = match self.handle_dns(packet, now) {
             Ok(response) => {
                 self.buffered_packets.push_back(response?.to_owned());
                 return None;
@@ -404,6 +463,16 @@ impl ClientState {
             Err(non_dns_packet) => non_dns_packet,
         };
 
+        self.encapsulate_to_resource(packet, dst, now)
+    }
+
+    /// Routes a packet to the gateway of the resource that `dst` belongs to.
+    fn encapsulate_to_resource<'s>(
+        &'s mut self,
+        packet: MutableIpPacket<'_>,
+        dst: IpAddr,
+        now: Instant,
+    ) -> Option<snownet::Transmit<'s>> {
         if is_definitely_not_a_resource(dst) {
             return None;
         }
@@ -413,6 +482,8 @@ impl ClientState {
             return None;
         };
 
+        self.stub_resolver.refresh_lease(&dst, now);
+
         // We read this here to prevent problems with the borrow checker
         let is_dns_resource = self.is_dns_resource(&resource);
 
@@ -422,13 +493,6 @@ impl ClientState {
             return None;
         };
 
-        let packet = maybe_mangle_dns_query_to_cidr_resource(
-            packet,
-            &self.dns_mapping,
-            &mut self.mangled_dns_queries,
-            now,
-        );
-
         // Allowed IPs will track the IPs that we have sent to the gateway along with a list of ResourceIds
         // for DNS resource we will send the IP one at a time.
         if is_dns_resource && peer.allowed_ips.exact_match(dst).is_none() {
@@ -439,6 +503,17 @@ impl ClientState {
 
         let gid = peer.id();
 
+        if is_dns_resource {
+            // DNS queries for non-address records of DNS resources are resolved by the gateway, see `ResolveStrategy::ForwardToGateway`.
+            track_dns_query_to_gateway(
+                &packet,
+                gid,
+                &self.dns_mapping,
+                &mut self.forwarded_dns_queries,
+                now,
+            );
+        }
+
         let transmit = self
             .node
             .encapsulate(gid, packet.as_immutable(), now)
@@ -466,6 +541,17 @@ impl ClientState {
         .inspect_err(|e| tracing::debug!(%local, num_bytes = %packet.len(), "Failed to decapsulate incoming packet: {e}"))
         .ok()??;
 
+        // Responses to DNS queries that we forwarded to the gateway come from our sentinel DNS servers and thus don't pass `ensure_allowed_src`.
+        if let Some(transport) = take_dns_query_to_gateway(
+            &packet,
+            gid,
+            &self.dns_mapping,
+            &mut self.forwarded_dns_queries,
+        ) {
+            self.on_forwarded_dns_response(packet.into_immutable(), transport, now);
+            return None;
+        }
+
         let Some(peer) = self.peers.get_mut(&gid) else {
             tracing::error!(%gid, "Couldn't find connection by ID");
 
@@ -476,13 +562,30 @@ impl ClientState {
             .inspect_err(|e| tracing::debug!(%gid, %local, %from, "{e}"))
             .ok()?;
 
-        let packet = maybe_mangle_dns_response_from_cidr_resource(
+        let (packet, transport) = maybe_mangle_dns_response_from_cidr_resource(
             packet,
-            &self.dns_mapping,
             &mut self.mangled_dns_queries,
             now,
         );
 
+        if self.dns_mapping.contains_left(&packet.source()) {
+            self.dns_query_log.on_response(&packet.as_immutable(), now);
+        }
+
+        self.stub_resolver.refresh_lease(&packet.source(), now);
+
+        if transport == dns::Transport::Tcp {
+            let response = packet.into_immutable();
+
+            self.tcp_dns_server.send_response(
+                tcp_dns_connection(&response),
+                response.udp_payload(),
+                now,
+            );
+
+            return None;
+        }
+
         Some(packet.into_immutable())
     }
 
@@ -591,6 +694,50 @@ impl ClientState {
         !interface.upstream_dns.is_empty()
     }
 
+    /// The DNS server with the given address, if it has been configured by the portal, either as upstream DNS server or as part of a forwarding rule.
+    fn dns_server_set_by_the_portal(&self, server: SocketAddr) -> Option<&DnsServer> {
+        if let Some(server) = self.stub_resolver.forwarding_server(server) {
+            return Some(server);
+        }
+
+        if !self.is_upstream_set_by_the_portal() {
+            return None;
+        }
+
+        self.dns_mapping
+            .right_values()
+            .find(|s| s.address() == server)
+    }
+
+    /// Decides how to forward a DNS query to the given upstream server, regardless of whether it reached us via UDP or TCP.
+    fn dns_forwarding(&self, upstream: SocketAddr) -> DnsForwarding {
+        // There's an edge case here, where the resolver's ip has been resolved before as
+        // a dns resource... we will ignore that weird case for now.
+        // In case the DNS server is a CIDR resource, it needs to go through the tunnel.
+        let Some(server) = self
+            .dns_server_set_by_the_portal(upstream)
+            .filter(|_| self.cidr_resources.longest_match(upstream.ip()).is_some())
+        else {
+            return DnsForwarding::Host;
+        };
+
+        // We can only send plain DNS through the tunnel and must never downgrade an encrypted upstream.
+        if matches!(server, DnsServer::Tls(_) | DnsServer::Https(_)) {
+            return DnsForwarding::Refuse;
+        }
+
+        DnsForwarding::Tunnel
+    }
+
+    /// All DNS servers that we forward queries to, i.e. the effective DNS servers and the ones from the forwarding rules.
+    pub(crate) fn upstream_dns_servers(&self) -> Vec<DnsServer> {
+        self.dns_mapping
+            .right_values()
+            .cloned()
+            .chain(self.stub_resolver.forwarding_servers())
+            .collect()
+    }
+
     /// Attempt to handle the given packet as a DNS packet.
     ///
     /// Returns `Ok` if the packet is in fact a DNS query with an optional response to send back.
@@ -598,30 +745,73 @@ impl ClientState {
     fn handle_dns<'a>(
         &mut self,
         packet: MutableIpPacket<'a>,
+        now: Instant,
     ) -> Result<Option<IpPacket<'a>>, (MutableIpPacket<'a>, IpAddr)> {
+        if self.is_tcp_dns_packet(&packet) {
+            self.tcp_dns_server
+                .handle_inbound(packet.as_immutable(), now);
+            self.handle_tcp_dns_queries(now);
+
+            return Ok(None);
+        }
+
         match self
             .stub_resolver
-            .handle(&self.dns_mapping, packet.as_immutable())
+            .handle(&self.dns_mapping, packet.as_immutable(), now)
         {
-            Some(dns::ResolveStrategy::LocalResponse(query)) => Ok(Some(query)),
+            Some(dns::ResolveStrategy::LocalResponse(response)) => {
+                self.log_dns_query(&packet.as_immutable(), DnsResolution::Local, now);
+                self.dns_query_log.on_response(&response, now);
+
+                Ok(Some(response))
+            }
+            Some(dns::ResolveStrategy::ForwardToGateway(proxy_ip)) => {
+                self.log_dns_query(&packet.as_immutable(), DnsResolution::Gateway, now);
+
+                Err((packet, proxy_ip))
+            }
             Some(dns::ResolveStrategy::ForwardQuery(query)) => {
-                // There's an edge case here, where the resolver's ip has been resolved before as
-                // a dns resource... we will ignore that weird case for now.
-                if let Some(upstream_dns) = self.dns_mapping.get_by_left(&query.query.destination())
-                {
-                    let ip = upstream_dns.ip();
-
-                    // In case the DNS server is a CIDR resource, it needs to go through the tunnel.
-                    if self.is_upstream_set_by_the_portal()
-                        && self.cidr_resources.longest_match(ip).is_some()
-                    {
-                        return Err((packet, ip));
-                    }
+                if let Some(response) = self.dns_cache.get(&query, now) {
+                    self.log_dns_query(&packet.as_immutable(), DnsResolution::Cache, now);
+                    self.dns_query_log.on_response(&response, now);
+
+                    return Ok(Some(response));
                 }
 
-                self.buffered_dns_queries.push_back(query.into_owned());
+                let upstream = query.upstream;
+                self.log_dns_query(
+                    &packet.as_immutable(),
+                    DnsResolution::Upstream { server: upstream },
+                    now,
+                );
+
+                match self.dns_forwarding(upstream) {
+                    DnsForwarding::Host => {
+                        self.buffered_dns_queries.push_back(query.into_owned());
 
-                Ok(None)
+                        Ok(None)
+                    }
+                    DnsForwarding::Tunnel => {
+                        let packet = mangle_dns_query_to_cidr_resource(
+                            packet,
+                            upstream,
+                            dns::Transport::Udp,
+                            &mut self.mangled_dns_queries,
+                            now,
+                        );
+
+                        Err((packet, upstream.ip()))
+                    }
+                    DnsForwarding::Refuse => {
+                        let response = dns_error_reply(
+                            packet.as_immutable().to_owned(),
+                            &format_args!("Not sending plaintext query to {upstream}"),
+                        );
+                        self.dns_query_log.on_response(&response, now);
+
+                        Ok(Some(response))
+                    }
+                }
             }
             None => {
                 let dest = packet.destination();
@@ -630,6 +820,146 @@ impl ClientState {
         }
     }
 
+    fn log_dns_query(&mut self, query: &IpPacket, resolution: DnsResolution, now: Instant) {
+        let stub_resolver = &self.stub_resolver;
+
+        self.dns_query_log.on_query(
+            query,
+            |name| stub_resolver.match_resource(name),
+            resolution,
+            now,
+        );
+    }
+
+    fn is_tcp_dns_packet(&self, packet: &MutableIpPacket) -> bool {
+        self.dns_mapping.contains_left(&packet.destination())
+            && packet
+                .as_immutable_tcp()
+                .is_some_and(|tcp| tcp.get_destination() == DNS_PORT)
+    }
+
+    /// Answers or forwards all DNS queries that we received via TCP.
+    ///
+    /// Like queries we receive via UDP, the ones for upstream DNS servers that are CIDR resources go through the tunnel.
+    /// Because we terminate the TCP connection locally, we send those as UDP packets.
+    fn handle_tcp_dns_queries(&mut self, now: Instant) {
+        while let Some(query) = self.tcp_dns_server.poll_query() {
+            let connection = query.connection;
+
+            // Wrap the message in a UDP packet so we can reuse the logic for UDP DNS queries.
+            let packet = ip_packet::make::udp_packet(
+                connection.remote.ip(),
+                connection.local.ip(),
+                connection.remote.port(),
+                connection.local.port(),
+                query.message,
+            );
+
+            match self
+                .stub_resolver
+                .handle(&self.dns_mapping, packet.as_immutable(), now)
+            {
+                Some(dns::ResolveStrategy::LocalResponse(response)) => {
+                    self.log_dns_query(&packet.as_immutable(), DnsResolution::Local, now);
+                    self.dns_query_log.on_response(&response, now);
+
+                    self.tcp_dns_server
+                        .send_response(connection, response.udp_payload(), now);
+                }
+                Some(dns::ResolveStrategy::ForwardQuery(query)) => {
+                    if let Some(response) = self.dns_cache.get(&query, now) {
+                        self.log_dns_query(&packet.as_immutable(), DnsResolution::Cache, now);
+                        self.dns_query_log.on_response(&response, now);
+
+                        self.tcp_dns_server
+                            .send_response(connection, response.udp_payload(), now);
+                        continue;
+                    }
+
+                    let upstream = query.upstream;
+                    self.log_dns_query(
+                        &packet.as_immutable(),
+                        DnsResolution::Upstream { server: upstream },
+                        now,
+                    );
+
+                    match self.dns_forwarding(upstream) {
+                        DnsForwarding::Host => {
+                            self.buffered_dns_queries.push_back(DnsQuery {
+                                transport: dns::Transport::Tcp,
+                                ..query.into_owned()
+                            });
+                        }
+                        DnsForwarding::Tunnel => {
+                            let packet = mangle_dns_query_to_cidr_resource(
+                                packet,
+                                upstream,
+                                dns::Transport::Tcp,
+                                &mut self.mangled_dns_queries,
+                                now,
+                            );
+
+                            let Some(transmit) = self
+                                .encapsulate_to_resource(packet, upstream.ip(), now)
+                                .map(|t| t.into_owned())
+                            else {
+                                // Let the client retry once we are connected to the gateway.
+                                self.tcp_dns_server.reset(connection);
+                                continue;
+                            };
+
+                            self.buffered_transmits.push_back(transmit);
+                        }
+                        DnsForwarding::Refuse => {
+                            let response = dns_error_reply(
+                                packet.as_immutable().to_owned(),
+                                &format_args!("Not sending plaintext query to {upstream}"),
+                            );
+                            self.dns_query_log.on_response(&response, now);
+
+                            self.tcp_dns_server.send_response(
+                                connection,
+                                response.udp_payload(),
+                                now,
+                            );
+                        }
+                    }
+                }
+                Some(dns::ResolveStrategy::ForwardToGateway(proxy_ip)) => {
+                    self.log_dns_query(&packet.as_immutable(), DnsResolution::Gateway, now);
+                    let query_id = dns_query_id(&packet);
+
+                    let Some(transmit) = self
+                        .encapsulate_to_resource(packet, proxy_ip, now)
+                        .map(|t| t.into_owned())
+                    else {
+                        // Let the client retry once we are connected to the gateway.
+                        self.tcp_dns_server.reset(connection);
+                        continue;
+                    };
+
+                    // The gateway always answers via UDP, remember to send the response on the TCP connection.
+                    for (_, (transport, _)) in
+                        self.forwarded_dns_queries.iter_mut().filter(|(q, _)| {
+                            q.src == connection.remote
+                                && q.sentinel == connection.local.ip()
+                                && Some(q.id) == query_id
+                        })
+                    {
+                        *transport = dns::Transport::Tcp;
+                    }
+
+                    self.buffered_transmits.push_back(transmit);
+                }
+                None => {
+                    tracing::debug!(remote = %connection.remote, "Received invalid DNS query over TCP");
+
+                    self.tcp_dns_server.reset(connection);
+                }
+            }
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(name = %query.name, server = %query.query.destination()))] // On debug level, we can log potentially sensitive information such as domain names.
     pub(crate) fn on_dns_result(
         &mut self,
@@ -641,35 +971,92 @@ impl ClientState {
             >,
             DnsQueryError,
         >,
+        now: Instant,
     ) {
-        let query = query.query;
-        let make_error_reply = {
-            let query = query.clone();
-
-            |e: &dyn fmt::Display| {
-                // To avoid sensitive data getting into the logs, only log the error if debug logging is enabled.
-                // We always want to see a warning.
-                if tracing::enabled!(Level::DEBUG) {
-                    tracing::warn!("DNS query failed: {e}");
-                } else {
-                    tracing::warn!("DNS query failed");
-                };
-
-                ip_packet::make::dns_err_response(query, hickory_proto::op::ResponseCode::ServFail)
-                    .into_immutable()
-            }
-        };
+        let dns_query = query;
+        let query = dns_query.query.clone();
 
         let dns_reply = match response {
             Ok(Ok(response)) => match dns::build_response_from_resolve_result(query, response) {
                 Ok(dns_reply) => dns_reply,
-                Err(e) => make_error_reply(&e),
+                Err(e) => dns_error_reply(dns_query.query.clone(), &e),
             },
-            Ok(Err(timeout)) => make_error_reply(&timeout),
-            Err(e) => make_error_reply(&e),
+            Ok(Err(timeout)) => dns_error_reply(query, &timeout),
+            Err(e) => dns_error_reply(query, &e),
+        };
+
+        self.send_dns_reply(&dns_query, dns_reply, now);
+    }
+
+    /// Handles the response of an upstream DNS server to a query that we forwarded as-is, see [`Io`](crate::io::Io).
+    pub(crate) fn on_raw_dns_response(
+        &mut self,
+        query: DnsQuery<'static>,
+        response: Result<io::Result<Vec<u8>>, futures_bounded::Timeout>,
+        now: Instant,
+    ) {
+        let dns_reply = match response {
+            Ok(Ok(message)) => dns::build_response(query.query.clone(), message),
+            Ok(Err(e)) => dns_error_reply(query.query.clone(), &e),
+            Err(timeout) => dns_error_reply(query.query.clone(), &timeout),
+        };
+
+        self.send_dns_reply(&query, dns_reply, now);
+    }
+
+    fn send_dns_reply(
+        &mut self,
+        dns_query: &DnsQuery<'static>,
+        dns_reply: IpPacket<'static>,
+        now: Instant,
+    ) {
+        self.dns_cache.insert(dns_query, &dns_reply, now);
+        self.dns_query_log.on_response(&dns_reply, now);
+
+        match dns_query.transport {
+            dns::Transport::Udp => {
+                self.buffered_packets.push_back(dns_reply);
+            }
+            dns::Transport::Tcp => {
+                self.tcp_dns_server.send_response(
+                    tcp_dns_connection(&dns_reply),
+                    dns_reply.udp_payload(),
+                    now,
+                );
+            }
+        }
+    }
+
+    fn on_forwarded_dns_response(
+        &mut self,
+        response: IpPacket<'_>,
+        transport: dns::Transport,
+        now: Instant,
+    ) {
+        let Some(response) = self.stub_resolver.rewrite_forwarded_response(response, now) else {
+            tracing::debug!("Failed to parse DNS response from gateway");
+            return;
         };
 
-        self.buffered_packets.push_back(dns_reply);
+        self.dns_query_log.on_response(&response, now);
+
+        match transport {
+            dns::Transport::Udp => {
+                self.buffered_packets.push_back(response);
+            }
+            dns::Transport::Tcp => {
+                let connection = dns::tcp::ConnectionId {
+                    local: SocketAddr::new(response.source(), DNS_PORT),
+                    remote: SocketAddr::new(
+                        response.destination(),
+                        response.unwrap_as_udp().get_destination(),
+                    ),
+                };
+
+                self.tcp_dns_server
+                    .send_response(connection, response.udp_payload(), now);
+            }
+        }
     }
 
     pub fn on_connection_failed(&mut self, resource: ResourceId) {
@@ -677,6 +1064,21 @@ impl ClientState {
         self.resources_gateways.remove(&resource);
     }
 
+    /// A gateway told us that our access to a resource expired.
+    ///
+    /// The next packet for the resource will request access again, possibly via a different gateway.
+    pub fn on_access_expired(&mut self, gateway_id: GatewayId, resource: ResourceId) {
+        if self.resources_gateways.get(&resource) != Some(&gateway_id) {
+            return;
+        }
+
+        tracing::debug!(gateway = %gateway_id, %resource, "Access to resource expired");
+
+        // The gateway forgets about us once all of our access expired, the next access then needs a new connection.
+        self.remove_resource_from_gateway(resource);
+        self.on_connection_failed(resource);
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(%resource))]
     fn on_not_connected_resource(
         &mut self,
@@ -747,6 +1149,8 @@ impl ClientState {
     fn set_dns_mapping(&mut self, new_mapping: BiMap<IpAddr, DnsServer>) {
         self.dns_mapping = new_mapping;
         self.mangled_dns_queries.clear();
+        self.forwarded_dns_queries.clear();
+        self.dns_cache.clear();
     }
 
     pub fn dns_mapping(&self) -> BiMap<IpAddr, DnsServer> {
@@ -782,6 +1186,11 @@ impl ClientState {
 
     #[must_use]
     pub(crate) fn update_system_resolvers(&mut self, new_dns: Vec<IpAddr>) -> bool {
+        // The system's resolvers change when we switch networks, cached responses may no longer be valid.
+        if self.system_resolvers != new_dns {
+            self.dns_cache.clear();
+        }
+
         self.system_resolvers = new_dns;
 
         self.update_dns_mapping()
@@ -789,13 +1198,26 @@ impl ClientState {
 
     #[must_use]
     pub(crate) fn update_interface_config(&mut self, config: InterfaceConfig) -> bool {
+        self.stub_resolver.set_proxy_ip_lease(
+            config
+                .proxy_ip_lease
+                .map(|secs| Duration::from_secs(secs.into())),
+        );
+        let forwarding_rules_changed = self
+            .stub_resolver
+            .set_forwarding_rules(&config.dns_forwarding_rules);
+        if forwarding_rules_changed {
+            self.dns_cache.clear();
+        }
         self.interface_config = Some(config);
 
-        self.update_dns_mapping()
+        self.update_dns_mapping() || forwarding_rules_changed
     }
 
     pub fn poll_packets(&mut self) -> Option<IpPacket<'static>> {
-        self.buffered_packets.pop_front()
+        self.buffered_packets
+            .pop_front()
+            .or_else(|| self.tcp_dns_server.poll_packet())
     }
 
     pub fn poll_dns_queries(&mut self) -> Option<DnsQuery<'static>> {
@@ -805,15 +1227,45 @@ impl ClientState {
     pub fn poll_timeout(&mut self) -> Option<Instant> {
         // The number of mangled DNS queries is expected to be fairly small because we only track them whilst connecting to a CIDR resource that is a DNS server.
         // Thus, sorting these values on-demand even within `poll_timeout` is expected to be performant enough.
-        let next_dns_query_expiry = self.mangled_dns_queries.values().min().copied();
+        let next_dns_query_expiry = self
+            .mangled_dns_queries
+            .values()
+            .flat_map(|queries| queries.values())
+            .map(|(_, _, exp)| exp)
+            .chain(self.forwarded_dns_queries.values().map(|(_, exp)| exp))
+            .min()
+            .copied();
         let next_node_timeout = self.node.poll_timeout();
-
-        earliest(next_dns_query_expiry, next_node_timeout)
+        let next_tcp_dns_timeout = self.tcp_dns_server.poll_timeout();
+        let next_lease_sweep = self.stub_resolver.poll_timeout();
+        let next_query_log_timeout = self.dns_query_log.poll_timeout();
+
+        earliest(
+            earliest(next_dns_query_expiry, next_node_timeout),
+            earliest(
+                earliest(next_tcp_dns_timeout, next_lease_sweep),
+                next_query_log_timeout,
+            ),
+        )
     }
 
     pub fn handle_timeout(&mut self, now: Instant) {
         self.node.handle_timeout(now);
-        self.mangled_dns_queries.retain(|_, exp| now < *exp);
+        self.mangled_dns_queries.retain(|_, queries| {
+            queries.retain(|_, (_, _, exp)| now < *exp);
+
+            !queries.is_empty()
+        });
+        self.forwarded_dns_queries.retain(|_, (_, exp)| now < *exp);
+        self.tcp_dns_server.handle_timeout(now);
+        self.dns_query_log.handle_timeout(now);
+
+        let recycled_proxy_ips = self.stub_resolver.handle_timeout(now);
+        if !recycled_proxy_ips.is_empty() {
+            // Recycled IPs may be handed out for a different domain, make sure we re-send them to the gateway on next use.
+            self.peers
+                .remove_ips(&recycled_proxy_ips.into_iter().map_into().collect_vec());
+        }
 
         self.drain_node_events();
     }
@@ -851,6 +1303,12 @@ impl ClientState {
                     self.update_site_status_by_gateway(&id, Status::Online);
                     resources_changed = true;
                 }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    candidate_pair,
+                } => {
+                    tracing::info!(gateway = %connection, ?candidate_pair, "Path to gateway changed");
+                }
             }
         }
 
@@ -890,7 +1348,53 @@ impl ClientState {
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<ClientEvent> {
-        self.buffered_events.pop_front()
+        if let Some(event) = self.buffered_events.pop_front() {
+            return Some(event);
+        }
+
+        self.dns_query_log
+            .poll_entry()
+            .map(|entry| ClientEvent::DnsQueryLogged { entry })
+    }
+
+    pub(crate) fn set_dns_query_log(&mut self, enabled: bool) {
+        self.dns_query_log.set_enabled(enabled);
+    }
+
+    pub(crate) fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.node.set_connection_config(config);
+    }
+
+    pub(crate) fn connection_stats(&self) -> HashMap<GatewayId, ConnectionStats> {
+        let (_, connections) = self.node.stats();
+
+        connections.collect()
+    }
+
+    pub(crate) fn gateway_candidates_in_use(&self) -> HashMap<GatewayId, String> {
+        self.node.remote_candidates_in_use().collect()
+    }
+
+    pub(crate) fn remember_gateway_candidates(
+        &mut self,
+        gateway: GatewayId,
+        candidates: Vec<String>,
+    ) {
+        self.node.remember_remote_candidates(gateway, candidates);
+    }
+
+    pub(crate) fn dns_resource_stats(&self) -> HashMap<String, DnsResourceStats> {
+        self.dns_query_log
+            .stats()
+            .iter()
+            .filter_map(|(id, stats)| {
+                let ResourceDescription::Dns(resource) = self.resources_by_id.get(id)? else {
+                    return None;
+                };
+
+                Some((resource.address.clone(), *stats))
+            })
+            .collect()
     }
 
     pub(crate) fn reset(&mut self) {
@@ -901,7 +1405,9 @@ impl ClientState {
     }
 
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'static>> {
-        self.node.poll_transmit()
+        self.buffered_transmits
+            .pop_front()
+            .or_else(|| self.node.poll_transmit())
     }
 
     /// Sets a new set of resources.
@@ -937,7 +1443,11 @@ impl ClientState {
 
         match &new_resource {
             ResourceDescription::Dns(dns) => {
-                self.stub_resolver.add_resource(dns.id, dns.address.clone());
+                self.stub_resolver.add_resource(
+                    dns.id,
+                    dns.address.clone(),
+                    &dns.excluded_addresses,
+                );
             }
             ResourceDescription::Cidr(cidr) => {
                 let existing = self.cidr_resources.insert(cidr.address, cidr.clone());
@@ -962,6 +1472,7 @@ impl ClientState {
     pub(crate) fn remove_resource(&mut self, id: ResourceId) {
         self.awaiting_connection_details.remove(&id);
         self.stub_resolver.remove_resource(id);
+        self.dns_query_log.remove_resource(&id);
         self.cidr_resources.retain(|_, r| {
             if r.id == id {
                 tracing::info!(address = %r.address, name = %r.name, "Deactivating CIDR resource");
@@ -972,7 +1483,12 @@ impl ClientState {
         });
 
         self.resources_by_id.remove(&id);
+        self.remove_resource_from_gateway(id);
+        self.resources_gateways.remove(&id);
+    }
 
+    /// Stops routing the resource's IPs to its gateway, removing the gateway altogether if none of its resources are left.
+    fn remove_resource_from_gateway(&mut self, id: ResourceId) {
         let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, id) else {
             return;
         };
@@ -1000,8 +1516,6 @@ impl ClientState {
             self.update_site_status_by_gateway(&gateway_id, Status::Unknown);
             // TODO: should we have a Node::remove_connection?
         }
-
-        self.resources_gateways.remove(&id);
     }
 
     fn update_dns_mapping(&mut self) -> bool {
@@ -1119,13 +1633,7 @@ fn effective_dns_servers(
 }
 
 fn not_sentinel(srv: DnsServer) -> Option<DnsServer> {
-    (!IpNetwork::from_str(DNS_SENTINELS_V4)
-        .unwrap()
-        .contains(srv.ip())
-        && !IpNetwork::from_str(DNS_SENTINELS_V6)
-            .unwrap()
-            .contains(srv.ip()))
-    .then_some(srv)
+    (!dns::is_sentinel(srv.ip())).then_some(srv)
 }
 
 fn sentinel_dns_mapping(
@@ -1170,65 +1678,76 @@ fn is_definitely_not_a_resource(ip: IpAddr) -> bool {
     false
 }
 
-/// In case the given packet is a DNS query, change its source IP to that of the actual DNS server.
-fn maybe_mangle_dns_query_to_cidr_resource<'p>(
+/// Change the destination of the given DNS query to that of the actual DNS server.
+fn mangle_dns_query_to_cidr_resource<'p>(
     mut packet: MutableIpPacket<'p>,
-    dns_mapping: &BiMap<IpAddr, DnsServer>,
-    mangeled_dns_queries: &mut HashMap<u16, Instant>,
+    upstream: SocketAddr,
+    transport: dns::Transport,
+    mangeled_dns_queries: &mut HashMap<
+        SocketAddr,
+        HashMap<u16, (SocketAddr, dns::Transport, Instant)>,
+    >,
     now: Instant,
 ) -> MutableIpPacket<'p> {
-    let dst = packet.destination();
-
-    let Some(srv) = dns_mapping.get_by_left(&dst) else {
+    let Some(id) = dns_query_id(&packet) else {
         return packet;
     };
-
-    let Some(dgm) = packet.as_udp() else {
-        return packet;
-    };
-
-    let Ok(message) = domain::base::Message::from_slice(dgm.payload()) else {
+    let Some(dst_port) = packet.as_immutable_udp().map(|udp| udp.get_destination()) else {
         return packet;
     };
+    let sentinel = SocketAddr::new(packet.destination(), dst_port);
 
-    tracing::trace!(old_dst = %dst, new_dst = %srv.ip(), "Mangling DNS query to CIDR resource");
+    tracing::trace!(old_dst = %sentinel, new_dst = %upstream, "Mangling DNS query to CIDR resource");
 
-    mangeled_dns_queries.insert(message.header().id(), now + IDS_EXPIRE);
-    packet.set_dst(srv.ip());
+    mangeled_dns_queries
+        .entry(upstream)
+        .or_default()
+        .insert(id, (sentinel, transport, now + IDS_EXPIRE));
+    packet.set_dst(upstream.ip());
+    packet.set_destination_protocol(upstream.port());
     packet.update_checksum();
 
     packet
 }
 
+/// Change the source of a DNS response from a CIDR resource back to the sentinel the query was sent to.
+///
+/// Also returns how the query reached us, packets that aren't such a response are always [`dns::Transport::Udp`].
 fn maybe_mangle_dns_response_from_cidr_resource<'p>(
     mut packet: MutableIpPacket<'p>,
-    dns_mapping: &BiMap<IpAddr, DnsServer>,
-    mangeled_dns_queries: &mut HashMap<u16, Instant>,
+    mangeled_dns_queries: &mut HashMap<
+        SocketAddr,
+        HashMap<u16, (SocketAddr, dns::Transport, Instant)>,
+    >,
     now: Instant,
-) -> MutableIpPacket<'p> {
+) -> (MutableIpPacket<'p>, dns::Transport) {
     let src_ip = packet.source();
 
     let Some(udp) = packet.as_udp() else {
-        return packet;
+        return (packet, dns::Transport::Udp);
     };
 
-    let src_port = udp.get_source();
+    let src = SocketAddr::new(src_ip, udp.get_source());
 
-    let Some(sentinel) = dns_mapping.get_by_right(&DnsServer::from((src_ip, src_port))) else {
-        return packet;
+    let Some(queries) = mangeled_dns_queries.get_mut(&src) else {
+        return (packet, dns::Transport::Udp);
     };
 
     let Ok(message) = domain::base::Message::from_slice(udp.payload()) else {
-        return packet;
+        return (packet, dns::Transport::Udp);
     };
 
-    let Some(query_sent_at) = mangeled_dns_queries
+    let Some((sentinel, transport, query_sent_at)) = queries
         .remove(&message.header().id())
-        .map(|expires_at| expires_at - IDS_EXPIRE)
+        .map(|(sentinel, transport, expires_at)| (sentinel, transport, expires_at - IDS_EXPIRE))
     else {
-        return packet;
+        return (packet, dns::Transport::Udp);
     };
 
+    if queries.is_empty() {
+        mangeled_dns_queries.remove(&src);
+    }
+
     let rtt = now.duration_since(query_sent_at);
 
     let domains = message
@@ -1236,17 +1755,127 @@ fn maybe_mangle_dns_response_from_cidr_resource<'p>(
         .filter_map(|q| Some(q.ok()?.into_qname()))
         .join(",");
 
-    tracing::trace!(old_src = %src_ip, new_src = %sentinel, ?rtt, %domains, "Mangling DNS response from CIDR resource");
+    tracing::trace!(old_src = %src, new_src = %sentinel, ?rtt, %domains, "Mangling DNS response from CIDR resource");
 
-    packet.set_src(*sentinel);
+    packet.set_src(sentinel.ip());
+    packet.set_source_protocol(sentinel.port());
     packet.update_checksum();
 
-    packet
+    (packet, transport)
+}
+
+/// The TCP connection to one of our sentinel DNS servers that the given response needs to be sent on.
+fn tcp_dns_connection(response: &IpPacket) -> dns::tcp::ConnectionId {
+    dns::tcp::ConnectionId {
+        local: SocketAddr::new(response.source(), response.unwrap_as_udp().get_source()),
+        remote: SocketAddr::new(
+            response.destination(),
+            response.unwrap_as_udp().get_destination(),
+        ),
+    }
+}
+
+fn dns_query_id(packet: &MutableIpPacket) -> Option<u16> {
+    let datagram = packet.as_immutable_udp()?;
+    let message = domain::base::Message::from_slice(datagram.payload()).ok()?;
+
+    Some(message.header().id())
+}
+
+/// How to forward a DNS query to an upstream server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DnsForwarding {
+    /// Via [`Io`](crate::io::Io), i.e. on the host's network.
+    Host,
+    /// Through the tunnel because the upstream server is a CIDR resource.
+    Tunnel,
+    /// Not at all because we'd have to downgrade an encrypted upstream to plain DNS.
+    Refuse,
+}
+
+/// A DNS query that we forwarded to a gateway.
+///
+/// DNS IDs are only 16 bit, thus we also need the sockets and the gateway to match the response to its query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ForwardedDnsQuery {
+    /// The gateway we forwarded the query to, only it may answer.
+    gateway: GatewayId,
+    /// The socket of the application that sent the query.
+    src: SocketAddr,
+    /// The sentinel DNS server the query was sent to.
+    sentinel: IpAddr,
+    id: u16,
+}
+
+fn track_dns_query_to_gateway(
+    packet: &MutableIpPacket,
+    gateway: GatewayId,
+    dns_mapping: &BiMap<IpAddr, DnsServer>,
+    forwarded_dns_queries: &mut HashMap<ForwardedDnsQuery, (dns::Transport, Instant)>,
+    now: Instant,
+) {
+    if !dns_mapping.contains_left(&packet.destination()) {
+        return;
+    }
+
+    let Some(datagram) = packet.as_immutable_udp() else {
+        return;
+    };
+    let Some(id) = dns_query_id(packet) else {
+        return;
+    };
+
+    let query = ForwardedDnsQuery {
+        gateway,
+        src: SocketAddr::new(packet.source(), datagram.get_source()),
+        sentinel: packet.destination(),
+        id,
+    };
+
+    forwarded_dns_queries.insert(query, (dns::Transport::Udp, now + IDS_EXPIRE));
+}
+
+fn take_dns_query_to_gateway(
+    packet: &MutableIpPacket,
+    gateway: GatewayId,
+    dns_mapping: &BiMap<IpAddr, DnsServer>,
+    forwarded_dns_queries: &mut HashMap<ForwardedDnsQuery, (dns::Transport, Instant)>,
+) -> Option<dns::Transport> {
+    if !dns_mapping.contains_left(&packet.source()) {
+        return None;
+    }
+
+    let datagram = packet.as_immutable_udp()?;
+    if datagram.get_source() != DNS_PORT {
+        return None;
+    }
+
+    let message = domain::base::Message::from_slice(datagram.payload()).ok()?;
+    if !message.header().qr() {
+        return None;
+    }
+
+    let query = ForwardedDnsQuery {
+        gateway,
+        src: SocketAddr::new(packet.destination(), datagram.get_destination()),
+        sentinel: packet.source(),
+        id: message.header().id(),
+    };
+
+    let (transport, _) = forwarded_dns_queries.remove(&query)?;
+
+    Some(transport)
 }
 
 pub struct IpProvider {
     ipv4: Box<dyn Iterator<Item = Ipv4Addr> + Send + Sync>,
     ipv6: Box<dyn Iterator<Item = Ipv6Addr> + Send + Sync>,
+
+    /// IPs that have been handed back to us, oldest first, and when we may reuse them.
+    ///
+    /// We prefer fresh IPs and only fall back to these once we ran out.
+    released_ipv4: VecDeque<(Ipv4Addr, Instant)>,
+    released_ipv6: VecDeque<(Ipv6Addr, Instant)>,
 }
 
 impl IpProvider {
@@ -1281,6 +1910,8 @@ impl IpProvider {
                     .map(|ip| ip.network_address())
                     .filter(move |ip| !exclusions.iter().any(|e| e.contains(*ip)))
             }),
+            released_ipv4: Default::default(),
+            released_ipv6: Default::default(),
         }
     }
 
@@ -1299,20 +1930,92 @@ impl IpProvider {
         proxy_ip
     }
 
-    pub fn get_n_ipv4(&mut self, n: usize) -> Vec<IpAddr> {
-        self.ipv4.by_ref().take(n).map_into().collect_vec()
+    pub fn get_n_ipv4(&mut self, n: usize, now: Instant) -> Vec<IpAddr> {
+        get_n(&mut self.ipv4, &mut self.released_ipv4, n, now)
+    }
+
+    pub fn get_n_ipv6(&mut self, n: usize, now: Instant) -> Vec<IpAddr> {
+        get_n(&mut self.ipv6, &mut self.released_ipv6, n, now)
     }
 
-    pub fn get_n_ipv6(&mut self, n: usize) -> Vec<IpAddr> {
-        self.ipv6.by_ref().take(n).map_into().collect_vec()
+    /// Hands an IP back to the provider so it can be reused from `reusable_at` on.
+    ///
+    /// Applications may still use the IP until then, e.g. because they cached our DNS answer.
+    pub fn release(&mut self, ip: IpAddr, reusable_at: Instant) {
+        match ip {
+            IpAddr::V4(v4) => self.released_ipv4.push_back((v4, reusable_at)),
+            IpAddr::V6(v6) => self.released_ipv6.push_back((v6, reusable_at)),
+        }
     }
 }
 
+/// Takes up to `n` fresh IPs and falls back to released ones whose quarantine has passed.
+fn get_n<T>(
+    fresh: &mut (dyn Iterator<Item = T> + Send + Sync),
+    released: &mut VecDeque<(T, Instant)>,
+    n: usize,
+    now: Instant,
+) -> Vec<IpAddr>
+where
+    T: Into<IpAddr>,
+{
+    let mut ips = fresh.take(n).map_into().collect_vec();
+    let num_reusable = released
+        .iter()
+        .take_while(|(_, reusable_at)| *reusable_at <= now)
+        .count()
+        .min(n - ips.len());
+
+    ips.extend(released.drain(..num_reusable).map(|(ip, _)| ip.into()));
+
+    ips
+}
+
+fn dns_error_reply(query: IpPacket<'static>, e: &dyn fmt::Display) -> IpPacket<'static> {
+    // To avoid sensitive data getting into the logs, only log the error if debug logging is enabled.
+    // We always want to see a warning.
+    if tracing::enabled!(Level::DEBUG) {
+        tracing::warn!("DNS query failed: {e}");
+    } else {
+        tracing::warn!("DNS query failed");
+    };
+
+    ip_packet::make::dns_err_response(query, hickory_proto::op::ResponseCode::ServFail)
+        .into_immutable()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connlib_shared::messages::TlsDnsServer;
+    use hickory_proto::op::{Message, ResponseCode};
+    use hickory_proto::rr::{Name, RData, RecordType};
+    use ip_packet::tcp::TcpFlags;
     use rand_core::OsRng;
 
+    #[test]
+    fn released_ips_are_only_reused_after_quarantine() {
+        let mut ip_provider = IpProvider::new(
+            "100.96.0.0/30".parse().unwrap(),
+            "fd00:2021:1111:8000::/127".parse().unwrap(),
+            vec![],
+        );
+        let now = Instant::now();
+
+        let ips = ip_provider.get_n_ipv4(2, now);
+        assert_eq!(ips, vec![ip("100.96.0.1"), ip("100.96.0.2")]);
+
+        ip_provider.release(ips[0], now + Duration::from_secs(60));
+
+        assert!(ip_provider
+            .get_n_ipv4(1, now + Duration::from_secs(30))
+            .is_empty());
+        assert_eq!(
+            ip_provider.get_n_ipv4(1, now + Duration::from_secs(60)),
+            vec![ips[0]]
+        );
+    }
+
     #[test]
     fn ignores_ip4_igmp_multicast() {
         assert!(is_definitely_not_a_resource(ip("224.0.0.22")))
@@ -1482,6 +2185,195 @@ mod tests {
         )
     }
 
+    #[test]
+    fn answers_dns_query_over_tcp() {
+        let mut client_state = ClientState::new(
+            StaticSecret::random_from_rng(OsRng),
+            HashMap::from([("foo.com".to_owned(), vec![ip("1.2.3.4")])]),
+        );
+        let _ = client_state.update_interface_config(interface_config_without_dns());
+        let _ = client_state.update_system_resolvers(vec![ip("1.1.1.1")]);
+        let sentinel = *client_state.dns_mapping().left_values().next().unwrap();
+        let app = SocketAddr::new(ip("10.0.0.1"), 45678);
+        let server = SocketAddr::new(sentinel, DNS_PORT);
+        let now = Instant::now();
+
+        let syn = ip_packet::make::tcp_segment(app, server, 1000, 0, TcpFlags::SYN, 1024, &[]);
+        assert!(client_state.encapsulate(syn, now).is_none());
+        let syn_ack = client_state.poll_packets().unwrap();
+        let iss = syn_ack.as_tcp().unwrap().get_sequence();
+
+        let query = ip_packet::make::dns_query(
+            Name::from_str("foo.com").unwrap(),
+            RecordType::A,
+            app,
+            server,
+            1,
+        )
+        .into_immutable();
+        let mut message = (query.udp_payload().len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query.udp_payload());
+        let segment = ip_packet::make::tcp_segment(
+            app,
+            server,
+            1001,
+            iss.wrapping_add(1),
+            TcpFlags::PSH | TcpFlags::ACK,
+            1024,
+            &message,
+        );
+        assert!(client_state.encapsulate(segment, now).is_none());
+
+        let _ack = client_state.poll_packets().unwrap();
+        let response = client_state.poll_packets().unwrap();
+        let response = Message::from_vec(&response.as_tcp().unwrap().payload()[2..]).unwrap();
+
+        assert_eq!(response.id(), 1);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(1, 2, 3, 4).into()))
+        );
+    }
+
+    #[test]
+    fn mangles_dns_query_to_cidr_resource_on_non_standard_port() {
+        let mut mangled_dns_queries = HashMap::new();
+        let app = SocketAddr::new(ip("10.0.0.1"), 45678);
+        let sentinel = SocketAddr::new(ip("100.100.111.1"), DNS_PORT);
+        let upstream = SocketAddr::new(ip("10.10.10.10"), 5353);
+        let now = Instant::now();
+
+        let query = ip_packet::make::dns_query(
+            Name::from_str("foo.corp.internal").unwrap(),
+            RecordType::A,
+            app,
+            sentinel,
+            1,
+        );
+        let query = mangle_dns_query_to_cidr_resource(
+            query,
+            upstream,
+            dns::Transport::Udp,
+            &mut mangled_dns_queries,
+            now,
+        )
+        .into_immutable();
+
+        assert_eq!(query.destination(), upstream.ip());
+        assert_eq!(query.as_udp().unwrap().get_destination(), upstream.port());
+
+        let response =
+            ip_packet::make::dns_ok_response(query, |_| std::iter::once(ip("10.10.10.11")));
+        let (response, transport) =
+            maybe_mangle_dns_response_from_cidr_resource(response, &mut mangled_dns_queries, now);
+        let response = response.into_immutable();
+
+        assert_eq!(transport, dns::Transport::Udp);
+        assert_eq!(response.source(), sentinel.ip());
+        assert_eq!(response.as_udp().unwrap().get_source(), sentinel.port());
+        assert!(mangled_dns_queries.is_empty());
+    }
+
+    #[test]
+    fn only_takes_dns_response_from_gateway_the_query_was_forwarded_to() {
+        let mut forwarded_dns_queries = HashMap::new();
+        let sentinel = SocketAddr::new(ip("100.100.111.1"), DNS_PORT);
+        let dns_mapping = BiMap::from_iter([(sentinel.ip(), dns("1.1.1.1:53"))]);
+        let gateway = "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap();
+        let other_gateway = "ed29c148-2acf-4ceb-8db5-d796c2671631".parse().unwrap();
+        let app_1 = SocketAddr::new(ip("10.0.0.1"), 45678);
+        let app_2 = SocketAddr::new(ip("10.0.0.1"), 45679);
+        let now = Instant::now();
+
+        let query_1 = srv_query(app_1, sentinel);
+        let query_2 = srv_query(app_2, sentinel);
+        for query in [&query_1, &query_2] {
+            track_dns_query_to_gateway(
+                query,
+                gateway,
+                &dns_mapping,
+                &mut forwarded_dns_queries,
+                now,
+            );
+        }
+        let response =
+            ip_packet::make::dns_ok_response(query_1.into_immutable(), |_| std::iter::empty());
+
+        assert_eq!(
+            forwarded_dns_queries.len(),
+            2,
+            "queries with the same ID from different sockets must not overwrite each other"
+        );
+        assert!(take_dns_query_to_gateway(
+            &response,
+            other_gateway,
+            &dns_mapping,
+            &mut forwarded_dns_queries
+        )
+        .is_none());
+        assert!(take_dns_query_to_gateway(
+            &response,
+            gateway,
+            &dns_mapping,
+            &mut forwarded_dns_queries
+        )
+        .is_some());
+        assert_eq!(forwarded_dns_queries.len(), 1);
+    }
+
+    #[test]
+    fn refuses_to_send_queries_for_encrypted_upstream_via_cidr_resource_in_plaintext() {
+        let mut client_state = ClientState::for_test();
+        let _ = client_state.update_interface_config(InterfaceConfig {
+            upstream_dns: vec![DnsServer::Tls(TlsDnsServer {
+                address: "10.10.10.10:853".parse().unwrap(),
+                server_name: "dns.corp.internal".to_owned(),
+            })],
+            ..interface_config_without_dns()
+        });
+        client_state.add_resource(ResourceDescription::Cidr(ResourceDescriptionCidr {
+            id: ResourceId::from_u128(1),
+            address: "10.10.10.0/24".parse().unwrap(),
+            name: "Corporate DNS".to_owned(),
+            address_description: None,
+            sites: vec![],
+        }));
+        while client_state.poll_event().is_some() {}
+        let sentinel = *client_state.dns_mapping().left_values().next().unwrap();
+        let now = Instant::now();
+
+        let query = ip_packet::make::dns_query(
+            Name::from_str("foo.com").unwrap(),
+            RecordType::A,
+            SocketAddr::new(ip("10.0.0.1"), 45678),
+            SocketAddr::new(sentinel, DNS_PORT),
+            1,
+        );
+
+        assert!(client_state.encapsulate(query, now).is_none());
+        assert_eq!(
+            client_state
+                .poll_packets()
+                .unwrap()
+                .unwrap_as_dns()
+                .response_code(),
+            ResponseCode::ServFail
+        );
+        assert!(client_state.poll_transmit().is_none());
+        assert!(client_state.poll_event().is_none());
+        assert!(client_state.poll_dns_queries().is_none());
+    }
+
+    fn srv_query(src: SocketAddr, dst: SocketAddr) -> MutableIpPacket<'static> {
+        ip_packet::make::dns_query(
+            Name::from_str("_ldap._tcp.foo.com").unwrap(),
+            RecordType::SRV,
+            src,
+            dst,
+            1,
+        )
+    }
+
     impl ClientState {
         pub fn for_test() -> ClientState {
             ClientState::new(StaticSecret::random_from_rng(OsRng), HashMap::new())
@@ -1500,6 +2392,8 @@ mod tests {
             ipv4: "10.0.0.1".parse().unwrap(),
             ipv6: "fe80::".parse().unwrap(),
             upstream_dns: Vec::new(),
+            proxy_ip_lease: None,
+            dns_forwarding_rules: vec![],
         }
     }
 
@@ -1508,6 +2402,8 @@ mod tests {
             ipv4: "10.0.0.1".parse().unwrap(),
             ipv6: "fe80::".parse().unwrap(),
             upstream_dns: dns_list(),
+            proxy_ip_lease: None,
+            dns_forwarding_rules: vec![],
         }
     }
 
@@ -1771,6 +2667,30 @@ mod proptests {
         }
     }
 
+    #[test_strategy::proptest]
+    fn expired_access_is_only_reset_for_gateway_of_resource(
+        #[strategy(resource())] resource: ResourceDescription,
+        #[strategy(gateway_id())] gateway: GatewayId,
+        #[strategy(gateway_id())] other_gateway: GatewayId,
+    ) {
+        prop_assume!(gateway != other_gateway);
+
+        let mut client_state = ClientState::for_test();
+        client_state.add_resource(resource.clone());
+        client_state
+            .resources_gateways
+            .insert(resource.id(), gateway);
+
+        client_state.on_access_expired(other_gateway, resource.id());
+        assert_eq!(
+            client_state.gateway_by_resource(&resource.id()),
+            Some(gateway)
+        );
+
+        client_state.on_access_expired(gateway, resource.id());
+        assert_eq!(client_state.gateway_by_resource(&resource.id()), None);
+    }
+
     #[test_strategy::proptest]
     fn setting_resource_offline_doesnt_set_all_related_resources_offline(
         #[strategy(resources_sharing_n_sites(2))] multi_site_resources: Vec<ResourceDescription>,
@@ -1,7 +1,6 @@
-use crate::client::IpProvider;
-use connlib_shared::messages::{DnsServer, ResourceId};
+use crate::client::{IpProvider, DNS_SENTINELS_V4, DNS_SENTINELS_V6};
+use connlib_shared::messages::{DnsForwardingRule, DnsServer, ResourceId};
 use connlib_shared::DomainName;
-use domain::base::RelativeName;
 use domain::base::{
     iana::{Class, Rcode, Rtype},
     Message, MessageBuilder, ToName,
@@ -9,16 +8,34 @@ use domain::base::{
 use domain::rdata::AllRecordData;
 use hickory_resolver::lookup::Lookup;
 use hickory_resolver::proto::error::{ProtoError, ProtoErrorKind};
-use hickory_resolver::proto::op::MessageType;
-use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::proto::op::{Message as HickoryMessage, MessageType};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use ip_network::IpNetwork;
 use ip_packet::udp::UdpPacket;
 use ip_packet::Packet as _;
 use ip_packet::{udp::MutableUdpPacket, IpPacket, MutableIpPacket, MutablePacket, PacketSize};
 use itertools::Itertools;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-
+use pattern::DomainPatterns;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+pub(crate) mod cache;
+pub(crate) mod pattern;
+pub(crate) mod query_log;
+pub(crate) mod tcp;
+
+/// The TTL of our DNS answers in case no proxy IP lease is configured.
 const DNS_TTL: u32 = 1;
+/// The shortest proxy IP lease we accept.
+///
+/// The lease is also the TTL of our DNS answers, a very short lease would have applications re-query us all the time.
+const MIN_PROXY_IP_LEASE: Duration = Duration::from_secs(60);
+/// How often we check for expired proxy IP leases.
+const LEASE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 const UDP_HEADER_SIZE: usize = 8;
 const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
@@ -31,8 +48,25 @@ pub struct StubResolver {
     ip_provider: IpProvider,
     /// All DNS resources we know about, indexed by their domain (could be wildcard domain like `*.mycompany.com`).
     dns_resources: HashMap<String, ResourceId>,
+    /// The domains of [`StubResolver::dns_resources`], for finding the resource a domain belongs to.
+    resource_patterns: DomainPatterns<ResourceId>,
+    /// Domains that are excluded from a resource even though they match its address, indexed by resource.
+    resource_exclusions: HashMap<ResourceId, DomainPatterns<()>>,
     /// Fixed dns name that will be resolved to fixed ip addrs, similar to /etc/hosts
     known_hosts: KnownHosts,
+    /// DNS servers that queries for specific domains are forwarded to, indexed by domain (could be wildcard domain like `*.mycompany.com`).
+    forwarding_rules: HashMap<String, DnsServer>,
+    forwarding_patterns: DomainPatterns<DnsServer>,
+    /// The DNS servers of [`StubResolver::forwarding_rules`], indexed by their address.
+    forwarding_servers_by_address: HashMap<SocketAddr, DnsServer>,
+
+    /// For how long the proxy IPs of a domain stay assigned after they have last been used.
+    ///
+    /// `None` means proxy IPs are never recycled.
+    proxy_ip_lease: Option<Duration>,
+    /// When the proxy IPs of a domain have last been handed out in a DNS answer or seen in a packet.
+    last_used: HashMap<DomainName, Instant>,
+    next_lease_sweep: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -42,6 +76,19 @@ pub struct DnsQuery<'a> {
     // We could be much more efficient with this field,
     // we only need the header to create the response.
     pub query: ip_packet::IpPacket<'a>,
+    /// How the query reached us and thus, how we need to send the response.
+    pub transport: Transport,
+    /// The DNS server that should resolve the query.
+    pub upstream: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    /// The query was received on a TCP connection to one of our sentinel DNS servers.
+    ///
+    /// To reuse as much logic as possible, `query` is still a UDP packet, wrapping the DNS message we received on the connection.
+    Tcp,
 }
 
 /// Tells the Client how to reply to a single DNS query
@@ -51,6 +98,10 @@ pub(crate) enum ResolveStrategy<'a> {
     LocalResponse(IpPacket<'static>),
     /// The query is for a non-Resource, forward it to an upstream or system resolver
     ForwardQuery(DnsQuery<'a>),
+    /// The query is for a non-address record of a Resource, forward it to the gateway of the Resource.
+    ///
+    /// The [`IpAddr`] is one of the proxy IPs of the queried domain, the query needs to be routed as if it was a packet for this IP.
+    ForwardToGateway(IpAddr),
 }
 
 struct KnownHosts {
@@ -103,10 +154,127 @@ impl StubResolver {
             ips_to_fqdn: Default::default(),
             ip_provider: IpProvider::for_resources(),
             dns_resources: Default::default(),
+            resource_patterns: Default::default(),
+            resource_exclusions: Default::default(),
             known_hosts: KnownHosts::new(known_hosts),
+            proxy_ip_lease: None,
+            forwarding_rules: Default::default(),
+            forwarding_patterns: Default::default(),
+            forwarding_servers_by_address: Default::default(),
+            last_used: Default::default(),
+            next_lease_sweep: None,
+        }
+    }
+
+    /// Configures for how long proxy IPs stay assigned to a domain after their last use.
+    ///
+    /// This is also the TTL of the DNS answers we synthesize for DNS resources.
+    pub(crate) fn set_proxy_ip_lease(&mut self, lease: Option<Duration>) {
+        let lease = lease.map(|l| l.max(MIN_PROXY_IP_LEASE));
+
+        if self.proxy_ip_lease != lease {
+            tracing::debug!(?lease, "Setting proxy IP lease");
+        }
+
+        self.proxy_ip_lease = lease;
+    }
+
+    /// Sets the DNS forwarding rules, i.e. which domains are resolved by which DNS servers.
+    ///
+    /// Returns whether the rules changed.
+    pub(crate) fn set_forwarding_rules(&mut self, rules: &[DnsForwardingRule]) -> bool {
+        let rules = rules
+            .iter()
+            .map(|r| (r.domain.clone(), r.server.clone()))
+            .collect::<HashMap<_, _>>();
+
+        if rules == self.forwarding_rules {
+            return false;
+        }
+
+        tracing::debug!(?rules, "Setting DNS forwarding rules");
+        self.forwarding_patterns = DomainPatterns::default();
+        for (domain, server) in &rules {
+            self.forwarding_patterns.insert(domain, server.clone());
+        }
+        self.forwarding_servers_by_address =
+            rules.values().map(|s| (s.address(), s.clone())).collect();
+        self.forwarding_rules = rules;
+
+        true
+    }
+
+    /// All DNS servers referenced by a forwarding rule.
+    pub(crate) fn forwarding_servers(&self) -> impl Iterator<Item = DnsServer> + '_ {
+        self.forwarding_rules.values().cloned()
+    }
+
+    /// The DNS server with the given address, if it is referenced by a forwarding rule.
+    pub(crate) fn forwarding_server(&self, server: SocketAddr) -> Option<&DnsServer> {
+        self.forwarding_servers_by_address.get(&server)
+    }
+
+    /// Extends the lease of the given proxy IP.
+    ///
+    /// This is in the hot-path of packet routing and must be fast!
+    pub(crate) fn refresh_lease(&mut self, ip: &IpAddr, now: Instant) {
+        let Some((fqdn, _)) = self.ips_to_fqdn.get(ip) else {
+            return;
+        };
+
+        if let Some(last_used) = self.last_used.get_mut(fqdn) {
+            *last_used = now;
         }
     }
 
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.next_lease_sweep
+    }
+
+    /// Recycles the proxy IPs of all domains whose lease has expired.
+    ///
+    /// Returns the recycled IPs.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) -> Vec<IpAddr> {
+        if !self.next_lease_sweep.is_some_and(|t| t <= now) {
+            return Vec::new();
+        }
+
+        let Some(lease) = self.proxy_ip_lease else {
+            self.next_lease_sweep = None;
+            return Vec::new();
+        };
+
+        let mut recycled = Vec::new();
+
+        self.last_used.retain(|fqdn, last_used| {
+            if now < *last_used + lease {
+                return true;
+            }
+
+            let ips = self.fqdn_to_ips.remove(fqdn).unwrap_or_default();
+            tracing::debug!(%fqdn, ?ips, "Proxy IP lease expired");
+
+            // Applications may have cached our answer for as long as its TTL, i.e. the lease.
+            for ip in &ips {
+                self.ips_to_fqdn.remove(ip);
+                self.ip_provider.release(*ip, now + lease);
+            }
+            recycled.extend(ips);
+
+            false
+        });
+
+        self.next_lease_sweep = (!self.last_used.is_empty()).then(|| now + LEASE_SWEEP_INTERVAL);
+
+        recycled
+    }
+
+    fn dns_ttl(&self) -> u32 {
+        self.proxy_ip_lease
+            .map(|l| u32::try_from(l.as_secs()).unwrap_or(u32::MAX))
+            .unwrap_or(DNS_TTL)
+    }
+
     /// Attempts to resolve an IP to a given resource.
     ///
     /// Semantically, this is like a PTR query, i.e. we check whether we handed out this IP as part of answering a DNS query for one of our resources.
@@ -122,50 +290,76 @@ impl StubResolver {
         Some((fqdn, self.fqdn_to_ips.get(fqdn).unwrap()))
     }
 
-    pub(crate) fn add_resource(&mut self, id: ResourceId, address: String) {
+    pub(crate) fn add_resource(
+        &mut self,
+        id: ResourceId,
+        address: String,
+        excluded_addresses: &[String],
+    ) {
+        self.resource_patterns.insert(&address, id);
         let existing = self.dns_resources.insert(address.clone(), id);
 
         if existing.is_none() {
             tracing::info!(%address, "Activating DNS resource");
         }
+
+        if excluded_addresses.is_empty() {
+            self.resource_exclusions.remove(&id);
+            return;
+        }
+
+        let mut exclusions = DomainPatterns::default();
+        for excluded in excluded_addresses {
+            exclusions.insert(excluded, ());
+        }
+        self.resource_exclusions.insert(id, exclusions);
     }
 
     pub(crate) fn remove_resource(&mut self, id: ResourceId) {
         self.dns_resources.retain(|address, r| {
             if *r == id {
                 tracing::info!(%address, "Deactivating DNS resource");
+                self.resource_patterns.remove(address);
                 return false;
             }
 
             true
         });
+        self.resource_exclusions.remove(&id);
     }
 
     fn get_or_assign_a_records(
         &mut self,
         fqdn: DomainName,
         resource_id: ResourceId,
+        now: Instant,
     ) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
-        to_a_records(self.get_or_assign_ips(fqdn, resource_id).into_iter())
+        to_a_records(self.get_or_assign_ips(fqdn, resource_id, now).into_iter())
     }
 
     fn get_or_assign_aaaa_records(
         &mut self,
         fqdn: DomainName,
         resource_id: ResourceId,
+        now: Instant,
     ) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
-        to_aaaa_records(self.get_or_assign_ips(fqdn, resource_id).into_iter())
+        to_aaaa_records(self.get_or_assign_ips(fqdn, resource_id, now).into_iter())
     }
 
-    fn get_or_assign_ips(&mut self, fqdn: DomainName, resource_id: ResourceId) -> Vec<IpAddr> {
+    fn get_or_assign_ips(
+        &mut self,
+        fqdn: DomainName,
+        resource_id: ResourceId,
+        now: Instant,
+    ) -> Vec<IpAddr> {
         let ips = self
             .fqdn_to_ips
             .entry(fqdn.clone())
             .or_insert_with(|| {
                 // TODO: the side effeccts are executed even if this is not inserted
                 // make it so that's not the case
-                let mut ips = self.ip_provider.get_n_ipv4(4);
-                ips.extend_from_slice(&self.ip_provider.get_n_ipv6(4));
+                let mut ips = self.ip_provider.get_n_ipv4(4, now);
+                ips.extend_from_slice(&self.ip_provider.get_n_ipv6(4, now));
                 ips
             })
             .clone();
@@ -173,11 +367,26 @@ impl StubResolver {
             self.ips_to_fqdn.insert(*ip, (fqdn.clone(), resource_id));
         }
 
+        self.last_used.insert(fqdn, now);
+        if self.proxy_ip_lease.is_some() {
+            self.next_lease_sweep
+                .get_or_insert(now + LEASE_SWEEP_INTERVAL);
+        }
+
         ips
     }
 
-    fn match_resource(&self, domain_name: &DomainName) -> Option<ResourceId> {
-        match_domain(domain_name, &self.dns_resources)
+    pub(crate) fn match_resource(&self, domain_name: &DomainName) -> Option<ResourceId> {
+        self.resource_patterns
+            .matches(domain_name)
+            .into_iter()
+            .find(|id| {
+                !self
+                    .resource_exclusions
+                    .get(id)
+                    .is_some_and(|exclusions| exclusions.contains(domain_name))
+            })
+            .copied()
     }
 
     fn resource_address_name_by_reservse_dns(
@@ -205,8 +414,9 @@ impl StubResolver {
         &mut self,
         dns_mapping: &bimap::BiMap<IpAddr, DnsServer>,
         packet: IpPacket<'a>,
+        now: Instant,
     ) -> Option<ResolveStrategy<'a>> {
-        dns_mapping.get_by_left(&packet.destination())?;
+        let default_upstream = dns_mapping.get_by_left(&packet.destination())?.address();
         let datagram = packet.as_udp()?;
         let message = as_dns(&datagram)?;
         if message.header().qr() {
@@ -220,7 +430,7 @@ impl StubResolver {
         tracing::trace!("Parsed packet as DNS query: '{qtype} {domain}'");
 
         if let Some(records) = self.known_hosts.get_records(qtype, &domain) {
-            let response = build_dns_with_answer(message, domain, records)?;
+            let response = build_dns_with_answer(message, domain, records, self.dns_ttl())?;
             return Some(ResolveStrategy::LocalResponse(build_response(
                 packet, response,
             )));
@@ -229,30 +439,117 @@ impl StubResolver {
         let maybe_resource = self.match_resource(&domain);
 
         let resource_records = match (qtype, maybe_resource) {
-            (Rtype::A, Some(resource)) => self.get_or_assign_a_records(domain.clone(), resource),
+            (Rtype::A, Some(resource)) => {
+                self.get_or_assign_a_records(domain.clone(), resource, now)
+            }
             (Rtype::AAAA, Some(resource)) => {
-                self.get_or_assign_aaaa_records(domain.clone(), resource)
+                self.get_or_assign_aaaa_records(domain.clone(), resource, now)
             }
             (Rtype::PTR, _) => {
                 let fqdn = self.resource_address_name_by_reservse_dns(&domain)?;
 
                 vec![AllRecordData::Ptr(domain::rdata::Ptr::new(fqdn))]
             }
+            (_, Some(resource)) => {
+                let ips = self.get_or_assign_ips(domain, resource, now);
+                let proxy_ip = ips
+                    .iter()
+                    .find(|ip| ip.is_ipv4() == packet.destination().is_ipv4())
+                    .or(ips.first())?;
+
+                return Some(ResolveStrategy::ForwardToGateway(*proxy_ip));
+            }
             _ => {
+                let upstream = self
+                    .forwarding_patterns
+                    .best_match(&domain)
+                    .map(|server| server.address())
+                    .unwrap_or(default_upstream);
+
                 return Some(ResolveStrategy::ForwardQuery(DnsQuery {
                     name: domain,
                     record_type: u16::from(qtype).into(),
                     query: packet,
-                }))
+                    transport: Transport::Udp,
+                    upstream,
+                }));
             }
         };
 
-        let response = build_dns_with_answer(message, domain, resource_records)?;
+        let response = build_dns_with_answer(message, domain, resource_records, self.dns_ttl())?;
 
         Some(ResolveStrategy::LocalResponse(build_response(
             packet, response,
         )))
     }
+
+    /// Rewrites a DNS response that a gateway resolved for us such that all address records of resources point to proxy IPs.
+    ///
+    /// Non-address records like SRV or CNAME point to other domains.
+    /// If those are resources too, the A and AAAA records we hand out for them must be the same as if we had been asked for them directly.
+    pub(crate) fn rewrite_forwarded_response(
+        &mut self,
+        response: IpPacket<'_>,
+        now: Instant,
+    ) -> Option<IpPacket<'static>> {
+        let datagram = response.as_udp()?;
+        let mut message = HickoryMessage::from_vec(datagram.payload()).ok()?;
+
+        let answers = self.proxy_address_records(message.take_answers(), now);
+        message.insert_answers(answers);
+        let additionals = self.proxy_address_records(message.take_additionals(), now);
+        message.insert_additionals(additionals);
+
+        let packet = ip_packet::make::udp_packet(
+            response.source(),
+            response.destination(),
+            datagram.get_source(),
+            datagram.get_destination(),
+            message.to_vec().ok()?,
+        );
+
+        Some(packet.into_immutable())
+    }
+
+    fn proxy_address_records(&mut self, records: Vec<Record>, now: Instant) -> Vec<Record> {
+        let mut proxied = HashSet::new();
+        let mut rewritten = Vec::with_capacity(records.len());
+
+        for record in records {
+            let record_type = record.record_type();
+
+            let resource = matches!(record_type, RecordType::A | RecordType::AAAA)
+                .then(|| DomainName::vec_from_str(&record.name().to_ascii()).ok())
+                .flatten()
+                .and_then(|name| Some((self.match_resource(&name)?, name)));
+            let Some((resource, name)) = resource else {
+                rewritten.push(record);
+                continue;
+            };
+
+            // All address records of a resource are replaced at once by its proxy IPs.
+            if !proxied.insert((name.clone(), record_type)) {
+                continue;
+            }
+
+            let ttl = self.dns_ttl();
+            let rdata = self
+                .get_or_assign_ips(name, resource, now)
+                .into_iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(v4) if record_type == RecordType::A => Some(RData::A(A(v4))),
+                    IpAddr::V6(v6) if record_type == RecordType::AAAA => {
+                        Some(RData::AAAA(AAAA(v6)))
+                    }
+                    IpAddr::V4(_) | IpAddr::V6(_) => None,
+                });
+
+            rewritten
+                .extend(rdata.map(|rdata| Record::from_rdata(record.name().clone(), ttl, rdata)));
+        }
+
+        rewritten
+    }
 }
 
 impl<'a> DnsQuery<'a> {
@@ -261,6 +558,8 @@ impl<'a> DnsQuery<'a> {
             name,
             record_type,
             query,
+            transport,
+            upstream,
         } = self;
         let buf = query.packet().to_vec();
         let query = ip_packet::IpPacket::owned(buf)
@@ -270,8 +569,22 @@ impl<'a> DnsQuery<'a> {
             name,
             record_type,
             query,
+            transport,
+            upstream,
         }
     }
+
+    /// Whether the query has the DNSSEC OK (DO) bit set, i.e. the application wants DNSSEC records in the response.
+    pub(crate) fn dnssec_ok(&self) -> bool {
+        let Some(datagram) = self.query.as_udp() else {
+            return false;
+        };
+        let Ok(message) = Message::from_slice(datagram.payload()) else {
+            return false;
+        };
+
+        message.opt().is_some_and(|opt| opt.dnssec_ok())
+    }
 }
 
 impl Clone for DnsQuery<'static> {
@@ -280,6 +593,8 @@ impl Clone for DnsQuery<'static> {
             name: self.name.clone(),
             record_type: self.record_type,
             query: self.query.clone(),
+            transport: self.transport,
+            upstream: self.upstream,
         }
     }
 }
@@ -305,6 +620,9 @@ pub(crate) fn build_response_from_resolve_result(
     let mut message = original_pkt.unwrap_as_dns();
 
     message.set_message_type(MessageType::Response);
+    // The EDNS record of the query (and thus its DO bit) is mirrored because we build the response from the query.
+    // We never validate the records of a `Lookup` though, so we must not claim that they are authentic.
+    message.set_authentic_data(false);
 
     let response = match response.map_err(|err| err.kind().clone()) {
         Ok(response) => message.add_answers(response.records().to_vec()),
@@ -333,8 +651,54 @@ pub(crate) fn build_response_from_resolve_result(
     Ok(packet)
 }
 
+/// Parses a packet as a DNS query that a client forwarded to us because it is for a non-address record of a DNS resource.
+///
+/// Clients forward these queries unchanged, i.e. they are still addressed to one of the client's sentinel DNS servers.
+pub(crate) fn parse_forwarded_query(packet: IpPacket<'_>) -> Option<DnsQuery<'_>> {
+    if !is_sentinel(packet.destination()) {
+        return None;
+    }
+
+    let datagram = packet.as_udp()?;
+    let message = as_dns(&datagram)?;
+    if message.header().qr() {
+        return None;
+    }
+
+    let question = message.first_question()?;
+    let name = question.qname().to_vec();
+    let record_type = u16::from(question.qtype()).into();
+    let upstream = SocketAddr::new(packet.destination(), datagram.get_destination());
+
+    Some(DnsQuery {
+        name,
+        record_type,
+        query: packet,
+        transport: Transport::Udp,
+        upstream,
+    })
+}
+
+pub(crate) fn is_sentinel(ip: IpAddr) -> bool {
+    // `IpNetwork` doesn't support `const`; parse once because we check every packet the gateway decapsulates.
+    static SENTINELS: OnceLock<[IpNetwork; 2]> = OnceLock::new();
+
+    SENTINELS
+        .get_or_init(|| {
+            [
+                IpNetwork::from_str(DNS_SENTINELS_V4).expect("valid sentinel range"),
+                IpNetwork::from_str(DNS_SENTINELS_V6).expect("valid sentinel range"),
+            ]
+        })
+        .iter()
+        .any(|sentinels| sentinels.contains(ip))
+}
+
 /// Constructs an IP packet responding to an IP packet containing a DNS query
-fn build_response(original_pkt: IpPacket<'_>, mut dns_answer: Vec<u8>) -> IpPacket<'static> {
+pub(crate) fn build_response(
+    original_pkt: IpPacket<'_>,
+    mut dns_answer: Vec<u8>,
+) -> IpPacket<'static> {
     let response_len = dns_answer.len();
     let original_dgm = original_pkt.unwrap_as_udp();
     let hdr_len = original_pkt.packet_size() - original_dgm.payload().len();
@@ -375,6 +739,7 @@ fn build_dns_with_answer(
     message: &Message<[u8]>,
     qname: DomainName,
     records: Vec<AllRecordData<Vec<u8>, DomainName>>,
+    ttl: u32,
 ) -> Option<Vec<u8>> {
     let msg_buf = Vec::with_capacity(message.as_slice().len() * 2);
     let msg_builder = MessageBuilder::from_target(msg_buf).expect(
@@ -383,14 +748,29 @@ fn build_dns_with_answer(
 
     let mut answer_builder = msg_builder.start_answer(message, Rcode::NOERROR).ok()?;
     answer_builder.header_mut().set_ra(true);
+    // We make up these records ourselves, they can't possibly be signed.
+    answer_builder.header_mut().set_ad(false);
 
     for record in records {
-        answer_builder
-            .push((&qname, Class::IN, DNS_TTL, record))
+        answer_builder.push((&qname, Class::IN, ttl, record)).ok()?;
+    }
+
+    let mut additional_builder = answer_builder.additional();
+
+    // Validating resolvers consider a server that drops the OPT record as not supporting DNSSEC at all.
+    // Mirroring it (including the DO bit) makes our answers look like the ones of an unsigned zone instead.
+    if let Some(opt) = message.opt() {
+        additional_builder
+            .opt(|builder| {
+                builder.set_udp_payload_size(opt.udp_payload_size());
+                builder.set_dnssec_ok(opt.dnssec_ok());
+
+                Ok(())
+            })
             .ok()?;
     }
 
-    Some(answer_builder.finish())
+    Some(additional_builder.finish())
 }
 
 pub fn as_dns<'a>(pkt: &'a UdpPacket<'a>) -> Option<&'a Message<[u8]>> {
@@ -399,69 +779,9 @@ pub fn as_dns<'a>(pkt: &'a UdpPacket<'a>) -> Option<&'a Message<[u8]>> {
         .flatten()
 }
 
+/// Whether the given name matches the address of a DNS resource, see [`pattern`] for the syntax.
 pub fn is_subdomain(name: &DomainName, resource: &str) -> bool {
-    let question_mark = RelativeName::<Vec<_>>::from_octets(b"\x01?".as_ref().into()).unwrap();
-    let Ok(resource) = DomainName::vec_from_str(resource) else {
-        return false;
-    };
-
-    if resource.starts_with(&question_mark) {
-        return resource
-            .parent()
-            .is_some_and(|r| r == name || name.parent().is_some_and(|n| r == n));
-    }
-
-    if resource.starts_with(&RelativeName::wildcard_vec()) {
-        let Some(resource) = resource.parent() else {
-            return false;
-        };
-        return name.iter_suffixes().any(|n| n == resource);
-    }
-
-    name == &resource
-}
-
-fn match_domain<T>(name: &DomainName, resources: &HashMap<String, T>) -> Option<T>
-where
-    T: Copy,
-{
-    // Safety: `?` is less than 254 bytes long.
-    const QUESTION_MARK: RelativeName<&'static [u8]> =
-        unsafe { RelativeName::from_octets_unchecked(b"\x01?") };
-    // Safety: `*` is less than 254 bytes long.
-    const WILDCARD: RelativeName<&'static [u8]> =
-        unsafe { RelativeName::from_octets_unchecked(b"\x01*") };
-
-    // First, check for full match.
-    if let Some(resource) = resources.get(&name.to_string()) {
-        return Some(*resource);
-    }
-
-    // Second, check for `?` matching this domain exactly.
-    let qm_dot_domain = QUESTION_MARK.chain(name).ok()?.to_string();
-    if let Some(resource) = resources.get(&qm_dot_domain) {
-        return Some(*resource);
-    }
-
-    // Third, check for `?` matching up to 1 parent.
-    if let Some(parent) = name.parent() {
-        let qm_dot_parent = QUESTION_MARK.chain(parent).ok()?.to_string();
-
-        if let Some(resource) = resources.get(&qm_dot_parent) {
-            return Some(*resource);
-        }
-    }
-
-    // Last, check for any wildcard domains, starting with the most specific one.
-    for suffix in name.iter_suffixes() {
-        let wildcard_dot_suffix = WILDCARD.chain(suffix).ok()?.to_string();
-
-        if let Some(resource) = resources.get(&wildcard_dot_suffix) {
-            return Some(*resource);
-        }
-    }
-
-    None
+    pattern::matches(resource, name)
 }
 
 fn reverse_dns_addr(name: &str) -> Option<IpAddr> {
@@ -537,6 +857,7 @@ fn ips_to_fqdn_for_known_hosts(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hickory_resolver::proto::rr::rdata::SRV;
 
     #[test]
     fn reverse_dns_addr_works_v4() {
@@ -593,31 +914,63 @@ mod tests {
 
     #[test]
     fn wildcard_matching() {
-        let resources = HashMap::from([("*.foo.com".to_string(), 0), ("*.com".to_string(), 1)]);
+        let resources = patterns([("*.foo.com", 0), ("*.com", 1)]);
 
-        assert_eq!(match_domain(&domain("a.foo.com"), &resources), Some(0));
-        assert_eq!(match_domain(&domain("foo.com"), &resources), Some(0));
-        assert_eq!(match_domain(&domain("a.b.foo.com"), &resources), Some(0));
-        assert_eq!(match_domain(&domain("oo.com"), &resources), Some(1));
-        assert_eq!(match_domain(&domain("oo.xyz"), &resources), None);
+        assert_eq!(resources.best_match(&domain("a.foo.com")), Some(&0));
+        assert_eq!(resources.best_match(&domain("foo.com")), Some(&0));
+        assert_eq!(resources.best_match(&domain("a.b.foo.com")), Some(&0));
+        assert_eq!(resources.best_match(&domain("oo.com")), Some(&1));
+        assert_eq!(resources.best_match(&domain("oo.xyz")), None);
     }
 
     #[test]
     fn question_mark_matching() {
-        let resources = HashMap::from([("?.bar.com".to_string(), 1)]);
+        let resources = patterns([("?.bar.com", 1)]);
 
-        assert_eq!(match_domain(&domain("a.bar.com"), &resources), Some(1));
-        assert_eq!(match_domain(&domain("bar.com"), &resources), Some(1));
-        assert_eq!(match_domain(&domain("a.b.bar.com"), &resources), None);
+        assert_eq!(resources.best_match(&domain("a.bar.com")), Some(&1));
+        assert_eq!(resources.best_match(&domain("bar.com")), Some(&1));
+        assert_eq!(resources.best_match(&domain("a.b.bar.com")), None);
     }
 
     #[test]
     fn exact_matching() {
-        let resources = HashMap::from([("baz.com".to_string(), 2)]);
+        let resources = patterns([("baz.com", 2)]);
+
+        assert_eq!(resources.best_match(&domain("baz.com")), Some(&2));
+        assert_eq!(resources.best_match(&domain("a.baz.com")), None);
+        assert_eq!(resources.best_match(&domain("a.b.baz.com")), None);
+    }
+
+    #[test]
+    fn excluded_domains_fall_back_to_next_most_specific_resource() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        let corp = ResourceId::from_u128(1);
+        let public = ResourceId::from_u128(2);
+        resolver.add_resource(
+            corp,
+            "*.corp.com".to_owned(),
+            &["public.corp.com".to_owned(), "*.dmz.corp.com".to_owned()],
+        );
+        resolver.add_resource(public, "?.public.corp.com".to_owned(), &[]);
+
+        assert_eq!(resolver.match_resource(&domain("app.corp.com")), Some(corp));
+        assert_eq!(
+            resolver.match_resource(&domain("public.corp.com")),
+            Some(public)
+        );
+        assert_eq!(
+            resolver.match_resource(&domain("www.public.corp.com")),
+            Some(public)
+        );
+        assert_eq!(
+            resolver.match_resource(&domain("a.b.public.corp.com")),
+            Some(corp)
+        );
+        assert_eq!(resolver.match_resource(&domain("web.dmz.corp.com")), None);
+
+        resolver.remove_resource(public);
 
-        assert_eq!(match_domain(&domain("baz.com"), &resources), Some(2));
-        assert_eq!(match_domain(&domain("a.baz.com"), &resources), None);
-        assert_eq!(match_domain(&domain("a.b.baz.com"), &resources), None);
+        assert_eq!(resolver.match_resource(&domain("public.corp.com")), None);
     }
 
     #[test]
@@ -651,6 +1004,202 @@ mod tests {
         assert!(!is_subdomain(&domain("afoo.com"), "?.foo.com"));
     }
 
+    #[test]
+    fn expired_proxy_ips_are_recycled() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.set_proxy_ip_lease(Some(Duration::from_secs(120)));
+        let now = Instant::now();
+
+        let foo_ips = resolver.get_or_assign_ips(domain("foo.com"), ResourceId::from_u128(1), now);
+
+        let recycled = resolver.handle_timeout(now + Duration::from_secs(130));
+        assert_eq!(recycled, foo_ips);
+        assert!(resolver.resolve_resource_by_ip(&foo_ips[0]).is_none());
+
+        // We still have fresh IPs, so we don't reuse the ones that applications may have cached.
+        let bar_ips = resolver.get_or_assign_ips(
+            domain("bar.com"),
+            ResourceId::from_u128(2),
+            now + Duration::from_secs(130),
+        );
+        assert!(bar_ips.iter().all(|ip| !foo_ips.contains(ip)));
+    }
+
+    #[test]
+    fn traffic_extends_proxy_ip_lease() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.set_proxy_ip_lease(Some(Duration::from_secs(120)));
+        let now = Instant::now();
+
+        let ips = resolver.get_or_assign_ips(domain("foo.com"), ResourceId::from_u128(1), now);
+        resolver.refresh_lease(&ips[0], now + Duration::from_secs(100));
+
+        let recycled = resolver.handle_timeout(now + Duration::from_secs(130));
+        assert!(recycled.is_empty());
+        assert!(resolver.resolve_resource_by_ip(&ips[0]).is_some());
+    }
+
+    #[test]
+    fn proxy_ips_are_not_recycled_without_lease() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        let now = Instant::now();
+
+        resolver.get_or_assign_ips(domain("foo.com"), ResourceId::from_u128(1), now);
+
+        assert!(resolver.poll_timeout().is_none());
+        assert!(resolver
+            .handle_timeout(now + Duration::from_secs(60 * 60))
+            .is_empty());
+    }
+
+    #[test]
+    fn forwarded_response_has_proxy_ips_for_resources() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(ResourceId::from_u128(1), "*.corp.example".to_owned());
+        let now = Instant::now();
+
+        let mut message = HickoryMessage::new();
+        message.set_message_type(MessageType::Response);
+        message.add_answer(Record::from_rdata(
+            name("_ldap._tcp.corp.example."),
+            300,
+            RData::SRV(SRV::new(0, 0, 389, name("dc.corp.example."))),
+        ));
+        message.add_additional(Record::from_rdata(
+            name("dc.corp.example."),
+            300,
+            RData::A(A::new(10, 0, 0, 1)),
+        ));
+        message.add_additional(Record::from_rdata(
+            name("dc.corp.example."),
+            300,
+            RData::A(A::new(10, 0, 0, 2)),
+        ));
+        message.add_additional(Record::from_rdata(
+            name("ntp.example."),
+            300,
+            RData::A(A::new(1, 1, 1, 1)),
+        ));
+        let response = ip_packet::make::udp_packet(
+            "100.100.111.1".parse().unwrap(),
+            "100.64.0.1".parse().unwrap(),
+            53,
+            9999,
+            message.to_vec().unwrap(),
+        )
+        .into_immutable();
+
+        let rewritten = resolver
+            .rewrite_forwarded_response(response, now)
+            .unwrap()
+            .unwrap_as_dns();
+
+        let proxy_ips = resolver
+            .get_or_assign_ips(domain("dc.corp.example"), ResourceId::from_u128(1), now)
+            .into_iter()
+            .filter(IpAddr::is_ipv4)
+            .collect_vec();
+        let additionals = rewritten
+            .additionals()
+            .iter()
+            .filter_map(|r| {
+                let RData::A(A(ip)) = r.data()? else {
+                    return None;
+                };
+
+                Some((r.name().to_ascii(), IpAddr::from(*ip)))
+            })
+            .collect_vec();
+
+        assert_eq!(rewritten.answers().len(), 1);
+        assert_eq!(
+            additionals,
+            proxy_ips
+                .into_iter()
+                .map(|ip| ("dc.corp.example.".to_owned(), ip))
+                .chain([("ntp.example.".to_owned(), "1.1.1.1".parse().unwrap())])
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn non_address_query_for_resource_is_forwarded_to_gateway() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        resolver.add_resource(ResourceId::from_u128(1), "*.corp.example".to_owned());
+        let sentinel = IpAddr::from(Ipv4Addr::new(100, 100, 111, 1));
+        let dns_mapping = bimap::BiMap::from_iter([(
+            sentinel,
+            DnsServer::from(("1.1.1.1".parse::<IpAddr>().unwrap(), 53)),
+        )]);
+
+        let query = ip_packet::make::dns_query(
+            name("_kerberos._udp.corp.example."),
+            RecordType::SRV,
+            "100.64.0.1:9999".parse().unwrap(),
+            SocketAddr::new(sentinel, 53),
+            0,
+        )
+        .into_immutable();
+
+        let Some(ResolveStrategy::ForwardToGateway(proxy_ip)) =
+            resolver.handle(&dns_mapping, query, Instant::now())
+        else {
+            panic!("Expected query to be forwarded to gateway")
+        };
+        assert_eq!(
+            resolver.get_fqdn(&proxy_ip).unwrap().0,
+            &domain("_kerberos._udp.corp.example")
+        );
+    }
+
+    #[test]
+    fn query_matching_forwarding_rule_uses_rule_server() {
+        let mut resolver = StubResolver::new(HashMap::new());
+        let rule_server = DnsServer::from(("10.0.0.53".parse::<IpAddr>().unwrap(), 53));
+        resolver.set_forwarding_rules(&[DnsForwardingRule {
+            domain: "*.corp.internal".to_owned(),
+            server: rule_server.clone(),
+        }]);
+        let sentinel = IpAddr::from(Ipv4Addr::new(100, 100, 111, 1));
+        let default_server = DnsServer::from(("1.1.1.1".parse::<IpAddr>().unwrap(), 53));
+        let dns_mapping = bimap::BiMap::from_iter([(sentinel, default_server.clone())]);
+
+        for (domain, expected) in [
+            ("git.corp.internal.", rule_server),
+            ("example.com.", default_server),
+        ] {
+            let query = ip_packet::make::dns_query(
+                name(domain),
+                RecordType::A,
+                "100.64.0.1:9999".parse().unwrap(),
+                SocketAddr::new(sentinel, 53),
+                0,
+            )
+            .into_immutable();
+
+            let Some(ResolveStrategy::ForwardQuery(query)) =
+                resolver.handle(&dns_mapping, query, Instant::now())
+            else {
+                panic!("Expected query to be forwarded")
+            };
+            assert_eq!(query.upstream, expected.address());
+        }
+    }
+
+    fn name(name: &str) -> hickory_resolver::proto::rr::Name {
+        hickory_resolver::proto::rr::Name::from_ascii(name).unwrap()
+    }
+
+    fn patterns<const N: usize>(patterns: [(&str, u8); N]) -> DomainPatterns<u8> {
+        let mut set = DomainPatterns::default();
+
+        for (pattern, value) in patterns {
+            set.insert(pattern, value);
+        }
+
+        set
+    }
+
     fn domain(name: &str) -> DomainName {
         DomainName::vec_from_str(name).unwrap()
     }
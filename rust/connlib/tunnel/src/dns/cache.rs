@@ -0,0 +1,387 @@
+//! A cache for the responses to DNS queries that we forward to upstream DNS servers.
+//!
+//! Positive responses are cached for the smallest TTL of their answers.
+//! Negative responses (NXDOMAIN and NODATA) are cached according to RFC 2308, i.e. for the TTL of the SOA record in the authority section, capped by its `MINIMUM` field.
+//! Negative responses without an SOA record are not cached.
+
+use super::DnsQuery;
+use connlib_shared::DomainName;
+use hickory_resolver::proto::op::{Message, ResponseCode};
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use ip_packet::{IpPacket, Packet as _};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The max. number of responses we cache.
+const MAX_ENTRIES: usize = 1000;
+/// The max. TTL we honor for positive responses.
+const MAX_TTL: u32 = 24 * 60 * 60;
+/// The max. TTL we honor for negative responses, as recommended by RFC 2308.
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+/// The upstream server, the queried name and type and whether the query has the DO bit set.
+///
+/// Responses to queries with the DO bit include DNSSEC records, we must not hand those to applications that didn't ask for them and vice versa.
+type Key = (SocketAddr, DomainName, RecordType, bool);
+
+#[derive(Default)]
+pub(crate) struct ResponseCache {
+    entries: HashMap<Key, Entry>,
+}
+
+struct Entry {
+    response: Message,
+    inserted_at: Instant,
+    expires_at: Instant,
+}
+
+impl ResponseCache {
+    /// Answers the given query from the cache, if we have a response that is still valid.
+    ///
+    /// The TTLs of all records are reduced by the time the response has spent in the cache.
+    pub(crate) fn get(&self, query: &DnsQuery<'_>, now: Instant) -> Option<IpPacket<'static>> {
+        let entry = self.entries.get(&key(query))?;
+        if now >= entry.expires_at {
+            return None;
+        }
+
+        let datagram = query.query.as_udp()?;
+        let request = Message::from_vec(datagram.payload()).ok()?;
+
+        let age =
+            u32::try_from(now.duration_since(entry.inserted_at).as_secs()).unwrap_or(u32::MAX);
+
+        let mut response = entry.response.clone();
+        response.set_id(request.id());
+        let answers = age_records(response.take_answers(), age);
+        response.insert_answers(answers);
+        let name_servers = age_records(response.take_name_servers(), age);
+        response.insert_name_servers(name_servers);
+        let additionals = age_records(response.take_additionals(), age);
+        response.insert_additionals(additionals);
+
+        let packet = ip_packet::make::udp_packet(
+            query.query.destination(),
+            query.query.source(),
+            datagram.get_destination(),
+            datagram.get_source(),
+            response.to_vec().ok()?,
+        );
+
+        Some(packet.into_immutable())
+    }
+
+    /// Caches the response to the given query, if it is cacheable.
+    pub(crate) fn insert(&mut self, query: &DnsQuery<'_>, response: &IpPacket<'_>, now: Instant) {
+        let Some(response) = response
+            .as_udp()
+            .and_then(|datagram| Message::from_vec(datagram.payload()).ok())
+        else {
+            return;
+        };
+        let Some(ttl) = cache_ttl(&response).filter(|ttl| *ttl > 0) else {
+            return;
+        };
+
+        if self.entries.len() >= MAX_ENTRIES {
+            self.evict(now);
+        }
+
+        self.entries.insert(
+            key(query),
+            Entry {
+                response,
+                inserted_at: now,
+                expires_at: now + Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Makes room for a new entry by removing all expired entries or, if there aren't any, the one that expires next.
+    fn evict(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| now < entry.expires_at);
+
+        if self.entries.len() < MAX_ENTRIES {
+            return;
+        }
+
+        let Some(next_to_expire) = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires_at)
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+
+        self.entries.remove(&next_to_expire);
+    }
+}
+
+fn key(query: &DnsQuery<'_>) -> Key {
+    (
+        query.upstream,
+        query.name.clone(),
+        query.record_type,
+        query.dnssec_ok(),
+    )
+}
+
+/// For how long we may cache the given response.
+fn cache_ttl(response: &Message) -> Option<u32> {
+    if response.truncated() {
+        return None;
+    }
+
+    let response_code = response.response_code();
+
+    if response_code == ResponseCode::NoError && !response.answers().is_empty() {
+        let ttl = response.answers().iter().map(Record::ttl).min()?;
+
+        return Some(ttl.min(MAX_TTL));
+    }
+
+    if response_code == ResponseCode::NoError || response_code == ResponseCode::NXDomain {
+        let ttl = response.name_servers().iter().find_map(|record| {
+            let Some(RData::SOA(soa)) = record.data() else {
+                return None;
+            };
+
+            Some(record.ttl().min(soa.minimum()))
+        })?;
+
+        return Some(ttl.min(MAX_NEGATIVE_TTL));
+    }
+
+    None
+}
+
+fn age_records(records: Vec<Record>, age: u32) -> Vec<Record> {
+    records
+        .into_iter()
+        .map(|mut record| {
+            let ttl = record.ttl().saturating_sub(age);
+            record.set_ttl(ttl);
+
+            record
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Transport;
+    use hickory_resolver::proto::op::{Edns, MessageType};
+    use hickory_resolver::proto::rr::rdata::{A, SOA};
+    use hickory_resolver::proto::rr::Name;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn positive_response_is_cached_with_decreasing_ttl() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("example.com.", RecordType::A, 1);
+        let retry = DnsQuery {
+            query: ip_packet::make::dns_query(
+                Name::from_ascii("example.com.").unwrap(),
+                RecordType::A,
+                "100.64.0.1:9999".parse().unwrap(),
+                "100.100.111.1:53".parse().unwrap(),
+                2,
+            )
+            .into_immutable(),
+            ..query.clone()
+        };
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::NoError, |m| {
+                m.add_answer(a_record("example.com.", 300));
+            }),
+            now,
+        );
+
+        let cached = cache
+            .get(&retry, now + Duration::from_secs(100))
+            .unwrap()
+            .unwrap_as_dns();
+
+        assert_eq!(cached.id(), 2);
+        assert_eq!(cached.answers()[0].ttl(), 200);
+        assert!(cache.get(&query, now + Duration::from_secs(300)).is_none());
+    }
+
+    #[test]
+    fn nxdomain_is_cached_for_soa_minimum() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("nope.example.com.", RecordType::A, 1);
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::NXDomain, |m| {
+                m.add_name_server(soa_record("example.com.", 3600, 60));
+            }),
+            now,
+        );
+
+        let cached = cache
+            .get(&query, now + Duration::from_secs(59))
+            .unwrap()
+            .unwrap_as_dns();
+
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+        assert!(cache.get(&query, now + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn negative_response_without_soa_is_not_cached() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("example.com.", RecordType::AAAA, 1);
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::NoError, |_| {}),
+            now,
+        );
+
+        assert!(cache.get(&query, now).is_none());
+    }
+
+    #[test]
+    fn server_failure_is_not_cached() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("example.com.", RecordType::A, 1);
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::ServFail, |m| {
+                m.add_name_server(soa_record("example.com.", 3600, 60));
+            }),
+            now,
+        );
+
+        assert!(cache.get(&query, now).is_none());
+    }
+
+    #[test]
+    fn responses_are_cached_per_upstream_server() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("example.com.", RecordType::A, 1);
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::NoError, |m| {
+                m.add_answer(a_record("example.com.", 300));
+            }),
+            now,
+        );
+
+        let other_upstream = DnsQuery {
+            upstream: "8.8.8.8:53".parse().unwrap(),
+            ..query.clone()
+        };
+
+        assert!(cache.get(&other_upstream, now).is_none());
+    }
+
+    #[test]
+    fn responses_are_cached_per_dnssec_ok_bit() {
+        let mut cache = ResponseCache::default();
+        let now = Instant::now();
+        let query = query("example.com.", RecordType::A, 1);
+
+        cache.insert(
+            &query,
+            &response(&query, ResponseCode::NoError, |m| {
+                m.add_answer(a_record("example.com.", 300));
+            }),
+            now,
+        );
+
+        let mut message = query.query.unwrap_as_dns();
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true);
+        message.set_edns(edns);
+        let datagram = query.query.unwrap_as_udp();
+        let dnssec_query = DnsQuery {
+            query: ip_packet::make::udp_packet(
+                query.query.source(),
+                query.query.destination(),
+                datagram.get_source(),
+                datagram.get_destination(),
+                message.to_vec().unwrap(),
+            )
+            .into_immutable(),
+            ..query.clone()
+        };
+
+        assert!(cache.get(&dnssec_query, now).is_none());
+    }
+
+    fn query(name: &str, record_type: RecordType, id: u16) -> DnsQuery<'static> {
+        DnsQuery {
+            name: DomainName::vec_from_str(name).unwrap(),
+            record_type,
+            query: ip_packet::make::dns_query(
+                Name::from_ascii(name).unwrap(),
+                record_type,
+                "100.64.0.1:9999".parse().unwrap(),
+                "100.100.111.1:53".parse().unwrap(),
+                id,
+            )
+            .into_immutable(),
+            transport: Transport::Udp,
+            upstream: "1.1.1.1:53".parse().unwrap(),
+        }
+    }
+
+    fn response(
+        query: &DnsQuery<'static>,
+        response_code: ResponseCode,
+        f: impl FnOnce(&mut Message),
+    ) -> IpPacket<'static> {
+        let mut message = query.query.unwrap_as_dns();
+        message.set_message_type(MessageType::Response);
+        message.set_response_code(response_code);
+        f(&mut message);
+
+        let datagram = query.query.unwrap_as_udp();
+
+        ip_packet::make::udp_packet(
+            query.query.destination(),
+            query.query.source(),
+            datagram.get_destination(),
+            datagram.get_source(),
+            message.to_vec().unwrap(),
+        )
+        .into_immutable()
+    }
+
+    fn a_record(name: &str, ttl: u32) -> Record {
+        Record::from_rdata(
+            Name::from_ascii(name).unwrap(),
+            ttl,
+            RData::A(A(Ipv4Addr::new(1, 2, 3, 4))),
+        )
+    }
+
+    fn soa_record(zone: &str, ttl: u32, minimum: u32) -> Record {
+        let zone = Name::from_ascii(zone).unwrap();
+
+        Record::from_rdata(
+            zone.clone(),
+            ttl,
+            RData::SOA(SOA::new(zone.clone(), zone, 1, 3600, 600, 86400, minimum)),
+        )
+    }
+}
@@ -0,0 +1,490 @@
+//! Matching of domain names against the address patterns of DNS resources and DNS forwarding rules.
+//!
+//! A pattern is a domain name where some labels may contain wildcards:
+//!
+//! - `example.com` only matches `example.com`.
+//! - `?.example.com` matches `example.com` and all names exactly one label below it, e.g. `app.example.com`.
+//! - `*.example.com` matches `example.com` and all names any number of labels below it, e.g. `a.b.example.com`.
+//! - `*` within a label matches any number of characters within that label, e.g. `app-*.example.com` matches `app-1.example.com` and `*.*.example.com` matches `a.b.example.com` and `a.b.c.example.com`.
+//!
+//! Matching is case-insensitive.
+//!
+//! If several patterns match a name, the most specific one wins.
+//! We compare the labels of the patterns from right to left (i.e. starting with the TLD) and at the first label where they differ, the pattern with the more specific label wins:
+//!
+//! 1. A literal label, e.g. `app`.
+//! 2. A label containing a `*`, e.g. `app-*`. Amongst those, the one with more literal characters wins.
+//! 3. A `*` label that isn't the first one, e.g. the `*` in `app.*.example.com`.
+//!
+//! If there are no more labels to compare, an exact match wins over a `?.` prefix which in turn wins over a `*.` prefix.
+//! For example, `a.b.example.com` is matched by `*.b.example.com` rather than `a.*.example.com` or `*.example.com`.
+//!
+//! The patterns are stored in a trie of their labels in reverse order, i.e. matching a name only needs to look at the patterns that share a suffix with it.
+
+use connlib_shared::DomainName;
+use std::collections::HashMap;
+
+/// A set of domain name patterns, each with an associated value.
+pub(crate) struct DomainPatterns<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    /// Children for literal labels, indexed by the lower-cased label.
+    literals: HashMap<String, Node<T>>,
+    /// Children for labels with a `*` in them, sorted by specificity.
+    globs: Vec<(String, Node<T>)>,
+
+    /// The value of the pattern that ends at this node.
+    exact: Option<T>,
+    /// The value of the `?.` pattern rooted at this node.
+    single_label: Option<T>,
+    /// The value of the `*.` pattern rooted at this node.
+    any_labels: Option<T>,
+}
+
+/// The part of a pattern that determines where in a [`Node`] its value is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefix {
+    None,
+    QuestionMark,
+    Wildcard,
+}
+
+impl<T> Default for DomainPatterns<T> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            literals: HashMap::default(),
+            globs: Vec::default(),
+            exact: None,
+            single_label: None,
+            any_labels: None,
+        }
+    }
+}
+
+impl<T> DomainPatterns<T> {
+    /// Adds a pattern, returning the value previously associated with it.
+    ///
+    /// Malformed patterns (i.e. ones with empty labels) never match anything and are ignored.
+    pub(crate) fn insert(&mut self, pattern: &str, value: T) -> Option<T> {
+        let pattern = pattern.to_ascii_lowercase();
+        let Some((prefix, labels)) = parse(&pattern) else {
+            tracing::debug!(%pattern, "Ignoring malformed domain pattern");
+            return None;
+        };
+
+        let mut node = &mut self.root;
+
+        for label in labels {
+            node = node.child_mut(label);
+        }
+
+        node.slot_mut(prefix).replace(value)
+    }
+
+    /// Removes a pattern, returning the value associated with it.
+    pub(crate) fn remove(&mut self, pattern: &str) -> Option<T> {
+        let pattern = pattern.to_ascii_lowercase();
+        let (prefix, labels) = parse(&pattern)?;
+
+        self.root.remove(&labels, prefix)
+    }
+
+    /// Returns the value of the most specific pattern that matches the given name.
+    pub(crate) fn best_match(&self, name: &DomainName) -> Option<&T> {
+        self.matches(name).into_iter().next()
+    }
+
+    /// Returns the values of all patterns that match the given name, most specific first.
+    pub(crate) fn matches(&self, name: &DomainName) -> Vec<&T> {
+        let name = name.to_string().to_ascii_lowercase();
+        let labels = labels_reversed(&name);
+
+        let mut matches = Vec::new();
+        self.root.collect_matches(&labels, &mut matches);
+
+        matches
+    }
+
+    pub(crate) fn contains(&self, name: &DomainName) -> bool {
+        self.best_match(name).is_some()
+    }
+}
+
+/// Whether a single pattern matches the given name.
+///
+/// Unlike [`DomainPatterns`], this doesn't allocate a trie and is thus cheap enough to check a name against one pattern for every request.
+pub(crate) fn matches(pattern: &str, name: &DomainName) -> bool {
+    let Some((prefix, pattern_labels)) = parse(pattern) else {
+        return false;
+    };
+    let name = name.to_string();
+    let name_labels = labels_reversed(&name);
+
+    if name_labels.len() < pattern_labels.len() {
+        return false;
+    }
+
+    let (suffix, rest) = name_labels.split_at(pattern_labels.len());
+    let suffix_matches = pattern_labels
+        .iter()
+        .zip(suffix)
+        .all(|(pattern_label, label)| {
+            if pattern_label.contains('*') {
+                glob_matches(pattern_label, label)
+            } else {
+                pattern_label.eq_ignore_ascii_case(label)
+            }
+        });
+
+    if !suffix_matches {
+        return false;
+    }
+
+    match prefix {
+        Prefix::None => rest.is_empty(),
+        Prefix::QuestionMark => rest.len() <= 1,
+        Prefix::Wildcard => true,
+    }
+}
+
+impl<T> Node<T> {
+    fn child_mut(&mut self, label: &str) -> &mut Node<T> {
+        if !label.contains('*') {
+            return self.literals.entry(label.to_owned()).or_default();
+        }
+
+        let index = match self.globs.iter().position(|(glob, _)| glob == label) {
+            Some(index) => index,
+            None => {
+                self.globs.push((label.to_owned(), Node::default()));
+                self.globs
+                    .sort_by(|(a, _), (b, _)| glob_specificity(b).cmp(&glob_specificity(a)));
+
+                self.globs
+                    .iter()
+                    .position(|(glob, _)| glob == label)
+                    .expect("we just inserted the glob")
+            }
+        };
+
+        &mut self.globs[index].1
+    }
+
+    fn slot_mut(&mut self, prefix: Prefix) -> &mut Option<T> {
+        match prefix {
+            Prefix::None => &mut self.exact,
+            Prefix::QuestionMark => &mut self.single_label,
+            Prefix::Wildcard => &mut self.any_labels,
+        }
+    }
+
+    /// Removes the pattern below this node, pruning all nodes that end up empty.
+    fn remove(&mut self, labels: &[&str], prefix: Prefix) -> Option<T> {
+        let Some((label, rest)) = labels.split_first() else {
+            return self.slot_mut(prefix).take();
+        };
+
+        if !label.contains('*') {
+            let child = self.literals.get_mut(*label)?;
+            let removed = child.remove(rest, prefix);
+
+            if child.is_empty() {
+                self.literals.remove(*label);
+            }
+
+            return removed;
+        }
+
+        let index = self.globs.iter().position(|(glob, _)| glob == label)?;
+        let removed = self.globs[index].1.remove(rest, prefix);
+
+        if self.globs[index].1.is_empty() {
+            self.globs.remove(index);
+        }
+
+        removed
+    }
+
+    /// Collects the values of all patterns below this node that match the remaining labels of a name, most specific first.
+    fn collect_matches<'a>(&'a self, labels: &[&str], matches: &mut Vec<&'a T>) {
+        match labels.split_first() {
+            Some((label, rest)) => {
+                if let Some(child) = self.literals.get(*label) {
+                    child.collect_matches(rest, matches);
+                }
+
+                for (glob, child) in &self.globs {
+                    if glob_matches(glob, label) {
+                        child.collect_matches(rest, matches);
+                    }
+                }
+            }
+            None => matches.extend(&self.exact),
+        }
+
+        if labels.len() <= 1 {
+            matches.extend(&self.single_label);
+        }
+
+        matches.extend(&self.any_labels);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_none()
+            && self.single_label.is_none()
+            && self.any_labels.is_none()
+            && self.literals.is_empty()
+            && self.globs.is_empty()
+    }
+}
+
+/// Splits a pattern into its prefix and its remaining labels, in reverse order.
+fn parse(pattern: &str) -> Option<(Prefix, Vec<&str>)> {
+    let mut labels = labels_reversed(pattern);
+
+    let prefix = match labels.last().copied() {
+        Some("?") => Prefix::QuestionMark,
+        Some("*") => Prefix::Wildcard,
+        Some(_) | None => Prefix::None,
+    };
+    if prefix != Prefix::None {
+        labels.pop();
+    }
+
+    if labels.iter().any(|label| label.is_empty()) {
+        return None;
+    }
+
+    Some((prefix, labels))
+}
+
+fn labels_reversed(name: &str) -> Vec<&str> {
+    let name = name.trim_end_matches('.');
+
+    if name.is_empty() {
+        return Vec::new();
+    }
+
+    name.rsplit('.').collect()
+}
+
+/// The number of literal characters in a glob, a glob with more of them matches fewer labels.
+fn glob_specificity(glob: &str) -> usize {
+    glob.chars().filter(|c| *c != '*').count()
+}
+
+/// Matches a single label against a glob where `*` matches any number of characters.
+fn glob_matches(glob: &str, label: &str) -> bool {
+    let glob = glob.as_bytes();
+    let label = label.as_bytes();
+
+    let mut g = 0;
+    let mut l = 0;
+    // Where to resume if the current attempt fails: the position after the last `*` and the label position it was matched against.
+    let mut backtrack = None;
+
+    while l < label.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                backtrack = Some((g + 1, l));
+                g += 1;
+            }
+            Some(c) if c.eq_ignore_ascii_case(&label[l]) => {
+                g += 1;
+                l += 1;
+            }
+            Some(_) | None => {
+                let Some((next_g, last_l)) = backtrack else {
+                    return false;
+                };
+
+                g = next_g;
+                l = last_l + 1;
+                backtrack = Some((next_g, last_l + 1));
+            }
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let patterns = patterns([
+            "*.example.com",
+            "?.example.com",
+            "*.b.example.com",
+            "a.*.example.com",
+            "a-*.b.example.com",
+            "a-1.b.example.com",
+        ]);
+
+        assert_eq!(
+            best_match(&patterns, "a-1.b.example.com"),
+            Some("a-1.b.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "a-2.b.example.com"),
+            Some("a-*.b.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "a.b.example.com"),
+            Some("*.b.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "a.c.example.com"),
+            Some("a.*.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "c.example.com"),
+            Some("?.example.com")
+        );
+        assert_eq!(best_match(&patterns, "example.com"), Some("?.example.com"));
+        assert_eq!(
+            best_match(&patterns, "b.c.example.com"),
+            Some("*.example.com")
+        );
+        assert_eq!(best_match(&patterns, "example.org"), None);
+    }
+
+    #[test]
+    fn more_literal_characters_win_amongst_globs() {
+        let patterns = patterns([
+            "app-*.example.com",
+            "app-*-prod.example.com",
+            "*.example.com",
+        ]);
+
+        assert_eq!(
+            best_match(&patterns, "app-1-prod.example.com"),
+            Some("app-*-prod.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "app-1.example.com"),
+            Some("app-*.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "web.example.com"),
+            Some("*.example.com")
+        );
+    }
+
+    #[test]
+    fn glob_only_matches_within_a_label() {
+        let patterns = patterns(["app-*.example.com"]);
+
+        assert_eq!(
+            best_match(&patterns, "app-.example.com"),
+            Some("app-*.example.com")
+        );
+        assert_eq!(
+            best_match(&patterns, "APP-1.Example.com"),
+            Some("app-*.example.com")
+        );
+        assert_eq!(best_match(&patterns, "app-1.b.example.com"), None);
+        assert_eq!(best_match(&patterns, "b.app-1.example.com"), None);
+        assert_eq!(best_match(&patterns, "web-1.example.com"), None);
+    }
+
+    #[test]
+    fn all_matches_are_sorted_by_specificity() {
+        let patterns = patterns(["*.example.com", "foo.example.com", "?.example.com"]);
+
+        assert_eq!(
+            patterns
+                .matches(&domain("foo.example.com"))
+                .into_iter()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec!["foo.example.com", "?.example.com", "*.example.com"]
+        );
+    }
+
+    #[test]
+    fn removing_patterns_prunes_the_trie() {
+        let mut patterns = patterns(["*.example.com", "a-*.b.example.com"]);
+
+        assert_eq!(
+            patterns.remove("a-*.b.example.com"),
+            Some("a-*.b.example.com")
+        );
+        assert_eq!(patterns.remove("*.example.com"), Some("*.example.com"));
+        assert_eq!(patterns.remove("*.example.com"), None);
+        assert!(patterns.root.is_empty());
+    }
+
+    #[test]
+    fn single_pattern_matches_like_a_set_of_one() {
+        let names = [
+            "example.com",
+            "app.example.com",
+            "APP-1.Example.com",
+            "a.b.example.com",
+            "a.b.c.example.com",
+            "example.org",
+            "com",
+        ];
+
+        for pattern in [
+            "example.com",
+            "?.example.com",
+            "*.example.com",
+            "app-*.example.com",
+            "a.*.example.com",
+            "*.*.example.com",
+            "a..example.com",
+        ] {
+            let set = patterns([pattern]);
+
+            for name in names {
+                assert_eq!(
+                    matches(pattern, &domain(name)),
+                    set.contains(&domain(name)),
+                    "{pattern} vs {name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn globs_match_labels() {
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("a*c", "abbbc"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(glob_matches("*-prod", "app-prod"));
+        assert!(!glob_matches("*-prod", "app-prod1"));
+        assert!(!glob_matches("a*c", "abcd"));
+    }
+
+    fn patterns<const N: usize>(patterns: [&'static str; N]) -> DomainPatterns<&'static str> {
+        let mut set = DomainPatterns::default();
+
+        for pattern in patterns {
+            set.insert(pattern, pattern);
+        }
+
+        set
+    }
+
+    fn best_match(patterns: &DomainPatterns<&'static str>, name: &str) -> Option<&'static str> {
+        patterns.best_match(&domain(name)).copied()
+    }
+
+    fn domain(name: &str) -> DomainName {
+        DomainName::vec_from_str(name).unwrap()
+    }
+}
@@ -0,0 +1,293 @@
+//! Bookkeeping of the DNS queries we answer.
+//!
+//! For every query, we remember how we answered it until we see the response on its way back to the application.
+//! This allows us to report the response code and latency, regardless of whether we answered locally, via an upstream DNS server or via a gateway.
+
+use super::as_dns;
+use connlib_shared::callbacks::{DnsQueryLogEntry, DnsResolution};
+use connlib_shared::messages::ResourceId;
+use connlib_shared::DomainName;
+use domain::base::iana::Rcode;
+use domain::base::Message;
+use ip_packet::IpPacket;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// For how long we wait for the response to a query before we forget about it.
+const PENDING_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+/// The max. number of log entries we buffer before dropping the oldest ones.
+const MAX_BUFFERED_ENTRIES: usize = 1000;
+/// The max. number of queries we wait for a response for.
+///
+/// Applications that never read their responses must not make us grow without bounds.
+const MAX_PENDING_QUERIES: usize = 1000;
+
+/// Counters of the DNS queries for a single resource.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DnsResourceStats {
+    /// The number of queries that matched the resource.
+    pub queries: u64,
+    /// The number of queries we answered ourselves with the resource's proxy IPs.
+    pub local_responses: u64,
+    /// The number of queries we forwarded to the resource's gateway.
+    pub forwarded_to_gateway: u64,
+    /// The number of responses with an RCODE other than `NOERROR`.
+    pub errors: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct QueryLog {
+    /// Whether we emit a [`DnsQueryLogEntry`] for each answered query.
+    ///
+    /// The counters are always maintained.
+    enabled: bool,
+
+    /// Queries we haven't seen a response for yet, indexed by the application's socket and the query ID.
+    pending: HashMap<(SocketAddr, u16), PendingQuery>,
+    stats: HashMap<ResourceId, DnsResourceStats>,
+
+    buffered_entries: VecDeque<DnsQueryLogEntry>,
+}
+
+struct PendingQuery {
+    name: DomainName,
+    record_type: String,
+    resource: Option<ResourceId>,
+    resolution: DnsResolution,
+    received_at: Instant,
+}
+
+impl QueryLog {
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        if !enabled {
+            self.buffered_entries.clear();
+        }
+    }
+
+    /// Records a query that we are about to answer using `resolution`.
+    ///
+    /// `match_resource` is used to find the resource that the queried domain belongs to.
+    pub(crate) fn on_query(
+        &mut self,
+        query: &IpPacket,
+        match_resource: impl FnOnce(&DomainName) -> Option<ResourceId>,
+        resolution: DnsResolution,
+        now: Instant,
+    ) {
+        let Some(datagram) = query.as_udp() else {
+            return;
+        };
+        let Some(message) = as_dns(&datagram) else {
+            return;
+        };
+        let Some(question) = message.first_question() else {
+            return;
+        };
+
+        let name = question.qname().to_vec();
+        let resource = match_resource(&name);
+
+        if let Some(resource) = resource {
+            let stats = self.stats.entry(resource).or_default();
+            stats.queries += 1;
+
+            match resolution {
+                DnsResolution::Local => stats.local_responses += 1,
+                DnsResolution::Gateway => stats.forwarded_to_gateway += 1,
+                DnsResolution::Upstream { .. } | DnsResolution::Cache => {}
+            }
+        }
+
+        let key = (
+            SocketAddr::new(query.source(), datagram.get_source()),
+            message.header().id(),
+        );
+
+        if self.pending.len() >= MAX_PENDING_QUERIES && !self.pending.contains_key(&key) {
+            tracing::debug!(%name, "Too many pending DNS queries, not waiting for response");
+            return;
+        }
+
+        self.pending.insert(
+            key,
+            PendingQuery {
+                name,
+                record_type: question.qtype().to_string(),
+                resource,
+                resolution,
+                received_at: now,
+            },
+        );
+    }
+
+    /// Records a response that we are about to send back to the application.
+    pub(crate) fn on_response(&mut self, response: &IpPacket, now: Instant) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let Some(datagram) = response.as_udp() else {
+            return;
+        };
+        let Ok(message) = Message::from_slice(datagram.payload()) else {
+            return;
+        };
+        if !message.header().qr() {
+            return;
+        }
+
+        let Some(query) = self.pending.remove(&(
+            SocketAddr::new(response.destination(), datagram.get_destination()),
+            message.header().id(),
+        )) else {
+            return;
+        };
+
+        let response_code = message.header().rcode();
+
+        if let Some(stats) = query.resource.and_then(|r| self.stats.get_mut(&r)) {
+            if response_code != Rcode::NOERROR {
+                stats.errors += 1;
+            }
+        }
+
+        if !self.enabled {
+            return;
+        }
+
+        if self.buffered_entries.len() >= MAX_BUFFERED_ENTRIES {
+            self.buffered_entries.pop_front();
+        }
+
+        self.buffered_entries.push_back(DnsQueryLogEntry {
+            name: query.name.to_string(),
+            record_type: query.record_type,
+            resource: query.resource,
+            resolution: query.resolution,
+            response_code: response_code.to_string(),
+            latency: now.duration_since(query.received_at),
+        });
+    }
+
+    pub(crate) fn poll_entry(&mut self) -> Option<DnsQueryLogEntry> {
+        self.buffered_entries.pop_front()
+    }
+
+    pub(crate) fn stats(&self) -> &HashMap<ResourceId, DnsResourceStats> {
+        &self.stats
+    }
+
+    /// Forgets the counters of a resource that is no longer available.
+    pub(crate) fn remove_resource(&mut self, id: &ResourceId) {
+        self.stats.remove(id);
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|query| query.received_at + PENDING_QUERY_TIMEOUT)
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.pending
+            .retain(|_, query| now.duration_since(query.received_at) < PENDING_QUERY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::ResponseCode;
+    use hickory_resolver::proto::rr::{Name, RecordType};
+
+    #[test]
+    fn logs_response_code_and_latency_of_answered_query() {
+        let mut log = QueryLog::default();
+        log.set_enabled(true);
+        let resource = ResourceId::from_u128(1);
+        let now = Instant::now();
+
+        let query = query("foo.example.com.");
+        log.on_query(&query, |_| Some(resource), DnsResolution::Gateway, now);
+        log.on_response(
+            &ip_packet::make::dns_err_response(query, ResponseCode::NXDomain).into_immutable(),
+            now + Duration::from_millis(20),
+        );
+
+        let entry = log.poll_entry().unwrap();
+        assert_eq!(entry.name, "foo.example.com");
+        assert_eq!(entry.record_type, "SRV");
+        assert_eq!(entry.resource, Some(resource));
+        assert_eq!(entry.resolution, DnsResolution::Gateway);
+        assert_eq!(entry.response_code, "NXDOMAIN");
+        assert_eq!(entry.latency, Duration::from_millis(20));
+        assert_eq!(
+            log.stats()[&resource],
+            DnsResourceStats {
+                queries: 1,
+                local_responses: 0,
+                forwarded_to_gateway: 1,
+                errors: 1
+            }
+        );
+    }
+
+    #[test]
+    fn only_counts_queries_if_disabled() {
+        let mut log = QueryLog::default();
+        let resource = ResourceId::from_u128(1);
+        let now = Instant::now();
+
+        let query = query("foo.example.com.");
+        log.on_query(&query, |_| Some(resource), DnsResolution::Local, now);
+        log.on_response(
+            &ip_packet::make::dns_ok_response(query, |_| std::iter::empty()).into_immutable(),
+            now,
+        );
+
+        assert!(log.poll_entry().is_none());
+        assert_eq!(log.stats()[&resource].local_responses, 1);
+    }
+
+    #[test]
+    fn forgets_unanswered_queries_after_timeout() {
+        let mut log = QueryLog::default();
+        let now = Instant::now();
+
+        for id in 0..(MAX_PENDING_QUERIES as u16 + 10) {
+            log.on_query(
+                &query_with_id("foo.example.com.", id),
+                |_| None,
+                DnsResolution::Local,
+                now,
+            );
+        }
+
+        assert_eq!(log.pending.len(), MAX_PENDING_QUERIES);
+        assert_eq!(log.poll_timeout(), Some(now + PENDING_QUERY_TIMEOUT));
+
+        log.handle_timeout(now + PENDING_QUERY_TIMEOUT);
+
+        assert!(log.pending.is_empty());
+        assert_eq!(log.poll_timeout(), None);
+    }
+
+    fn query(name: &str) -> IpPacket<'static> {
+        query_with_id(name, 0)
+    }
+
+    fn query_with_id(name: &str, id: u16) -> IpPacket<'static> {
+        ip_packet::make::dns_query(
+            Name::from_ascii(name).unwrap(),
+            RecordType::SRV,
+            "100.64.0.1:9999".parse().unwrap(),
+            "100.100.111.1:53".parse().unwrap(),
+            id,
+        )
+        .into_immutable()
+    }
+}
@@ -0,0 +1,483 @@
+//! A minimal userspace TCP endpoint for serving DNS over TCP on our sentinel DNS servers.
+//!
+//! Resolvers fall back to TCP when a UDP response is truncated (see RFC 7766).
+//! Because the sentinel IPs only exist within connlib, we need to terminate these connections ourselves.
+//!
+//! We only need to support DNS' simple request-response pattern, see [`crate::tcp`] for which parts of TCP we skip.
+//! All packets stay on the local machine, thus we also ignore the application's receive window.
+
+use crate::tcp::{self, Tcb, RETRANSMIT_TIMEOUT};
+use ip_packet::tcp::TcpFlags;
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub(crate) use crate::tcp::ConnectionId;
+
+/// The max. number of bytes we buffer for a single connection.
+///
+/// This is large enough to fit a DNS message of the max. size and its length prefix.
+const MAX_RECEIVE_BUFFER: usize = u16::MAX as usize + 2;
+/// RFC 7766 recommends servers to close idle connections after a few seconds.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_CONNECTIONS: usize = 100;
+
+#[derive(Default)]
+pub(crate) struct Server {
+    connections: HashMap<ConnectionId, Connection>,
+
+    received_queries: VecDeque<Query>,
+    buffered_packets: VecDeque<MutableIpPacket<'static>>,
+}
+
+/// A DNS message received on one of our connections.
+#[derive(Debug)]
+pub(crate) struct Query {
+    pub(crate) connection: ConnectionId,
+    pub(crate) message: Vec<u8>,
+}
+
+struct Connection {
+    tcb: Tcb,
+    receive_buffer: Vec<u8>,
+
+    /// The number of queries we have received but not yet answered.
+    pending_queries: usize,
+
+    last_incoming: Instant,
+}
+
+impl Server {
+    /// Handles a TCP segment sent by an application to one of our sentinel DNS servers.
+    pub(crate) fn handle_inbound(&mut self, packet: IpPacket<'_>, now: Instant) {
+        let Some(tcp) = packet.as_tcp() else {
+            return;
+        };
+
+        let id = ConnectionId::inbound(&packet, &tcp);
+        let flags = tcp.get_flags();
+        let seq = tcp.get_sequence();
+
+        if flags & TcpFlags::RST != 0 {
+            if self.connections.remove(&id).is_some() {
+                tracing::debug!(remote = %id.remote, "DNS over TCP connection reset by peer");
+            }
+
+            return;
+        }
+
+        let Some(connection) = self.connections.get_mut(&id) else {
+            if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+                self.accept(id, seq, now);
+                return;
+            }
+
+            self.buffered_packets
+                .push_back(tcp::reset_unknown(id, &tcp));
+
+            return;
+        };
+
+        connection.last_incoming = now;
+
+        if flags & TcpFlags::SYN != 0 {
+            // The remote didn't receive our SYN-ACK yet, send it again.
+            if connection.tcb.is_syn_retransmit(seq) {
+                self.buffered_packets
+                    .push_back(connection.tcb.syn_ack(id, connection.window()));
+            }
+
+            return;
+        }
+
+        if flags & TcpFlags::ACK != 0 {
+            connection.tcb.handle_ack(tcp.get_acknowledgement(), now);
+        }
+
+        let mut needs_ack = false;
+
+        let payload = tcp.payload();
+        if !payload.is_empty() {
+            needs_ack = true;
+
+            connection.receive(id, seq, payload, &mut self.received_queries);
+        }
+
+        if flags & TcpFlags::FIN != 0 {
+            needs_ack = true;
+
+            connection.tcb.receive_fin(seq, payload.len());
+        }
+
+        if needs_ack {
+            self.buffered_packets
+                .push_back(connection.tcb.ack(id, connection.window()));
+        }
+
+        connection.transmit(id, now, &mut self.buffered_packets);
+
+        if connection.tcb.is_closed() {
+            tracing::debug!(remote = %id.remote, "DNS over TCP connection closed");
+            self.connections.remove(&id);
+        }
+    }
+
+    /// Sends a DNS response on the given connection.
+    pub(crate) fn send_response(&mut self, id: ConnectionId, message: &[u8], now: Instant) {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            tracing::debug!(remote = %id.remote, "Cannot send DNS response: connection is closed");
+            return;
+        };
+
+        let Ok(len) = u16::try_from(message.len()) else {
+            tracing::warn!("DNS response is too big for DNS over TCP");
+            return;
+        };
+
+        connection.pending_queries = connection.pending_queries.saturating_sub(1);
+
+        connection.tcb.send_buffer.extend(len.to_be_bytes());
+        connection.tcb.send_buffer.extend(message);
+        connection.transmit(id, now, &mut self.buffered_packets);
+    }
+
+    /// Aborts the given connection, i.e. sends a RST to the application.
+    pub(crate) fn reset(&mut self, id: ConnectionId) {
+        let Some(connection) = self.connections.remove(&id) else {
+            return;
+        };
+
+        self.buffered_packets.push_back(connection.tcb.rst(id));
+    }
+
+    pub(crate) fn poll_query(&mut self) -> Option<Query> {
+        self.received_queries.pop_front()
+    }
+
+    pub(crate) fn poll_packet(&mut self) -> Option<IpPacket<'static>> {
+        self.buffered_packets
+            .pop_front()
+            .map(MutableIpPacket::into_immutable)
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .flat_map(|c| [c.tcb.retransmit_at, Some(c.last_incoming + IDLE_TIMEOUT)])
+            .flatten()
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        for (id, connection) in self.connections.iter_mut() {
+            if connection.tcb.retransmit_at.is_some_and(|t| t <= now) {
+                let window = connection.window();
+
+                connection
+                    .tcb
+                    .retransmit(*id, usize::MAX, window, now, &mut self.buffered_packets);
+            }
+        }
+
+        self.connections.retain(|id, c| {
+            if now < c.last_incoming + IDLE_TIMEOUT {
+                return true;
+            }
+
+            tracing::debug!(remote = %id.remote, "Closing idle DNS over TCP connection");
+            self.buffered_packets.push_back(c.tcb.rst(*id));
+
+            false
+        });
+    }
+
+    fn accept(&mut self, id: ConnectionId, seq: u32, now: Instant) {
+        if self.connections.len() >= MAX_CONNECTIONS {
+            tracing::debug!(remote = %id.remote, "Too many DNS over TCP connections");
+
+            self.buffered_packets.push_back(tcp::refuse_syn(id, seq));
+            return;
+        }
+
+        let mut tcb = Tcb::new(seq);
+        tcb.retransmit_at = Some(now + RETRANSMIT_TIMEOUT);
+
+        let connection = Connection {
+            tcb,
+            receive_buffer: Vec::default(),
+            pending_queries: 0,
+            last_incoming: now,
+        };
+
+        tracing::debug!(remote = %id.remote, "New DNS over TCP connection");
+
+        self.buffered_packets
+            .push_back(connection.tcb.syn_ack(id, connection.window()));
+        self.connections.insert(id, connection);
+    }
+}
+
+impl Connection {
+    fn receive(
+        &mut self,
+        id: ConnectionId,
+        seq: u32,
+        payload: &[u8],
+        received_queries: &mut VecDeque<Query>,
+    ) {
+        let window = self.window() as usize;
+        let new_data = self.tcb.receive(seq, payload, window);
+        self.receive_buffer.extend_from_slice(new_data);
+
+        // Each DNS message is prefixed with its length as a 2-byte integer.
+        while let Some(len) = self
+            .receive_buffer
+            .get(..2)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        {
+            if self.receive_buffer.len() < 2 + len {
+                break;
+            }
+
+            let message = self.receive_buffer.drain(..2 + len).skip(2).collect();
+            self.pending_queries += 1;
+
+            received_queries.push_back(Query {
+                connection: id,
+                message,
+            });
+        }
+    }
+
+    /// Sends all buffered responses and our FIN, once the application is done and we answered all of its queries.
+    fn transmit(
+        &mut self,
+        id: ConnectionId,
+        now: Instant,
+        packets: &mut VecDeque<MutableIpPacket<'static>>,
+    ) {
+        if self.tcb.remote_fin && self.pending_queries == 0 {
+            self.tcb.close();
+        }
+
+        let window = self.window();
+        self.tcb.transmit(id, usize::MAX, window, now, packets);
+    }
+
+    fn window(&self) -> u16 {
+        (MAX_RECEIVE_BUFFER - self.receive_buffer.len()).min(u16::MAX as usize) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_query_after_handshake() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let iss = handshake(&mut server, id, 1000, now);
+
+        let query = length_prefixed(b"query");
+        server.handle_inbound(
+            client_segment(
+                id,
+                1001,
+                iss.wrapping_add(1),
+                TcpFlags::PSH | TcpFlags::ACK,
+                &query,
+            )
+            .into_immutable(),
+            now,
+        );
+
+        let ack = server.poll_packet().unwrap();
+        assert_eq!(ack.as_tcp().unwrap().get_acknowledgement(), 1001 + 7);
+
+        let query = server.poll_query().unwrap();
+        assert_eq!(query.connection, id);
+        assert_eq!(query.message, b"query");
+
+        server.send_response(id, b"response", now);
+
+        let response = server.poll_packet().unwrap();
+        let tcp = response.as_tcp().unwrap();
+        assert_eq!(tcp.get_sequence(), iss.wrapping_add(1));
+        assert_eq!(tcp.payload(), length_prefixed(b"response"));
+    }
+
+    #[test]
+    fn reassembles_query_split_across_segments() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let iss = handshake(&mut server, id, 1000, now);
+
+        let query = length_prefixed(b"query");
+        let (first, second) = query.split_at(3);
+        server.handle_inbound(
+            client_segment(id, 1001, iss.wrapping_add(1), TcpFlags::ACK, first).into_immutable(),
+            now,
+        );
+        assert!(server.poll_query().is_none());
+
+        server.handle_inbound(
+            client_segment(id, 1004, iss.wrapping_add(1), TcpFlags::ACK, second).into_immutable(),
+            now,
+        );
+        assert_eq!(server.poll_query().unwrap().message, b"query");
+    }
+
+    #[test]
+    fn large_responses_are_split_into_multiple_segments() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let iss = handshake(&mut server, id, 1000, now);
+
+        let response = vec![1u8; 3000];
+        server.send_response(id, &response, now);
+
+        let segments = std::iter::from_fn(|| server.poll_packet()).collect::<Vec<_>>();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(
+            segments[1].as_tcp().unwrap().get_sequence(),
+            iss.wrapping_add(1 + 1200)
+        );
+        assert_eq!(
+            segments
+                .iter()
+                .map(|s| s.as_tcp().unwrap().payload().len())
+                .sum::<usize>(),
+            3002
+        );
+    }
+
+    #[test]
+    fn retransmits_unacknowledged_response() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let iss = handshake(&mut server, id, 1000, now);
+        server.send_response(id, b"response", now);
+        let _ = server.poll_packet().unwrap();
+
+        server.handle_timeout(now + RETRANSMIT_TIMEOUT);
+
+        let retransmit = server.poll_packet().unwrap();
+        assert_eq!(
+            retransmit.as_tcp().unwrap().get_sequence(),
+            iss.wrapping_add(1)
+        );
+    }
+
+    #[test]
+    fn closes_connection_after_remote_fin() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let iss = handshake(&mut server, id, 1000, now);
+        server.handle_inbound(
+            client_segment(
+                id,
+                1001,
+                iss.wrapping_add(1),
+                TcpFlags::FIN | TcpFlags::ACK,
+                &[],
+            )
+            .into_immutable(),
+            now,
+        );
+
+        let _ack = server.poll_packet().unwrap();
+        let fin = server.poll_packet().unwrap();
+        assert_ne!(fin.as_tcp().unwrap().get_flags() & TcpFlags::FIN, 0);
+
+        server.handle_inbound(
+            client_segment(id, 1002, iss.wrapping_add(2), TcpFlags::ACK, &[]).into_immutable(),
+            now,
+        );
+        assert!(server.connections.is_empty());
+    }
+
+    #[test]
+    fn resets_unknown_connections() {
+        let mut server = Server::default();
+        let id = connection_id();
+
+        server.handle_inbound(
+            client_segment(id, 1001, 5000, TcpFlags::ACK, b"foo").into_immutable(),
+            Instant::now(),
+        );
+
+        let rst = server.poll_packet().unwrap();
+        let tcp = rst.as_tcp().unwrap();
+        assert_ne!(tcp.get_flags() & TcpFlags::RST, 0);
+        assert_eq!(tcp.get_sequence(), 5000);
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        handshake(&mut server, id, 1000, now);
+        server.handle_timeout(now + IDLE_TIMEOUT);
+
+        assert!(server.connections.is_empty());
+    }
+
+    /// Performs a TCP handshake and returns the server's initial sequence number.
+    fn handshake(server: &mut Server, id: ConnectionId, isn: u32, now: Instant) -> u32 {
+        server.handle_inbound(
+            client_segment(id, isn, 0, TcpFlags::SYN, &[]).into_immutable(),
+            now,
+        );
+
+        let syn_ack = server.poll_packet().unwrap();
+        let tcp = syn_ack.as_tcp().unwrap();
+        assert_eq!(tcp.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(tcp.get_acknowledgement(), isn + 1);
+        let iss = tcp.get_sequence();
+
+        server.handle_inbound(
+            client_segment(id, isn + 1, iss.wrapping_add(1), TcpFlags::ACK, &[]).into_immutable(),
+            now,
+        );
+        assert!(server.poll_packet().is_none());
+
+        iss
+    }
+
+    fn client_segment(
+        id: ConnectionId,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> MutableIpPacket<'static> {
+        ip_packet::make::tcp_segment(id.remote, id.local, seq, ack, flags, u16::MAX, payload)
+    }
+
+    fn connection_id() -> ConnectionId {
+        ConnectionId {
+            local: "100.100.111.1:53".parse().unwrap(),
+            remote: "100.64.0.1:45678".parse().unwrap(),
+        }
+    }
+
+    fn length_prefixed(message: &[u8]) -> Vec<u8> {
+        let mut buf = (message.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(message);
+
+        buf
+    }
+}
@@ -1,22 +1,33 @@
-use crate::peer::ClientOnGateway;
+use crate::dns::{self, DnsQuery};
+use crate::peer::{ClientOnGateway, NatConfig, ResourceRateLimiters};
 use crate::peer_store::PeerStore;
 use crate::utils::earliest;
 use crate::{GatewayEvent, GatewayTunnel, Tun};
 use boringtun::x25519::PublicKey;
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::{
-    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId, Key,
-    Offer, RelayId, ResourceId,
+    gateway::HealthProbe, gateway::RateLimit, gateway::ResolvedResourceDescriptionDns,
+    gateway::ResourceDescription, Answer, ClientId, Key, Offer, RelayId, ResourceId,
 };
 use connlib_shared::{DomainName, Error, Result, StaticSecret};
-use ip_packet::{IpPacket, MutableIpPacket};
+use egress_proxy::EgressProxy;
+use health_checks::HealthChecks;
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use itertools::Itertools as _;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{RelaySocket, ServerNode};
-use std::collections::{HashMap, HashSet, VecDeque};
+use snownet::{ConnectionConfig, RelaySocket, ServerNode};
+use stats::TrafficStats;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 
-const EXPIRE_RESOURCES_INTERVAL: Duration = Duration::from_secs(1);
+mod egress_proxy;
+mod health_checks;
+mod stats;
+
+pub use egress_proxy::EgressStreamId;
+pub use stats::{ClientStats, GatewayStats, TrafficStats};
 
 impl GatewayTunnel {
     pub fn set_tun(&mut self, tun: Tun) {
@@ -53,11 +64,12 @@ impl GatewayTunnel {
             expires_at,
             resource,
             Instant::now(),
+            Utc::now(),
         )
     }
 
     pub fn cleanup_connection(&mut self, id: &ClientId) {
-        self.role_state.peers.remove(id);
+        self.role_state.remove_peer(id);
     }
 
     pub fn allow_access(
@@ -67,8 +79,14 @@ impl GatewayTunnel {
         expires_at: Option<DateTime<Utc>>,
         domain: Option<(DomainName, Vec<IpAddr>)>,
     ) -> Result<()> {
-        self.role_state
-            .allow_access(resource, client, expires_at, domain, Instant::now())
+        self.role_state.allow_access(
+            resource,
+            client,
+            expires_at,
+            domain,
+            Instant::now(),
+            Utc::now(),
+        )
     }
 
     pub fn refresh_translation(
@@ -77,15 +95,107 @@ impl GatewayTunnel {
         resource_id: ResourceId,
         name: DomainName,
         resolved_ips: Vec<IpAddr>,
+        ttl: Option<Duration>,
     ) {
-        self.role_state
-            .refresh_translation(client, resource_id, name, resolved_ips, Instant::now())
+        self.role_state.refresh_translation(
+            client,
+            resource_id,
+            name,
+            resolved_ips,
+            ttl,
+            Instant::now(),
+        )
     }
 
     pub fn update_resource(&mut self, resource: ResourceDescription) {
+        let now = Instant::now();
+
+        self.role_state
+            .resource_rate_limiters
+            .set(resource.id(), resource.rate_limit(), now);
+
         for peer in self.role_state.peers.iter_mut() {
             peer.update_resource(&resource);
+            peer.set_resource_port_mappings(resource.id(), resource.port_mappings());
+            peer.set_resource_load_balancing(resource.id(), resource.load_balancing());
         }
+
+        self.role_state.backends_changed = true;
+        self.role_state.update_health_checks(now);
+    }
+
+    /// A snapshot of the gateway's state and traffic counters.
+    pub fn stats(&self) -> GatewayStats {
+        self.role_state.stats()
+    }
+
+    /// Records the result of a health check that we asked for via [`GatewayEvent::ProbeBackend`].
+    pub fn on_probe_result(&mut self, ip: IpAddr, probe: HealthProbe, healthy: bool) {
+        self.role_state.on_probe_result(ip, probe, healthy)
+    }
+
+    /// Terminates new TCP connections to these resources ourselves and relays them via [`GatewayEvent::EgressProxyConnect`].
+    pub fn set_egress_proxy_resources(&mut self, resources: HashSet<ResourceId>) {
+        self.role_state.set_egress_proxy_resources(resources);
+    }
+
+    /// The connection to the resource of the stream has been established through the upstream proxy.
+    pub fn on_egress_proxy_connected(&mut self, stream: EgressStreamId) {
+        self.role_state
+            .on_egress_proxy_connected(stream, Instant::now());
+    }
+
+    /// Sends data the resource sent to us to the client.
+    ///
+    /// Returns how many bytes were accepted, the rest has to be sent again after the next [`GatewayEvent`] or timeout.
+    pub fn send_egress_proxy_data(&mut self, stream: EgressStreamId, data: &[u8]) -> usize {
+        self.role_state
+            .send_egress_proxy_data(stream, data, Instant::now())
+    }
+
+    /// The given number of bytes of a [`GatewayEvent::EgressProxyData`] have been written to the upstream proxy.
+    pub fn on_egress_proxy_written(&mut self, stream: EgressStreamId, len: usize) {
+        self.role_state
+            .on_egress_proxy_written(stream, len, Instant::now());
+    }
+
+    /// The resource closed its side of the stream.
+    pub fn on_egress_proxy_eof(&mut self, stream: EgressStreamId) {
+        self.role_state.on_egress_proxy_eof(stream, Instant::now());
+    }
+
+    /// Aborts the stream, e.g. because the upstream proxy failed to connect to the resource.
+    pub fn reset_egress_proxy_stream(&mut self, stream: EgressStreamId) {
+        self.role_state
+            .reset_egress_proxy_stream(stream, Instant::now());
+    }
+
+    /// Enables or disables emitting [`GatewayEvent::FlowLogged`] for every flow that ends.
+    pub fn set_flow_log(&mut self, enabled: bool) {
+        self.role_state.set_flow_log(enabled);
+    }
+
+    /// Configures the NAT for DNS resources of all clients.
+    pub fn set_nat_config(&mut self, config: NatConfig) {
+        self.role_state.set_nat_config(config);
+    }
+
+    /// Configures the idle timeout and keep-alive of new connections to clients.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.role_state.set_connection_config(config);
+    }
+
+    /// Overrides the keep-alive of the next connection to this client, `None` to not send any.
+    ///
+    /// Must be called before [`GatewayTunnel::accept`].
+    pub fn set_client_keepalive(&mut self, client: ClientId, keepalive: Option<Duration>) {
+        self.role_state.set_client_keepalive(client, keepalive);
+    }
+
+    /// Limits all traffic of a client, replacing any previous limit.
+    pub fn set_client_rate_limit(&mut self, client: ClientId, limit: Option<RateLimit>) {
+        self.role_state
+            .set_client_rate_limit(client, limit, Instant::now())
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(%resource, %client))]
@@ -96,12 +206,26 @@ impl GatewayTunnel {
 
         peer.remove_resource(resource);
         if peer.is_emptied() {
-            self.role_state.peers.remove(client);
+            self.role_state.remove_peer(client);
         }
+        self.role_state.backends_changed = true;
 
         tracing::debug!("Access removed");
     }
 
+    pub fn on_dns_result(
+        &mut self,
+        conn_id: ClientId,
+        query: DnsQuery<'static>,
+        response: std::result::Result<
+            hickory_resolver::lookup::Lookup,
+            hickory_resolver::error::ResolveError,
+        >,
+    ) {
+        self.role_state
+            .on_dns_result(conn_id, query, response, Instant::now())
+    }
+
     pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
         self.role_state
             .add_ice_candidate(conn_id, ice_candidate, Instant::now());
@@ -123,10 +247,42 @@ pub struct GatewayState {
     /// All clients we are connected to and the associated, connection-specific state.
     peers: PeerStore<ClientId, ClientOnGateway>,
 
-    /// When to next check whether a resource-access policy has expired.
-    next_expiry_resources_check: Option<Instant>,
+    /// When to next call [`ClientOnGateway::handle_timeout`] for a client, earliest first.
+    ///
+    /// Entries that don't match [`GatewayState::next_wakeup`] are stale and skipped.
+    wakeups: BinaryHeap<Reverse<(Instant, ClientId)>>,
+    next_wakeup: HashMap<ClientId, Instant>,
+    /// Clients that may have to be woken up earlier, e.g. because they opened a new flow.
+    ///
+    /// We only look at them in [`GatewayState::poll_timeout`] instead of for every packet.
+    dirty_peers: HashSet<ClientId>,
+
+    /// Whether we emit a [`GatewayEvent::FlowLogged`] for every flow that ends.
+    flow_log: bool,
+    nat_config: NatConfig,
+    /// The idle timeout and keep-alive of new connections to clients.
+    connection_config: ConnectionConfig,
+
+    /// The health of the backends of load-balanced resources, shared by all clients.
+    health_checks: HealthChecks,
+    /// The rate limits of resources, shared by all clients.
+    resource_rate_limiters: ResourceRateLimiters,
+    /// Whether the backends of any client may have changed since we last updated [`GatewayState::health_checks`].
+    backends_changed: bool,
+
+    /// Our userspace TCP endpoint for connections that are relayed through an upstream proxy.
+    egress_proxy: EgressProxy,
+    /// The resources whose TCP connections we relay through the upstream proxy.
+    egress_proxy_resources: HashSet<ResourceId>,
+
+    /// The traffic we forwarded for each client that is still connected.
+    client_traffic: HashMap<ClientId, TrafficStats>,
+    /// The traffic we forwarded for each resource, never reset.
+    resource_traffic: HashMap<ResourceId, TrafficStats>,
+    handshake_failures: u64,
 
     buffered_events: VecDeque<GatewayEvent>,
+    buffered_transmits: VecDeque<snownet::Transmit<'static>>,
 }
 
 impl GatewayState {
@@ -134,8 +290,22 @@ impl GatewayState {
         Self {
             peers: Default::default(),
             node: ServerNode::new(private_key.into()),
-            next_expiry_resources_check: Default::default(),
+            wakeups: Default::default(),
+            next_wakeup: Default::default(),
+            dirty_peers: Default::default(),
+            flow_log: false,
+            nat_config: NatConfig::default(),
+            connection_config: ConnectionConfig::default(),
+            health_checks: Default::default(),
+            resource_rate_limiters: Default::default(),
+            backends_changed: false,
+            egress_proxy: Default::default(),
+            egress_proxy_resources: Default::default(),
+            client_traffic: Default::default(),
+            resource_traffic: Default::default(),
+            handshake_failures: 0,
             buffered_events: VecDeque::default(),
+            buffered_transmits: VecDeque::default(),
         }
     }
 
@@ -157,15 +327,25 @@ impl GatewayState {
             return None;
         };
         let cid = peer.id();
+        let resource = peer.resource_of(packet.source());
+
+        let packet = peer.encapsulate(packet, &mut self.resource_rate_limiters, now);
+        self.dirty_peers.insert(cid);
 
-        let packet = peer
-            .encapsulate(packet, now)
+        let packet = packet
             .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
             .ok()??;
 
+        self.record_traffic(
+            cid,
+            resource,
+            packet.packet().len(),
+            TrafficStats::on_from_resource,
+        );
+
         let transmit = self
             .node
-            .encapsulate(peer.id(), packet.as_immutable(), now)
+            .encapsulate(cid, packet.as_immutable(), now)
             .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
             .ok()??;
 
@@ -196,14 +376,202 @@ impl GatewayState {
             return None;
         };
 
-        let packet = peer
-            .decapsulate(packet, now)
+        if let Some(query) = dns::parse_forwarded_query(packet.as_immutable()) {
+            let query = query.into_owned();
+
+            match peer.ensure_allowed_dns_query(&query) {
+                Ok(()) => {
+                    self.buffered_events
+                        .push_back(GatewayEvent::ResolveDnsQuery {
+                            conn_id: cid,
+                            query,
+                        });
+                }
+                Err(e) => {
+                    tracing::debug!(%cid, "Refusing DNS query: {e}");
+
+                    let response = ip_packet::make::dns_err_response(
+                        query.query,
+                        hickory_proto::op::ResponseCode::Refused,
+                    );
+                    self.send_to_client(cid, response.into_immutable(), now);
+                }
+            }
+
+            return None;
+        }
+
+        let packet = peer.decapsulate(packet, &mut self.resource_rate_limiters, now);
+        self.dirty_peers.insert(cid);
+
+        let packet = packet
             .inspect_err(|e| tracing::debug!(%cid, "Invalid packet: {e}"))
             .ok()?;
 
+        let resource = peer.resource_of(packet.destination());
+
+        self.record_traffic(
+            cid,
+            resource,
+            packet.packet().len(),
+            TrafficStats::on_to_resource,
+        );
+
+        let relay_via_proxy = resource.is_some_and(|r| self.egress_proxy_resources.contains(&r));
+
+        if packet.as_immutable_tcp().is_some()
+            && (relay_via_proxy || self.egress_proxy.is_proxied(&packet.as_immutable()))
+        {
+            self.egress_proxy
+                .handle_inbound(packet.into_immutable(), now);
+            self.flush_egress_proxy(now);
+
+            return None;
+        }
+
         Some(packet.into_immutable())
     }
 
+    /// Sends the response for a DNS query that a client forwarded to us.
+    pub fn on_dns_result(
+        &mut self,
+        conn_id: ClientId,
+        query: DnsQuery<'static>,
+        response: std::result::Result<
+            hickory_resolver::lookup::Lookup,
+            hickory_resolver::error::ResolveError,
+        >,
+        now: Instant,
+    ) {
+        let response = match dns::build_response_from_resolve_result(query.query.clone(), response)
+        {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::debug!(%conn_id, name = %query.name, "DNS query failed: {e}");
+
+                ip_packet::make::dns_err_response(
+                    query.query,
+                    hickory_proto::op::ResponseCode::ServFail,
+                )
+                .into_immutable()
+            }
+        };
+
+        self.send_to_client(conn_id, response, now);
+    }
+
+    fn send_to_client(&mut self, conn_id: ClientId, packet: IpPacket<'_>, now: Instant) {
+        let Some(transmit) = self
+            .node
+            .encapsulate(conn_id, packet, now)
+            .inspect_err(|e| tracing::debug!(%conn_id, "Failed to encapsulate: {e}"))
+            .ok()
+            .flatten()
+        else {
+            return;
+        };
+
+        self.buffered_transmits.push_back(transmit.into_owned());
+    }
+
+    fn record_traffic(
+        &mut self,
+        cid: ClientId,
+        resource: Option<ResourceId>,
+        len: usize,
+        update: fn(&mut TrafficStats, usize),
+    ) {
+        update(self.client_traffic.entry(cid).or_default(), len);
+
+        if let Some(resource) = resource {
+            update(self.resource_traffic.entry(resource).or_default(), len);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> GatewayStats {
+        let (node_stats, connection_stats) = self.node.stats();
+        let mut connection_stats = connection_stats.collect::<HashMap<_, _>>();
+
+        let clients = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let id = peer.id();
+                let connection = connection_stats.remove(&id);
+                let stats = ClientStats {
+                    traffic: self.client_traffic.get(&id).copied().unwrap_or_default(),
+                    nat_sessions: peer.nat_sessions(),
+                    relayed: connection.is_some_and(|c| c.relayed),
+                    connection,
+                };
+
+                (id, stats)
+            })
+            .collect();
+        let active_resources = self
+            .peers
+            .iter()
+            .flat_map(|peer| peer.resource_ids())
+            .collect::<HashSet<_>>()
+            .len();
+
+        GatewayStats {
+            clients,
+            resources: self.resource_traffic.clone(),
+            active_resources,
+            handshake_failures: self.handshake_failures,
+            relay_control_bytes: node_stats.stun_bytes_to_relays.0 as u64,
+        }
+    }
+
+    /// Sends the segments of our userspace TCP endpoint to the clients, as if the resources sent them.
+    fn flush_egress_proxy(&mut self, now: Instant) {
+        while let Some(packet) = self.egress_proxy.poll_packet() {
+            let Some(transmit) = self.encapsulate(packet, now) else {
+                continue;
+            };
+            let transmit = transmit.into_owned();
+
+            self.buffered_transmits.push_back(transmit);
+        }
+    }
+
+    pub fn set_egress_proxy_resources(&mut self, resources: HashSet<ResourceId>) {
+        self.egress_proxy_resources = resources;
+    }
+
+    pub fn on_egress_proxy_connected(&mut self, stream: EgressStreamId, now: Instant) {
+        self.egress_proxy.on_connected(stream, now);
+        self.flush_egress_proxy(now);
+    }
+
+    pub fn send_egress_proxy_data(
+        &mut self,
+        stream: EgressStreamId,
+        data: &[u8],
+        now: Instant,
+    ) -> usize {
+        let len = self.egress_proxy.send(stream, data, now);
+        self.flush_egress_proxy(now);
+
+        len
+    }
+
+    pub fn on_egress_proxy_written(&mut self, stream: EgressStreamId, len: usize, now: Instant) {
+        self.egress_proxy.on_written(stream, len);
+        self.flush_egress_proxy(now);
+    }
+
+    pub fn on_egress_proxy_eof(&mut self, stream: EgressStreamId, now: Instant) {
+        self.egress_proxy.on_eof(stream, now);
+        self.flush_egress_proxy(now);
+    }
+
+    pub fn reset_egress_proxy_stream(&mut self, stream: EgressStreamId, now: Instant) {
+        self.egress_proxy.reset(stream);
+        self.flush_egress_proxy(now);
+    }
+
     pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String, now: Instant) {
         self.node.add_remote_candidate(conn_id, ice_candidate, now);
     }
@@ -225,6 +593,7 @@ impl GatewayState {
         expires_at: Option<DateTime<Utc>>,
         resource: ResourceDescription<ResolvedResourceDescriptionDns>,
         now: Instant,
+        utc_now: DateTime<Utc>,
     ) -> Result<Answer> {
         match (&domain, &resource) {
             (Some((domain, _)), ResourceDescription::Dns(r)) => {
@@ -239,6 +608,8 @@ impl GatewayState {
         let answer = self.node.accept_connection(client_id, offer, client, now);
 
         let mut peer = ClientOnGateway::new(client_id, ipv4, ipv6);
+        peer.set_flow_log(self.flow_log);
+        peer.set_nat_config(self.nat_config.clone());
 
         peer.add_resource(
             resource.addresses(),
@@ -247,10 +618,20 @@ impl GatewayState {
             expires_at,
             domain.clone().map(|(n, _)| n),
         );
+        peer.set_resource_port_mappings(resource.id(), resource.port_mappings());
+        peer.set_resource_load_balancing(resource.id(), resource.load_balancing());
+        peer.set_unhealthy_backends(self.health_checks.unhealthy());
+        self.resource_rate_limiters
+            .set(resource.id(), resource.rate_limit(), now);
 
         peer.assign_proxies(&resource, domain, now)?;
 
-        self.peers.insert(peer, &[ipv4.into(), ipv6.into()]);
+        if let Some(mut previous) = self.peers.insert(peer, &[ipv4.into(), ipv6.into()]) {
+            self.buffered_events.extend(previous.end_flows());
+        }
+        self.schedule_expiry(client_id, expires_at, now, utc_now);
+        self.backends_changed = true;
+        self.update_health_checks(now);
 
         Ok(Answer {
             username: answer.credentials.username,
@@ -264,13 +645,17 @@ impl GatewayState {
         resource_id: ResourceId,
         name: DomainName,
         resolved_ips: Vec<IpAddr>,
+        ttl: Option<Duration>,
         now: Instant,
     ) {
         let Some(peer) = self.peers.get_mut(&client) else {
             return;
         };
 
-        peer.refresh_translation(name, resource_id, resolved_ips, now);
+        peer.refresh_translation(name, resource_id, resolved_ips, ttl, now);
+        self.dirty_peers.insert(client);
+        self.backends_changed = true;
+        self.update_health_checks(now);
     }
 
     pub fn allow_access(
@@ -280,6 +665,7 @@ impl GatewayState {
         expires_at: Option<DateTime<Utc>>,
         domain: Option<(DomainName, Vec<IpAddr>)>,
         now: Instant,
+        utc_now: DateTime<Utc>,
     ) -> Result<()> {
         match (&domain, &resource) {
             (Some((domain, _)), ResourceDescription::Dns(r)) => {
@@ -304,40 +690,239 @@ impl GatewayState {
             expires_at,
             domain.map(|(n, _)| n),
         );
+        peer.set_resource_port_mappings(resource.id(), resource.port_mappings());
+        peer.set_resource_load_balancing(resource.id(), resource.load_balancing());
+        self.resource_rate_limiters
+            .set(resource.id(), resource.rate_limit(), now);
+        self.schedule_expiry(client, expires_at, now, utc_now);
+        self.backends_changed = true;
+        self.update_health_checks(now);
 
         tracing::info!(%client, resource = %resource.id(), expires = ?expires_at.map(|e| e.to_rfc3339()), "Allowing access to resource");
         Ok(())
     }
 
+    pub fn set_client_rate_limit(
+        &mut self,
+        client: ClientId,
+        limit: Option<RateLimit>,
+        now: Instant,
+    ) {
+        let Some(peer) = self.peers.get_mut(&client) else {
+            return;
+        };
+
+        peer.set_rate_limit(limit, now);
+    }
+
+    pub fn set_flow_log(&mut self, enabled: bool) {
+        self.flow_log = enabled;
+
+        for peer in self.peers.iter_mut() {
+            peer.set_flow_log(enabled);
+        }
+    }
+
+    pub fn set_nat_config(&mut self, config: NatConfig) {
+        for peer in self.peers.iter_mut() {
+            peer.set_nat_config(config.clone());
+            self.dirty_peers.insert(peer.id());
+        }
+
+        self.nat_config = config;
+    }
+
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.node.set_connection_config(config);
+        self.connection_config = config;
+    }
+
+    pub fn set_client_keepalive(&mut self, client: ClientId, keepalive: Option<Duration>) {
+        self.node.override_connection_config(
+            client,
+            ConnectionConfig {
+                keepalive,
+                ..self.connection_config
+            },
+        );
+    }
+
+    /// Removes a client, logging all of its flows that are still open.
+    fn remove_peer(&mut self, id: &ClientId) {
+        let Some(mut peer) = self.peers.remove(id) else {
+            return;
+        };
+
+        self.next_wakeup.remove(id);
+        self.dirty_peers.remove(id);
+        self.client_traffic.remove(id);
+        self.backends_changed = true;
+        self.buffered_events.extend(peer.end_flows());
+        self.egress_proxy.remove_client(&peer.allowed_ips());
+    }
+
+    /// Probes the backends of all clients' load-balanced resources, if they may have changed.
+    fn update_health_checks(&mut self, now: Instant) {
+        if !std::mem::take(&mut self.backends_changed) {
+            return;
+        }
+
+        let backends = self
+            .peers
+            .iter()
+            .flat_map(|peer| peer.health_checked_backends())
+            .map(|(ip, check)| ((ip, check.probe), check))
+            .collect_vec();
+
+        if self.health_checks.set_backends(backends, now) {
+            self.propagate_unhealthy_backends();
+        }
+    }
+
+    pub fn on_probe_result(&mut self, ip: IpAddr, probe: HealthProbe, healthy: bool) {
+        let Some(healthy) = self.health_checks.on_probe_result((ip, probe), healthy) else {
+            return;
+        };
+
+        if healthy {
+            tracing::info!(%ip, ?probe, "Backend is healthy again");
+        } else {
+            tracing::warn!(%ip, ?probe, "Backend is unhealthy, no longer opening sessions to it");
+        }
+
+        self.propagate_unhealthy_backends();
+    }
+
+    fn propagate_unhealthy_backends(&mut self) {
+        let unhealthy = self.health_checks.unhealthy();
+
+        for peer in self.peers.iter_mut() {
+            peer.set_unhealthy_backends(unhealthy.clone());
+        }
+    }
+
+    /// Wakes us up when the access of a client to a resource expires, in addition to the client's other timeouts.
+    fn schedule_expiry(
+        &mut self,
+        client: ClientId,
+        expires_at: Option<DateTime<Utc>>,
+        now: Instant,
+        utc_now: DateTime<Utc>,
+    ) {
+        if let Some(expires_at) = expires_at {
+            self.schedule_wakeup(client, to_instant(expires_at, now, utc_now));
+        }
+
+        self.dirty_peers.insert(client);
+    }
+
+    /// Wakes us up for the client at `at`, unless we already do so earlier.
+    fn schedule_wakeup(&mut self, client: ClientId, at: Instant) {
+        if self
+            .next_wakeup
+            .get(&client)
+            .is_some_and(|next| *next <= at)
+        {
+            return;
+        }
+
+        self.next_wakeup.insert(client, at);
+        self.wakeups.push(Reverse((at, client)));
+    }
+
+    /// Expires the resources of a client and handles its other timeouts.
+    fn handle_peer_timeout(&mut self, id: ClientId, now: Instant, utc_now: DateTime<Utc>) {
+        let Some(peer) = self.peers.get_mut(&id) else {
+            return;
+        };
+
+        for resource_id in peer.expire_resources(utc_now) {
+            tracing::info!(client = %id, resource = %resource_id, "Access to resource expired");
+
+            self.backends_changed = true;
+
+            self.buffered_events.push_back(GatewayEvent::AccessExpired {
+                conn_id: id,
+                resource_id,
+            });
+        }
+        peer.handle_timeout(now);
+
+        if peer.is_emptied() {
+            self.remove_peer(&id);
+            return;
+        }
+
+        let next_resource_expiry = peer
+            .next_resource_expiry()
+            .map(|expires_at| to_instant(expires_at, now, utc_now));
+
+        if let Some(at) = earliest(peer.poll_timeout(), next_resource_expiry) {
+            self.schedule_wakeup(id, at);
+        }
+    }
+
     pub fn poll_timeout(&mut self) -> Option<Instant> {
-        // TODO: This should check when the next resource actually expires instead of doing it at a fixed interval.
-        earliest(self.next_expiry_resources_check, self.node.poll_timeout())
+        for id in std::mem::take(&mut self.dirty_peers) {
+            let Some(at) = self.peers.get(&id).and_then(|p| p.poll_timeout()) else {
+                continue;
+            };
+
+            self.schedule_wakeup(id, at);
+        }
+
+        let next_wakeup = self.wakeups.peek().map(|Reverse((at, _))| *at);
+
+        [
+            next_wakeup,
+            self.health_checks.poll_timeout(),
+            self.egress_proxy.poll_timeout(),
+            self.node.poll_timeout(),
+        ]
+        .into_iter()
+        .fold(None, earliest)
     }
 
     pub fn handle_timeout(&mut self, now: Instant, utc_now: DateTime<Utc>) {
         self.node.handle_timeout(now);
 
-        match self.next_expiry_resources_check {
-            Some(next_expiry_resources_check) if now >= next_expiry_resources_check => {
-                self.peers.iter_mut().for_each(|p| {
-                    p.expire_resources(utc_now);
-                    p.handle_timeout(now)
-                });
-                self.peers.retain(|_, p| !p.is_emptied());
+        let mut due = Vec::new();
+        while let Some(Reverse((at, id))) = self.wakeups.peek().copied() {
+            if at > now {
+                break;
+            }
+
+            self.wakeups.pop();
 
-                self.next_expiry_resources_check = Some(now + EXPIRE_RESOURCES_INTERVAL);
+            if self.next_wakeup.get(&id) != Some(&at) {
+                continue; // Stale, the client was rescheduled or removed.
             }
-            None => self.next_expiry_resources_check = Some(now + EXPIRE_RESOURCES_INTERVAL),
-            Some(_) => {}
+
+            self.next_wakeup.remove(&id);
+            due.push(id);
         }
 
+        for id in due {
+            self.handle_peer_timeout(id, now, utc_now);
+        }
+
+        self.update_health_checks(now);
+        self.health_checks.handle_timeout(now);
+
+        self.egress_proxy.handle_timeout(now);
+        self.flush_egress_proxy(now);
+
         let mut added_ice_candidates = HashMap::<ClientId, HashSet<String>>::default();
         let mut removed_ice_candidates = HashMap::<ClientId, HashSet<String>>::default();
 
         while let Some(event) = self.node.poll_event() {
             match event {
-                snownet::Event::ConnectionFailed(id) | snownet::Event::ConnectionClosed(id) => {
-                    self.peers.remove(&id);
+                snownet::Event::ConnectionFailed(id) => {
+                    self.handshake_failures += 1;
+                    self.remove_peer(&id);
+                }
+                snownet::Event::ConnectionClosed(id) => {
+                    self.remove_peer(&id);
                 }
                 snownet::Event::NewIceCandidate {
                     connection,
@@ -358,6 +943,12 @@ impl GatewayState {
                         .insert(candidate);
                 }
                 snownet::Event::ConnectionEstablished(_) => {}
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    candidate_pair,
+                } => {
+                    tracing::info!(client = %connection, ?candidate_pair, "Path to client changed");
+                }
             }
         }
 
@@ -379,7 +970,9 @@ impl GatewayState {
     }
 
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'static>> {
-        self.node.poll_transmit()
+        self.buffered_transmits
+            .pop_front()
+            .or_else(|| self.node.poll_transmit())
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<GatewayEvent> {
@@ -387,6 +980,31 @@ impl GatewayState {
             return Some(ev);
         }
 
+        if let Some(((ip, probe), check)) = self.health_checks.poll_probe() {
+            return Some(GatewayEvent::ProbeBackend {
+                ip,
+                probe,
+                timeout: check.timeout(),
+            });
+        }
+
+        if let Some(ev) = self.egress_proxy.poll_event() {
+            return Some(match ev {
+                egress_proxy::Event::Connect { stream, dst } => {
+                    GatewayEvent::EgressProxyConnect { stream, dst }
+                }
+                egress_proxy::Event::Data { stream, data } => {
+                    GatewayEvent::EgressProxyData { stream, data }
+                }
+                egress_proxy::Event::Shutdown { stream } => {
+                    GatewayEvent::EgressProxyShutdown { stream }
+                }
+                egress_proxy::Event::Closed { stream } => {
+                    GatewayEvent::EgressProxyClosed { stream }
+                }
+            });
+        }
+
         for peer in self.peers.iter_mut() {
             if let Some(ev) = peer.poll_event() {
                 return Some(ev);
@@ -405,3 +1023,8 @@ impl GatewayState {
         self.node.update_relays(to_remove, &to_add, now);
     }
 }
+
+/// Converts a point in (wall-clock) time to an [`Instant`], points in the past map to `now`.
+fn to_instant(at: DateTime<Utc>, now: Instant, utc_now: DateTime<Utc>) -> Instant {
+    now + (at - utc_now).to_std().unwrap_or(Duration::ZERO)
+}
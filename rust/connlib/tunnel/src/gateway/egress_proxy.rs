@@ -0,0 +1,702 @@
+//! A userspace TCP endpoint for relaying clients' TCP connections to resources through an upstream proxy.
+//!
+//! Instead of writing the client's segments to the TUN device, we terminate the connection ourselves
+//! and pass the bytes to the user of [`GatewayTunnel`](crate::GatewayTunnel), which opens the actual connection through the proxy.
+//! The segments we send back are treated like packets the resource sent, i.e. they go through the same filters and NAT.
+//!
+//! See [`crate::tcp`] for which parts of TCP we skip, we do however respect the client's receive window.
+
+use crate::tcp::{self, ConnectionId, Tcb, MAX_SEGMENT_SIZE, RETRANSMIT_TIMEOUT};
+use ip_packet::tcp::TcpFlags;
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How many bytes the client may send before the upstream has written them.
+///
+/// We don't scale windows, thus this is also the largest window we can advertise.
+const MAX_RECEIVE_WINDOW: usize = u16::MAX as usize;
+/// How many bytes of the upstream we buffer until the client acknowledges them.
+const MAX_SEND_BUFFER: usize = 256 * 1024;
+/// After how many retransmissions without progress we give up on a connection.
+const MAX_RETRANSMITS: u32 = 10;
+/// How long we wait for the upstream connection before refusing the client's connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a connection may go without any data in either direction before we reset it.
+///
+/// This is the minimum RFC 5382 requires NATs to keep established TCP connections for.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60 + 4 * 60);
+const MAX_CONNECTIONS: usize = 4096;
+
+/// Identifies a connection relayed through the upstream proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EgressStreamId(u64);
+
+#[derive(Default)]
+pub(crate) struct EgressProxy {
+    connections: HashMap<ConnectionId, Connection>,
+    connection_by_stream: HashMap<EgressStreamId, ConnectionId>,
+    next_stream: u64,
+
+    buffered_events: VecDeque<Event>,
+    buffered_packets: VecDeque<MutableIpPacket<'static>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// A client opened a connection to `dst`, it is only accepted after [`EgressProxy::on_connected`].
+    Connect {
+        stream: EgressStreamId,
+        dst: SocketAddr,
+    },
+    /// The client sent data, it needs to be acknowledged via [`EgressProxy::on_written`] once it is written upstream.
+    Data {
+        stream: EgressStreamId,
+        data: Vec<u8>,
+    },
+    /// The client won't send any more data.
+    Shutdown { stream: EgressStreamId },
+    /// The connection is gone, e.g. because the client reset it or stopped responding.
+    Closed { stream: EgressStreamId },
+}
+
+struct Connection {
+    stream: EgressStreamId,
+    /// We only reply with a SYN-ACK once the upstream connection is established.
+    upstream_connected: bool,
+
+    tcb: Tcb,
+    /// The receive window the client last advertised.
+    snd_wnd: usize,
+    /// How many bytes of the client we passed on that the upstream hasn't written yet.
+    unwritten: usize,
+
+    created_at: Instant,
+    last_activity: Instant,
+}
+
+impl EgressProxy {
+    /// Handles a TCP segment a client sent to a resource.
+    pub(crate) fn handle_inbound(&mut self, packet: IpPacket<'_>, now: Instant) {
+        let Some(tcp) = packet.as_tcp() else {
+            return;
+        };
+
+        let id = ConnectionId::inbound(&packet, &tcp);
+        let flags = tcp.get_flags();
+        let seq = tcp.get_sequence();
+
+        if flags & TcpFlags::RST != 0 {
+            if let Some(connection) = self.remove(&id) {
+                tracing::debug!(remote = %id.remote, local = %id.local, "Proxied connection reset by client");
+
+                self.buffered_events.push_back(Event::Closed {
+                    stream: connection.stream,
+                });
+            }
+
+            return;
+        }
+
+        let Some(connection) = self.connections.get_mut(&id) else {
+            if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+                self.accept(id, seq, now);
+                return;
+            }
+
+            self.buffered_packets
+                .push_back(tcp::reset_unknown(id, &tcp));
+
+            return;
+        };
+
+        if flags & TcpFlags::SYN != 0 {
+            // The client didn't receive our SYN-ACK yet, send it again.
+            if connection.upstream_connected && connection.tcb.is_syn_retransmit(seq) {
+                self.buffered_packets
+                    .push_back(connection.tcb.syn_ack(id, connection.window_field()));
+            }
+
+            return;
+        }
+
+        if !connection.upstream_connected {
+            return;
+        }
+
+        connection.last_activity = now;
+
+        if flags & TcpFlags::ACK != 0 && connection.tcb.handle_ack(tcp.get_acknowledgement(), now) {
+            connection.snd_wnd = tcp.get_window() as usize;
+        }
+
+        let mut needs_ack = false;
+
+        let payload = tcp.payload();
+        if !payload.is_empty() {
+            needs_ack = true;
+
+            connection.receive(seq, payload, &mut self.buffered_events);
+        }
+
+        if flags & TcpFlags::FIN != 0 {
+            needs_ack = true;
+
+            if connection.tcb.receive_fin(seq, payload.len()) {
+                self.buffered_events.push_back(Event::Shutdown {
+                    stream: connection.stream,
+                });
+            }
+        }
+
+        if needs_ack {
+            self.buffered_packets
+                .push_back(connection.tcb.ack(id, connection.window_field()));
+        }
+
+        connection.transmit(id, now, &mut self.buffered_packets);
+
+        if connection.tcb.is_closed() {
+            tracing::debug!(remote = %id.remote, local = %id.local, "Proxied connection closed");
+
+            if let Some(connection) = self.remove(&id) {
+                self.buffered_events.push_back(Event::Closed {
+                    stream: connection.stream,
+                });
+            }
+        }
+    }
+
+    /// Whether a packet belongs to a connection we terminate.
+    pub(crate) fn is_proxied(&self, packet: &IpPacket<'_>) -> bool {
+        let Some(tcp) = packet.as_tcp() else {
+            return false;
+        };
+
+        self.connections
+            .contains_key(&ConnectionId::inbound(packet, &tcp))
+    }
+
+    /// The upstream connection has been established, we can now accept the client's connection.
+    pub(crate) fn on_connected(&mut self, stream: EgressStreamId, now: Instant) {
+        let Some(id) = self.connection_by_stream.get(&stream).copied() else {
+            return;
+        };
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+
+        if connection.upstream_connected {
+            return;
+        }
+
+        connection.upstream_connected = true;
+        connection.last_activity = now;
+        connection.tcb.retransmit_at = Some(now + RETRANSMIT_TIMEOUT);
+
+        self.buffered_packets
+            .push_back(connection.tcb.syn_ack(id, connection.window_field()));
+    }
+
+    /// Sends data of the upstream to the client.
+    ///
+    /// Returns how many bytes we accepted, the rest needs to be sent again later.
+    pub(crate) fn send(&mut self, stream: EgressStreamId, data: &[u8], now: Instant) -> usize {
+        let Some(id) = self.connection_by_stream.get(&stream).copied() else {
+            return 0;
+        };
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return 0;
+        };
+
+        if !connection.tcb.is_established() || connection.tcb.is_closing() {
+            return 0;
+        }
+
+        let len = data
+            .len()
+            .min(MAX_SEND_BUFFER.saturating_sub(connection.tcb.send_buffer.len()));
+        connection.tcb.send_buffer.extend(&data[..len]);
+        connection.last_activity = now;
+        connection.transmit(id, now, &mut self.buffered_packets);
+
+        len
+    }
+
+    /// The upstream has written the given number of bytes we passed on.
+    pub(crate) fn on_written(&mut self, stream: EgressStreamId, len: usize) {
+        let Some(id) = self.connection_by_stream.get(&stream).copied() else {
+            return;
+        };
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+
+        let window_before = connection.window();
+        connection.unwritten = connection.unwritten.saturating_sub(len);
+
+        // Let the client know that it can send again.
+        if window_before < MAX_SEGMENT_SIZE && connection.window() >= MAX_SEGMENT_SIZE {
+            self.buffered_packets
+                .push_back(connection.tcb.ack(id, connection.window_field()));
+        }
+    }
+
+    /// The upstream won't send any more data.
+    pub(crate) fn on_eof(&mut self, stream: EgressStreamId, now: Instant) {
+        let Some(id) = self.connection_by_stream.get(&stream).copied() else {
+            return;
+        };
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+
+        // We send a FIN once all of the upstream's data is sent.
+        connection.tcb.close();
+        connection.transmit(id, now, &mut self.buffered_packets);
+    }
+
+    /// Aborts the connection, e.g. because the upstream connection failed.
+    pub(crate) fn reset(&mut self, stream: EgressStreamId) {
+        let Some(id) = self.connection_by_stream.get(&stream).copied() else {
+            return;
+        };
+        let Some(connection) = self.remove(&id) else {
+            return;
+        };
+
+        self.buffered_packets.push_back(connection.rst(id));
+    }
+
+    /// Forgets all connections of a client, e.g. because it disconnected.
+    pub(crate) fn remove_client(&mut self, ips: &[IpAddr]) {
+        let ids = self
+            .connections
+            .keys()
+            .filter(|id| ips.contains(&id.remote.ip()))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            if let Some(connection) = self.remove(&id) {
+                self.buffered_events.push_back(Event::Closed {
+                    stream: connection.stream,
+                });
+            }
+        }
+    }
+
+    pub(crate) fn poll_event(&mut self) -> Option<Event> {
+        self.buffered_events.pop_front()
+    }
+
+    pub(crate) fn poll_packet(&mut self) -> Option<MutableIpPacket<'static>> {
+        self.buffered_packets.pop_front()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .flat_map(|c| {
+                if !c.upstream_connected {
+                    return [Some(c.created_at + CONNECT_TIMEOUT), None];
+                }
+
+                [c.tcb.retransmit_at, Some(c.last_activity + IDLE_TIMEOUT)]
+            })
+            .flatten()
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let mut failed = HashSet::new();
+
+        for (id, connection) in self.connections.iter_mut() {
+            if !connection.upstream_connected {
+                if now >= connection.created_at + CONNECT_TIMEOUT {
+                    tracing::debug!(remote = %id.remote, local = %id.local, "Timed out connecting to upstream proxy");
+                    failed.insert(*id);
+                }
+
+                continue;
+            }
+
+            if now >= connection.last_activity + IDLE_TIMEOUT {
+                tracing::debug!(remote = %id.remote, local = %id.local, "Closing idle proxied connection");
+                failed.insert(*id);
+
+                continue;
+            }
+
+            if !connection.tcb.retransmit_at.is_some_and(|t| t <= now) {
+                continue;
+            }
+
+            if connection.tcb.retransmits >= MAX_RETRANSMITS {
+                tracing::debug!(remote = %id.remote, local = %id.local, "Client stopped acknowledging proxied connection");
+                failed.insert(*id);
+
+                continue;
+            }
+
+            let window = connection.window_field();
+
+            connection.tcb.retransmit(
+                *id,
+                connection.snd_wnd,
+                window,
+                now,
+                &mut self.buffered_packets,
+            );
+        }
+
+        for id in failed {
+            let Some(connection) = self.remove(&id) else {
+                continue;
+            };
+
+            self.buffered_packets.push_back(connection.rst(id));
+            self.buffered_events.push_back(Event::Closed {
+                stream: connection.stream,
+            });
+        }
+    }
+
+    fn accept(&mut self, id: ConnectionId, seq: u32, now: Instant) {
+        if self.connections.len() >= MAX_CONNECTIONS {
+            tracing::debug!(remote = %id.remote, "Too many proxied connections");
+
+            self.buffered_packets.push_back(tcp::refuse_syn(id, seq));
+            return;
+        }
+
+        let stream = EgressStreamId(self.next_stream);
+        self.next_stream += 1;
+
+        let connection = Connection {
+            stream,
+            upstream_connected: false,
+            tcb: Tcb::new(seq),
+            snd_wnd: 0,
+            unwritten: 0,
+            created_at: now,
+            last_activity: now,
+        };
+
+        tracing::debug!(remote = %id.remote, local = %id.local, ?stream, "New proxied connection");
+
+        self.connections.insert(id, connection);
+        self.connection_by_stream.insert(stream, id);
+        self.buffered_events.push_back(Event::Connect {
+            stream,
+            dst: id.local,
+        });
+    }
+
+    fn remove(&mut self, id: &ConnectionId) -> Option<Connection> {
+        let connection = self.connections.remove(id)?;
+        self.connection_by_stream.remove(&connection.stream);
+
+        Some(connection)
+    }
+}
+
+impl Connection {
+    fn receive(&mut self, seq: u32, payload: &[u8], events: &mut VecDeque<Event>) {
+        let window = self.window();
+        let new_data = self.tcb.receive(seq, payload, window);
+
+        if new_data.is_empty() {
+            return;
+        }
+
+        self.unwritten += new_data.len();
+
+        events.push_back(Event::Data {
+            stream: self.stream,
+            data: new_data.to_vec(),
+        });
+    }
+
+    /// Sends all data that fits into the client's window.
+    fn transmit(
+        &mut self,
+        id: ConnectionId,
+        now: Instant,
+        packets: &mut VecDeque<MutableIpPacket<'static>>,
+    ) {
+        let window = self.window_field();
+        self.tcb.transmit(id, self.snd_wnd, window, now, packets);
+    }
+
+    fn rst(&self, id: ConnectionId) -> MutableIpPacket<'static> {
+        if !self.upstream_connected {
+            return self.tcb.refuse(id);
+        }
+
+        self.tcb.rst(id)
+    }
+
+    fn window(&self) -> usize {
+        MAX_RECEIVE_WINDOW.saturating_sub(self.unwritten)
+    }
+
+    fn window_field(&self) -> u16 {
+        self.window().min(u16::MAX as usize) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_connection_only_after_upstream_connected() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        proxy.handle_inbound(client_segment(id, 1000, 0, TcpFlags::SYN, &[]), now);
+
+        let Some(Event::Connect { stream, dst }) = proxy.poll_event() else {
+            panic!("Expected connect event");
+        };
+        assert_eq!(dst, id.local);
+        assert!(proxy.poll_packet().is_none());
+
+        proxy.on_connected(stream, now);
+
+        let syn_ack = proxy.poll_packet().unwrap();
+        let tcp = syn_ack.as_immutable_tcp().unwrap();
+        assert_eq!(tcp.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(tcp.get_acknowledgement(), 1001);
+    }
+
+    #[test]
+    fn relays_data_in_both_directions() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let (stream, iss) = establish(&mut proxy, id, 1000, u16::MAX, now);
+
+        proxy.handle_inbound(
+            client_segment(
+                id,
+                1001,
+                iss.wrapping_add(1),
+                TcpFlags::PSH | TcpFlags::ACK,
+                b"request",
+            ),
+            now,
+        );
+
+        assert_eq!(
+            proxy.poll_event(),
+            Some(Event::Data {
+                stream,
+                data: b"request".to_vec()
+            })
+        );
+        let ack = proxy.poll_packet().unwrap();
+        assert_eq!(ack.as_immutable_tcp().unwrap().get_acknowledgement(), 1008);
+
+        assert_eq!(proxy.send(stream, b"response", now), 8);
+
+        let response = proxy.poll_packet().unwrap();
+        let tcp = response.as_immutable_tcp().unwrap();
+        assert_eq!(tcp.get_sequence(), iss.wrapping_add(1));
+        assert_eq!(tcp.payload(), b"response");
+    }
+
+    #[test]
+    fn only_sends_what_fits_into_client_window() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let (stream, iss) = establish(&mut proxy, id, 1000, 1500, now);
+
+        assert_eq!(proxy.send(stream, &[1u8; 3000], now), 3000);
+        assert_eq!(payload_lens(&mut proxy), vec![1200, 300]);
+
+        proxy.handle_inbound(
+            client_segment(id, 1001, iss.wrapping_add(1 + 1500), TcpFlags::ACK, &[]),
+            now,
+        );
+        assert_eq!(payload_lens(&mut proxy), vec![1200, 300]);
+    }
+
+    #[test]
+    fn shrinks_receive_window_until_upstream_wrote_data() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let (stream, iss) = establish(&mut proxy, id, 1000, u16::MAX, now);
+
+        proxy.handle_inbound(
+            client_segment(id, 1001, iss.wrapping_add(1), TcpFlags::ACK, &[1u8; 1000]),
+            now,
+        );
+        let ack = proxy.poll_packet().unwrap();
+        assert_eq!(
+            ack.as_immutable_tcp().unwrap().get_window(),
+            u16::MAX - 1000
+        );
+
+        proxy.on_written(stream, 1000);
+        let connection = proxy.connections.get(&id).unwrap();
+        assert_eq!(connection.window_field(), u16::MAX);
+    }
+
+    #[test]
+    fn closes_connection_after_both_sides_finished() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let (stream, iss) = establish(&mut proxy, id, 1000, u16::MAX, now);
+
+        proxy.handle_inbound(
+            client_segment(
+                id,
+                1001,
+                iss.wrapping_add(1),
+                TcpFlags::FIN | TcpFlags::ACK,
+                &[],
+            ),
+            now,
+        );
+        assert_eq!(proxy.poll_event(), Some(Event::Shutdown { stream }));
+        let _ack = proxy.poll_packet().unwrap();
+
+        proxy.on_eof(stream, now);
+        let fin = proxy.poll_packet().unwrap();
+        assert_ne!(
+            fin.as_immutable_tcp().unwrap().get_flags() & TcpFlags::FIN,
+            0
+        );
+
+        proxy.handle_inbound(
+            client_segment(id, 1002, iss.wrapping_add(2), TcpFlags::ACK, &[]),
+            now,
+        );
+        assert_eq!(proxy.poll_event(), Some(Event::Closed { stream }));
+        assert!(proxy.connections.is_empty());
+    }
+
+    #[test]
+    fn refuses_connection_if_upstream_fails() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        proxy.handle_inbound(client_segment(id, 1000, 0, TcpFlags::SYN, &[]), now);
+        let Some(Event::Connect { stream, .. }) = proxy.poll_event() else {
+            panic!("Expected connect event");
+        };
+
+        proxy.reset(stream);
+
+        let rst = proxy.poll_packet().unwrap();
+        let tcp = rst.as_immutable_tcp().unwrap();
+        assert_eq!(tcp.get_flags(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(tcp.get_acknowledgement(), 1001);
+        assert!(proxy.connections.is_empty());
+    }
+
+    #[test]
+    fn gives_up_when_client_stops_acknowledging() {
+        let mut proxy = EgressProxy::default();
+        let mut now = Instant::now();
+        let id = connection_id();
+
+        let (stream, _) = establish(&mut proxy, id, 1000, u16::MAX, now);
+        proxy.send(stream, b"response", now);
+
+        while let Some(timeout) = proxy.poll_timeout() {
+            now = timeout;
+            proxy.handle_timeout(now);
+        }
+
+        assert_eq!(proxy.poll_event(), Some(Event::Closed { stream }));
+    }
+
+    #[test]
+    fn resets_idle_connections() {
+        let mut proxy = EgressProxy::default();
+        let now = Instant::now();
+        let id = connection_id();
+
+        let (stream, _) = establish(&mut proxy, id, 1000, u16::MAX, now);
+        assert_eq!(proxy.poll_timeout(), Some(now + IDLE_TIMEOUT));
+
+        proxy.handle_timeout(now + IDLE_TIMEOUT);
+
+        let rst = proxy.poll_packet().unwrap();
+        assert_ne!(
+            rst.as_immutable_tcp().unwrap().get_flags() & TcpFlags::RST,
+            0
+        );
+        assert_eq!(proxy.poll_event(), Some(Event::Closed { stream }));
+        assert!(proxy.connections.is_empty());
+    }
+
+    /// Performs a TCP handshake and returns the stream and our initial sequence number.
+    fn establish(
+        proxy: &mut EgressProxy,
+        id: ConnectionId,
+        isn: u32,
+        window: u16,
+        now: Instant,
+    ) -> (EgressStreamId, u32) {
+        proxy.handle_inbound(client_segment(id, isn, 0, TcpFlags::SYN, &[]), now);
+        let Some(Event::Connect { stream, .. }) = proxy.poll_event() else {
+            panic!("Expected connect event");
+        };
+
+        proxy.on_connected(stream, now);
+        let syn_ack = proxy.poll_packet().unwrap();
+        let iss = syn_ack.as_immutable_tcp().unwrap().get_sequence();
+
+        let ack = ip_packet::make::tcp_segment(
+            id.remote,
+            id.local,
+            isn + 1,
+            iss.wrapping_add(1),
+            TcpFlags::ACK,
+            window,
+            &[],
+        );
+        proxy.handle_inbound(ack.into_immutable(), now);
+        assert!(proxy.poll_packet().is_none());
+
+        (stream, iss)
+    }
+
+    fn payload_lens(proxy: &mut EgressProxy) -> Vec<usize> {
+        std::iter::from_fn(|| proxy.poll_packet())
+            .map(|p| p.as_immutable_tcp().unwrap().payload().len())
+            .collect()
+    }
+
+    fn client_segment(
+        id: ConnectionId,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> IpPacket<'static> {
+        ip_packet::make::tcp_segment(id.remote, id.local, seq, ack, flags, u16::MAX, payload)
+            .into_immutable()
+    }
+
+    fn connection_id() -> ConnectionId {
+        ConnectionId {
+            local: "10.0.0.5:443".parse().unwrap(),
+            remote: "100.64.0.1:45678".parse().unwrap(),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Active health checks of the backends of load-balanced DNS resources.
+//!
+//! We only keep track of when to probe which backend and what the results mean.
+//! The probes themselves are performed by the user of [`GatewayTunnel`](crate::GatewayTunnel).
+use connlib_shared::messages::gateway::{HealthCheck, HealthProbe};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// A backend is an address of a resource that we probe in a certain way.
+pub(crate) type Backend = (IpAddr, HealthProbe);
+
+#[derive(Debug, Default)]
+pub(crate) struct HealthChecks {
+    backends: HashMap<Backend, BackendState>,
+    /// The probes that are due but haven't been polled yet.
+    pending_probes: VecDeque<(Backend, HealthCheck)>,
+}
+
+#[derive(Debug)]
+struct BackendState {
+    check: HealthCheck,
+    healthy: bool,
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+    next_probe_at: Instant,
+}
+
+impl HealthChecks {
+    /// Replaces the backends we probe.
+    ///
+    /// New backends are considered healthy and probed right away, the state of existing ones is kept.
+    /// Returns whether the set of unhealthy backends changed.
+    pub(crate) fn set_backends(
+        &mut self,
+        backends: impl IntoIterator<Item = (Backend, HealthCheck)>,
+        now: Instant,
+    ) -> bool {
+        let backends = backends.into_iter().collect::<HashMap<_, _>>();
+
+        let unhealthy_before = self.unhealthy();
+
+        self.backends
+            .retain(|backend, _| backends.contains_key(backend));
+        self.pending_probes
+            .retain(|(backend, _)| backends.contains_key(backend));
+
+        for (backend, check) in backends {
+            self.backends
+                .entry(backend)
+                .and_modify(|state| state.check = check)
+                .or_insert_with(|| BackendState {
+                    check,
+                    healthy: true,
+                    consecutive_successes: 0,
+                    consecutive_failures: 0,
+                    next_probe_at: now,
+                });
+        }
+
+        self.unhealthy() != unhealthy_before
+    }
+
+    pub(crate) fn unhealthy(&self) -> HashSet<Backend> {
+        self.backends
+            .iter()
+            .filter_map(|(backend, state)| (!state.healthy).then_some(*backend))
+            .collect()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.backends
+            .values()
+            .map(|state| state.next_probe_at)
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        for (backend, state) in &mut self.backends {
+            if now < state.next_probe_at {
+                continue;
+            }
+
+            state.next_probe_at = now + state.check.interval();
+            self.pending_probes.push_back((*backend, state.check));
+        }
+    }
+
+    pub(crate) fn poll_probe(&mut self) -> Option<(Backend, HealthCheck)> {
+        self.pending_probes.pop_front()
+    }
+
+    /// Records the result of a probe.
+    ///
+    /// Returns the new health of the backend if it changed.
+    pub(crate) fn on_probe_result(&mut self, backend: Backend, success: bool) -> Option<bool> {
+        let state = self.backends.get_mut(&backend)?;
+
+        if success {
+            state.consecutive_failures = 0;
+            state.consecutive_successes = state.consecutive_successes.saturating_add(1);
+
+            if !state.healthy && state.consecutive_successes >= state.check.healthy_threshold {
+                state.healthy = true;
+                return Some(true);
+            }
+        } else {
+            state.consecutive_successes = 0;
+            state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+
+            if state.healthy && state.consecutive_failures >= state.check.unhealthy_threshold {
+                state.healthy = false;
+                return Some(false);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn backend_is_probed_at_its_interval() {
+        let mut checks = HealthChecks::default();
+        let now = Instant::now();
+
+        checks.set_backends([(backend(), check())], now);
+
+        assert_eq!(checks.poll_timeout(), Some(now));
+        checks.handle_timeout(now);
+        assert_eq!(checks.poll_probe(), Some((backend(), check())));
+        assert_eq!(checks.poll_probe(), None);
+
+        assert_eq!(checks.poll_timeout(), Some(now + Duration::from_secs(10)));
+        checks.handle_timeout(now + Duration::from_secs(9));
+        assert_eq!(checks.poll_probe(), None);
+    }
+
+    #[test]
+    fn backend_changes_health_only_after_consecutive_results() {
+        let mut checks = HealthChecks::default();
+        checks.set_backends([(backend(), check())], Instant::now());
+
+        assert_eq!(checks.on_probe_result(backend(), false), None);
+        assert_eq!(checks.on_probe_result(backend(), false), None);
+        assert_eq!(checks.on_probe_result(backend(), true), None);
+        assert_eq!(checks.on_probe_result(backend(), false), None);
+        assert_eq!(checks.on_probe_result(backend(), false), None);
+        assert_eq!(checks.on_probe_result(backend(), false), Some(false));
+        assert_eq!(checks.unhealthy(), HashSet::from([backend()]));
+
+        assert_eq!(checks.on_probe_result(backend(), true), None);
+        assert_eq!(checks.on_probe_result(backend(), true), Some(true));
+        assert!(checks.unhealthy().is_empty());
+    }
+
+    #[test]
+    fn removing_an_unhealthy_backend_changes_the_unhealthy_set() {
+        let mut checks = HealthChecks::default();
+        let now = Instant::now();
+        checks.set_backends([(backend(), check())], now);
+        for _ in 0..3 {
+            checks.on_probe_result(backend(), false);
+        }
+
+        assert!(!checks.set_backends([(backend(), check())], now));
+        assert!(checks.set_backends(std::iter::empty(), now));
+        assert_eq!(checks.poll_timeout(), None);
+    }
+
+    fn backend() -> Backend {
+        ("10.0.0.5".parse().unwrap(), HealthProbe::Tcp { port: 443 })
+    }
+
+    fn check() -> HealthCheck {
+        HealthCheck {
+            probe: HealthProbe::Tcp { port: 443 },
+            interval_secs: 10,
+            timeout_secs: 2,
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Counters and a snapshot of the gateway's state, e.g. for exporting them as metrics.
+use connlib_shared::messages::{ClientId, ResourceId};
+use snownet::ConnectionStats;
+use std::collections::HashMap;
+
+/// A snapshot of the gateway's state, see [`GatewayTunnel::stats`](crate::GatewayTunnel::stats).
+#[derive(Debug, Clone, Default)]
+pub struct GatewayStats {
+    pub clients: HashMap<ClientId, ClientStats>,
+    /// The traffic to and from every resource that any client accessed since the gateway started.
+    ///
+    /// Unlike [`GatewayStats::clients`], this also includes resources that no client has access to anymore.
+    pub resources: HashMap<ResourceId, TrafficStats>,
+    /// How many resources at least one client currently has access to.
+    pub active_resources: usize,
+    /// How many connections to clients failed since the gateway started.
+    pub handshake_failures: u64,
+    /// How many bytes we sent to relays for maintaining our allocations.
+    pub relay_control_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientStats {
+    pub traffic: TrafficStats,
+    /// How many NAT sessions for DNS resources the client currently has.
+    pub nat_sessions: usize,
+    /// Whether we talk to the client via a relay.
+    pub relayed: bool,
+    /// Statistics of the connection to the client, `None` if we don't have one.
+    pub connection: Option<ConnectionStats>,
+}
+
+/// Counts the packets between clients and resources that we forwarded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub packets_to_resource: u64,
+    pub bytes_to_resource: u64,
+    pub packets_from_resource: u64,
+    pub bytes_from_resource: u64,
+}
+
+impl TrafficStats {
+    pub(crate) fn on_to_resource(&mut self, bytes: usize) {
+        self.packets_to_resource += 1;
+        self.bytes_to_resource += bytes as u64;
+    }
+
+    pub(crate) fn on_from_resource(&mut self, bytes: usize) {
+        self.packets_from_resource += 1;
+        self.bytes_from_resource += bytes as u64;
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{
     device_channel::Device,
-    dns::DnsQuery,
+    dns::{DnsQuery, Transport},
     sockets::{Received, Sockets},
+    MAX_UDP_SIZE,
 };
 use connlib_shared::messages::DnsServer;
 use futures::Future;
@@ -17,17 +18,25 @@ use hickory_resolver::{
 use ip_packet::{IpPacket, MutableIpPacket};
 use socket_factory::SocketFactory;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
-    net::{IpAddr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
     time::{Duration, Instant},
 };
-use tokio::net::{TcpSocket, UdpSocket};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpSocket, UdpSocket},
+};
 
 const DNS_QUERIES_QUEUE_SIZE: usize = 100;
+/// For how long we wait for an upstream DNS server to answer a query.
+///
+/// Applications typically give up on a query after 5 seconds (see `timeout` in resolv.conf(5)).
+/// Waiting much longer than that only occupies a slot in our queue whilst nobody is waiting for the answer anymore.
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Bundles together all side-effects that connlib needs to have access to.
 pub struct Io {
@@ -43,11 +52,18 @@ pub struct Io {
 
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
 
-    upstream_dns_servers: HashMap<IpAddr, AsyncResolver<GenericConnector<TokioRuntimeProvider>>>,
+    upstream_dns_servers:
+        HashMap<SocketAddr, AsyncResolver<GenericConnector<TokioRuntimeProvider>>>,
     forwarded_dns_queries: FuturesTupleSet<
         Result<hickory_resolver::lookup::Lookup, hickory_resolver::error::ResolveError>,
         DnsQuery<'static>,
     >,
+    /// Upstream servers that speak plain DNS over UDP and TCP on their address.
+    plain_dns_servers: HashSet<SocketAddr>,
+    /// Queries that we forward as-is to one of the [`Io::plain_dns_servers`].
+    ///
+    /// A [`Lookup`](hickory_resolver::lookup::Lookup) only contains the records for the queried type, DNSSEC-aware applications need the full response including its RRSIGs.
+    forwarded_raw_dns_queries: FuturesTupleSet<io::Result<Vec<u8>>, DnsQuery<'static>>,
 }
 
 pub enum Input<'a, I> {
@@ -61,6 +77,10 @@ pub enum Input<'a, I> {
             futures_bounded::Timeout,
         >,
     ),
+    RawDnsResponse(
+        DnsQuery<'static>,
+        Result<io::Result<Vec<u8>>, futures_bounded::Timeout>,
+    ),
 }
 
 impl Io {
@@ -71,7 +91,7 @@ impl Io {
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
     ) -> io::Result<Self> {
-        let mut sockets = Sockets::default();
+        let mut sockets = Sockets::new(tcp_socket_factory.clone());
         sockets.rebind(udp_socket_factory.as_ref())?; // Bind sockets on startup. Must happen within a tokio runtime context.
 
         Ok(Self {
@@ -81,8 +101,10 @@ impl Io {
             tcp_socket_factory,
             udp_socket_factory,
             upstream_dns_servers: HashMap::default(),
-            forwarded_dns_queries: FuturesTupleSet::new(
-                Duration::from_secs(60),
+            forwarded_dns_queries: FuturesTupleSet::new(DNS_QUERY_TIMEOUT, DNS_QUERIES_QUEUE_SIZE),
+            plain_dns_servers: HashSet::default(),
+            forwarded_raw_dns_queries: FuturesTupleSet::new(
+                DNS_QUERY_TIMEOUT,
                 DNS_QUERIES_QUEUE_SIZE,
             ),
         })
@@ -99,6 +121,10 @@ impl Io {
             return Poll::Ready(Ok(Input::DnsResponse(query, response)));
         }
 
+        if let Poll::Ready((response, query)) = self.forwarded_raw_dns_queries.poll_unpin(cx) {
+            return Poll::Ready(Ok(Input::RawDnsResponse(query, response)));
+        }
+
         if let Some(timeout) = self.timeout.as_mut() {
             if timeout.poll_unpin(cx).is_ready() {
                 return Poll::Ready(Ok(Input::Timeout(timeout.deadline().into())));
@@ -128,14 +154,20 @@ impl Io {
         Ok(())
     }
 
-    pub fn set_upstream_dns_servers(
-        &mut self,
-        dns_servers: impl IntoIterator<Item = (IpAddr, DnsServer)>,
-    ) {
+    pub fn set_upstream_dns_servers(&mut self, dns_servers: impl IntoIterator<Item = DnsServer>) {
         tracing::info!("Setting new DNS resolvers");
 
+        let dns_servers = dns_servers.into_iter().collect::<Vec<_>>();
+
         self.forwarded_dns_queries =
-            FuturesTupleSet::new(Duration::from_secs(60), DNS_QUERIES_QUEUE_SIZE);
+            FuturesTupleSet::new(DNS_QUERY_TIMEOUT, DNS_QUERIES_QUEUE_SIZE);
+        self.forwarded_raw_dns_queries =
+            FuturesTupleSet::new(DNS_QUERY_TIMEOUT, DNS_QUERIES_QUEUE_SIZE);
+        self.plain_dns_servers = dns_servers
+            .iter()
+            .filter(|server| matches!(server, DnsServer::IpPort(_)))
+            .map(|server| server.address())
+            .collect();
         self.upstream_dns_servers = create_resolvers(
             dns_servers,
             TokioRuntimeProvider::new(
@@ -146,7 +178,12 @@ impl Io {
     }
 
     pub fn perform_dns_query(&mut self, query: DnsQuery<'static>) -> Result<(), DnsQueryError> {
-        let upstream = query.query.destination();
+        let upstream = query.upstream;
+
+        if query.dnssec_ok() && self.plain_dns_servers.contains(&upstream) {
+            return self.forward_raw_dns_query(query);
+        }
+
         let resolver = self
             .upstream_dns_servers
             .get(&upstream)
@@ -172,6 +209,34 @@ impl Io {
         Ok(())
     }
 
+    /// Forwards the DNS message of the query unchanged to its upstream server, using the same transport as the application.
+    fn forward_raw_dns_query(&mut self, query: DnsQuery<'static>) -> Result<(), DnsQueryError> {
+        let upstream = query.upstream;
+        let message = query.query.unwrap_as_udp().payload().to_vec();
+
+        let result = match query.transport {
+            Transport::Udp => {
+                let socket = (self.udp_socket_factory)(&unspecified_addr(upstream))
+                    .map_err(DnsQueryError::Io)?;
+
+                self.forwarded_raw_dns_queries
+                    .try_push(udp_dns_exchange(socket, upstream, message), query)
+            }
+            Transport::Tcp => {
+                let socket = (self.tcp_socket_factory)(&upstream).map_err(DnsQueryError::Io)?;
+
+                self.forwarded_raw_dns_queries
+                    .try_push(tcp_dns_exchange(socket, upstream, message), query)
+            }
+        };
+
+        if result.is_err() {
+            return Err(DnsQueryError::TooManyQueries);
+        }
+
+        Ok(())
+    }
+
     pub fn reset_timeout(&mut self, timeout: Instant) {
         let timeout = tokio::time::Instant::from_std(timeout);
 
@@ -201,6 +266,58 @@ impl Io {
 pub enum DnsQueryError {
     #[error("Too many ongoing DNS queries")]
     TooManyQueries,
+    #[error("Failed to create socket: {0}")]
+    Io(io::Error),
+}
+
+async fn udp_dns_exchange(
+    socket: UdpSocket,
+    upstream: SocketAddr,
+    message: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    socket.send_to(&message, upstream).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+
+        // Ignore anything that isn't a response to our query, i.e. doesn't come from the server or has a different ID.
+        if from != upstream || len < 2 || buf[..2] != message[..2] {
+            continue;
+        }
+
+        buf.truncate(len);
+
+        return Ok(buf);
+    }
+}
+
+async fn tcp_dns_exchange(
+    socket: TcpSocket,
+    upstream: SocketAddr,
+    message: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let mut stream = socket.connect(upstream).await?;
+
+    // DNS messages over TCP are prefixed with their length, see RFC 1035, section 4.2.2.
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS message too long"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&message).await?;
+
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+fn unspecified_addr(upstream: SocketAddr) -> SocketAddr {
+    match upstream {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
 }
 
 /// Identical to [`TokioRuntimeProvider`](hickory_resolver::name_server::TokioRuntimeProvider) but using our own [`SocketFactory`].
@@ -259,21 +376,41 @@ impl RuntimeProvider for TokioRuntimeProvider {
 }
 
 fn create_resolvers(
-    dns_servers: impl IntoIterator<Item = (IpAddr, DnsServer)>,
+    dns_servers: impl IntoIterator<Item = DnsServer>,
     runtime_provider: TokioRuntimeProvider,
-) -> HashMap<IpAddr, AsyncResolver<GenericConnector<TokioRuntimeProvider>>> {
+) -> HashMap<SocketAddr, AsyncResolver<GenericConnector<TokioRuntimeProvider>>> {
     dns_servers
         .into_iter()
-        .map(|(sentinel, srv)| {
+        .map(|srv| {
             let mut resolver_config = ResolverConfig::new();
-            resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Udp));
-            resolver_config.add_name_server(NameServerConfig::new(srv.address(), Protocol::Tcp));
+            match &srv {
+                DnsServer::IpPort(s) => {
+                    resolver_config
+                        .add_name_server(NameServerConfig::new(s.address, Protocol::Udp));
+                    resolver_config
+                        .add_name_server(NameServerConfig::new(s.address, Protocol::Tcp));
+                }
+                DnsServer::Tls(s) => {
+                    resolver_config.add_name_server(NameServerConfig {
+                        tls_dns_name: Some(s.server_name.clone()),
+                        ..NameServerConfig::new(s.address, Protocol::Tls)
+                    });
+                }
+                DnsServer::Https(s) => {
+                    resolver_config.add_name_server(NameServerConfig {
+                        tls_dns_name: Some(s.server_name.clone()),
+                        ..NameServerConfig::new(s.address, Protocol::Https)
+                    });
+                }
+            }
 
             let mut resolver_opts = ResolverOpts::default();
             resolver_opts.edns0 = true;
+            // We cache responses ourselves, see `dns::cache`.
+            resolver_opts.cache_size = 0;
 
             (
-                sentinel,
+                srv.address(),
                 AsyncResolver::new_with_conn(
                     resolver_config,
                     resolver_opts,
@@ -7,7 +7,9 @@ use boringtun::x25519::StaticSecret;
 use chrono::Utc;
 use connlib_shared::{
     callbacks,
-    messages::{ClientId, GatewayId, Relay, RelayId, ResourceId, ReuseConnection},
+    messages::{
+        gateway::HealthProbe, ClientId, GatewayId, Relay, RelayId, ResourceId, ReuseConnection,
+    },
     DomainName, Result,
 };
 use io::Io;
@@ -16,12 +18,16 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bimap::BiMap;
 pub use client::{ClientState, Request};
-pub use gateway::GatewayState;
+pub use dns::query_log::DnsResourceStats;
+pub use dns::DnsQuery;
+pub use gateway::{ClientStats, EgressStreamId, GatewayState, GatewayStats, TrafficStats};
+pub use peer::{FlowRecord, FlowVerdict, NatConfig};
+pub use snownet::{CandidatePair, CandidateType, ConnectionConfig, ConnectionStats};
 use utils::turn;
 
 mod client;
@@ -32,6 +38,7 @@ mod io;
 mod peer;
 mod peer_store;
 mod sockets;
+mod tcp;
 mod utils;
 
 pub use device_channel::Tun;
@@ -112,7 +119,8 @@ impl ClientTunnel {
 
             if let Some(dns_query) = self.role_state.poll_dns_queries() {
                 if let Err(e) = self.io.perform_dns_query(dns_query.clone()) {
-                    self.role_state.on_dns_result(dns_query, Err(e))
+                    self.role_state
+                        .on_dns_result(dns_query, Err(e), Instant::now())
                 }
                 continue;
             }
@@ -158,7 +166,13 @@ impl ClientTunnel {
                     continue;
                 }
                 Poll::Ready(io::Input::DnsResponse(query, response)) => {
-                    self.role_state.on_dns_result(query, Ok(response));
+                    self.role_state
+                        .on_dns_result(query, Ok(response), Instant::now());
+                    continue;
+                }
+                Poll::Ready(io::Input::RawDnsResponse(query, response)) => {
+                    self.role_state
+                        .on_raw_dns_response(query, response, Instant::now());
                     continue;
                 }
                 Poll::Pending => {}
@@ -240,7 +254,7 @@ impl GatewayTunnel {
 
                     continue;
                 }
-                Poll::Ready(io::Input::DnsResponse(_, _)) => {
+                Poll::Ready(io::Input::DnsResponse(_, _) | io::Input::RawDnsResponse(_, _)) => {
                     unreachable!("Gateway does not (yet) resolve DNS queries via `Io`")
                 }
                 Poll::Pending => {}
@@ -288,6 +302,10 @@ pub enum ClientEvent {
         ip4: Vec<Ipv4Network>,
         ip6: Vec<Ipv6Network>,
     },
+    /// We answered a DNS query, only emitted if the DNS query log is enabled.
+    DnsQueryLogged {
+        entry: callbacks::DnsQueryLogEntry,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -300,9 +318,71 @@ pub enum GatewayEvent {
         conn_id: ClientId,
         candidates: HashSet<String>,
     },
+    /// The addresses of a domain need to be re-resolved, either because its records' TTL expired or because the resolved IPs stopped responding.
     RefreshDns {
         name: DomainName,
         conn_id: ClientId,
         resource_id: ResourceId,
     },
+    /// A client forwarded a DNS query for a non-address record of a DNS resource to us.
+    ///
+    /// The result needs to be passed back via [`GatewayTunnel::on_dns_result`].
+    ResolveDnsQuery {
+        conn_id: ClientId,
+        query: DnsQuery<'static>,
+    },
+    /// We dropped traffic of a client because it exceeded a rate limit.
+    ///
+    /// Emitted at most once per second for each limit, with the totals since the last report.
+    RateLimited {
+        conn_id: ClientId,
+        /// `None` if the limit of the client was exceeded, otherwise the resource whose limit was exceeded.
+        resource_id: Option<ResourceId>,
+        dropped_packets: u64,
+        dropped_bytes: u64,
+    },
+    /// A client is nearing its max. number of NAT sessions or we failed to translate some of its packets for DNS resources.
+    ///
+    /// Emitted once when the client crosses 90% of its session limit and at most once per second while translations fail.
+    NatExhaustion {
+        conn_id: ClientId,
+        sessions: usize,
+        max_sessions: usize,
+        /// How many packets we dropped because we couldn't map them since the last report.
+        failed_translations: u64,
+    },
+    /// A flow between a client and a resource ended, only emitted if the flow log is enabled.
+    FlowLogged {
+        conn_id: ClientId,
+        record: FlowRecord,
+    },
+    /// The access of a client to a resource expired, the client should be told so it stops routing traffic to us.
+    AccessExpired {
+        conn_id: ClientId,
+        resource_id: ResourceId,
+    },
+    /// A backend of a load-balanced resource needs to be health-checked.
+    ///
+    /// The result needs to be passed back via [`GatewayTunnel::on_probe_result`], a probe that doesn't complete within `timeout` failed.
+    ProbeBackend {
+        ip: IpAddr,
+        probe: HealthProbe,
+        timeout: Duration,
+    },
+    /// A client opened a TCP connection to a resource that we relay through the upstream proxy.
+    ///
+    /// Once connected, call [`GatewayTunnel::on_egress_proxy_connected`], otherwise [`GatewayTunnel::reset_egress_proxy_stream`].
+    EgressProxyConnect {
+        stream: EgressStreamId,
+        dst: SocketAddr,
+    },
+    /// A client sent data on a relayed connection, call [`GatewayTunnel::on_egress_proxy_written`] once it is written.
+    EgressProxyData {
+        stream: EgressStreamId,
+        data: Vec<u8>,
+    },
+    /// A client won't send any more data on a relayed connection.
+    EgressProxyShutdown { stream: EgressStreamId },
+    /// A relayed connection is gone, the upstream connection should be closed.
+    EgressProxyClosed { stream: EgressStreamId },
 }
@@ -1,109 +1,297 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
 use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
-use connlib_shared::messages::gateway::{ResolvedResourceDescriptionDns, ResourceDescription};
-use connlib_shared::messages::{
-    gateway::Filter, gateway::Filters, ClientId, GatewayId, ResourceId,
+use connlib_shared::messages::gateway::{
+    Filter, FilterAction, Filters, HealthCheck, HealthProbe, IcmpFilter, IcmpMessageType,
+    LoadBalancing, LoadBalancingStrategy, PortFilter, PortMapping, PortMappingProtocol, RateLimit,
 };
+use connlib_shared::messages::gateway::{ResolvedResourceDescriptionDns, ResourceDescription};
+use connlib_shared::messages::{ClientId, GatewayId, ResourceId};
 use connlib_shared::DomainName;
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 use ip_packet::ip::IpNextHeaderProtocols;
-use ip_packet::{IpPacket, MutableIpPacket};
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _, Protocol};
 use itertools::Itertools;
 use rangemap::RangeInclusiveSet;
 
-use crate::utils::network_contains_network;
+use crate::dns::DnsQuery;
+use crate::utils::{earliest, network_contains_network};
 use crate::GatewayEvent;
 
+pub use flow_table::{FlowRecord, FlowVerdict};
+pub use nat_table::NatConfig;
+
+use flow_table::FlowTable;
 use nat_table::NatTable;
+use rate_limiter::{Dropped, RateLimiter};
+
+pub(crate) use rate_limiter::ResourceRateLimiters;
 
+mod flow_table;
 mod nat_table;
+mod rate_limiter;
 
+/// Lower bound for how often we re-resolve a domain based on its TTL.
+///
+/// Protects the upstream resolvers from records with very short (or zero) TTLs.
+/// This is also how long we wait before retrying a failed refresh.
+const MIN_DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often we report dropped packets at most, see [`GatewayEvent::RateLimited`] and [`GatewayEvent::NatExhaustion`].
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The filters of all resources that cover an IP.
+///
+/// Traffic is allowed if no deny rule matches it and either an allow rule matches it or none of the resources has any allow filters.
 #[derive(Debug)]
-enum FilterEngine {
-    PermitAll,
-    PermitSome(AllowRules),
+struct FilterEngine {
+    permit_all: bool,
+    allow: FilterRules,
+    deny: FilterRules,
 }
 
-#[derive(Debug)]
-struct AllowRules {
-    udp: RangeInclusiveSet<u16>,
-    tcp: RangeInclusiveSet<u16>,
-    icmp: bool,
+#[derive(Debug, Default)]
+struct FilterRules {
+    udp: PortRules,
+    tcp: PortRules,
+    sctp: PortRules,
+    icmp: IcmpRules,
+    gre: bool,
+    esp: bool,
+}
+
+#[derive(Debug, Default)]
+struct PortRules {
+    /// Destination ports that match regardless of the source port.
+    any_source: RangeInclusiveSet<u16>,
+    /// Destination ports that only match together with the given source ports.
+    with_source: Vec<(RangeInclusive<u16>, RangeInclusive<u16>)>,
+}
+
+#[derive(Debug, Default)]
+struct IcmpRules {
+    all: bool,
+    /// Message types that match with any code (`None`) or only with the given code.
+    messages: HashSet<(IcmpMessageType, Option<u8>)>,
+}
+
+/// What the filters look at in a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Traffic {
+    Tcp {
+        sport: u16,
+        dport: u16,
+    },
+    Udp {
+        sport: u16,
+        dport: u16,
+    },
+    Sctp {
+        sport: u16,
+        dport: u16,
+    },
+    Icmp {
+        /// `None` for message types that filters can't refer to.
+        message: Option<IcmpMessageType>,
+        code: u8,
+    },
+    Gre,
+    Esp,
+    /// Any other protocol, or a packet that is too short to be parsed.
+    Other,
 }
 
 impl FilterEngine {
     fn empty() -> FilterEngine {
-        Self::PermitSome(AllowRules::new())
+        Self {
+            permit_all: false,
+            allow: FilterRules::default(),
+            deny: FilterRules::default(),
+        }
     }
 
     fn is_allowed(&self, packet: &IpPacket) -> bool {
-        match self {
-            FilterEngine::PermitAll => true,
-            FilterEngine::PermitSome(filter_engine) => filter_engine.is_allowed(packet),
-        }
+        self.permits(Traffic::new(packet))
+    }
+
+    /// Whether the given traffic is allowed.
+    fn permits(&self, traffic: Traffic) -> bool {
+        !self.denies(traffic) && (self.permit_all || self.allow.matches(traffic))
+    }
+
+    /// Whether the given traffic is explicitly denied.
+    fn denies(&self, traffic: Traffic) -> bool {
+        self.deny.matches(traffic)
     }
 
     fn permit_all(&mut self) {
-        *self = FilterEngine::PermitAll;
+        self.permit_all = true;
     }
 
     fn add_filters<'a>(&mut self, filters: impl IntoIterator<Item = &'a Filter>) {
-        match self {
-            FilterEngine::PermitAll => {}
-            FilterEngine::PermitSome(filter_engine) => filter_engine.add_filters(filters),
+        for filter in filters {
+            match filter.action() {
+                FilterAction::Allow => self.allow.add_filter(filter),
+                FilterAction::Deny => self.deny.add_filter(filter),
+            }
         }
     }
 }
 
-impl AllowRules {
-    fn new() -> AllowRules {
-        AllowRules {
-            udp: RangeInclusiveSet::new(),
-            tcp: RangeInclusiveSet::new(),
-            icmp: false,
+impl FilterRules {
+    fn matches(&self, traffic: Traffic) -> bool {
+        match traffic {
+            Traffic::Tcp { sport, dport } => self.tcp.matches(sport, dport),
+            Traffic::Udp { sport, dport } => self.udp.matches(sport, dport),
+            Traffic::Sctp { sport, dport } => self.sctp.matches(sport, dport),
+            Traffic::Icmp { message, code } => self.icmp.matches(message, code),
+            Traffic::Gre => self.gre,
+            Traffic::Esp => self.esp,
+            Traffic::Other => false,
         }
     }
 
-    fn is_allowed(&self, packet: &IpPacket) -> bool {
-        match packet.next_header() {
-            // Note: possible optimization here
-            // if we want to get the port here, and we assume correct formatting
-            // we can do packet.payload()[2..=3] (for UDP and TCP bytes 2 and 3 are the port)
-            // but it might be a bit harder to read
-            IpNextHeaderProtocols::Tcp => packet
-                .as_tcp()
-                .is_some_and(|p| self.tcp.contains(&p.get_destination())),
-            IpNextHeaderProtocols::Udp => packet
-                .as_udp()
-                .is_some_and(|p| self.udp.contains(&p.get_destination())),
-            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => self.icmp,
-            _ => false,
+    fn add_filter(&mut self, filter: &Filter) {
+        match filter {
+            Filter::Udp(filter) => self.udp.add_filter(filter),
+            Filter::Tcp(filter) => self.tcp.add_filter(filter),
+            Filter::Sctp(filter) => self.sctp.add_filter(filter),
+            Filter::Icmp(filter) => self.icmp.add_filter(filter),
+            Filter::Gre(_) => self.gre = true,
+            Filter::Esp(_) => self.esp = true,
         }
     }
+}
 
-    fn add_filters<'a>(&mut self, filters: impl IntoIterator<Item = &'a Filter>) {
-        for filter in filters {
-            match filter {
-                Filter::Udp(range) => {
-                    self.udp
-                        .insert(range.port_range_start..=range.port_range_end);
-                }
-                Filter::Tcp(range) => {
-                    self.tcp
-                        .insert(range.port_range_start..=range.port_range_end);
-                }
-                Filter::Icmp => {
-                    self.icmp = true;
-                }
+impl PortRules {
+    fn matches(&self, sport: u16, dport: u16) -> bool {
+        self.any_source.contains(&dport)
+            || self
+                .with_source
+                .iter()
+                .any(|(dports, sports)| dports.contains(&dport) && sports.contains(&sport))
+    }
+
+    fn add_filter(&mut self, filter: &PortFilter) {
+        let dports = filter.ports.port_range_start..=filter.ports.port_range_end;
+
+        match filter.source_ports {
+            Some(sports) => self
+                .with_source
+                .push((dports, sports.port_range_start..=sports.port_range_end)),
+            None => self.any_source.insert(dports),
+        }
+    }
+}
+
+impl IcmpRules {
+    fn matches(&self, message: Option<IcmpMessageType>, code: u8) -> bool {
+        if self.all {
+            return true;
+        }
+
+        let Some(message) = message else {
+            return false;
+        };
+
+        self.messages.contains(&(message, None)) || self.messages.contains(&(message, Some(code)))
+    }
+
+    fn add_filter(&mut self, filter: &IcmpFilter) {
+        match filter.icmp_type {
+            Some(message) => {
+                self.messages.insert((message, filter.icmp_code));
+            }
+            None => self.all = true,
+        }
+    }
+}
+
+impl Traffic {
+    /// Classifies a packet by the first 8 bytes of its payload.
+    ///
+    /// For TCP, UDP and SCTP, the source and destination ports are the first 4 bytes of the header.
+    pub(crate) fn new(packet: &IpPacket<'_>) -> Self {
+        let payload = packet.payload();
+        let ports = || {
+            let sport = u16::from_be_bytes(payload.get(0..2)?.try_into().ok()?);
+            let dport = u16::from_be_bytes(payload.get(2..4)?.try_into().ok()?);
+
+            Some((sport, dport))
+        };
+        let icmp = |message: fn(u8) -> Option<IcmpMessageType>| {
+            let [ty, code, ..] = payload else {
+                return Traffic::Other;
+            };
+
+            Traffic::Icmp {
+                message: message(*ty),
+                code: *code,
             }
+        };
+
+        match packet.next_header() {
+            IpNextHeaderProtocols::Tcp => ports().map_or(Traffic::Other, |(sport, dport)| {
+                Traffic::Tcp { sport, dport }
+            }),
+            IpNextHeaderProtocols::Udp => ports().map_or(Traffic::Other, |(sport, dport)| {
+                Traffic::Udp { sport, dport }
+            }),
+            IpNextHeaderProtocols::Sctp => ports().map_or(Traffic::Other, |(sport, dport)| {
+                Traffic::Sctp { sport, dport }
+            }),
+            IpNextHeaderProtocols::Icmp => icmp(icmpv4_message_type),
+            IpNextHeaderProtocols::Icmpv6 => icmp(icmpv6_message_type),
+            IpNextHeaderProtocols::Gre => Traffic::Gre,
+            IpNextHeaderProtocols::Esp => Traffic::Esp,
+            _ => Traffic::Other,
         }
     }
 }
 
+/// The port of a packet, if a port mapping of the given protocol applies to it.
+fn mapped_port(protocol: PortMappingProtocol, port: Protocol) -> Option<u16> {
+    match (protocol, port) {
+        (PortMappingProtocol::Tcp, Protocol::Tcp(port))
+        | (PortMappingProtocol::Udp, Protocol::Udp(port)) => Some(port),
+        (PortMappingProtocol::Tcp, Protocol::Udp(_) | Protocol::Icmp(_))
+        | (PortMappingProtocol::Udp, Protocol::Tcp(_) | Protocol::Icmp(_)) => None,
+    }
+}
+
+fn icmpv4_message_type(ty: u8) -> Option<IcmpMessageType> {
+    let message = match ty {
+        0 => IcmpMessageType::EchoReply,
+        3 => IcmpMessageType::DestinationUnreachable,
+        5 => IcmpMessageType::Redirect,
+        8 => IcmpMessageType::EchoRequest,
+        11 => IcmpMessageType::TimeExceeded,
+        12 => IcmpMessageType::ParameterProblem,
+        _ => return None,
+    };
+
+    Some(message)
+}
+
+fn icmpv6_message_type(ty: u8) -> Option<IcmpMessageType> {
+    let message = match ty {
+        1 => IcmpMessageType::DestinationUnreachable,
+        2 => IcmpMessageType::PacketTooBig,
+        3 => IcmpMessageType::TimeExceeded,
+        4 => IcmpMessageType::ParameterProblem,
+        128 => IcmpMessageType::EchoRequest,
+        129 => IcmpMessageType::EchoReply,
+        137 => IcmpMessageType::Redirect,
+        _ => return None,
+    };
+
+    Some(message)
+}
+
 /// The state of one gateway on a client.
 pub(crate) struct GatewayOnClient {
     id: GatewayId,
@@ -145,14 +333,119 @@ impl ClientOnGateway {
             filters: IpNetworkTable::new(),
             permanent_translations: Default::default(),
             nat_table: Default::default(),
+            flow_table: Default::default(),
+            dns_refresh_at: Default::default(),
+            rate_limiter: None,
+            resource_drops: Default::default(),
+            resource_ips: IpNetworkTable::new(),
+            port_mappings: Default::default(),
+            load_balancing: Default::default(),
+            unhealthy_backends: Default::default(),
+            round_robin: Default::default(),
+            report_at: None,
             buffered_events: Default::default(),
         }
     }
 
+    /// Limits all traffic of the client, in both directions.
+    ///
+    /// Replaces any previous limit, `None` removes it.
+    pub(crate) fn set_rate_limit(&mut self, limit: Option<RateLimit>, now: Instant) {
+        if self.rate_limiter.as_ref().map(|l| l.config()) == limit {
+            return;
+        }
+
+        tracing::debug!(conn_id = %self.id, ?limit, "Setting rate limit");
+
+        self.rate_limiter = limit.map(|limit| RateLimiter::new(limit, now));
+    }
+
+    /// Exposes ports of the resource to the client under different ports.
+    ///
+    /// Replaces any previous mappings of the resource.
+    pub(crate) fn set_resource_port_mappings(
+        &mut self,
+        resource: ResourceId,
+        mappings: Vec<PortMapping>,
+    ) {
+        if !self.resources.contains_key(&resource) {
+            return;
+        }
+
+        let mut ports = HashSet::new();
+        let mut target_ports = HashSet::new();
+        let mappings = mappings
+            .into_iter()
+            .filter(|m| {
+                // Replies are mapped back by their port, thus each port may only be used once.
+                if ports.contains(&(m.protocol, m.port))
+                    || target_ports.contains(&(m.protocol, m.target_port))
+                {
+                    tracing::warn!(conn_id = %self.id, %resource, mapping = ?m, "Ignoring conflicting port mapping");
+                    return false;
+                }
+
+                ports.insert((m.protocol, m.port));
+                target_ports.insert((m.protocol, m.target_port));
+
+                true
+            })
+            .collect_vec();
+
+        if mappings.is_empty() {
+            self.port_mappings.remove(&resource);
+        } else {
+            self.port_mappings.insert(resource, mappings);
+        }
+    }
+
+    /// Treats the addresses of a DNS resource as a pool of backends, `None` goes back to mapping each proxy IP to one address.
+    pub(crate) fn set_resource_load_balancing(
+        &mut self,
+        resource: ResourceId,
+        load_balancing: Option<LoadBalancing>,
+    ) {
+        if !self.resources.contains_key(&resource) {
+            return;
+        }
+
+        match load_balancing {
+            Some(load_balancing) => {
+                self.load_balancing.insert(resource, load_balancing);
+            }
+            None => {
+                self.load_balancing.remove(&resource);
+            }
+        }
+    }
+
+    /// The backends that failed their health checks, we don't open any new sessions to these.
+    pub(crate) fn set_unhealthy_backends(&mut self, backends: HashSet<(IpAddr, HealthProbe)>) {
+        self.unhealthy_backends = backends;
+    }
+
+    /// The backends of load-balanced resources that need to be health-checked.
+    pub(crate) fn health_checked_backends(
+        &self,
+    ) -> impl Iterator<Item = (IpAddr, HealthCheck)> + '_ {
+        self.load_balancing
+            .iter()
+            .filter_map(|(id, lb)| Some((id, lb.health_check?)))
+            .flat_map(|(id, check)| {
+                self.resources
+                    .get(id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|r| r.domain.is_some())
+                    .flat_map(|r| &r.ips)
+                    .map(move |ip| (ip.network_address(), check))
+            })
+    }
+
     /// A client is only allowed to send packets from their (portal-assigned) tunnel IPs.
     ///
     /// Failure to enforce this would allow one client to send traffic masquarading as a different client.
-    fn allowed_ips(&self) -> [IpAddr; 2] {
+    pub(crate) fn allowed_ips(&self) -> [IpAddr; 2] {
         [IpAddr::from(self.ipv4), IpAddr::from(self.ipv6)]
     }
 
@@ -161,12 +454,17 @@ impl ClientOnGateway {
         name: DomainName,
         resource_id: ResourceId,
         resolved_ips: Vec<IpAddr>,
+        ttl: Option<Duration>,
         now: Instant,
     ) {
         let Some(resource) = self.resources.get_mut(&resource_id) else {
             return;
         };
 
+        if let Some(ttl) = ttl {
+            self.schedule_dns_refresh(name.clone(), resource_id, ttl, now);
+        }
+
         let old_ips: HashSet<&IpAddr> =
             HashSet::from_iter(self.permanent_translations.values().filter_map(|state| {
                 (state.name == name && state.resource_id == resource_id)
@@ -243,6 +541,10 @@ impl ClientOnGateway {
                     return Ok(());
                 }
 
+                if let Some(ttl) = r.ttl {
+                    self.schedule_dns_refresh(name.clone(), r.id, ttl, now);
+                }
+
                 self.assign_translations(name, r.id, &r.addresses, resource_ips, now);
             }
             (ResourceDescription::Cidr(_), None) => {}
@@ -253,28 +555,126 @@ impl ClientOnGateway {
         Ok(())
     }
 
+    fn schedule_dns_refresh(
+        &mut self,
+        name: DomainName,
+        resource_id: ResourceId,
+        ttl: Duration,
+        now: Instant,
+    ) {
+        self.dns_refresh_at
+            .insert((name, resource_id), now + ttl.max(MIN_DNS_REFRESH_INTERVAL));
+    }
+
     pub(crate) fn is_emptied(&self) -> bool {
         self.resources.is_empty()
     }
 
-    pub(crate) fn expire_resources(&mut self, now: DateTime<Utc>) {
+    /// The resources the client currently has access to.
+    pub(crate) fn resource_ids(&self) -> impl Iterator<Item = ResourceId> + '_ {
+        self.resources.keys().copied()
+    }
+
+    /// How many NAT sessions for DNS resources the client currently has.
+    pub(crate) fn nat_sessions(&self) -> usize {
+        self.nat_table.num_sessions()
+    }
+
+    /// Removes the access to all resources that expired.
+    ///
+    /// Returns the resources the client no longer has any access to.
+    pub(crate) fn expire_resources(&mut self, now: DateTime<Utc>) -> Vec<ResourceId> {
         for resource in self.resources.values_mut() {
             resource.retain(|r| !r.expires_at.is_some_and(|e| e <= now));
         }
 
+        let expired = self
+            .resources
+            .iter()
+            .filter_map(|(id, r)| r.is_empty().then_some(*id))
+            .collect_vec();
+
         self.resources.retain(|_, r| !r.is_empty());
+        self.dns_refresh_at
+            .retain(|(_, rid), _| self.resources.contains_key(rid));
         self.recalculate_filters();
+
+        expired
+    }
+
+    /// When the access to the next resource (or one of its subdomains) expires.
+    pub(crate) fn next_resource_expiry(&self) -> Option<DateTime<Utc>> {
+        self.resources
+            .values()
+            .flatten()
+            .filter_map(|r| r.expires_at)
+            .min()
+    }
+
+    /// When [`ClientOnGateway::handle_timeout`] needs to be called next.
+    ///
+    /// This doesn't include the expiry of resources, see [`ClientOnGateway::next_resource_expiry`].
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        let translations = self
+            .permanent_translations
+            .values()
+            .filter_map(TranslationState::next_check)
+            .min();
+        let dns_refresh = self.dns_refresh_at.values().min().copied();
+
+        [
+            translations,
+            dns_refresh,
+            self.nat_table.poll_timeout(),
+            self.flow_table.poll_timeout(),
+            self.report_at,
+        ]
+        .into_iter()
+        .fold(None, earliest)
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<GatewayEvent> {
-        self.buffered_events.pop_front()
+        if let Some(event) = self.buffered_events.pop_front() {
+            return Some(event);
+        }
+
+        self.flow_table
+            .poll_record()
+            .map(|record| GatewayEvent::FlowLogged {
+                conn_id: self.id,
+                record,
+            })
+    }
+
+    pub(crate) fn set_nat_config(&mut self, config: NatConfig) {
+        self.nat_table.set_config(config);
+    }
+
+    /// Enables or disables emitting [`GatewayEvent::FlowLogged`] for every flow that ends.
+    pub(crate) fn set_flow_log(&mut self, enabled: bool) {
+        self.flow_table.set_log_enabled(enabled);
+    }
+
+    /// Ends all flows of the client, e.g. because we are about to remove it.
+    ///
+    /// Returns the records of all flows that haven't been polled yet.
+    pub(crate) fn end_flows(&mut self) -> Vec<GatewayEvent> {
+        self.flow_table.end_all();
+
+        std::iter::from_fn(|| self.flow_table.poll_record())
+            .map(|record| GatewayEvent::FlowLogged {
+                conn_id: self.id,
+                record,
+            })
+            .collect()
     }
 
     pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let is_due = |state: &TranslationState| state.next_check().is_some_and(|at| now >= at);
         let expired_translations = self
             .permanent_translations
             .iter()
-            .filter(|(_, state)| state.is_expired(now));
+            .filter(|(_, state)| is_due(state));
 
         let mut for_refresh = HashSet::new();
 
@@ -296,6 +696,27 @@ impl ClientOnGateway {
             }
         }
 
+        for ((name, resource_id), refresh_at) in self.dns_refresh_at.iter_mut() {
+            if now < *refresh_at {
+                continue;
+            }
+
+            tracing::debug!(domain = %name, conn_id = %self.id, %resource_id, "TTL expired, refreshing DNS");
+
+            for_refresh.insert((name.clone(), *resource_id));
+
+            // Retry in case the refresh fails, a successful one reschedules based on the new TTL.
+            *refresh_at = now + MIN_DNS_REFRESH_INTERVAL;
+        }
+
+        for state in self
+            .permanent_translations
+            .values_mut()
+            .filter(|state| is_due(state))
+        {
+            state.on_expiry_handled(now);
+        }
+
         for (name, resource_id) in for_refresh {
             self.buffered_events.push_back(GatewayEvent::RefreshDns {
                 name,
@@ -305,10 +726,52 @@ impl ClientOnGateway {
         }
 
         self.nat_table.handle_timeout(now);
+        self.flow_table.handle_timeout(now);
+
+        if self.report_at.is_some_and(|at| now >= at) {
+            self.report_at = None;
+            self.report_dropped_packets();
+        }
+    }
+
+    /// Reports what we dropped since the last report instead of emitting an event per packet.
+    fn report_dropped_packets(&mut self) {
+        if let Some(exhaustion) = self.nat_table.take_exhaustion() {
+            tracing::debug!(conn_id = %self.id, sessions = %exhaustion.sessions, max_sessions = %exhaustion.max_sessions, failed_translations = %exhaustion.failed_translations, "Client is exhausting its NAT sessions");
+
+            self.buffered_events.push_back(GatewayEvent::NatExhaustion {
+                conn_id: self.id,
+                sessions: exhaustion.sessions,
+                max_sessions: exhaustion.max_sessions,
+                failed_translations: exhaustion.failed_translations,
+            });
+        }
+
+        let dropped_for_client = self
+            .rate_limiter
+            .as_mut()
+            .and_then(|l| l.take_dropped())
+            .map(|d| (None, d));
+        let dropped_for_resources = self
+            .resource_drops
+            .drain()
+            .map(|(id, dropped)| (Some(id), dropped));
+
+        for (resource_id, dropped) in dropped_for_client.into_iter().chain(dropped_for_resources) {
+            tracing::debug!(conn_id = %self.id, resource = ?resource_id, packets = %dropped.packets, bytes = %dropped.bytes, "Dropped packets exceeding rate limit");
+
+            self.buffered_events.push_back(GatewayEvent::RateLimited {
+                conn_id: self.id,
+                resource_id,
+                dropped_packets: dropped.packets,
+                dropped_bytes: dropped.bytes,
+            });
+        }
     }
 
     pub(crate) fn remove_resource(&mut self, resource: &ResourceId) {
         self.resources.remove(resource);
+        self.dns_refresh_at.retain(|(_, rid), _| rid != resource);
         self.recalculate_filters();
     }
 
@@ -362,8 +825,11 @@ impl ClientOnGateway {
                         .then_some(&r.filters)
                 });
 
-                // Empty filters means permit all
-                if filters.clone().any(|f| f.is_empty()) {
+                // Filters without any allow entries (including empty filters) permit everything that isn't denied.
+                if filters
+                    .clone()
+                    .any(|f| f.iter().all(|f| f.action() == FilterAction::Deny))
+                {
                     filter_engine.permit_all();
                 }
 
@@ -371,6 +837,28 @@ impl ClientOnGateway {
                 self.filters.insert(*ip, filter_engine);
             }
         }
+
+        self.resource_drops
+            .retain(|id, _| self.resources.contains_key(id));
+        self.port_mappings
+            .retain(|id, _| self.resources.contains_key(id));
+        self.load_balancing
+            .retain(|id, _| self.resources.contains_key(id));
+        self.round_robin
+            .retain(|(_, id), _| self.resources.contains_key(id));
+        self.resource_ips = IpNetworkTable::new();
+        for (id, resource) in &self.resources {
+            for ip in resource.iter().flat_map(|r| &r.ips) {
+                self.resource_ips.insert(*ip, *id);
+            }
+        }
+
+        // Tear down all flows that the (new) filters wouldn't have allowed in the first place.
+        self.flow_table.retain(|key| {
+            self.filters
+                .longest_match(key.dst)
+                .is_some_and(|(_, filter)| filter.permits(key.protocol.traffic()))
+        });
     }
 
     fn transform_network_to_tun<'a>(
@@ -378,13 +866,22 @@ impl ClientOnGateway {
         packet: MutableIpPacket<'a>,
         now: Instant,
     ) -> Result<MutableIpPacket<'a>, connlib_shared::Error> {
-        let Some(state) = self.permanent_translations.get_mut(&packet.destination()) else {
+        let proxy_ip = packet.destination();
+        let Some(backend) = self.pick_backend(&packet.as_immutable(), proxy_ip)? else {
             return Ok(packet);
         };
+        let state = self
+            .permanent_translations
+            .get_mut(&proxy_ip)
+            .expect("to have translation for picked backend");
 
-        let (source_protocol, real_ip) =
-            self.nat_table
-                .translate_outgoing(packet.as_immutable(), state.resolved_ip, now)?;
+        let translation = self
+            .nat_table
+            .translate_outgoing(packet.as_immutable(), backend, now);
+        if self.nat_table.has_exhaustion() {
+            self.report_at.get_or_insert(now + REPORT_INTERVAL);
+        }
+        let (source_protocol, real_ip) = translation?;
 
         let mut packet = packet
             .translate_destination(self.ipv4, self.ipv6, real_ip)
@@ -397,25 +894,140 @@ impl ClientOnGateway {
         Ok(packet)
     }
 
+    /// Picks the IP we translate a packet to a proxy IP to, `None` if the proxy IP isn't translated.
+    ///
+    /// Without load balancing, this is the IP assigned to the proxy IP.
+    /// Otherwise, sessions stick to their backend while it is healthy and new ones go to the backend picked by the resource's strategy.
+    fn pick_backend(
+        &mut self,
+        packet: &IpPacket<'_>,
+        proxy_ip: IpAddr,
+    ) -> Result<Option<IpAddr>, connlib_shared::Error> {
+        let Some(state) = self.permanent_translations.get(&proxy_ip) else {
+            return Ok(None);
+        };
+        let Some(load_balancing) = self.load_balancing.get(&state.resource_id) else {
+            return Ok(Some(state.resolved_ip));
+        };
+
+        let probe = load_balancing.health_check.map(|c| c.probe);
+        let candidates = self
+            .backends(state.resource_id, &state.name, proxy_ip)
+            .into_iter()
+            .filter(|ip| probe.map_or(true, |p| !self.unhealthy_backends.contains(&(*ip, p))))
+            .collect_vec();
+
+        if let Some(ip) = self
+            .nat_table
+            .outside_ip(packet)
+            .filter(|ip| candidates.contains(ip))
+        {
+            return Ok(Some(ip));
+        }
+
+        let backend = match load_balancing.strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                let key = (state.name.clone(), state.resource_id);
+                let next = self.round_robin.entry(key).or_default();
+                let backend = candidates.get(*next % candidates.len().max(1)).copied();
+                *next = next.wrapping_add(1);
+
+                backend
+            }
+            LoadBalancingStrategy::LeastConnections => candidates
+                .iter()
+                .copied()
+                .min_by_key(|ip| self.nat_table.sessions_to(*ip)),
+        };
+
+        let Some(backend) = backend else {
+            tracing::debug!(conn_id = %self.id, resource = %state.resource_id, domain = %state.name, "All backends are unhealthy");
+
+            return Err(connlib_shared::Error::NoHealthyBackend);
+        };
+
+        tracing::trace!(conn_id = %self.id, %proxy_ip, %backend, "Picked backend for new session");
+
+        Ok(Some(backend))
+    }
+
+    /// The addresses of a domain that we may translate a proxy IP to, in the order they were resolved.
+    fn backends(
+        &self,
+        resource_id: ResourceId,
+        name: &DomainName,
+        proxy_ip: IpAddr,
+    ) -> Vec<IpAddr> {
+        let resolved = self
+            .resources
+            .get(&resource_id)
+            .into_iter()
+            .flatten()
+            .filter(|r| r.domain.as_ref() == Some(name))
+            .flat_map(|r| &r.ips)
+            .map(|ip| ip.network_address())
+            .unique()
+            .collect_vec();
+
+        match proxy_ip {
+            IpAddr::V4(_) => mapped_ipv4(&resolved),
+            IpAddr::V6(_) => mapped_ipv6(&resolved),
+        }
+    }
+
     pub fn decapsulate<'a>(
         &mut self,
         packet: MutableIpPacket<'a>,
+        resource_rate_limiters: &mut ResourceRateLimiters,
         now: Instant,
     ) -> Result<MutableIpPacket<'a>, connlib_shared::Error> {
         self.ensure_allowed_src(&packet)?;
 
         let packet = self.transform_network_to_tun(packet, now)?;
 
-        self.ensure_allowed_dst(&packet)?;
+        let resource = self
+            .resource_ips
+            .longest_match(packet.destination())
+            .map(|(_, id)| *id);
 
-        Ok(packet)
+        if let Err(e) = self
+            .ensure_allowed_dst(&packet)
+            .and_then(|()| self.ensure_not_target_port(resource, &packet))
+        {
+            self.flow_table
+                .on_denied(&packet.to_immutable(), resource, now);
+
+            return Err(e);
+        }
+        self.ensure_within_rate_limit(packet.destination(), &packet, resource_rate_limiters, now)?;
+        self.flow_table
+            .on_outbound(&packet.to_immutable(), resource, now)?;
+
+        Ok(self.map_destination_port(resource, packet))
     }
 
     pub fn encapsulate<'a>(
         &mut self,
         packet: MutableIpPacket<'a>,
+        resource_rate_limiters: &mut ResourceRateLimiters,
         now: Instant,
     ) -> Result<Option<MutableIpPacket<'a>>, connlib_shared::Error> {
+        // Flows use the ports the client sees, thus we need to map the port back first.
+        // ICMP errors about mapped ports don't match any flow because the packet they embed still has the target port.
+        let packet = self.map_source_port(packet);
+
+        self.flow_table
+            .ensure_inbound(&packet.as_immutable(), now)?;
+
+        self.ensure_allowed_icmp_error(&packet)?;
+        self.ensure_within_rate_limit(packet.source(), &packet, resource_rate_limiters, now)?;
+
+        // The NAT only handles TCP, UDP and ICMP echo; we don't translate the packet embedded in ICMP errors (yet).
+        // Thus, everything else is only meaningful to the client for flows that don't go through the NAT, i.e. CIDR resources.
+        if packet.as_immutable().destination_protocol().is_err() {
+            return Ok(Some(packet));
+        }
+
         let Some((proto, ip)) = self
             .nat_table
             .translate_incoming(packet.as_immutable(), now)?
@@ -438,6 +1050,33 @@ impl ClientOnGateway {
         Ok(Some(packet))
     }
 
+    /// Check if a DNS query that the client forwarded to us is ok to be resolved.
+    ///
+    /// Clients may only resolve names they have been granted access to as part of a DNS resource.
+    pub(crate) fn ensure_allowed_dns_query(
+        &self,
+        query: &DnsQuery<'_>,
+    ) -> Result<(), connlib_shared::Error> {
+        let src = query.query.source();
+
+        if !self.allowed_ips().contains(&src) {
+            return Err(connlib_shared::Error::SrcNotAllowed { src });
+        }
+
+        if !self
+            .resources
+            .values()
+            .flatten()
+            .any(|r| r.domain.as_ref() == Some(&query.name))
+        {
+            return Err(connlib_shared::Error::DnsQueryNotAllowed {
+                name: query.name.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
     fn ensure_allowed_src(
         &self,
         packet: &MutableIpPacket<'_>,
@@ -468,15 +1107,167 @@ impl ClientOnGateway {
         Ok(())
     }
 
-    pub fn id(&self) -> ClientId {
-        self.id
-    }
-}
-
-impl GatewayOnClient {
-    pub(crate) fn ensure_allowed_src(
-        &self,
-        packet: &MutableIpPacket,
+    /// Consumes the packet from the rate limits of the client and of the resource behind `resource_ip`, if any.
+    ///
+    /// The packet only consumes tokens if it is within both limits.
+    /// Otherwise, traffic dropped by the client's limit would still drain the resource's limit that is shared with other clients.
+    fn ensure_within_rate_limit(
+        &mut self,
+        resource_ip: IpAddr,
+        packet: &MutableIpPacket<'_>,
+        resource_rate_limiters: &mut ResourceRateLimiters,
+        now: Instant,
+    ) -> Result<(), connlib_shared::Error> {
+        let len = packet.as_immutable().packet().len();
+
+        let resource = self
+            .resource_ips
+            .longest_match(resource_ip)
+            .map(|(_, id)| *id);
+        let mut resource_limiter = resource.and_then(|id| resource_rate_limiters.get_mut(&id));
+        let mut client_limiter = self.rate_limiter.as_mut();
+
+        let within_resource_limit = resource_limiter
+            .as_mut()
+            .map_or(true, |l| l.allows(len, now));
+        let within_client_limit = client_limiter.as_mut().map_or(true, |l| l.allows(len, now));
+
+        if !within_resource_limit || !within_client_limit {
+            if let Some(resource) = resource.filter(|_| !within_resource_limit) {
+                self.resource_drops.entry(resource).or_default().count(len);
+            }
+            if let Some(limiter) = client_limiter.filter(|_| !within_client_limit) {
+                limiter.count_dropped(len);
+            }
+
+            self.report_at.get_or_insert(now + REPORT_INTERVAL);
+            return Err(connlib_shared::Error::RateLimitExceeded);
+        }
+
+        for limiter in resource_limiter.into_iter().chain(client_limiter) {
+            limiter.consume(len);
+        }
+
+        Ok(())
+    }
+
+    /// Clients may only reach the target port of a port mapping through the mapped port.
+    ///
+    /// Otherwise, we couldn't tell which port to map the replies back to.
+    fn ensure_not_target_port(
+        &self,
+        resource: Option<ResourceId>,
+        packet: &MutableIpPacket<'_>,
+    ) -> Result<(), connlib_shared::Error> {
+        let Ok(dst) = packet.as_immutable().destination_protocol() else {
+            return Ok(());
+        };
+        let mappings = self.port_mappings(resource);
+
+        let is_mapped_port = mappings
+            .iter()
+            .any(|m| mapped_port(m.protocol, dst) == Some(m.port));
+        let is_target_port = mappings
+            .iter()
+            .any(|m| mapped_port(m.protocol, dst) == Some(m.target_port));
+
+        if is_target_port && !is_mapped_port {
+            return Err(connlib_shared::Error::DstNotAllowed {
+                dst: packet.destination(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the destination port of a packet to a resource to the target port of its mapping, if any.
+    fn map_destination_port<'a>(
+        &self,
+        resource: Option<ResourceId>,
+        mut packet: MutableIpPacket<'a>,
+    ) -> MutableIpPacket<'a> {
+        let Ok(dst) = packet.as_immutable().destination_protocol() else {
+            return packet;
+        };
+        let Some(mapping) = self
+            .port_mappings(resource)
+            .iter()
+            .find(|m| mapped_port(m.protocol, dst) == Some(m.port))
+        else {
+            return packet;
+        };
+
+        packet.set_destination_protocol(mapping.target_port);
+        packet.update_checksum();
+
+        packet
+    }
+
+    /// Rewrites the source port of a packet from a resource back to the port the client connected to, if any.
+    fn map_source_port<'a>(&self, mut packet: MutableIpPacket<'a>) -> MutableIpPacket<'a> {
+        let resource = self
+            .resource_ips
+            .longest_match(packet.source())
+            .map(|(_, id)| *id);
+        let Ok(src) = packet.as_immutable().source_protocol() else {
+            return packet;
+        };
+        let Some(mapping) = self
+            .port_mappings(resource)
+            .iter()
+            .find(|m| mapped_port(m.protocol, src) == Some(m.target_port))
+        else {
+            return packet;
+        };
+
+        packet.set_source_protocol(mapping.port);
+        packet.update_checksum();
+
+        packet
+    }
+
+    /// The resource that the given IP belongs to, if any.
+    pub(crate) fn resource_of(&self, ip: IpAddr) -> Option<ResourceId> {
+        self.resource_ips.longest_match(ip).map(|(_, id)| *id)
+    }
+
+    fn port_mappings(&self, resource: Option<ResourceId>) -> &[PortMapping] {
+        resource
+            .and_then(|id| self.port_mappings.get(&id))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// ICMP errors are replies to the client's traffic but deny rules of the resource may still block them, e.g. redirects.
+    fn ensure_allowed_icmp_error(
+        &self,
+        packet: &MutableIpPacket<'_>,
+    ) -> Result<(), connlib_shared::Error> {
+        let packet = packet.as_immutable();
+        let Some(dst) = packet.icmp_error_packet().map(|p| p.destination()) else {
+            return Ok(());
+        };
+
+        if self
+            .filters
+            .longest_match(dst)
+            .is_some_and(|(_, filter)| filter.denies(Traffic::new(&packet)))
+        {
+            return Err(connlib_shared::Error::DstNotAllowed { dst });
+        }
+
+        Ok(())
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+}
+
+impl GatewayOnClient {
+    pub(crate) fn ensure_allowed_src(
+        &self,
+        packet: &MutableIpPacket,
     ) -> Result<(), connlib_shared::Error> {
         let src = packet.source();
 
@@ -524,10 +1315,16 @@ struct TranslationState {
     /// We don't want to immediately trigger a refresh in that case because protocols like TCP and ICMP have responses.
     /// Thus, a DNS refresh is triggered after a grace-period of 1s after the packet that detected the missing responses.
     ack_grace_period_started_at: Option<Instant>,
+    /// When we last acted upon this translation being expired.
+    ///
+    /// We only look at it again after [`MIN_DNS_REFRESH_INTERVAL`], unless it receives traffic in the meantime.
+    expiry_handled_at: Option<Instant>,
 }
 
 impl TranslationState {
     const USED_WINDOW: Duration = Duration::from_secs(10);
+    const ACK_GRACE_PERIOD: Duration = Duration::from_secs(1);
+    const CONNTRACK_UDP_STREAM_TIMEOUT: Duration = Duration::from_secs(120);
 
     fn new(resource_id: ResourceId, name: DomainName, resolved_ip: IpAddr, now: Instant) -> Self {
         Self {
@@ -539,6 +1336,7 @@ impl TranslationState {
             first_outgoing: None,
             last_outgoing: None,
             ack_grace_period_started_at: None,
+            expiry_handled_at: None,
         }
     }
 
@@ -547,26 +1345,49 @@ impl TranslationState {
         self.ack_grace_period_expired(now) && self.no_incoming_in_120s(now)
     }
 
+    /// When [`TranslationState::is_expired`] turns true, unless we receive traffic before.
+    fn expires_at(&self) -> Option<Instant> {
+        let grace_period_end = self.ack_grace_period_started_at? + Self::ACK_GRACE_PERIOD;
+        let silence_end =
+            self.last_incoming.unwrap_or(self.created_at) + Self::CONNTRACK_UDP_STREAM_TIMEOUT;
+
+        Some(grace_period_end.max(silence_end))
+    }
+
+    /// When we need to look at this translation again.
+    fn next_check(&self) -> Option<Instant> {
+        let expires_at = self.expires_at()?;
+
+        let Some(handled_at) = self.expiry_handled_at else {
+            return Some(expires_at);
+        };
+
+        Some(expires_at.max(handled_at + MIN_DNS_REFRESH_INTERVAL))
+    }
+
     fn ack_grace_period_expired(&self, now: Instant) -> bool {
         self.ack_grace_period_started_at
             .is_some_and(|missing_responses_detected_at| {
-                now.duration_since(missing_responses_detected_at) >= Duration::from_secs(1)
+                now.duration_since(missing_responses_detected_at) >= Self::ACK_GRACE_PERIOD
             })
     }
 
     fn no_incoming_in_120s(&self, now: Instant) -> bool {
-        const CONNTRACK_UDP_STREAM_TIMEOUT: Duration = Duration::from_secs(120);
-
         if let Some(last_incoming) = self.last_incoming {
-            now.duration_since(last_incoming) >= CONNTRACK_UDP_STREAM_TIMEOUT
+            now.duration_since(last_incoming) >= Self::CONNTRACK_UDP_STREAM_TIMEOUT
         } else {
-            now.duration_since(self.created_at) >= CONNTRACK_UDP_STREAM_TIMEOUT
+            now.duration_since(self.created_at) >= Self::CONNTRACK_UDP_STREAM_TIMEOUT
         }
     }
 
     fn on_incoming_traffic(&mut self, now: Instant) {
         self.last_incoming = Some(now);
         self.ack_grace_period_started_at = None;
+        self.expiry_handled_at = None;
+    }
+
+    fn on_expiry_handled(&mut self, now: Instant) {
+        self.expiry_handled_at = Some(now);
     }
 
     fn on_outgoing_traffic(&mut self, now: Instant) {
@@ -599,94 +1420,663 @@ pub struct ClientOnGateway {
     filters: IpNetworkTable<FilterEngine>,
     permanent_translations: HashMap<IpAddr, TranslationState>,
     nat_table: NatTable,
+    /// The flows opened by the client, only traffic belonging to these is allowed back to the client.
+    flow_table: FlowTable,
+    /// When to re-resolve a domain because the TTL of its records has expired.
+    dns_refresh_at: HashMap<(DomainName, ResourceId), Instant>,
+    /// Limits all traffic of the client.
+    rate_limiter: Option<RateLimiter>,
+    /// What we dropped because of the rate limits of resources since the last report.
+    ///
+    /// The limits themselves are shared by all clients, see [`ResourceRateLimiters`].
+    resource_drops: HashMap<ResourceId, Dropped>,
+    /// Which resource an IP belongs to, for the flow log and port mappings.
+    resource_ips: IpNetworkTable<ResourceId>,
+    /// The ports under which the client reaches the services of a resource.
+    port_mappings: HashMap<ResourceId, Vec<PortMapping>>,
+    /// How we spread the sessions to load-balanced resources across their addresses.
+    load_balancing: HashMap<ResourceId, LoadBalancing>,
+    unhealthy_backends: HashSet<(IpAddr, HealthProbe)>,
+    /// Which backend we pick next for a domain of a resource with [`LoadBalancingStrategy::RoundRobin`].
+    round_robin: HashMap<(DomainName, ResourceId), usize>,
+    /// When to report the packets we dropped because of rate limits or the NAT.
+    report_at: Option<Instant>,
     buffered_events: VecDeque<GatewayEvent>,
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
         time::{Duration, Instant},
     };
 
     use chrono::Utc;
     use connlib_shared::messages::{
-        gateway::{Filter, PortRange},
+        gateway::{
+            Filter, FilterAction, HealthCheck, HealthProbe, IcmpFilter, IcmpMessageType,
+            LoadBalancing, LoadBalancingStrategy, PortMapping, PortMappingProtocol, PortRange,
+            RateLimit, ResolvedResourceDescriptionDns, ResourceDescription,
+        },
         ClientId, ResourceId,
     };
+    use connlib_shared::DomainName;
     use ip_network::Ipv4Network;
+    use ip_packet::{ip::IpNextHeaderProtocols, MutableIpPacket, Packet as _, Protocol};
+    use itertools::Itertools as _;
 
-    use super::{ClientOnGateway, TranslationState};
+    use super::{ClientOnGateway, FlowVerdict, ResourceRateLimiters, TranslationState};
+    use crate::GatewayEvent;
 
     #[test]
     fn gateway_filters_expire_individually() {
         let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
-        let now = Utc::now();
-        let then = now + Duration::from_secs(10);
-        let after_then = then + Duration::from_secs(10);
+        let now = Utc::now();
+        let then = now + Duration::from_secs(10);
+        let after_then = then + Duration::from_secs(10);
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![Filter::Tcp(
+                PortRange {
+                    port_range_start: 20,
+                    port_range_end: 100,
+                }
+                .into(),
+            )],
+            Some(then),
+            None,
+        );
+
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource2_id(),
+            vec![Filter::Udp(
+                PortRange {
+                    port_range_start: 20,
+                    port_range_end: 100,
+                }
+                .into(),
+            )],
+            Some(after_then),
+            None,
+        );
+
+        let tcp_packet = ip_packet::make::tcp_packet(
+            source_v4_addr(),
+            cidr_v4_resource().hosts().next().unwrap(),
+            5401,
+            80,
+            vec![0; 100],
+        );
+
+        let udp_packet = ip_packet::make::udp_packet(
+            source_v4_addr(),
+            cidr_v4_resource().hosts().next().unwrap(),
+            5401,
+            80,
+            vec![0; 100],
+        );
+
+        assert!(peer.expire_resources(now).is_empty());
+        assert_eq!(peer.next_resource_expiry(), Some(then));
+
+        assert!(peer.ensure_allowed_dst(&tcp_packet).is_ok());
+        assert!(peer.ensure_allowed_dst(&udp_packet).is_ok());
+
+        assert_eq!(peer.expire_resources(then), vec![resource_id()]);
+        assert_eq!(peer.next_resource_expiry(), Some(after_then));
+
+        assert!(matches!(
+            peer.ensure_allowed_dst(&tcp_packet),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+        assert!(peer.ensure_allowed_dst(&udp_packet).is_ok());
+
+        assert_eq!(peer.expire_resources(after_then), vec![resource2_id()]);
+        assert_eq!(peer.next_resource_expiry(), None);
+
+        assert!(matches!(
+            peer.ensure_allowed_dst(&tcp_packet),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+        assert!(matches!(
+            peer.ensure_allowed_dst(&udp_packet),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn gateway_only_lets_replies_through_for_flows_of_allowed_resources() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![],
+            None,
+            None,
+        );
+
+        let request =
+            ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 80, vec![0; 10]);
+        let reply =
+            || ip_packet::make::udp_packet(resource_addr, source_v4_addr(), 80, 5401, vec![0; 10]);
+
+        assert!(matches!(
+            peer.encapsulate(reply(), &mut ResourceRateLimiters::default(), now),
+            Err(connlib_shared::Error::UnsolicitedPacket { .. })
+        ));
+
+        peer.decapsulate(request, &mut ResourceRateLimiters::default(), now)
+            .unwrap();
+
+        assert!(peer
+            .encapsulate(reply(), &mut ResourceRateLimiters::default(), now)
+            .unwrap()
+            .is_some());
+
+        peer.remove_resource(&resource_id());
+
+        assert!(matches!(
+            peer.encapsulate(reply(), &mut ResourceRateLimiters::default(), now),
+            Err(connlib_shared::Error::UnsolicitedPacket { .. })
+        ));
+    }
+
+    #[test]
+    fn gateway_allows_ping_but_blocks_icmp_redirects() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        let router = Ipv4Addr::new(10, 0, 0, 254);
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![
+                Filter::Icmp(IcmpFilter::default()),
+                Filter::Icmp(IcmpFilter {
+                    icmp_type: Some(IcmpMessageType::Redirect),
+                    icmp_code: None,
+                    action: FilterAction::Deny,
+                }),
+            ],
+            None,
+            None,
+        );
+
+        let ping =
+            ip_packet::make::icmp_request_packet(source_v4_addr().into(), resource_addr, 1, 42);
+        let pong =
+            ip_packet::make::icmp_reply_packet(resource_addr.into(), source_v4_addr(), 1, 42);
+        let unreachable =
+            ip_packet::make::icmp_destination_unreachable(router.into(), &ping.to_immutable());
+        let redirect = icmp_redirect(router.into(), source_v4_addr().into(), &ping);
+        let redirect_from_client =
+            icmp_redirect(source_v4_addr().into(), resource_addr.into(), &ping);
+
+        assert!(matches!(
+            peer.ensure_allowed_dst(&redirect_from_client),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+
+        peer.decapsulate(ping, &mut ResourceRateLimiters::default(), now)
+            .unwrap();
+
+        assert!(peer
+            .encapsulate(pong, &mut ResourceRateLimiters::default(), now)
+            .unwrap()
+            .is_some());
+        assert!(peer
+            .encapsulate(unreachable, &mut ResourceRateLimiters::default(), now)
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            peer.encapsulate(redirect, &mut ResourceRateLimiters::default(), now),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn gateway_maps_ports_of_resource() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![],
+            None,
+            None,
+        );
+        peer.set_resource_port_mappings(
+            resource_id(),
+            vec![PortMapping {
+                protocol: PortMappingProtocol::Tcp,
+                port: 5432,
+                target_port: 15432,
+            }],
+        );
+
+        let request = peer
+            .decapsulate(
+                ip_packet::make::tcp_packet(source_v4_addr(), resource_addr, 5401, 5432, vec![]),
+                &mut ResourceRateLimiters::default(),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            request.as_immutable().destination_protocol().unwrap(),
+            Protocol::Tcp(15432)
+        );
+
+        let reply = peer
+            .encapsulate(
+                ip_packet::make::tcp_packet(resource_addr, source_v4_addr(), 15432, 5401, vec![]),
+                &mut ResourceRateLimiters::default(),
+                now,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            reply.as_immutable().source_protocol().unwrap(),
+            Protocol::Tcp(5432)
+        );
+
+        // The target port is only reachable through the mapping and other protocols aren't mapped.
+        assert!(matches!(
+            peer.decapsulate(
+                ip_packet::make::tcp_packet(source_v4_addr(), resource_addr, 5401, 15432, vec![]),
+                &mut ResourceRateLimiters::default(),
+                now,
+            ),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+        let udp = peer
+            .decapsulate(
+                ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 5432, vec![]),
+                &mut ResourceRateLimiters::default(),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            udp.as_immutable().destination_protocol().unwrap(),
+            Protocol::Udp(5432)
+        );
+    }
+
+    #[test]
+    fn gateway_drops_traffic_exceeding_rate_limits_and_reports_it() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let mut resource_rate_limiters = ResourceRateLimiters::default();
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![],
+            None,
+            None,
+        );
+        resource_rate_limiters.set(
+            resource_id(),
+            Some(RateLimit {
+                packets_per_second: Some(1),
+                ..Default::default()
+            }),
+            now,
+        );
+        peer.set_rate_limit(
+            Some(RateLimit {
+                packets_per_second: Some(2),
+                ..Default::default()
+            }),
+            now,
+        );
+
+        let request =
+            || ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 80, vec![0; 10]);
+        let reply =
+            || ip_packet::make::udp_packet(resource_addr, source_v4_addr(), 80, 5401, vec![0; 10]);
+
+        peer.decapsulate(request(), &mut resource_rate_limiters, now)
+            .unwrap();
+        assert!(matches!(
+            peer.decapsulate(request(), &mut resource_rate_limiters, now),
+            Err(connlib_shared::Error::RateLimitExceeded)
+        ));
+
+        peer.decapsulate(
+            request(),
+            &mut resource_rate_limiters,
+            now + Duration::from_secs(1),
+        )
+        .unwrap();
+        // Replies count against the same limits.
+        assert!(matches!(
+            peer.encapsulate(
+                reply(),
+                &mut resource_rate_limiters,
+                now + Duration::from_secs(1)
+            ),
+            Err(connlib_shared::Error::RateLimitExceeded)
+        ));
+
+        peer.handle_timeout(now + Duration::from_secs(1));
+
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::RateLimited { resource_id: Some(r), dropped_packets: 2, .. }) if r == resource_id()
+        ));
+        assert!(peer.poll_event().is_none());
+    }
+
+    #[test]
+    fn traffic_dropped_by_client_rate_limit_does_not_drain_resource_rate_limit() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let mut resource_rate_limiters = ResourceRateLimiters::default();
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![],
+            None,
+            None,
+        );
+        resource_rate_limiters.set(
+            resource_id(),
+            Some(RateLimit {
+                packets_per_second: Some(2),
+                ..Default::default()
+            }),
+            now,
+        );
+        peer.set_rate_limit(
+            Some(RateLimit {
+                packets_per_second: Some(1),
+                ..Default::default()
+            }),
+            now,
+        );
+
+        let request =
+            || ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 80, vec![0; 10]);
+
+        peer.decapsulate(request(), &mut resource_rate_limiters, now)
+            .unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                peer.decapsulate(request(), &mut resource_rate_limiters, now),
+                Err(connlib_shared::Error::RateLimitExceeded)
+            ));
+        }
+
+        peer.handle_timeout(now + Duration::from_secs(1));
+
+        // All drops are attributed to the client's limit, the resource's limit didn't drop anything.
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::RateLimited {
+                resource_id: None,
+                dropped_packets: 5,
+                ..
+            })
+        ));
+        assert!(peer.poll_event().is_none());
+    }
+
+    #[test]
+    fn resource_rate_limit_is_shared_by_all_clients() {
+        let mut resource_rate_limiters = ResourceRateLimiters::default();
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        let mut alice = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let mut bob = ClientOnGateway::new(client2_id(), client2_v4_addr(), source_v6_addr());
+        for peer in [&mut alice, &mut bob] {
+            peer.add_resource(
+                vec![cidr_v4_resource().into()],
+                resource_id(),
+                vec![],
+                None,
+                None,
+            );
+        }
+        resource_rate_limiters.set(
+            resource_id(),
+            Some(RateLimit {
+                packets_per_second: Some(1),
+                ..Default::default()
+            }),
+            now,
+        );
+
+        alice
+            .decapsulate(
+                ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 80, vec![]),
+                &mut resource_rate_limiters,
+                now,
+            )
+            .unwrap();
+        assert!(matches!(
+            bob.decapsulate(
+                ip_packet::make::udp_packet(client2_v4_addr(), resource_addr, 5401, 80, vec![]),
+                &mut resource_rate_limiters,
+                now,
+            ),
+            Err(connlib_shared::Error::RateLimitExceeded)
+        ));
+
+        alice.handle_timeout(now + Duration::from_secs(1));
+        bob.handle_timeout(now + Duration::from_secs(1));
+
+        // Only the client whose packet we dropped reports it.
+        assert!(alice.poll_event().is_none());
+        assert!(matches!(
+            bob.poll_event(),
+            Some(GatewayEvent::RateLimited { resource_id: Some(r), dropped_packets: 1, .. }) if r == resource_id()
+        ));
+    }
+
+    #[test]
+    fn gateway_logs_allowed_and_denied_flows_of_resource() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let resource_addr = cidr_v4_resource().hosts().next().unwrap();
+        peer.set_flow_log(true);
         peer.add_resource(
             vec![cidr_v4_resource().into()],
             resource_id(),
-            vec![Filter::Tcp(PortRange {
-                port_range_start: 20,
-                port_range_end: 100,
-            })],
-            Some(then),
+            vec![Filter::Udp(
+                PortRange {
+                    port_range_start: 80,
+                    port_range_end: 80,
+                }
+                .into(),
+            )],
             None,
+            None,
+        );
+
+        let allowed =
+            ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 80, vec![0; 10]);
+        let denied =
+            ip_packet::make::udp_packet(source_v4_addr(), resource_addr, 5401, 443, vec![0; 10]);
+
+        peer.decapsulate(allowed, &mut ResourceRateLimiters::default(), now)
+            .unwrap();
+        peer.decapsulate(denied, &mut ResourceRateLimiters::default(), now)
+            .unwrap_err();
+
+        let mut records = peer
+            .end_flows()
+            .into_iter()
+            .map(|event| {
+                let GatewayEvent::FlowLogged { conn_id, record } = event else {
+                    panic!("Unexpected event: {event:?}");
+                };
+                assert_eq!(conn_id, client_id());
+                assert_eq!(record.resource_id, Some(resource_id()));
+
+                (record.dst_port, record.verdict)
+            })
+            .collect::<Vec<_>>();
+        records.sort_by_key(|(port, _)| *port);
+
+        assert_eq!(
+            records,
+            vec![
+                (Some(80), FlowVerdict::Allowed),
+                (Some(443), FlowVerdict::Denied)
+            ]
         );
+    }
 
+    #[test]
+    fn gateway_only_resolves_dns_queries_for_allowed_domains() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
         peer.add_resource(
-            vec![cidr_v4_resource().into()],
-            resource2_id(),
-            vec![Filter::Udp(PortRange {
-                port_range_start: 20,
-                port_range_end: 100,
-            })],
-            Some(after_then),
+            vec![],
+            resource_id(),
+            vec![],
             None,
+            Some(DomainName::vec_from_str("_ldap._tcp.corp.example").unwrap()),
         );
 
-        let tcp_packet = ip_packet::make::tcp_packet(
-            source_v4_addr(),
-            cidr_v4_resource().hosts().next().unwrap(),
-            5401,
-            80,
-            vec![0; 100],
-        );
+        let allowed = dns_query("_ldap._tcp.corp.example.");
+        let not_allowed = dns_query("_kerberos._udp.corp.example.");
 
-        let udp_packet = ip_packet::make::udp_packet(
-            source_v4_addr(),
-            cidr_v4_resource().hosts().next().unwrap(),
-            5401,
-            80,
-            vec![0; 100],
+        assert!(peer
+            .ensure_allowed_dns_query(&crate::dns::parse_forwarded_query(allowed).unwrap())
+            .is_ok());
+        assert!(matches!(
+            peer.ensure_allowed_dns_query(&crate::dns::parse_forwarded_query(not_allowed).unwrap()),
+            Err(connlib_shared::Error::DnsQueryNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn dns_is_refreshed_once_ttl_expires_and_retried_until_it_succeeds() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let name = DomainName::vec_from_str("gitlab.mycorp.internal").unwrap();
+        let resolved_ip = IpAddr::from(cidr_v4_resource().hosts().next().unwrap());
+        let now = Instant::now();
+
+        peer.add_resource(
+            vec![resolved_ip.into()],
+            resource_id(),
+            vec![],
+            None,
+            Some(name.clone()),
         );
+        peer.assign_proxies(
+            &ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                id: resource_id(),
+                domain: "gitlab.mycorp.internal".to_owned(),
+                name: "GitLab".to_owned(),
+                addresses: vec![resolved_ip],
+                ttl: Some(Duration::from_secs(300)),
+                filters: vec![],
+                rate_limit: None,
+                port_mappings: vec![],
+                load_balancing: None,
+            }),
+            Some((name.clone(), vec!["100.96.0.1".parse().unwrap()])),
+            now,
+        )
+        .unwrap();
 
-        peer.expire_resources(now);
+        peer.handle_timeout(now + Duration::from_secs(299));
+        assert!(peer.poll_event().is_none());
 
-        assert!(peer.ensure_allowed_dst(&tcp_packet).is_ok());
-        assert!(peer.ensure_allowed_dst(&udp_packet).is_ok());
+        peer.handle_timeout(now + Duration::from_secs(300));
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::RefreshDns { name: n, resource_id: r, .. }) if n == name && r == resource_id()
+        ));
 
-        peer.expire_resources(then);
+        peer.handle_timeout(now + Duration::from_secs(301));
+        assert!(peer.poll_event().is_none());
 
+        peer.handle_timeout(now + Duration::from_secs(330));
         assert!(matches!(
-            peer.ensure_allowed_dst(&tcp_packet),
-            Err(connlib_shared::Error::DstNotAllowed { .. })
+            peer.poll_event(),
+            Some(GatewayEvent::RefreshDns { .. })
         ));
-        assert!(peer.ensure_allowed_dst(&udp_packet).is_ok());
 
-        peer.expire_resources(after_then);
+        peer.refresh_translation(
+            name,
+            resource_id(),
+            vec![resolved_ip],
+            Some(Duration::from_secs(1)),
+            now + Duration::from_secs(331),
+        );
+
+        peer.handle_timeout(now + Duration::from_secs(360));
+        assert!(peer.poll_event().is_none());
 
+        peer.handle_timeout(now + Duration::from_secs(361));
         assert!(matches!(
-            peer.ensure_allowed_dst(&tcp_packet),
-            Err(connlib_shared::Error::DstNotAllowed { .. })
+            peer.poll_event(),
+            Some(GatewayEvent::RefreshDns { .. })
         ));
+    }
+
+    #[test]
+    fn gateway_spreads_new_sessions_across_healthy_backends_in_turn() {
+        let now = Instant::now();
+        let (mut peer, [backend1, backend2]) =
+            load_balanced_peer(LoadBalancingStrategy::RoundRobin, now);
+
+        assert_eq!(send_from_port(&mut peer, 5401, now).unwrap(), backend1);
+        assert_eq!(send_from_port(&mut peer, 5402, now).unwrap(), backend2);
+        assert_eq!(send_from_port(&mut peer, 5403, now).unwrap(), backend1);
+        // Sessions stick to their backend.
+        assert_eq!(send_from_port(&mut peer, 5402, now).unwrap(), backend2);
+
+        assert_eq!(
+            peer.health_checked_backends()
+                .map(|(ip, _)| ip)
+                .sorted()
+                .collect::<Vec<_>>(),
+            vec![backend1, backend2]
+        );
+
+        peer.set_unhealthy_backends(HashSet::from([(backend2, tcp_probe())]));
+        assert_eq!(send_from_port(&mut peer, 5402, now).unwrap(), backend1);
+        assert_eq!(send_from_port(&mut peer, 5404, now).unwrap(), backend1);
+
+        peer.set_unhealthy_backends(HashSet::from([
+            (backend1, tcp_probe()),
+            (backend2, tcp_probe()),
+        ]));
         assert!(matches!(
-            peer.ensure_allowed_dst(&udp_packet),
-            Err(connlib_shared::Error::DstNotAllowed { .. })
+            send_from_port(&mut peer, 5405, now),
+            Err(connlib_shared::Error::NoHealthyBackend)
         ));
     }
 
+    #[test]
+    fn gateway_picks_backend_with_fewest_sessions() {
+        let now = Instant::now();
+        let (mut peer, [backend1, backend2]) =
+            load_balanced_peer(LoadBalancingStrategy::LeastConnections, now);
+
+        assert_eq!(send_from_port(&mut peer, 5401, now).unwrap(), backend1);
+        assert_eq!(send_from_port(&mut peer, 5402, now).unwrap(), backend2);
+
+        send_from_port(&mut peer, 5402, now + Duration::from_secs(100)).unwrap();
+
+        let later = now + Duration::from_secs(121);
+        peer.handle_timeout(later); // Only the session to backend 1 expires.
+
+        assert_eq!(send_from_port(&mut peer, 5403, later).unwrap(), backend1);
+        assert_eq!(send_from_port(&mut peer, 5404, later).unwrap(), backend1);
+        assert_eq!(send_from_port(&mut peer, 5405, later).unwrap(), backend2);
+    }
+
     #[test]
     fn initial_translation_state_is_not_expired() {
         let now = Instant::now();
@@ -750,6 +2140,32 @@ mod tests {
 
         assert!(state.is_expired(now));
     }
+
+    #[test]
+    fn expired_translation_state_is_only_checked_again_after_refresh_interval() {
+        let mut now = Instant::now();
+        let mut state = TranslationState::new(
+            ResourceId::random(),
+            "example.com".parse().unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            now,
+        );
+        assert_eq!(state.next_check(), None);
+
+        now += Duration::from_secs(121);
+        state.on_outgoing_traffic(now);
+
+        now += Duration::from_secs(1);
+        assert_eq!(state.next_check(), Some(now));
+        assert!(state.is_expired(now));
+
+        state.on_expiry_handled(now);
+        assert_eq!(state.next_check(), Some(now + Duration::from_secs(30)));
+
+        state.on_incoming_traffic(now + Duration::from_secs(1));
+        assert_eq!(state.next_check(), None);
+    }
+
     #[test]
     fn translation_state_is_not_expired_with_incoming_packets() {
         let mut now = Instant::now();
@@ -954,6 +2370,95 @@ mod tests {
         assert!(state.is_expired(now));
     }
 
+    fn dns_query(name: &str) -> ip_packet::IpPacket<'static> {
+        ip_packet::make::dns_query(
+            hickory_proto::rr::Name::from_ascii(name).unwrap(),
+            hickory_proto::rr::RecordType::SRV,
+            SocketAddr::new(source_v4_addr().into(), 9999),
+            "100.100.111.1:53".parse().unwrap(),
+            0,
+        )
+        .into_immutable()
+    }
+
+    /// A client with access to a DNS resource that resolves to two backends, which it reaches via a single proxy IP.
+    fn load_balanced_peer(
+        strategy: LoadBalancingStrategy,
+        now: Instant,
+    ) -> (ClientOnGateway, [IpAddr; 2]) {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let name = DomainName::vec_from_str("api.mycorp.internal").unwrap();
+        let backends = cidr_v4_resource()
+            .hosts()
+            .take(2)
+            .map(IpAddr::from)
+            .collect::<Vec<_>>();
+        let load_balancing = LoadBalancing {
+            strategy,
+            health_check: Some(HealthCheck {
+                probe: tcp_probe(),
+                interval_secs: 10,
+                timeout_secs: 2,
+                unhealthy_threshold: 3,
+                healthy_threshold: 2,
+            }),
+        };
+
+        peer.add_resource(
+            backends.iter().copied().map_into().collect(),
+            resource_id(),
+            vec![],
+            None,
+            Some(name.clone()),
+        );
+        peer.set_resource_load_balancing(resource_id(), Some(load_balancing));
+        peer.assign_proxies(
+            &ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                id: resource_id(),
+                domain: "api.mycorp.internal".to_owned(),
+                name: "API".to_owned(),
+                addresses: backends.clone(),
+                ttl: None,
+                filters: vec![],
+                rate_limit: None,
+                port_mappings: vec![],
+                load_balancing: Some(load_balancing),
+            }),
+            Some((name, vec![proxy_ip()])),
+            now,
+        )
+        .unwrap();
+
+        (peer, [backends[0], backends[1]])
+    }
+
+    /// Sends a UDP packet from the given port of the client to the proxy IP and returns which backend it was translated to.
+    fn send_from_port(
+        peer: &mut ClientOnGateway,
+        port: u16,
+        now: Instant,
+    ) -> Result<IpAddr, connlib_shared::Error> {
+        let packet = ip_packet::make::udp_packet(
+            IpAddr::from(source_v4_addr()),
+            proxy_ip(),
+            port,
+            443,
+            vec![],
+        );
+
+        Ok(peer
+            .decapsulate(packet, &mut ResourceRateLimiters::default(), now)?
+            .destination())
+    }
+
+    fn proxy_ip() -> IpAddr {
+        "100.96.0.1".parse().unwrap()
+    }
+
+    fn tcp_probe() -> HealthProbe {
+        HealthProbe::Tcp { port: 443 }
+    }
+
     fn source_v4_addr() -> Ipv4Addr {
         "100.64.0.1".parse().unwrap()
     }
@@ -974,24 +2479,59 @@ mod tests {
         "ed29c148-2acf-4ceb-8db5-d796c2671631".parse().unwrap()
     }
 
+    /// Makes an ICMP redirect that embeds the IP header and the first 8 bytes of the payload of `original`, like a router would.
+    fn icmp_redirect(
+        src: IpAddr,
+        dst: IpAddr,
+        original: &MutableIpPacket<'_>,
+    ) -> MutableIpPacket<'static> {
+        let original = original.to_immutable();
+        let embedded = &original.packet()[..20 + 8];
+        let payload = [&[5, 1, 0, 0, 0, 0, 0, 0][..], embedded].concat(); // Redirect for host.
+
+        ip_packet::make::ip_packet(src, dst, IpNextHeaderProtocols::Icmp, &payload)
+    }
+
     fn client_id() -> ClientId {
         "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap()
     }
+
+    fn client2_id() -> ClientId {
+        "ed29c148-2acf-4ceb-8db5-d796c2671631".parse().unwrap()
+    }
+
+    fn client2_v4_addr() -> Ipv4Addr {
+        "100.64.0.2".parse().unwrap()
+    }
 }
 
 #[cfg(all(test, feature = "proptest"))]
 mod proptests {
     use super::*;
-    use connlib_shared::{messages::gateway::PortRange, proptest::*};
-    use ip_packet::make::{icmp_request_packet, tcp_packet, udp_packet};
+    use connlib_shared::{
+        messages::gateway::{PortRange, ProtocolFilter},
+        proptest::*,
+    };
+    use ip_packet::make::{ip_packet, tcp_packet, udp_packet};
     use proptest::{
         arbitrary::any,
-        collection, prop_oneof,
+        collection, option, prop_oneof,
         sample::select,
-        strategy::{Just, Strategy},
+        strategy::{BoxedStrategy, Just, Strategy, Union},
     };
     use std::ops::RangeInclusive;
-    use test_strategy::Arbitrary;
+
+    /// The ICMP message types we generate packets for.
+    ///
+    /// [`IcmpMessageType::PacketTooBig`] only exists in ICMPv6, so we leave it out.
+    const ICMP_MESSAGE_TYPES: [IcmpMessageType; 6] = [
+        IcmpMessageType::EchoRequest,
+        IcmpMessageType::EchoReply,
+        IcmpMessageType::DestinationUnreachable,
+        IcmpMessageType::TimeExceeded,
+        IcmpMessageType::ParameterProblem,
+        IcmpMessageType::Redirect,
+    ];
 
     #[test_strategy::proptest()]
     fn gateway_accepts_allowed_packet(
@@ -1003,7 +2543,6 @@ mod proptests {
         #[strategy(collection::vec(filters_with_allowed_protocol(), 1..=5))] protocol_config: Vec<
             (Filters, Protocol),
         >,
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr, dest) = config;
@@ -1037,11 +2576,7 @@ mod proptests {
         }
 
         for (_, protocol) in &protocol_config[0..resources] {
-            let packet = match protocol {
-                Protocol::Tcp { dport } => tcp_packet(src, dest, sport, *dport, payload.clone()),
-                Protocol::Udp { dport } => udp_packet(src, dest, sport, *dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-            };
+            let packet = packet(src, dest, *protocol, payload.clone());
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
         }
     }
@@ -1054,7 +2589,6 @@ mod proptests {
         #[strategy(any::<Ipv6Addr>())] src_v6: Ipv6Addr,
         #[strategy(collection::vec(cidr_with_host(), 1..=5))] config: Vec<(IpNetwork, IpAddr)>,
         #[strategy(filters_with_allowed_protocol())] protocol_config: (Filters, Protocol),
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr, dest): (Vec<_>, Vec<_>) = config.into_iter().unzip();
@@ -1069,11 +2603,7 @@ mod proptests {
             } else {
                 src_v6.into()
             };
-            let packet = match protocol {
-                Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-            };
+            let packet = packet(src, dest, protocol, payload.clone());
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
         }
     }
@@ -1093,7 +2623,6 @@ mod proptests {
             IpAddr,
         )>,
         #[strategy(filters_with_allowed_protocol())] protocol_config: (Filters, Protocol),
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr_1, dest_1): (Vec<_>, Vec<_>) = config_res_1.into_iter().unzip();
@@ -1110,11 +2639,7 @@ mod proptests {
             } else {
                 src_v6.into()
             };
-            let packet = match protocol {
-                Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-            };
+            let packet = packet(src, dest, protocol, payload.clone());
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
         }
 
@@ -1124,11 +2649,7 @@ mod proptests {
             } else {
                 src_v6.into()
             };
-            let packet = match protocol {
-                Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-            };
+            let packet = packet(src, dest, protocol, payload.clone());
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
         }
     }
@@ -1143,7 +2664,6 @@ mod proptests {
         #[strategy(collection::vec(filters_with_allowed_protocol(), 1..=10))] protocol_config: Vec<
             (Filters, Protocol),
         >,
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr, dest) = config;
@@ -1166,11 +2686,7 @@ mod proptests {
         }
 
         for (_, protocol) in protocol_config {
-            let packet = match protocol {
-                Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-            };
+            let packet = packet(src, dest, protocol, payload.clone());
 
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
         }
@@ -1184,7 +2700,6 @@ mod proptests {
         #[strategy(any::<Ipv6Addr>())] src_v6: Ipv6Addr,
         #[strategy(cidr_with_host())] config: (IpNetwork, IpAddr),
         #[strategy(filters_with_rejected_protocol())] protocol_config: (Filters, Protocol),
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr, dest) = config;
@@ -1196,12 +2711,50 @@ mod proptests {
         let (filters, protocol) = protocol_config;
         // This test could be extended to test multiple src
         let mut peer = ClientOnGateway::new(client_id, src_v4, src_v6);
-        let packet = match protocol {
-            Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload),
-            Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
+        let packet = packet(src, dest, protocol, payload);
+
+        peer.add_resource(vec![resource_addr], resource_id, filters, None, None);
+
+        assert!(matches!(
+            peer.ensure_allowed_dst(&packet),
+            Err(connlib_shared::Error::DstNotAllowed { .. })
+        ));
+    }
+
+    #[test_strategy::proptest()]
+    fn gateway_rejects_denied_packet(
+        #[strategy(client_id())] client_id: ClientId,
+        #[strategy(resource_id())] resource_id: ResourceId,
+        #[strategy(resource_id())] resource_id_deny: ResourceId,
+        #[strategy(any::<Ipv4Addr>())] src_v4: Ipv4Addr,
+        #[strategy(any::<Ipv6Addr>())] src_v6: Ipv6Addr,
+        #[strategy(cidr_with_host())] config: (IpNetwork, IpAddr),
+        #[strategy(filters_with_allowed_protocol())] protocol_config: (Filters, Protocol),
+        #[strategy(any::<bool>())] deny_in_other_resource: bool,
+        #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
+    ) {
+        let (resource_addr, dest) = config;
+        let src = if dest.is_ipv4() {
+            src_v4.into()
+        } else {
+            src_v6.into()
         };
+        let (mut filters, protocol) = protocol_config;
+        let mut peer = ClientOnGateway::new(client_id, src_v4, src_v6);
+        let packet = packet(src, dest, protocol, payload);
 
+        // Deny rules take precedence, regardless of whether they come from the same resource or another one.
+        if deny_in_other_resource {
+            peer.add_resource(
+                vec![resource_addr],
+                resource_id_deny,
+                vec![deny_filter(protocol)],
+                None,
+                None,
+            );
+        } else {
+            filters.push(deny_filter(protocol));
+        }
         peer.add_resource(vec![resource_addr], resource_id, filters, None, None);
 
         assert!(matches!(
@@ -1222,7 +2775,6 @@ mod proptests {
             (Filters, Protocol),
             (Filters, Protocol),
         ),
-        #[strategy(any::<u16>())] sport: u16,
         #[strategy(any::<Vec<u8>>())] payload: Vec<u8>,
     ) {
         let (resource_addr, dest) = config;
@@ -1236,17 +2788,8 @@ mod proptests {
         // This test could be extended to test multiple src
         let mut peer = ClientOnGateway::new(client_id, src_v4, src_v6);
 
-        let packet_allowed = match protocol_allowed {
-            Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
-            Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-        };
-
-        let packet_rejected = match protocol_removed {
-            Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload),
-            Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0),
-        };
+        let packet_allowed = packet(src, dest, protocol_allowed, payload.clone());
+        let packet_rejected = packet(src, dest, protocol_removed, payload);
 
         peer.add_resource(
             vec![supernet(resource_addr).unwrap_or(resource_addr)],
@@ -1279,7 +2822,7 @@ mod proptests {
     fn filters_with_allowed_protocol() -> impl Strategy<Value = (Filters, Protocol)> {
         filters().prop_flat_map(|filters| {
             if filters.is_empty() {
-                any::<Protocol>().prop_map(|p| (vec![], p)).boxed()
+                protocol().prop_map(|p| (vec![], p)).boxed()
             } else {
                 select(filters.clone())
                     .prop_flat_map(move |filter| {
@@ -1316,41 +2859,58 @@ mod proptests {
     fn filters_with_rejected_protocol() -> impl Strategy<Value = (Filters, Protocol)> {
         filters()
             .prop_filter("empty filters accepts every packet", |f| !f.is_empty())
+            .prop_filter("filters need to leave a gap", |f| {
+                !rejected_protocols(f).is_empty()
+            })
             .prop_flat_map(|f| {
-                let filters = f.clone();
-                any::<ProtocolKind>()
-                    .prop_filter_map(
-                        "If ICMP is contained there is no way to generate gaps",
-                        move |p| {
-                            (p != ProtocolKind::Icmp || !filters.contains(&Filter::Icmp))
-                                .then_some(p)
-                        },
-                    )
-                    .prop_flat_map(move |p| {
-                        if p == ProtocolKind::Icmp {
-                            Just((f.clone(), Protocol::Icmp)).boxed()
-                        } else {
-                            let f = f.clone();
-                            select(gaps(f.clone(), p))
-                                .prop_flat_map(move |g| {
-                                    let f = f.clone();
-                                    g.prop_map(move |dport| (f.clone(), p.into_protocol(dport)))
-                                })
-                                .boxed()
-                        }
-                    })
+                let rejected = Union::new(rejected_protocols(&f));
+
+                (Just(f), rejected)
             })
     }
 
-    fn gaps(filters: Filters, protocol: ProtocolKind) -> Vec<RangeInclusive<u16>> {
+    /// Strategies for all protocols that none of the filters allow.
+    fn rejected_protocols(filters: &Filters) -> Vec<BoxedStrategy<Protocol>> {
+        let mut rejected = Vec::new();
+
+        for kind in [ProtocolKind::Tcp, ProtocolKind::Udp, ProtocolKind::Sctp] {
+            for gap in gaps(filters, kind) {
+                rejected.push(
+                    (any::<u16>(), gap)
+                        .prop_map(move |(sport, dport)| kind.into_protocol(sport, dport))
+                        .boxed(),
+                );
+            }
+        }
+
+        for message in unused_icmp_message_types(filters) {
+            rejected.push(
+                any::<u8>()
+                    .prop_map(move |code| Protocol::Icmp { message, code })
+                    .boxed(),
+            );
+        }
+
+        if !filters.iter().any(|f| matches!(f, Filter::Gre(_))) {
+            rejected.push(Just(Protocol::Gre).boxed());
+        }
+
+        if !filters.iter().any(|f| matches!(f, Filter::Esp(_))) {
+            rejected.push(Just(Protocol::Esp).boxed());
+        }
+
+        rejected
+    }
+
+    /// The destination ports that none of the filters for the given protocol cover, regardless of their source ports.
+    fn gaps(filters: &Filters, protocol: ProtocolKind) -> Vec<RangeInclusive<u16>> {
         filters
-            .into_iter()
+            .iter()
             .filter_map(|f| match (f, protocol) {
-                (Filter::Udp(inner), ProtocolKind::Udp) => {
-                    Some(inner.port_range_start..=inner.port_range_end)
-                }
-                (Filter::Tcp(inner), ProtocolKind::Tcp) => {
-                    Some(inner.port_range_start..=inner.port_range_end)
+                (Filter::Udp(inner), ProtocolKind::Udp)
+                | (Filter::Tcp(inner), ProtocolKind::Tcp)
+                | (Filter::Sctp(inner), ProtocolKind::Sctp) => {
+                    Some(inner.ports.port_range_start..=inner.ports.port_range_end)
                 }
                 (_, _) => None,
             })
@@ -1359,41 +2919,90 @@ mod proptests {
             .collect_vec()
     }
 
+    /// The ICMP message types that none of the filters refer to.
+    fn unused_icmp_message_types(filters: &Filters) -> Vec<IcmpMessageType> {
+        let icmp_filters = filters
+            .iter()
+            .filter_map(|f| {
+                if let Filter::Icmp(inner) = f {
+                    Some(inner)
+                } else {
+                    None
+                }
+            })
+            .collect_vec();
+
+        if icmp_filters.iter().any(|f| f.icmp_type.is_none()) {
+            return vec![];
+        }
+
+        ICMP_MESSAGE_TYPES
+            .into_iter()
+            .filter(|message| !icmp_filters.iter().any(|f| f.icmp_type == Some(*message)))
+            .collect()
+    }
+
     fn protocol_from_filter(f: Filter) -> impl Strategy<Value = Protocol> {
         match f {
-            Filter::Udp(PortRange {
-                port_range_end,
-                port_range_start,
-            }) => (port_range_start..=port_range_end)
-                .prop_map(|dport| Protocol::Udp { dport })
-                .boxed(),
-            Filter::Tcp(PortRange {
-                port_range_end,
-                port_range_start,
-            }) => (port_range_start..=port_range_end)
-                .prop_map(|dport| Protocol::Tcp { dport })
+            Filter::Udp(filter) => port_protocol(filter, ProtocolKind::Udp).boxed(),
+            Filter::Tcp(filter) => port_protocol(filter, ProtocolKind::Tcp).boxed(),
+            Filter::Sctp(filter) => port_protocol(filter, ProtocolKind::Sctp).boxed(),
+            Filter::Icmp(IcmpFilter {
+                icmp_type: None, ..
+            }) => icmp_protocol().boxed(),
+            Filter::Icmp(IcmpFilter {
+                icmp_type: Some(message),
+                icmp_code: Some(code),
+                ..
+            }) => Just(Protocol::Icmp { message, code }).boxed(),
+            Filter::Icmp(IcmpFilter {
+                icmp_type: Some(message),
+                icmp_code: None,
+                ..
+            }) => any::<u8>()
+                .prop_map(move |code| Protocol::Icmp { message, code })
                 .boxed(),
-            Filter::Icmp => Just(Protocol::Icmp).boxed(),
+            Filter::Gre(_) => Just(Protocol::Gre).boxed(),
+            Filter::Esp(_) => Just(Protocol::Esp).boxed(),
         }
     }
 
-    fn filters_in_gaps(filters: Filters) -> impl Strategy<Value = Filters> {
-        let contains_icmp_filter = filters.contains(&Filter::Icmp);
+    fn port_protocol(filter: PortFilter, kind: ProtocolKind) -> impl Strategy<Value = Protocol> {
+        let sports = filter
+            .source_ports
+            .map_or(0..=u16::MAX, |r| r.port_range_start..=r.port_range_end);
+        let dports = filter.ports.port_range_start..=filter.ports.port_range_end;
 
-        let ranges_without_tcp_filter = gaps(filters.clone(), ProtocolKind::Tcp);
-        let tcp_filters = filter_from_vec(ranges_without_tcp_filter, ProtocolKind::Tcp);
+        (sports, dports).prop_map(move |(sport, dport)| kind.into_protocol(sport, dport))
+    }
 
-        let ranges_without_udp_filter = gaps(filters, ProtocolKind::Udp);
-        let udp_filters = filter_from_vec(ranges_without_udp_filter, ProtocolKind::Udp);
+    fn filters_in_gaps(filters: Filters) -> impl Strategy<Value = Filters> {
+        let tcp_filters = filter_from_vec(gaps(&filters, ProtocolKind::Tcp), ProtocolKind::Tcp);
+        let udp_filters = filter_from_vec(gaps(&filters, ProtocolKind::Udp), ProtocolKind::Udp);
+        let sctp_filters = filter_from_vec(gaps(&filters, ProtocolKind::Sctp), ProtocolKind::Sctp);
 
-        let icmp_filter = if contains_icmp_filter {
-            Just(vec![])
-        } else {
-            Just(vec![Filter::Icmp])
-        };
+        let mut other_filters = unused_icmp_message_types(&filters)
+            .into_iter()
+            .map(|message| {
+                Filter::Icmp(IcmpFilter {
+                    icmp_type: Some(message),
+                    icmp_code: None,
+                    action: FilterAction::Allow,
+                })
+            })
+            .collect_vec();
+        if !filters.iter().any(|f| matches!(f, Filter::Gre(_))) {
+            other_filters.push(Filter::Gre(ProtocolFilter::default()));
+        }
+        if !filters.iter().any(|f| matches!(f, Filter::Esp(_))) {
+            other_filters.push(Filter::Esp(ProtocolFilter::default()));
+        }
 
-        (tcp_filters, udp_filters, icmp_filter)
-            .prop_map(|(udp, tcp, icmp)| Vec::from_iter(tcp.into_iter().chain(udp).chain(icmp)))
+        (tcp_filters, udp_filters, sctp_filters, Just(other_filters)).prop_map(
+            |(tcp, udp, sctp, other)| {
+                Vec::from_iter(tcp.into_iter().chain(udp).chain(sctp).chain(other))
+            },
+        )
     }
 
     fn filter_from_vec(
@@ -1419,14 +3028,38 @@ mod proptests {
     fn filters() -> impl Strategy<Value = Filters> {
         collection::vec(
             prop_oneof![
-                Just(Filter::Icmp),
-                port_range().prop_map(Filter::Udp),
-                port_range().prop_map(Filter::Tcp),
+                icmp_filter().prop_map(Filter::Icmp),
+                port_filter().prop_map(Filter::Udp),
+                port_filter().prop_map(Filter::Tcp),
+                port_filter().prop_map(Filter::Sctp),
+                Just(Filter::Gre(ProtocolFilter::default())),
+                Just(Filter::Esp(ProtocolFilter::default())),
             ],
             0..=100,
         )
     }
 
+    fn port_filter() -> impl Strategy<Value = PortFilter> {
+        (port_range(), option::of(port_range())).prop_map(|(ports, source_ports)| PortFilter {
+            ports,
+            source_ports,
+            action: FilterAction::Allow,
+        })
+    }
+
+    fn icmp_filter() -> impl Strategy<Value = IcmpFilter> {
+        prop_oneof![
+            Just(IcmpFilter::default()),
+            (icmp_message_type(), option::of(any::<u8>())).prop_map(|(message, code)| {
+                IcmpFilter {
+                    icmp_type: Some(message),
+                    icmp_code: code,
+                    action: FilterAction::Allow,
+                }
+            }),
+        ]
+    }
+
     fn port_range() -> impl Strategy<Value = PortRange> {
         any::<u16>().prop_flat_map(|s| {
             (s..=u16::MAX).prop_map(move |d| PortRange {
@@ -1436,6 +3069,111 @@ mod proptests {
         })
     }
 
+    fn icmp_message_type() -> impl Strategy<Value = IcmpMessageType> {
+        select(ICMP_MESSAGE_TYPES.to_vec())
+    }
+
+    fn protocol() -> impl Strategy<Value = Protocol> {
+        prop_oneof![
+            (any::<u16>(), any::<u16>()).prop_map(|(sport, dport)| Protocol::Tcp { sport, dport }),
+            (any::<u16>(), any::<u16>()).prop_map(|(sport, dport)| Protocol::Udp { sport, dport }),
+            (any::<u16>(), any::<u16>()).prop_map(|(sport, dport)| Protocol::Sctp { sport, dport }),
+            icmp_protocol(),
+            Just(Protocol::Gre),
+            Just(Protocol::Esp),
+        ]
+    }
+
+    fn icmp_protocol() -> impl Strategy<Value = Protocol> {
+        (icmp_message_type(), any::<u8>())
+            .prop_map(|(message, code)| Protocol::Icmp { message, code })
+    }
+
+    /// A filter that denies exactly the given protocol.
+    fn deny_filter(protocol: Protocol) -> Filter {
+        let port_filter = |port: u16| PortFilter {
+            ports: PortRange {
+                port_range_start: port,
+                port_range_end: port,
+            },
+            source_ports: None,
+            action: FilterAction::Deny,
+        };
+        let deny = ProtocolFilter {
+            action: FilterAction::Deny,
+        };
+
+        match protocol {
+            Protocol::Tcp { dport, .. } => Filter::Tcp(port_filter(dport)),
+            Protocol::Udp { dport, .. } => Filter::Udp(port_filter(dport)),
+            Protocol::Sctp { dport, .. } => Filter::Sctp(port_filter(dport)),
+            Protocol::Icmp { message, code } => Filter::Icmp(IcmpFilter {
+                icmp_type: Some(message),
+                icmp_code: Some(code),
+                action: FilterAction::Deny,
+            }),
+            Protocol::Gre => Filter::Gre(deny),
+            Protocol::Esp => Filter::Esp(deny),
+        }
+    }
+
+    fn packet(
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: Protocol,
+        payload: Vec<u8>,
+    ) -> MutableIpPacket<'static> {
+        match protocol {
+            Protocol::Tcp { sport, dport } => tcp_packet(src, dst, sport, dport, payload),
+            Protocol::Udp { sport, dport } => udp_packet(src, dst, sport, dport, payload),
+            Protocol::Sctp { sport, dport } => {
+                // Common header: ports, verification tag and checksum.
+                let header = [&sport.to_be_bytes()[..], &dport.to_be_bytes(), &[0; 8]].concat();
+
+                ip_packet(
+                    src,
+                    dst,
+                    IpNextHeaderProtocols::Sctp,
+                    &[header, payload].concat(),
+                )
+            }
+            Protocol::Icmp { message, code } => {
+                let (next_header, ty) = match src {
+                    IpAddr::V4(_) => (IpNextHeaderProtocols::Icmp, icmpv4_type(message)),
+                    IpAddr::V6(_) => (IpNextHeaderProtocols::Icmpv6, icmpv6_type(message)),
+                };
+
+                ip_packet(src, dst, next_header, &[ty, code, 0, 0, 0, 1, 0, 1])
+            }
+            Protocol::Gre => ip_packet(src, dst, IpNextHeaderProtocols::Gre, &payload),
+            Protocol::Esp => ip_packet(src, dst, IpNextHeaderProtocols::Esp, &payload),
+        }
+    }
+
+    fn icmpv4_type(message: IcmpMessageType) -> u8 {
+        match message {
+            IcmpMessageType::EchoReply => 0,
+            IcmpMessageType::DestinationUnreachable => 3,
+            IcmpMessageType::Redirect => 5,
+            IcmpMessageType::EchoRequest => 8,
+            IcmpMessageType::TimeExceeded => 11,
+            IcmpMessageType::ParameterProblem => 12,
+            IcmpMessageType::PacketTooBig => unreachable!("only exists in ICMPv6"),
+        }
+    }
+
+    fn icmpv6_type(message: IcmpMessageType) -> u8 {
+        match message {
+            IcmpMessageType::DestinationUnreachable => 1,
+            IcmpMessageType::PacketTooBig => 2,
+            IcmpMessageType::TimeExceeded => 3,
+            IcmpMessageType::ParameterProblem => 4,
+            IcmpMessageType::EchoRequest => 128,
+            IcmpMessageType::EchoReply => 129,
+            IcmpMessageType::Redirect => 137,
+        }
+    }
+
     fn supernet(ip: IpNetwork) -> Option<IpNetwork> {
         match ip {
             IpNetwork::V4(v4) => v4.supernet().map(Into::into),
@@ -1443,50 +3181,43 @@ mod proptests {
         }
     }
 
-    #[derive(Debug, Clone, Copy, Arbitrary)]
+    #[derive(Debug, Clone, Copy)]
     enum Protocol {
-        Tcp { dport: u16 },
-        Udp { dport: u16 },
-        Icmp,
+        Tcp { sport: u16, dport: u16 },
+        Udp { sport: u16, dport: u16 },
+        Sctp { sport: u16, dport: u16 },
+        Icmp { message: IcmpMessageType, code: u8 },
+        Gre,
+        Esp,
     }
 
-    impl From<&Filter> for ProtocolKind {
-        fn from(value: &Filter) -> Self {
-            match value {
-                Filter::Udp(_) => ProtocolKind::Udp,
-                Filter::Tcp(_) => ProtocolKind::Tcp,
-                Filter::Icmp => ProtocolKind::Icmp,
-            }
-        }
-    }
-
-    #[derive(Debug, Clone, Copy, Arbitrary, PartialEq, Eq)]
+    /// Protocols with ports.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     enum ProtocolKind {
         Tcp,
         Udp,
-        Icmp,
+        Sctp,
     }
 
     impl ProtocolKind {
-        fn into_protocol(self, dport: u16) -> Protocol {
+        fn into_protocol(self, sport: u16, dport: u16) -> Protocol {
             match self {
-                ProtocolKind::Tcp => Protocol::Tcp { dport },
-                ProtocolKind::Udp => Protocol::Udp { dport },
-                ProtocolKind::Icmp => Protocol::Icmp,
+                ProtocolKind::Tcp => Protocol::Tcp { sport, dport },
+                ProtocolKind::Udp => Protocol::Udp { sport, dport },
+                ProtocolKind::Sctp => Protocol::Sctp { sport, dport },
             }
         }
 
         fn into_filter(self, range: RangeInclusive<u16>) -> Filter {
+            let filter = PortFilter::from(PortRange {
+                port_range_start: *range.start(),
+                port_range_end: *range.end(),
+            });
+
             match self {
-                ProtocolKind::Tcp => Filter::Tcp(PortRange {
-                    port_range_start: *range.start(),
-                    port_range_end: *range.end(),
-                }),
-                ProtocolKind::Udp => Filter::Udp(PortRange {
-                    port_range_start: *range.start(),
-                    port_range_end: *range.end(),
-                }),
-                ProtocolKind::Icmp => Filter::Icmp,
+                ProtocolKind::Tcp => Filter::Tcp(filter),
+                ProtocolKind::Udp => Filter::Udp(filter),
+                ProtocolKind::Sctp => Filter::Sctp(filter),
             }
         }
     }
@@ -0,0 +1,944 @@
+//! A conntrack-style flow table that only lets traffic from resources through if it belongs to a flow the client opened.
+//!
+//! Flows are keyed by the packets as they leave the gateway towards the resource, i.e. after the NAT but with the ports the client connected to, before any port mapping.
+//! TCP flows follow the handshake and teardown of the connection, all other flows are pseudo-flows that expire after some inactivity.
+//! ICMP errors are matched against the flow of the packet they embed.
+//!
+//! If the flow log is enabled, we also track the flows that the filters rejected and emit a [`FlowRecord`] for every flow that ends.
+use super::Traffic;
+use crate::utils::earliest;
+use connlib_shared::messages::gateway::IcmpMessageType;
+use connlib_shared::messages::ResourceId;
+use ip_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use ip_packet::tcp::TcpFlags;
+use ip_packet::{IpPacket, Packet as _};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long we wait for the resource to answer a SYN.
+const TCP_SYN_SENT_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long an established TCP connection may be idle, see <https://www.rfc-editor.org/rfc/rfc5382#section-5>.
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440);
+/// How long we keep a TCP connection around after either side sent a FIN.
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long we keep flows of protocols other than TCP, UDP and ICMP around, e.g. SCTP, GRE and ESP.
+const OTHER_TIMEOUT: Duration = Duration::from_secs(120);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+/// After how much inactivity we report the packets of a denied flow.
+const DENIED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The max. number of flows we track for a client at once, further flows are refused until some of them end.
+///
+/// Like [`MAX_DENIED_FLOWS`], this keeps a single client from exhausting our memory.
+const MAX_FLOWS: usize = 65_536;
+/// The max. number of denied flows we track at once, a client scanning a resource shouldn't be able to exhaust our memory.
+const MAX_DENIED_FLOWS: usize = 10_000;
+/// The max. number of records we buffer before dropping the oldest ones.
+const MAX_BUFFERED_RECORDS: usize = 1000;
+
+#[derive(Debug, Default)]
+pub(crate) struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    /// Flows whose packets the filters rejected, only tracked if the flow log is enabled.
+    denied: HashMap<FlowKey, Flow>,
+    /// No flow expires before this, we only look at them again once it has passed.
+    next_expiry: Option<Instant>,
+
+    log: FlowLog,
+}
+
+/// Identifies a flow from the client's side, i.e. `src` is the client and `dst` the resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct FlowKey {
+    pub(crate) src: IpAddr,
+    pub(crate) dst: IpAddr,
+    pub(crate) protocol: FlowProtocol,
+}
+
+/// The protocol of a flow and the ports as seen from the client's side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum FlowProtocol {
+    Tcp {
+        sport: u16,
+        dport: u16,
+    },
+    Udp {
+        sport: u16,
+        dport: u16,
+    },
+    Sctp {
+        sport: u16,
+        dport: u16,
+    },
+    /// ICMP echo requests and their replies, identified by the identifier of the request.
+    IcmpEcho {
+        id: u16,
+    },
+    /// Protocols without ports, e.g. GRE and ESP.
+    Other(IpNextHeaderProtocol),
+}
+
+/// A flow between a client and a resource that ended, either because it was closed, expired or is no longer allowed.
+///
+/// Addresses and ports are the ones of the packets as they leave the gateway towards the resource, i.e. after the NAT of DNS resources.
+/// For resources with port mappings, the destination port is the one the client connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    /// The resource whose addresses the flow went to, if any.
+    pub resource_id: Option<ResourceId>,
+    /// The client's tunnel IP.
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// The IANA protocol number, e.g. 6 for TCP.
+    pub protocol: u8,
+    /// The ports of the flow, for protocols that have them.
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    /// Packets and bytes from the client to the resource.
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    /// Packets and bytes from the resource to the client.
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    /// When we saw the first packet of the flow.
+    pub start: Instant,
+    /// When we saw the last packet of the flow.
+    pub end: Instant,
+    pub verdict: FlowVerdict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowVerdict {
+    /// We forwarded the packets of the flow.
+    Allowed,
+    /// The filters of the resource rejected the packets of the flow.
+    Denied,
+}
+
+#[derive(Debug)]
+struct Flow {
+    state: FlowState,
+    resource: Option<ResourceId>,
+
+    started: Instant,
+    last_seen: Instant,
+
+    packets_out: u64,
+    bytes_out: u64,
+    packets_in: u64,
+    bytes_in: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlowState {
+    TcpSynSent,
+    TcpEstablished,
+    TcpClosing,
+    Udp,
+    Icmp,
+    Other,
+}
+
+#[derive(Debug, Default)]
+struct FlowLog {
+    /// Whether we emit a [`FlowRecord`] for every flow that ends.
+    enabled: bool,
+
+    buffered_records: VecDeque<FlowRecord>,
+    /// How many records we dropped since the buffer was last drained because it was full.
+    dropped_records: u64,
+}
+
+impl FlowTable {
+    /// Enables or disables the flow log.
+    pub(crate) fn set_log_enabled(&mut self, enabled: bool) {
+        self.log.enabled = enabled;
+
+        if !enabled {
+            self.denied.clear();
+            self.log.buffered_records.clear();
+            self.log.dropped_records = 0;
+        }
+    }
+
+    /// Tracks a packet that the client sends to the resource with the given ID.
+    ///
+    /// Only call this for packets that passed the filters.
+    /// Fails if the packet would open a new flow but the client already has [`MAX_FLOWS`] flows, the packet must be dropped then.
+    pub(crate) fn on_outbound(
+        &mut self,
+        packet: &IpPacket<'_>,
+        resource: Option<ResourceId>,
+        now: Instant,
+    ) -> Result<(), connlib_shared::Error> {
+        let Some(key) = FlowKey::outbound(packet) else {
+            return Ok(());
+        };
+        let len = packet.packet().len();
+
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&key) {
+            tracing::debug!(?key, "Too many flows, refusing new flow");
+
+            return Err(connlib_shared::Error::TooManyFlows);
+        }
+
+        let Some(flags) = tcp_flags(packet) else {
+            self.flows
+                .entry(key)
+                .or_insert_with(|| {
+                    tracing::debug!(?key, "New flow");
+
+                    Flow::new(FlowState::for_protocol(key.protocol), resource, now)
+                })
+                .on_outbound(len, now);
+            self.schedule_expiry(&key);
+            return Ok(());
+        };
+
+        if flags & TcpFlags::RST != 0 {
+            if let Some(flow) = self.flows.get_mut(&key) {
+                flow.on_outbound(len, now);
+            }
+
+            self.remove(&key, "Client reset TCP connection");
+            return Ok(());
+        }
+
+        match self.flows.get_mut(&key) {
+            Some(flow) => {
+                flow.on_outbound(len, now);
+                flow.state = flow.state.on_tcp_flags(flags);
+            }
+            None => {
+                // We also pick up connections mid-stream, e.g. after the client roamed to this gateway.
+                let state = if is_syn(flags) {
+                    FlowState::TcpSynSent
+                } else {
+                    FlowState::TcpEstablished
+                };
+
+                tracing::debug!(?key, ?state, "New flow");
+
+                let mut flow = Flow::new(state, resource, now);
+                flow.on_outbound(len, now);
+
+                self.flows.insert(key, flow);
+            }
+        }
+
+        self.schedule_expiry(&key);
+
+        Ok(())
+    }
+
+    /// Tracks a packet that the client sent but the filters rejected, for the flow log.
+    pub(crate) fn on_denied(
+        &mut self,
+        packet: &IpPacket<'_>,
+        resource: Option<ResourceId>,
+        now: Instant,
+    ) {
+        if !self.log.enabled {
+            return;
+        }
+
+        let Some(key) = FlowKey::outbound(packet) else {
+            return;
+        };
+
+        if self.denied.len() >= MAX_DENIED_FLOWS && !self.denied.contains_key(&key) {
+            tracing::debug!(?key, "Too many denied flows, not logging");
+            return;
+        }
+
+        let flow = self
+            .denied
+            .entry(key)
+            .or_insert_with(|| Flow::new(FlowState::for_protocol(key.protocol), resource, now));
+        flow.on_outbound(packet.packet().len(), now);
+
+        self.next_expiry = earliest(self.next_expiry, Some(flow.last_seen + DENIED_TIMEOUT));
+    }
+
+    /// Checks whether a packet from a resource belongs to a flow that the client opened.
+    pub(crate) fn ensure_inbound(
+        &mut self,
+        packet: &IpPacket<'_>,
+        now: Instant,
+    ) -> Result<(), connlib_shared::Error> {
+        let src = packet.source();
+        let len = packet.packet().len();
+
+        if let Some(origin) = packet.icmp_error_packet() {
+            let Some(key) = FlowKey::outbound(&origin) else {
+                return Err(connlib_shared::Error::UnsolicitedPacket { src });
+            };
+            if key.src != packet.destination() {
+                return Err(connlib_shared::Error::UnsolicitedPacket { src });
+            }
+            let Some(flow) = self.flows.get_mut(&key) else {
+                return Err(connlib_shared::Error::UnsolicitedPacket { src });
+            };
+
+            flow.on_inbound(len, now);
+
+            return Ok(());
+        }
+
+        let Some(key) = FlowKey::inbound(packet) else {
+            return Err(connlib_shared::Error::UnsolicitedPacket { src });
+        };
+        let Some(flow) = self.flows.get_mut(&key) else {
+            return Err(connlib_shared::Error::UnsolicitedPacket { src });
+        };
+
+        flow.on_inbound(len, now);
+
+        if let Some(flags) = tcp_flags(packet) {
+            if flags & TcpFlags::RST != 0 {
+                self.remove(&key, "Resource reset TCP connection");
+                return Ok(());
+            }
+
+            flow.state = flow.state.on_tcp_flags(flags);
+            self.schedule_expiry(&key);
+        }
+
+        Ok(())
+    }
+
+    /// Removes all flows for which `f` returns `false`.
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&FlowKey) -> bool) {
+        let log = &mut self.log;
+
+        self.flows.retain(|key, flow| {
+            let retain = f(key);
+
+            if !retain {
+                tracing::debug!(?key, "Flow is no longer allowed");
+
+                log.on_flow_end(key, flow, FlowVerdict::Allowed);
+            }
+
+            retain
+        });
+    }
+
+    /// Ends all flows, e.g. because the client disconnected.
+    pub(crate) fn end_all(&mut self) {
+        for (key, flow) in self.flows.drain() {
+            self.log.on_flow_end(&key, &flow, FlowVerdict::Allowed);
+        }
+        for (key, flow) in self.denied.drain() {
+            self.log.on_flow_end(&key, &flow, FlowVerdict::Denied);
+        }
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        if self.next_expiry.is_some_and(|next| now < next) {
+            return;
+        }
+
+        let log = &mut self.log;
+
+        self.flows.retain(|key, flow| {
+            let expired = now.duration_since(flow.last_seen) >= flow.state.timeout();
+
+            if expired {
+                tracing::debug!(?key, state = ?flow.state, "Flow expired");
+
+                log.on_flow_end(key, flow, FlowVerdict::Allowed);
+            }
+
+            !expired
+        });
+        self.denied.retain(|key, flow| {
+            let expired = now.duration_since(flow.last_seen) >= DENIED_TIMEOUT;
+
+            if expired {
+                log.on_flow_end(key, flow, FlowVerdict::Denied);
+            }
+
+            !expired
+        });
+
+        self.next_expiry = self
+            .flows
+            .values()
+            .map(|flow| flow.last_seen + flow.state.timeout())
+            .chain(
+                self.denied
+                    .values()
+                    .map(|flow| flow.last_seen + DENIED_TIMEOUT),
+            )
+            .min();
+    }
+
+    pub(crate) fn poll_record(&mut self) -> Option<FlowRecord> {
+        let record = self.log.buffered_records.pop_front();
+
+        if record.is_none() && self.log.dropped_records > 0 {
+            tracing::warn!(dropped = %self.log.dropped_records, "Flow log buffer was full, dropped the oldest records");
+
+            self.log.dropped_records = 0;
+        }
+
+        record
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Makes sure we wake up in time to expire the flow, e.g. after it was created or its state changed.
+    fn schedule_expiry(&mut self, key: &FlowKey) {
+        let Some(flow) = self.flows.get(key) else {
+            return;
+        };
+
+        self.next_expiry = earliest(
+            self.next_expiry,
+            Some(flow.last_seen + flow.state.timeout()),
+        );
+    }
+
+    fn remove(&mut self, key: &FlowKey, reason: &str) {
+        if let Some(flow) = self.flows.remove(key) {
+            tracing::debug!(?key, "{reason}");
+
+            self.log.on_flow_end(key, &flow, FlowVerdict::Allowed);
+        }
+    }
+}
+
+impl FlowLog {
+    fn on_flow_end(&mut self, key: &FlowKey, flow: &Flow, verdict: FlowVerdict) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.buffered_records.len() >= MAX_BUFFERED_RECORDS {
+            self.buffered_records.pop_front();
+            self.dropped_records += 1;
+        }
+
+        let (src_port, dst_port) = key.protocol.ports().unzip();
+
+        self.buffered_records.push_back(FlowRecord {
+            resource_id: flow.resource,
+            src: key.src,
+            dst: key.dst,
+            protocol: key.protocol.number(key.src).0,
+            src_port,
+            dst_port,
+            packets_out: flow.packets_out,
+            bytes_out: flow.bytes_out,
+            packets_in: flow.packets_in,
+            bytes_in: flow.bytes_in,
+            start: flow.started,
+            end: flow.last_seen,
+            verdict,
+        });
+    }
+}
+
+impl FlowKey {
+    fn outbound(packet: &IpPacket<'_>) -> Option<Self> {
+        Some(Self {
+            src: packet.source(),
+            dst: packet.destination(),
+            protocol: FlowProtocol::new(packet)?,
+        })
+    }
+
+    fn inbound(packet: &IpPacket<'_>) -> Option<Self> {
+        Some(Self {
+            src: packet.destination(),
+            dst: packet.source(),
+            protocol: FlowProtocol::new(packet)?.reversed(),
+        })
+    }
+}
+
+impl FlowProtocol {
+    /// Reads the protocol of a flow from a packet.
+    ///
+    /// This only looks at the first 8 bytes of the payload so it also works for the truncated packets embedded in ICMP errors.
+    fn new(packet: &IpPacket<'_>) -> Option<Self> {
+        let protocol = match Traffic::new(packet) {
+            Traffic::Tcp { sport, dport } => FlowProtocol::Tcp { sport, dport },
+            Traffic::Udp { sport, dport } => FlowProtocol::Udp { sport, dport },
+            Traffic::Sctp { sport, dport } => FlowProtocol::Sctp { sport, dport },
+            Traffic::Icmp {
+                message: Some(IcmpMessageType::EchoRequest | IcmpMessageType::EchoReply),
+                ..
+            } => {
+                let id = packet.payload().get(4..6)?;
+
+                FlowProtocol::IcmpEcho {
+                    id: u16::from_be_bytes([id[0], id[1]]),
+                }
+            }
+            // Other ICMP messages are either errors, which we match against the flow they belong to, or don't have replies.
+            Traffic::Icmp { .. } => return None,
+            Traffic::Gre | Traffic::Esp | Traffic::Other => {
+                FlowProtocol::Other(packet.next_header())
+            }
+        };
+
+        Some(protocol)
+    }
+
+    fn reversed(self) -> Self {
+        match self {
+            FlowProtocol::Tcp { sport, dport } => FlowProtocol::Tcp {
+                sport: dport,
+                dport: sport,
+            },
+            FlowProtocol::Udp { sport, dport } => FlowProtocol::Udp {
+                sport: dport,
+                dport: sport,
+            },
+            FlowProtocol::Sctp { sport, dport } => FlowProtocol::Sctp {
+                sport: dport,
+                dport: sport,
+            },
+            FlowProtocol::IcmpEcho { .. } | FlowProtocol::Other(_) => self,
+        }
+    }
+
+    /// The traffic that opened this flow, for checking it against the filters.
+    pub(crate) fn traffic(&self) -> Traffic {
+        match *self {
+            FlowProtocol::Tcp { sport, dport } => Traffic::Tcp { sport, dport },
+            FlowProtocol::Udp { sport, dport } => Traffic::Udp { sport, dport },
+            FlowProtocol::Sctp { sport, dport } => Traffic::Sctp { sport, dport },
+            FlowProtocol::IcmpEcho { .. } => Traffic::Icmp {
+                message: Some(IcmpMessageType::EchoRequest),
+                code: 0,
+            },
+            FlowProtocol::Other(IpNextHeaderProtocols::Gre) => Traffic::Gre,
+            FlowProtocol::Other(IpNextHeaderProtocols::Esp) => Traffic::Esp,
+            FlowProtocol::Other(_) => Traffic::Other,
+        }
+    }
+
+    /// The protocol number of the flow's packets, ICMP depends on the IP version of the flow.
+    fn number(&self, src: IpAddr) -> IpNextHeaderProtocol {
+        match self {
+            FlowProtocol::Tcp { .. } => IpNextHeaderProtocols::Tcp,
+            FlowProtocol::Udp { .. } => IpNextHeaderProtocols::Udp,
+            FlowProtocol::Sctp { .. } => IpNextHeaderProtocols::Sctp,
+            FlowProtocol::IcmpEcho { .. } if src.is_ipv4() => IpNextHeaderProtocols::Icmp,
+            FlowProtocol::IcmpEcho { .. } => IpNextHeaderProtocols::Icmpv6,
+            FlowProtocol::Other(protocol) => *protocol,
+        }
+    }
+
+    fn ports(&self) -> Option<(u16, u16)> {
+        match *self {
+            FlowProtocol::Tcp { sport, dport }
+            | FlowProtocol::Udp { sport, dport }
+            | FlowProtocol::Sctp { sport, dport } => Some((sport, dport)),
+            FlowProtocol::IcmpEcho { .. } | FlowProtocol::Other(_) => None,
+        }
+    }
+}
+
+impl Flow {
+    fn new(state: FlowState, resource: Option<ResourceId>, now: Instant) -> Self {
+        Self {
+            state,
+            resource,
+            started: now,
+            last_seen: now,
+            packets_out: 0,
+            bytes_out: 0,
+            packets_in: 0,
+            bytes_in: 0,
+        }
+    }
+
+    fn on_outbound(&mut self, len: usize, now: Instant) {
+        self.last_seen = now;
+        self.packets_out += 1;
+        self.bytes_out += len as u64;
+    }
+
+    fn on_inbound(&mut self, len: usize, now: Instant) {
+        self.last_seen = now;
+        self.packets_in += 1;
+        self.bytes_in += len as u64;
+    }
+}
+
+impl FlowState {
+    fn for_protocol(protocol: FlowProtocol) -> Self {
+        match protocol {
+            FlowProtocol::Tcp { .. } => FlowState::TcpEstablished,
+            FlowProtocol::Udp { .. } => FlowState::Udp,
+            FlowProtocol::IcmpEcho { .. } => FlowState::Icmp,
+            FlowProtocol::Sctp { .. } | FlowProtocol::Other(_) => FlowState::Other,
+        }
+    }
+
+    fn on_tcp_flags(self, flags: u8) -> Self {
+        if flags & TcpFlags::FIN != 0 {
+            return FlowState::TcpClosing;
+        }
+
+        match self {
+            FlowState::TcpSynSent if flags & TcpFlags::ACK != 0 => FlowState::TcpEstablished,
+            FlowState::TcpClosing if is_syn(flags) => FlowState::TcpSynSent, // Port reuse after the connection was closed.
+            FlowState::TcpSynSent
+            | FlowState::TcpEstablished
+            | FlowState::TcpClosing
+            | FlowState::Udp
+            | FlowState::Icmp
+            | FlowState::Other => self,
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        match self {
+            FlowState::TcpSynSent => TCP_SYN_SENT_TIMEOUT,
+            FlowState::TcpEstablished => TCP_ESTABLISHED_TIMEOUT,
+            FlowState::TcpClosing => TCP_CLOSING_TIMEOUT,
+            FlowState::Udp => UDP_TIMEOUT,
+            FlowState::Icmp => ICMP_TIMEOUT,
+            FlowState::Other => OTHER_TIMEOUT,
+        }
+    }
+}
+
+fn tcp_flags(packet: &IpPacket<'_>) -> Option<u8> {
+    Some(packet.as_tcp()?.get_flags())
+}
+
+fn is_syn(flags: u8) -> bool {
+    flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ip_packet::{make, MutableIpPacket, Packet as _};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn replies_are_only_allowed_for_flows_opened_by_the_client() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![]);
+        let reply = make::udp_packet(resource(), client(), 53, 5000, vec![]);
+        let other = make::udp_packet(resource(), client(), 53, 5001, vec![]);
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_err());
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_ok());
+        assert!(table.ensure_inbound(&other.to_immutable(), now).is_err());
+    }
+
+    #[test]
+    fn udp_flow_expires_after_inactivity() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![]);
+        let reply = make::udp_packet(resource(), client(), 53, 5000, vec![]);
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+        table.handle_timeout(now + UDP_TIMEOUT);
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_err());
+    }
+
+    #[test]
+    fn wakes_up_when_the_next_flow_expires() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![]);
+
+        assert_eq!(table.poll_timeout(), None);
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+        assert_eq!(table.poll_timeout(), Some(now + UDP_TIMEOUT));
+
+        table
+            .on_outbound(&request.to_immutable(), None, later)
+            .unwrap();
+        table.handle_timeout(now + UDP_TIMEOUT);
+
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.poll_timeout(), Some(later + UDP_TIMEOUT));
+    }
+
+    #[test]
+    fn tcp_flow_follows_handshake_and_reset() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+        let client = SocketAddr::new(client(), 5000);
+        let resource = SocketAddr::new(resource(), 443);
+
+        let syn = make::tcp_segment(client, resource, 1, 0, TcpFlags::SYN, 128, &[]);
+        let syn_ack = make::tcp_segment(
+            resource,
+            client,
+            1,
+            2,
+            TcpFlags::SYN | TcpFlags::ACK,
+            128,
+            &[],
+        );
+        let rst = make::tcp_segment(resource, client, 2, 2, TcpFlags::RST, 128, &[]);
+
+        table.on_outbound(&syn.to_immutable(), None, now).unwrap();
+        table.ensure_inbound(&syn_ack.to_immutable(), now).unwrap();
+
+        // An established connection outlives the SYN-sent timeout.
+        table.handle_timeout(now + TCP_SYN_SENT_TIMEOUT);
+        assert_eq!(table.len(), 1);
+
+        table.ensure_inbound(&rst.to_immutable(), now).unwrap();
+        assert_eq!(table.len(), 0);
+        assert!(table.ensure_inbound(&syn_ack.to_immutable(), now).is_err());
+    }
+
+    #[test]
+    fn icmp_errors_are_allowed_for_existing_flows() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![0; 32]);
+        let unrelated = make::udp_packet(client(), resource(), 5001, 53, vec![0; 32]);
+        let router = "10.0.0.254".parse().unwrap();
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+
+        let error = make::icmp_destination_unreachable(router, &request.to_immutable());
+        let unrelated_error = make::icmp_destination_unreachable(router, &unrelated.to_immutable());
+
+        assert!(table.ensure_inbound(&error.to_immutable(), now).is_ok());
+        assert!(table
+            .ensure_inbound(&unrelated_error.to_immutable(), now)
+            .is_err());
+    }
+
+    #[test]
+    fn removed_flows_no_longer_allow_replies() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = make::icmp_request_packet(client(), resource(), 1, 42);
+        let reply = make::icmp_reply_packet(resource(), client(), 1, 42);
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_ok());
+
+        table.retain(|key| key.dst != resource());
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_err());
+    }
+
+    #[test]
+    fn sctp_replies_are_matched_by_ports() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = sctp_packet(client(), resource(), 5000, 2905);
+        let reply = sctp_packet(resource(), client(), 2905, 5000);
+        let other = sctp_packet(resource(), client(), 2906, 5000);
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_ok());
+        assert!(table.ensure_inbound(&other.to_immutable(), now).is_err());
+
+        table.handle_timeout(now + OTHER_TIMEOUT);
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_err());
+    }
+
+    #[test]
+    fn ended_flows_are_logged_with_their_counters() {
+        let mut table = FlowTable::default();
+        table.set_log_enabled(true);
+        let now = Instant::now();
+        let resource_id = ResourceId::from_u128(1);
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![0; 12]);
+        let reply = make::udp_packet(resource(), client(), 53, 5000, vec![0; 100]);
+
+        table
+            .on_outbound(&request.to_immutable(), Some(resource_id), now)
+            .unwrap();
+        table
+            .on_outbound(
+                &request.to_immutable(),
+                Some(resource_id),
+                now + Duration::from_secs(1),
+            )
+            .unwrap();
+        table
+            .ensure_inbound(&reply.to_immutable(), now + Duration::from_secs(2))
+            .unwrap();
+
+        assert_eq!(table.poll_record(), None);
+
+        table.handle_timeout(now + Duration::from_secs(2) + UDP_TIMEOUT);
+
+        assert_eq!(
+            table.poll_record(),
+            Some(FlowRecord {
+                resource_id: Some(resource_id),
+                src: client(),
+                dst: resource(),
+                protocol: 17,
+                src_port: Some(5000),
+                dst_port: Some(53),
+                packets_out: 2,
+                bytes_out: 2 * request.packet().len() as u64,
+                packets_in: 1,
+                bytes_in: reply.packet().len() as u64,
+                start: now,
+                end: now + Duration::from_secs(2),
+                verdict: FlowVerdict::Allowed,
+            })
+        );
+        assert_eq!(table.poll_record(), None);
+    }
+
+    #[test]
+    fn denied_flows_are_logged_but_dont_allow_replies() {
+        let mut table = FlowTable::default();
+        table.set_log_enabled(true);
+        let now = Instant::now();
+
+        let request = make::icmp_request_packet(client(), resource(), 1, 42);
+        let reply = make::icmp_reply_packet(resource(), client(), 1, 42);
+
+        table.on_denied(&request.to_immutable(), None, now);
+        table.on_denied(&request.to_immutable(), None, now);
+
+        assert!(table.ensure_inbound(&reply.to_immutable(), now).is_err());
+
+        table.end_all();
+
+        let record = table.poll_record().unwrap();
+        assert_eq!(record.verdict, FlowVerdict::Denied);
+        assert_eq!(record.protocol, 1);
+        assert_eq!(record.packets_out, 2);
+        assert_eq!(record.src_port, None);
+    }
+
+    #[test]
+    fn counts_records_dropped_from_a_full_buffer() {
+        let mut table = FlowTable::default();
+        table.set_log_enabled(true);
+        let now = Instant::now();
+
+        for port in 0..MAX_BUFFERED_RECORDS as u16 + 5 {
+            let request = make::udp_packet(client(), resource(), port, 53, vec![]);
+
+            table
+                .on_outbound(&request.to_immutable(), None, now)
+                .unwrap();
+        }
+        table.end_all();
+
+        assert_eq!(table.log.dropped_records, 5);
+        assert_eq!(
+            std::iter::from_fn(|| table.poll_record()).count(),
+            MAX_BUFFERED_RECORDS
+        );
+        assert_eq!(table.log.dropped_records, 0);
+    }
+
+    #[test]
+    fn refuses_new_flows_beyond_max_flows() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        for port in 0..MAX_FLOWS {
+            let request = make::udp_packet(client(), resource(), port as u16, 53, vec![]);
+
+            table
+                .on_outbound(&request.to_immutable(), None, now)
+                .unwrap();
+        }
+
+        let existing = make::udp_packet(client(), resource(), 0, 53, vec![]);
+        let new = make::udp_packet(client(), resource(), 0, 54, vec![]);
+
+        table
+            .on_outbound(&existing.to_immutable(), None, now)
+            .unwrap();
+        assert!(matches!(
+            table.on_outbound(&new.to_immutable(), None, now),
+            Err(connlib_shared::Error::TooManyFlows)
+        ));
+        assert_eq!(table.len(), MAX_FLOWS);
+
+        table.handle_timeout(now + UDP_TIMEOUT);
+
+        table
+            .on_outbound(&new.to_immutable(), None, now + UDP_TIMEOUT)
+            .unwrap();
+    }
+
+    #[test]
+    fn nothing_is_logged_if_the_flow_log_is_disabled() {
+        let mut table = FlowTable::default();
+        let now = Instant::now();
+
+        let request = make::udp_packet(client(), resource(), 5000, 53, vec![]);
+
+        table
+            .on_outbound(&request.to_immutable(), None, now)
+            .unwrap();
+        table.on_denied(&request.to_immutable(), None, now);
+        table.end_all();
+
+        assert_eq!(table.poll_record(), None);
+    }
+
+    fn sctp_packet(src: IpAddr, dst: IpAddr, sport: u16, dport: u16) -> MutableIpPacket<'static> {
+        let mut header = [0u8; 12];
+        header[0..2].copy_from_slice(&sport.to_be_bytes());
+        header[2..4].copy_from_slice(&dport.to_be_bytes());
+
+        make::ip_packet(src, dst, IpNextHeaderProtocols::Sctp, &header)
+    }
+
+    fn client() -> IpAddr {
+        "100.64.0.1".parse().unwrap()
+    }
+
+    fn resource() -> IpAddr {
+        "10.0.0.1".parse().unwrap()
+    }
+}
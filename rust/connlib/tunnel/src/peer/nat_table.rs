@@ -1,14 +1,20 @@
 //! a stateful symmetric NAT table that performs conversion between a client's picked proxy ip and the actual resource's IP
+use crate::utils::earliest;
 use bimap::BiMap;
+use ip_packet::tcp::TcpFlags;
 use ip_packet::{IpPacket, Protocol};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::time::{Duration, Instant};
 
+/// From which share of [`NatConfig::max_sessions`] on we warn about a client exhausting its NAT sessions, in percent.
+const EXHAUSTION_WARNING_THRESHOLD: usize = 90;
+
 /// The stateful NAT table converts a client's picked proxy ip for a domain name into the real IP for that IP
 /// it also picks a source port to keep track of the original proxy IP used.
-/// The NAT sessions, i.e. the mapping between (source_port, proxy_ip) to (source_port', real_ip) is kept for 60 seconds
-/// after no incoming traffic is received.
+/// The NAT sessions, i.e. the mapping between (source_port, proxy_ip) to (source_port', real_ip) are kept until they
+/// have been idle for the timeout of their protocol, see [`NatConfig`].
 ///
 /// Note that for ICMP echo/reply the identity number is used as a stand in for the source port.
 ///
@@ -17,28 +23,121 @@ use std::time::{Duration, Instant};
 /// This nat table doesn't perform any mangling just provides the converted port/ip for upper layers
 #[derive(Default, Debug)]
 pub(crate) struct NatTable {
-    pub(crate) table: BiMap<(Protocol, IpAddr), (Protocol, IpAddr)>,
-    pub(crate) last_seen: HashMap<(Protocol, IpAddr), Instant>,
+    config: NatConfig,
+
+    table: BiMap<(Protocol, IpAddr), (Protocol, IpAddr)>,
+    /// The state of each session, indexed by its outside tuple.
+    sessions: HashMap<(Protocol, IpAddr), Session>,
+    /// The ports we hand out for each protocol and outside IP, indexed by the protocol with a port of 0.
+    pools: HashMap<(Protocol, IpAddr), PortPool>,
+    /// No session expires before this, we only look at them again once it has passed.
+    next_expiry: Option<Instant>,
+
+    /// How many packets we couldn't translate since the last call to [`NatTable::take_exhaustion`].
+    failed_translations: u64,
+    /// Whether we already warned about the client nearing its session limit.
+    warned: bool,
+}
+
+/// The configuration of the NAT for DNS resources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatConfig {
+    /// How long an established TCP session may be idle, see <https://www.rfc-editor.org/rfc/rfc5382#section-5>.
+    pub tcp_established_timeout: Duration,
+    /// How long a TCP session that is being opened or closed may be idle.
+    pub tcp_transitory_timeout: Duration,
+    /// See <https://www.rfc-editor.org/rfc/rfc4787#section-4.3>.
+    pub udp_timeout: Duration,
+    /// See <https://www.rfc-editor.org/rfc/rfc5508#section-3.2>.
+    pub icmp_timeout: Duration,
+    /// How many sessions a single client may have at once.
+    pub max_sessions: usize,
+    /// The ports (and ICMP identifiers) we map the client's sessions to.
+    pub ports: RangeInclusive<u16>,
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            tcp_established_timeout: Duration::from_secs(7440),
+            tcp_transitory_timeout: Duration::from_secs(240),
+            udp_timeout: Duration::from_secs(120),
+            icmp_timeout: Duration::from_secs(60),
+            max_sessions: 16_384,
+            ports: 1..=u16::MAX,
+        }
+    }
+}
+
+/// A client is running out of NAT sessions or we already failed to translate some of its packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Exhaustion {
+    pub(crate) sessions: usize,
+    pub(crate) max_sessions: usize,
+    pub(crate) failed_translations: u64,
+}
+
+#[derive(Debug)]
+struct Session {
+    state: SessionState,
+    last_seen: Instant,
+    /// Whether the outside port goes back to the [`PortPool`] once the session ends.
+    ///
+    /// Ports that we picked because they are the same as the inside port are still part of the pool.
+    pooled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    TcpOpening,
+    TcpEstablished,
+    TcpClosing,
+    Udp,
+    Icmp,
 }
 
-const TTL: Duration = Duration::from_secs(60);
+/// Hands out the outside ports for one protocol and outside IP in O(1).
+///
+/// We first hand out the ports we never used, then the ones that were released, in the order they were released.
+#[derive(Debug)]
+struct PortPool {
+    ports: RangeInclusive<u16>,
+    /// The next port we haven't handed out yet.
+    next_unused: u32,
+    released: VecDeque<u16>,
+    /// How many sessions use a port of this pool.
+    in_use: usize,
+}
 
 impl NatTable {
+    pub(crate) fn set_config(&mut self, config: NatConfig) {
+        self.config = config;
+        self.next_expiry = self.earliest_session_expiry();
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+
     pub(crate) fn handle_timeout(&mut self, now: Instant) {
-        let mut removed = Vec::new();
-        for (outside, e) in self.last_seen.iter() {
-            if now.duration_since(*e) >= TTL {
-                if let Some((inside, _)) = self.table.remove_by_right(outside) {
-                    tracing::debug!(?inside, ?outside, "NAT session expired");
-                }
+        if self.next_expiry.is_some_and(|next| now < next) {
+            return;
+        }
+
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| now >= session.expires_at(&self.config))
+            .map(|(outside, _)| *outside)
+            .collect::<Vec<_>>();
 
-                removed.push(*outside);
+        for outside in expired {
+            if let Some(inside) = self.remove(&outside) {
+                tracing::debug!(?inside, ?outside, "NAT session expired");
             }
         }
 
-        for r in removed {
-            self.last_seen.remove(&r);
-        }
+        self.next_expiry = self.earliest_session_expiry();
     }
 
     pub(crate) fn translate_outgoing(
@@ -51,32 +150,75 @@ impl NatTable {
             .source_protocol()
             .map_err(connlib_shared::Error::UnsupportedProtocol)?;
         let dst = packet.destination();
+        let tcp_flags = tcp_flags(&packet);
 
         let inside = (src, dst);
 
-        if let Some(outside) = self.table.get_by_left(&inside) {
+        if let Some(outside) = self.table.get_by_left(&inside).copied() {
             if outside.1 == outside_dst {
                 tracing::trace!(?inside, ?outside, "Translating outgoing packet");
 
-                self.last_seen.insert(*outside, now);
-                return Ok(*outside);
+                if let Some(session) = self.sessions.get_mut(&outside) {
+                    session.last_seen = now;
+                    session.state = session.state.on_tcp_flags(tcp_flags, false);
+
+                    // Closing a connection shortens its timeout.
+                    self.next_expiry =
+                        earliest(self.next_expiry, Some(session.expires_at(&self.config)));
+                }
+
+                return Ok(outside);
             }
 
             tracing::trace!(?inside, ?outside, "Outgoing packet for expired translation");
+
+            self.remove(&outside);
+        }
+
+        if self.sessions.len() >= self.config.max_sessions {
+            self.failed_translations += 1;
+
+            return Err(connlib_shared::Error::TooManyNatSessions);
         }
 
-        // Find the first available public port, starting from the port of the to-be-mapped packet.
+        let pool = self
+            .pools
+            .entry((src.with_value(0), outside_dst))
+            .or_insert_with(|| PortPool::new(self.config.ports.clone()));
+
+        // Prefer the port of the to-be-mapped packet.
         // This will re-assign the same port in most cases, even after the mapping expires.
-        let outside = (src.value()..=u16::MAX)
-            .chain(1..src.value())
-            .map(|p| (src.with_value(p), outside_dst))
-            .find(|outside| !self.table.contains_right(outside))
-            .ok_or(connlib_shared::Error::ExhaustedNat)?;
+        let preferred = (src, outside_dst);
+        let (outside, pooled) =
+            if pool.ports.contains(&src.value()) && !self.table.contains_right(&preferred) {
+                (preferred, false)
+            } else {
+                loop {
+                    let Some(port) = pool.next() else {
+                        self.failed_translations += 1;
+
+                        return Err(connlib_shared::Error::ExhaustedNat);
+                    };
+                    let outside = (src.with_value(port), outside_dst);
+
+                    match self.sessions.get_mut(&outside) {
+                        // The port is in use by a session that preferred it, it goes back to the pool once that session ends.
+                        Some(session) => session.pooled = true,
+                        None => break (outside, true),
+                    }
+                }
+            };
+        pool.in_use += 1;
 
-        let inside = (src, dst);
+        let session = Session {
+            state: SessionState::new(src, tcp_flags),
+            last_seen: now,
+            pooled,
+        };
+        self.next_expiry = earliest(self.next_expiry, Some(session.expires_at(&self.config)));
 
         self.table.insert(inside, outside);
-        self.last_seen.insert(outside, now);
+        self.sessions.insert(outside, session);
 
         tracing::debug!(?inside, ?outside, "New NAT session");
 
@@ -98,7 +240,14 @@ impl NatTable {
         if let Some(inside) = self.table.get_by_right(&outside) {
             tracing::trace!(?inside, ?outside, "Translating incoming packet");
 
-            self.last_seen.insert(*inside, now);
+            if let Some(session) = self.sessions.get_mut(&outside) {
+                session.last_seen = now;
+                session.state = session.state.on_tcp_flags(tcp_flags(&packet), true);
+
+                self.next_expiry =
+                    earliest(self.next_expiry, Some(session.expires_at(&self.config)));
+            }
+
             return Ok(Some(*inside));
         }
 
@@ -106,10 +255,371 @@ impl NatTable {
 
         Ok(None)
     }
+
+    /// The outside IP of the session an outgoing packet belongs to, if any.
+    pub(crate) fn outside_ip(&self, packet: &IpPacket) -> Option<IpAddr> {
+        let src = packet.source_protocol().ok()?;
+        let (_, ip) = self.table.get_by_left(&(src, packet.destination()))?;
+
+        Some(*ip)
+    }
+
+    pub(crate) fn num_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// How many sessions use the given outside IP.
+    pub(crate) fn sessions_to(&self, ip: IpAddr) -> usize {
+        [Protocol::Tcp(0), Protocol::Udp(0), Protocol::Icmp(0)]
+            .into_iter()
+            .filter_map(|protocol| self.pools.get(&(protocol, ip)))
+            .map(|pool| pool.in_use)
+            .sum()
+    }
+
+    /// Returns whether the client is nearing its session limit or we failed to translate some of its packets since the last call.
+    ///
+    /// Nearing the limit is only reported once until the client drops below the threshold again.
+    pub(crate) fn take_exhaustion(&mut self) -> Option<Exhaustion> {
+        let failed_translations = std::mem::take(&mut self.failed_translations);
+        let sessions = self.sessions.len();
+        let max_sessions = self.config.max_sessions;
+
+        let near_limit = self.is_near_limit();
+        let warn = near_limit && !self.warned;
+        self.warned = near_limit;
+
+        (warn || failed_translations > 0).then_some(Exhaustion {
+            sessions,
+            max_sessions,
+            failed_translations,
+        })
+    }
+
+    /// Whether [`NatTable::take_exhaustion`] would report something.
+    pub(crate) fn has_exhaustion(&self) -> bool {
+        self.failed_translations > 0 || (self.is_near_limit() && !self.warned)
+    }
+
+    fn is_near_limit(&self) -> bool {
+        self.sessions.len() * 100 >= self.config.max_sessions * EXHAUSTION_WARNING_THRESHOLD
+    }
+
+    fn earliest_session_expiry(&self) -> Option<Instant> {
+        self.sessions
+            .values()
+            .map(|session| session.expires_at(&self.config))
+            .min()
+    }
+
+    /// Removes a session and returns its port to the pool.
+    fn remove(&mut self, outside: &(Protocol, IpAddr)) -> Option<(Protocol, IpAddr)> {
+        let (inside, _) = self.table.remove_by_right(outside)?;
+        let session = self.sessions.remove(outside)?;
+
+        let key = (outside.0.with_value(0), outside.1);
+        let pool = self.pools.get_mut(&key)?;
+
+        if session.pooled {
+            pool.released.push_back(outside.0.value());
+        }
+
+        pool.in_use -= 1;
+        if pool.in_use == 0 {
+            self.pools.remove(&key);
+        }
+
+        Some(inside)
+    }
 }
 
-#[cfg(all(test, feature = "proptest"))]
+impl Session {
+    fn expires_at(&self, config: &NatConfig) -> Instant {
+        self.last_seen + self.state.timeout(config)
+    }
+}
+
+impl SessionState {
+    fn new(protocol: Protocol, tcp_flags: Option<u8>) -> Self {
+        match protocol {
+            Protocol::Tcp(_) if tcp_flags.is_some_and(is_syn) => SessionState::TcpOpening,
+            // We also pick up connections mid-stream, e.g. after the client roamed to this gateway.
+            Protocol::Tcp(_) => SessionState::TcpEstablished.on_tcp_flags(tcp_flags, false),
+            Protocol::Udp(_) => SessionState::Udp,
+            Protocol::Icmp(_) => SessionState::Icmp,
+        }
+    }
+
+    fn on_tcp_flags(self, flags: Option<u8>, inbound: bool) -> Self {
+        let Some(flags) = flags else {
+            return self;
+        };
+
+        if flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            return SessionState::TcpClosing;
+        }
+
+        match self {
+            SessionState::TcpOpening if inbound => SessionState::TcpEstablished,
+            SessionState::TcpClosing if is_syn(flags) => SessionState::TcpOpening, // Port reuse after the connection was closed.
+            SessionState::TcpOpening
+            | SessionState::TcpEstablished
+            | SessionState::TcpClosing
+            | SessionState::Udp
+            | SessionState::Icmp => self,
+        }
+    }
+
+    fn timeout(&self, config: &NatConfig) -> Duration {
+        match self {
+            SessionState::TcpOpening | SessionState::TcpClosing => config.tcp_transitory_timeout,
+            SessionState::TcpEstablished => config.tcp_established_timeout,
+            SessionState::Udp => config.udp_timeout,
+            SessionState::Icmp => config.icmp_timeout,
+        }
+    }
+}
+
+impl PortPool {
+    fn new(ports: RangeInclusive<u16>) -> Self {
+        Self {
+            next_unused: u32::from(*ports.start()),
+            ports,
+            released: VecDeque::new(),
+            in_use: 0,
+        }
+    }
+
+    /// Returns the next port of the pool.
+    ///
+    /// The port may still be used by a session that picked it as its preferred port.
+    fn next(&mut self) -> Option<u16> {
+        if self.next_unused <= u32::from(*self.ports.end()) {
+            let port = self.next_unused as u16;
+            self.next_unused += 1;
+
+            return Some(port);
+        }
+
+        self.released.pop_front()
+    }
+}
+
+fn tcp_flags(packet: &IpPacket<'_>) -> Option<u8> {
+    Some(packet.as_tcp()?.get_flags())
+}
+
+fn is_syn(flags: u8) -> bool {
+    flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0
+}
+
+#[cfg(test)]
 mod tests {
+    use super::*;
+    use ip_packet::make;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn sessions_expire_after_the_timeout_of_their_protocol() {
+        let mut table = NatTable::default();
+        let now = Instant::now();
+
+        let udp = make::udp_packet(client(), proxy(), 5000, 53, vec![]);
+        let ping = make::icmp_request_packet(client(), proxy(), 1, 42);
+
+        table
+            .translate_outgoing(udp.to_immutable(), resource(), now)
+            .unwrap();
+        table
+            .translate_outgoing(ping.to_immutable(), resource(), now)
+            .unwrap();
+
+        assert_eq!(
+            table.poll_timeout(),
+            Some(now + NatConfig::default().icmp_timeout)
+        );
+
+        table.handle_timeout(now + NatConfig::default().icmp_timeout);
+        assert_eq!(table.sessions.len(), 1);
+        assert_eq!(
+            table.poll_timeout(),
+            Some(now + NatConfig::default().udp_timeout)
+        );
+
+        table.handle_timeout(now + NatConfig::default().udp_timeout);
+        assert_eq!(table.sessions.len(), 0);
+        assert_eq!(table.poll_timeout(), None);
+    }
+
+    #[test]
+    fn established_tcp_sessions_outlive_transitory_ones() {
+        let mut table = NatTable::default();
+        let now = Instant::now();
+        let config = NatConfig::default();
+
+        let syn = make::tcp_segment(
+            SocketAddr::new(client(), 5000),
+            SocketAddr::new(proxy(), 443),
+            1,
+            0,
+            TcpFlags::SYN,
+            128,
+            &[],
+        );
+        let (outside_port, _) = table
+            .translate_outgoing(syn.to_immutable(), resource(), now)
+            .unwrap();
+        let syn_ack = make::tcp_segment(
+            SocketAddr::new(resource(), 443),
+            SocketAddr::new(client(), outside_port.value()),
+            1,
+            2,
+            TcpFlags::SYN | TcpFlags::ACK,
+            128,
+            &[],
+        );
+        table
+            .translate_incoming(syn_ack.to_immutable(), now)
+            .unwrap()
+            .unwrap();
+
+        table.handle_timeout(now + config.tcp_transitory_timeout);
+        assert_eq!(table.sessions.len(), 1);
+
+        table.handle_timeout(now + config.tcp_established_timeout);
+        assert_eq!(table.sessions.len(), 0);
+    }
+
+    #[test]
+    fn allocates_ports_from_pool_and_reuses_released_ones() {
+        let mut table = NatTable::default();
+        table.set_config(NatConfig {
+            ports: 1000..=1001,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let packet = |sport| make::udp_packet(client(), proxy(), sport, 53, vec![]);
+
+        let first = table
+            .translate_outgoing(packet(5000).to_immutable(), resource(), now)
+            .unwrap();
+        let second = table
+            .translate_outgoing(
+                packet(5001).to_immutable(),
+                resource(),
+                now + Duration::from_secs(1),
+            )
+            .unwrap();
+
+        assert_eq!(first.0, Protocol::Udp(1000));
+        assert_eq!(second.0, Protocol::Udp(1001));
+        assert!(matches!(
+            table.translate_outgoing(packet(5002).to_immutable(), resource(), now),
+            Err(connlib_shared::Error::ExhaustedNat)
+        ));
+
+        table.handle_timeout(now + NatConfig::default().udp_timeout);
+
+        let third = table
+            .translate_outgoing(packet(5002).to_immutable(), resource(), now)
+            .unwrap();
+        assert_eq!(third.0, Protocol::Udp(1000));
+    }
+
+    #[test]
+    fn prefers_inside_port_and_returns_it_to_pool_if_handed_out_meanwhile() {
+        let mut table = NatTable::default();
+        table.set_config(NatConfig {
+            ports: 1000..=1001,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let packet = |sport| make::udp_packet(client(), proxy(), sport, 53, vec![]);
+
+        let preferred = table
+            .translate_outgoing(packet(1000).to_immutable(), resource(), now)
+            .unwrap();
+        let other = table
+            .translate_outgoing(packet(5000).to_immutable(), resource(), now)
+            .unwrap();
+
+        assert_eq!(preferred.0, Protocol::Udp(1000));
+        assert_eq!(other.0, Protocol::Udp(1001));
+
+        // Keep the second session alive so the pool sticks around.
+        let later = now + Duration::from_secs(60);
+        table
+            .translate_outgoing(packet(5000).to_immutable(), resource(), later)
+            .unwrap();
+        table.handle_timeout(now + NatConfig::default().udp_timeout);
+
+        // All ports were handed out, thus port 1000 must have been returned to the pool.
+        let again = table
+            .translate_outgoing(packet(5001).to_immutable(), resource(), later)
+            .unwrap();
+        assert_eq!(again.0, Protocol::Udp(1000));
+    }
+
+    #[test]
+    fn reports_session_limit_once_and_failed_translations() {
+        let mut table = NatTable::default();
+        table.set_config(NatConfig {
+            max_sessions: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let packet = |sport| make::udp_packet(client(), proxy(), sport, 53, vec![]);
+
+        table
+            .translate_outgoing(packet(5000).to_immutable(), resource(), now)
+            .unwrap();
+        assert_eq!(table.take_exhaustion(), None);
+
+        table
+            .translate_outgoing(packet(5001).to_immutable(), resource(), now)
+            .unwrap();
+        assert_eq!(
+            table.take_exhaustion(),
+            Some(Exhaustion {
+                sessions: 2,
+                max_sessions: 2,
+                failed_translations: 0
+            })
+        );
+        assert_eq!(table.take_exhaustion(), None);
+
+        assert!(matches!(
+            table.translate_outgoing(packet(5002).to_immutable(), resource(), now),
+            Err(connlib_shared::Error::TooManyNatSessions)
+        ));
+        assert_eq!(
+            table.take_exhaustion(),
+            Some(Exhaustion {
+                sessions: 2,
+                max_sessions: 2,
+                failed_translations: 1
+            })
+        );
+    }
+
+    fn client() -> IpAddr {
+        "100.64.0.1".parse().unwrap()
+    }
+
+    fn proxy() -> IpAddr {
+        "100.96.0.1".parse().unwrap()
+    }
+
+    fn resource() -> IpAddr {
+        "10.0.0.1".parse().unwrap()
+    }
+}
+
+#[cfg(all(test, feature = "proptest"))]
+mod proptests {
     use super::*;
     use ip_packet::{proptest::*, MutableIpPacket};
     use proptest::prelude::*;
@@ -119,7 +629,7 @@ mod tests {
     fn translates_back_and_forth_packet(
         #[strategy(udp_or_tcp_or_icmp_packet())] packet: MutableIpPacket<'static>,
         #[strategy(any::<IpAddr>())] outside_dst: IpAddr,
-        #[strategy(0..120u64)] response_delay: u64,
+        #[strategy(0..300u64)] response_delay: u64,
     ) {
         proptest::prop_assume!(packet.destination().is_ipv4() == outside_dst.is_ipv4()); // Required for our test to simulate a response.
 
@@ -145,6 +655,10 @@ mod tests {
         response.set_destination_protocol(new_source_protocol.value());
         response.set_src(new_dst_ip);
 
+        let timeout = table.sessions[&(new_source_protocol, new_dst_ip)]
+            .state
+            .timeout(&table.config);
+
         // Update time.
         table.handle_timeout(sent_at + response_delay);
 
@@ -154,7 +668,7 @@ mod tests {
             .unwrap();
 
         // Assert
-        if response_delay >= Duration::from_secs(60) {
+        if response_delay >= timeout {
             assert!(translate_incoming.is_none());
         } else {
             assert_eq!(translate_incoming, Some((src, dst)));
@@ -0,0 +1,242 @@
+//! Token-bucket rate limiting of the traffic between a client and its resources.
+//!
+//! Each bucket refills continuously at its configured rate and holds at most its burst size.
+//! A byte bucket always holds at least one packet of [`MTU`] bytes, otherwise a rate or burst below that would drop all full-size packets forever.
+//! A packet passes if all buckets of all limiters that apply to it have enough tokens left, in which case it consumes from all of them.
+use crate::MTU;
+use connlib_shared::messages::{gateway::RateLimit, ResourceId};
+use std::{collections::HashMap, time::Instant};
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimit,
+
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+
+    /// What we dropped since the last call to [`RateLimiter::take_dropped`].
+    dropped: Dropped,
+}
+
+/// The rate limits of resources, shared by all clients of a gateway.
+///
+/// The clients count what they dropped because of these limits themselves, so we can report it per client.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceRateLimiters {
+    limiters: HashMap<ResourceId, RateLimiter>,
+}
+
+/// Packets and bytes that exceeded a rate limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Dropped {
+    pub(crate) packets: u64,
+    pub(crate) bytes: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens per second.
+    rate: f64,
+    capacity: f64,
+
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimit, now: Instant) -> Self {
+        Self {
+            config,
+            bytes: config
+                .bytes_per_second
+                .map(|rate| TokenBucket::new(rate, config.burst_bytes, MTU as u64, now)),
+            packets: config
+                .packets_per_second
+                .map(|rate| TokenBucket::new(rate, config.burst_packets, 0, now)),
+            dropped: Dropped::default(),
+        }
+    }
+
+    pub(crate) fn config(&self) -> RateLimit {
+        self.config
+    }
+
+    /// Whether a packet of `len` bytes is within the limit.
+    ///
+    /// This doesn't consume any tokens, so the packet can be checked against other limits first, see [`RateLimiter::consume`].
+    pub(crate) fn allows(&mut self, len: usize, now: Instant) -> bool {
+        let len = len as f64;
+
+        let has_bytes = self.bytes.as_mut().map_or(true, |b| b.has(len, now));
+        let has_packets = self.packets.as_mut().map_or(true, |b| b.has(1.0, now));
+
+        has_bytes && has_packets
+    }
+
+    /// Consumes the tokens for a packet of `len` bytes that is within the limit.
+    pub(crate) fn consume(&mut self, len: usize) {
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.tokens -= len as f64;
+        }
+        if let Some(packets) = self.packets.as_mut() {
+            packets.tokens -= 1.0;
+        }
+    }
+
+    /// Counts a packet of `len` bytes that exceeded the limit as dropped.
+    pub(crate) fn count_dropped(&mut self, len: usize) {
+        self.dropped.count(len);
+    }
+
+    /// Returns what we dropped since the last call, if anything.
+    pub(crate) fn take_dropped(&mut self) -> Option<Dropped> {
+        let dropped = std::mem::take(&mut self.dropped);
+
+        (dropped != Dropped::default()).then_some(dropped)
+    }
+}
+
+impl ResourceRateLimiters {
+    /// Limits the traffic of all clients to and from the given resource, in both directions.
+    ///
+    /// Replaces any previous limit, `None` removes it.
+    pub(crate) fn set(&mut self, resource: ResourceId, limit: Option<RateLimit>, now: Instant) {
+        if self.limiters.get(&resource).map(|l| l.config()) == limit {
+            return;
+        }
+
+        tracing::debug!(%resource, ?limit, "Setting rate limit of resource");
+
+        match limit {
+            Some(limit) => {
+                self.limiters.insert(resource, RateLimiter::new(limit, now));
+            }
+            None => {
+                self.limiters.remove(&resource);
+            }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, resource: &ResourceId) -> Option<&mut RateLimiter> {
+        self.limiters.get_mut(resource)
+    }
+}
+
+impl Dropped {
+    pub(crate) fn count(&mut self, len: usize) {
+        self.packets += 1;
+        self.bytes += len as u64;
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: Option<u64>, min_capacity: u64, now: Instant) -> Self {
+        let capacity = burst.unwrap_or(rate).max(min_capacity) as f64;
+
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn has(&mut self, tokens: f64, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens >= tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    impl RateLimiter {
+        fn try_consume(&mut self, len: usize, now: Instant) -> bool {
+            if !self.allows(len, now) {
+                self.count_dropped(len);
+                return false;
+            }
+
+            self.consume(len);
+
+            true
+        }
+    }
+
+    #[test]
+    fn drops_packets_beyond_burst_until_refilled() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimit {
+                packets_per_second: Some(10),
+                burst_packets: Some(2),
+                ..Default::default()
+            },
+            now,
+        );
+
+        assert!(limiter.try_consume(100, now));
+        assert!(limiter.try_consume(100, now));
+        assert!(!limiter.try_consume(100, now));
+
+        assert!(limiter.try_consume(100, now + Duration::from_millis(100)));
+        assert!(!limiter.try_consume(100, now + Duration::from_millis(100)));
+
+        assert_eq!(
+            limiter.take_dropped(),
+            Some(Dropped {
+                packets: 2,
+                bytes: 200
+            })
+        );
+        assert_eq!(limiter.take_dropped(), None);
+    }
+
+    #[test]
+    fn packet_must_fit_into_all_buckets() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimit {
+                bytes_per_second: Some(2000),
+                packets_per_second: Some(100),
+                ..Default::default()
+            },
+            now,
+        );
+
+        assert!(!limiter.try_consume(2001, now));
+        assert!(limiter.try_consume(2000, now));
+        assert!(!limiter.try_consume(1, now));
+
+        // A dropped packet doesn't consume any tokens.
+        assert!(limiter.try_consume(1000, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn byte_bucket_always_fits_full_size_packet() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimit {
+                bytes_per_second: Some(100),
+                burst_bytes: Some(10),
+                ..Default::default()
+            },
+            now,
+        );
+
+        assert!(limiter.try_consume(MTU, now));
+        assert!(!limiter.try_consume(MTU, now));
+
+        // Refilling takes long but eventually allows another full-size packet.
+        assert!(!limiter.try_consume(MTU, now + Duration::from_secs(12)));
+        assert!(limiter.try_consume(MTU, now + Duration::from_secs(13)));
+    }
+}
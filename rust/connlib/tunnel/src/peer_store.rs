@@ -35,6 +35,17 @@ impl PeerStore<GatewayId, GatewayOnClient> {
             peer.insert_id(ip, resource);
         }
     }
+
+    /// Stops routing the given IPs to any gateway.
+    pub(crate) fn remove_ips(&mut self, ips: &[IpNetwork]) {
+        for ip in ips {
+            self.id_by_ip.remove(*ip);
+
+            for peer in self.peer_by_id.values_mut() {
+                peer.allowed_ips.remove(*ip);
+            }
+        }
+    }
 }
 
 impl<TId, P> PeerStore<TId, P>
@@ -91,6 +102,10 @@ where
         self.peer_by_id.get_mut(id)
     }
 
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &P> {
+        self.peer_by_id.values()
+    }
+
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut P> {
         self.peer_by_id.values_mut()
     }
@@ -5,19 +5,35 @@ use std::{
     collections::VecDeque,
     io::{self, IoSliceMut},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
     task::{ready, Context, Poll},
 };
-use tokio::{io::Interest, net::UdpSocket};
+use tokio::{
+    io::Interest,
+    net::{TcpSocket, UdpSocket},
+};
 
 use crate::Result;
 
-#[derive(Default)]
+mod streams;
+
 pub(crate) struct Sockets {
     socket_v4: Option<Socket>,
     socket_v6: Option<Socket>,
+
+    /// Connections to relays that we talk to via TCP or TLS.
+    streams: streams::Streams,
 }
 
 impl Sockets {
+    pub fn new(tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>) -> Self {
+        Self {
+            socket_v4: None,
+            socket_v6: None,
+            streams: streams::Streams::new(tcp_socket_factory),
+        }
+    }
+
     pub fn rebind(
         &mut self,
         socket_factory: &dyn SocketFactory<tokio::net::UdpSocket>,
@@ -46,6 +62,7 @@ impl Sockets {
 
         self.socket_v4 = socket_v4.ok();
         self.socket_v6 = socket_v6.ok();
+        self.streams.clear(); // Connections of the old interfaces won't work anymore.
 
         Ok(())
     }
@@ -66,6 +83,15 @@ impl Sockets {
     }
 
     pub fn send(&mut self, transmit: snownet::Transmit) -> io::Result<()> {
+        match transmit.transport {
+            snownet::Transport::Udp => {}
+            snownet::Transport::Tcp | snownet::Transport::Tls => {
+                self.streams.send(transmit);
+
+                return Ok(());
+            }
+        }
+
         let socket = match transmit.dst {
             SocketAddr::V4(dst) => self.socket_v4.as_mut().ok_or(io::Error::new(
                 io::ErrorKind::NotConnected,
@@ -82,13 +108,29 @@ impl Sockets {
     }
 
     pub fn poll_recv_from<'b>(
-        &self,
+        &mut self,
         ip4_buffer: &'b mut [u8],
         ip6_buffer: &'b mut [u8],
         cx: &mut Context<'_>,
     ) -> Poll<io::Result<impl Iterator<Item = Received<'b>>>> {
         let mut iter = PacketIter::new();
 
+        if let Poll::Ready((local, from, message)) = self.streams.poll_recv(cx) {
+            let Some(buffer) = ip4_buffer.get_mut(..message.len()) else {
+                tracing::warn!(%from, "Dropping message from stream because it is too large");
+                return Poll::Ready(Ok(iter));
+            };
+            buffer.copy_from_slice(&message);
+
+            iter.stream = Some(Received {
+                local,
+                from,
+                packet: buffer,
+            });
+
+            return Poll::Ready(Ok(iter));
+        }
+
         if let Some(Poll::Ready(packets)) = self
             .socket_v4
             .as_ref()
@@ -113,25 +155,27 @@ impl Sockets {
     }
 }
 
-struct PacketIter<T4, T6> {
+struct PacketIter<'a, T4, T6> {
     ip4: Option<T4>,
     ip6: Option<T6>,
+    stream: Option<Received<'a>>,
 }
 
-impl<T4, T6> PacketIter<T4, T6> {
+impl<'a, T4, T6> PacketIter<'a, T4, T6> {
     fn new() -> Self {
         Self {
             ip4: None,
             ip6: None,
+            stream: None,
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.ip4.is_none() && self.ip6.is_none()
+        self.ip4.is_none() && self.ip6.is_none() && self.stream.is_none()
     }
 }
 
-impl<'a, T4, T6> Iterator for PacketIter<T4, T6>
+impl<'a, T4, T6> Iterator for PacketIter<'a, T4, T6>
 where
     T4: Iterator<Item = Received<'a>>,
     T6: Iterator<Item = Received<'a>>,
@@ -139,6 +183,10 @@ where
     type Item = Received<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(packet) = self.stream.take() {
+            return Some(packet);
+        }
+
         if let Some(packet) = self.ip4.as_mut().and_then(|i| i.next()) {
             return Some(packet);
         }
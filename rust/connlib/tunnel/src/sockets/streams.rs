@@ -0,0 +1,294 @@
+//! TCP and TLS connections to relays that we cannot reach via UDP.
+//!
+//! `snownet` decides when to talk to a relay via TCP or TLS, see [`snownet::Transport`].
+//! We connect on demand, i.e. when we send the first message, and frame all messages as described in [`snownet::stream`].
+
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures_util::FutureExt as _;
+use socket_factory::SocketFactory;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpSocket,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+/// How many bytes we buffer per stream before we start dropping packets.
+///
+/// Like with UDP, it is up to the protocols on top to deal with packet loss.
+const MAX_BUFFERED_BYTES: usize = 1024 * 1024;
+
+pub(crate) struct Streams {
+    tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    tls_connector: TlsConnector,
+
+    streams: HashMap<(SocketAddr, snownet::Transport), Stream>,
+
+    read_buffer: Box<[u8; 16 * 1024]>,
+}
+
+impl Streams {
+    pub(crate) fn new(tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>) -> Self {
+        Self {
+            tcp_socket_factory,
+            tls_connector: TlsConnector::from(tls_config()),
+            streams: HashMap::default(),
+            read_buffer: Box::new([0u8; 16 * 1024]),
+        }
+    }
+
+    /// Closes all streams.
+    pub(crate) fn clear(&mut self) {
+        self.streams.clear();
+    }
+
+    pub(crate) fn send(&mut self, transmit: snownet::Transmit) {
+        debug_assert_ne!(transmit.transport, snownet::Transport::Udp);
+
+        tracing::trace!(target: "wire::net::send", dst = %transmit.dst, transport = ?transmit.transport, num_bytes = %transmit.payload.len());
+
+        let stream = self
+            .streams
+            .entry((transmit.dst, transmit.transport))
+            .or_insert_with(|| {
+                tracing::info!(dst = %transmit.dst, transport = ?transmit.transport, "Connecting to relay");
+
+                Stream::connect(
+                    self.tcp_socket_factory.as_ref(),
+                    self.tls_connector.clone(),
+                    transmit.dst,
+                    transmit.transport,
+                )
+            });
+
+        if stream.write_buffer.len() + transmit.payload.len() > MAX_BUFFERED_BYTES {
+            tracing::debug!(dst = %transmit.dst, "Dropping packet because stream is busy");
+            return;
+        }
+
+        stream.write_buffer.extend_from_slice(&transmit.payload);
+        stream
+            .write_buffer
+            .extend_from_slice(snownet::stream::padding(&transmit.payload));
+    }
+
+    /// Drives all streams and returns the next message received on any of them.
+    ///
+    /// Returns our local address of the stream, the address of the relay and the message.
+    /// Streams that fail are closed, the next message will re-connect.
+    pub(crate) fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<(SocketAddr, SocketAddr, Bytes)> {
+        let mut failed = Vec::new();
+        let mut received = None;
+
+        for ((dst, transport), stream) in self.streams.iter_mut() {
+            match stream.poll_recv(self.read_buffer.as_mut(), cx) {
+                Poll::Ready(Ok((local, message))) => {
+                    tracing::trace!(target: "wire::net::recv", src = %dst, dst = %local, num_bytes = %message.len());
+
+                    received = Some((local, *dst, message));
+                    break;
+                }
+                Poll::Ready(Err(e)) => {
+                    tracing::info!(%dst, ?transport, "Stream to relay failed: {e}");
+
+                    failed.push((*dst, *transport));
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        for key in failed {
+            self.streams.remove(&key);
+        }
+
+        match received {
+            Some(received) => Poll::Ready(received),
+            None => Poll::Pending,
+        }
+    }
+}
+
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+struct Stream {
+    state: State,
+    write_buffer: BytesMut,
+    decoder: snownet::stream::Decoder,
+}
+
+enum State {
+    Connecting(BoxFuture<'static, io::Result<(SocketAddr, Box<dyn AsyncStream>)>>),
+    Connected {
+        local: SocketAddr,
+        io: Box<dyn AsyncStream>,
+    },
+}
+
+impl Stream {
+    fn connect(
+        tcp_socket_factory: &dyn SocketFactory<TcpSocket>,
+        tls_connector: TlsConnector,
+        dst: SocketAddr,
+        transport: snownet::Transport,
+    ) -> Self {
+        let socket = tcp_socket_factory(&dst);
+
+        let connect = async move {
+            let stream = socket?.connect(dst).await?;
+            stream.set_nodelay(true)?;
+            let local = stream.local_addr()?;
+
+            let io: Box<dyn AsyncStream> = match transport {
+                snownet::Transport::Tcp => Box::new(stream),
+                snownet::Transport::Tls => Box::new(
+                    tls_connector
+                        .connect(ServerName::from(dst.ip()), stream)
+                        .await?,
+                ),
+                snownet::Transport::Udp => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "UDP is not a stream",
+                    ))
+                }
+            };
+
+            Ok((local, io))
+        };
+
+        Self {
+            state: State::Connecting(connect.boxed()),
+            write_buffer: BytesMut::new(),
+            decoder: snownet::stream::Decoder::default(),
+        }
+    }
+
+    fn poll_recv(
+        &mut self,
+        read_buffer: &mut [u8],
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(SocketAddr, Bytes)>> {
+        loop {
+            let (local, io) = match &mut self.state {
+                State::Connecting(connect) => {
+                    let (local, io) = std::task::ready!(connect.poll_unpin(cx))?;
+                    self.state = State::Connected { local, io };
+
+                    continue;
+                }
+                State::Connected { local, io } => (*local, io),
+            };
+
+            while !self.write_buffer.is_empty() {
+                match Pin::new(&mut *io).poll_write(cx, &self.write_buffer)? {
+                    Poll::Ready(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Poll::Ready(n) => {
+                        let _ = self.write_buffer.split_to(n);
+                    }
+                    Poll::Pending => break,
+                }
+            }
+
+            if let Some(message) = self.decoder.decode()? {
+                return Poll::Ready(Ok((local, message)));
+            }
+
+            let mut buf = ReadBuf::new(read_buffer);
+            std::task::ready!(Pin::new(&mut *io).poll_read(cx, &mut buf))?;
+
+            if buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            self.decoder.push(buf.filled());
+        }
+    }
+}
+
+/// We don't verify the certificate of the relay.
+///
+/// TLS only makes our traffic to the relay look like any other HTTPS traffic to networks that block everything else.
+/// We authenticate ourselves to the relay via TURN's long-term credentials and our data is end-to-end encrypted by WireGuard.
+/// Thus, a machine-in-the-middle could at most drop our packets, which it can do regardless.
+fn tls_config() -> Arc<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,341 @@
+//! The parts of a userspace TCP endpoint that [`crate::dns::tcp`] and the gateway's egress proxy share.
+//!
+//! Both only ever act as the passive side of a connection and skip most of TCP's complexity:
+//!
+//! - Out-of-order segments are dropped and will be retransmitted by the remote's TCP stack.
+//! - We don't implement congestion control.
+//! - Retransmissions use a fixed timeout and send everything that hasn't been acknowledged (go-back-N).
+//! - We don't negotiate any TCP options, thus windows are never scaled.
+
+use ip_packet::tcp::{TcpFlags, TcpPacket};
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use rand_core::{OsRng, RngCore as _};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The max. number of payload bytes we put into a single segment.
+///
+/// Our MTU is 1280 and IPv6 + TCP headers take up 60 bytes.
+pub(crate) const MAX_SEGMENT_SIZE: usize = 1200;
+pub(crate) const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ConnectionId {
+    /// The address we terminate the connection on.
+    pub(crate) local: SocketAddr,
+    /// The address of the side that opened the connection.
+    pub(crate) remote: SocketAddr,
+}
+
+impl ConnectionId {
+    /// The connection an inbound segment belongs to.
+    pub(crate) fn inbound(packet: &IpPacket<'_>, tcp: &TcpPacket<'_>) -> Self {
+        Self {
+            local: SocketAddr::new(packet.destination(), tcp.get_destination()),
+            remote: SocketAddr::new(packet.source(), tcp.get_source()),
+        }
+    }
+}
+
+/// The sequence space and send buffer of a single connection.
+pub(crate) struct Tcb {
+    /// The next sequence number we expect to receive.
+    rcv_nxt: u32,
+    /// The oldest sequence number that has not yet been acknowledged by the remote.
+    snd_una: u32,
+    /// The sequence number we will use for the next byte we send.
+    snd_nxt: u32,
+
+    /// Data that the remote has not yet acknowledged, starting at `snd_una`.
+    ///
+    /// Everything after `snd_nxt` hasn't been sent yet.
+    pub(crate) send_buffer: VecDeque<u8>,
+
+    /// Whether the remote acknowledged our SYN.
+    established: bool,
+    pub(crate) remote_fin: bool,
+    /// Whether we send a FIN once all buffered data is sent.
+    local_fin: bool,
+    local_fin_sent: bool,
+
+    pub(crate) retransmit_at: Option<Instant>,
+    /// How many retransmissions we did since the remote last acknowledged something.
+    pub(crate) retransmits: u32,
+}
+
+impl Tcb {
+    /// Creates the state for a connection that was opened with a SYN carrying the given sequence number.
+    pub(crate) fn new(seq: u32) -> Self {
+        let iss = OsRng.next_u32();
+
+        Self {
+            rcv_nxt: seq.wrapping_add(1),
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            send_buffer: VecDeque::default(),
+            established: false,
+            remote_fin: false,
+            local_fin: false,
+            local_fin_sent: false,
+            retransmit_at: None,
+            retransmits: 0,
+        }
+    }
+
+    pub(crate) fn is_established(&self) -> bool {
+        self.established
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.local_fin
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.remote_fin && self.local_fin_sent && self.snd_una == self.snd_nxt
+    }
+
+    /// Whether a SYN is a retransmission of the one that opened this connection, i.e. the remote didn't receive our SYN-ACK.
+    pub(crate) fn is_syn_retransmit(&self, seq: u32) -> bool {
+        !self.established && seq.wrapping_add(1) == self.rcv_nxt
+    }
+
+    /// Processes an acknowledgement of the remote.
+    ///
+    /// Returns `false` if it acknowledges something we never sent.
+    pub(crate) fn handle_ack(&mut self, ack: u32, now: Instant) -> bool {
+        let acked = ack.wrapping_sub(self.snd_una);
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+
+        if acked > in_flight {
+            return false; // Invalid ACK.
+        }
+
+        if acked == 0 {
+            return true; // Duplicate ACK or window update.
+        }
+
+        let mut acked = acked as usize;
+
+        if !self.established {
+            self.established = true;
+            acked -= 1; // Our SYN consumed one sequence number.
+        }
+
+        let acked_data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..acked_data);
+        self.snd_una = ack;
+        self.retransmits = 0;
+
+        self.retransmit_at = (self.snd_una != self.snd_nxt).then_some(now + RETRANSMIT_TIMEOUT);
+
+        true
+    }
+
+    /// Returns the data of a segment that we haven't received yet, at most `window` bytes of it.
+    pub(crate) fn receive<'a>(&mut self, seq: u32, payload: &'a [u8], window: usize) -> &'a [u8] {
+        if !self.established || self.remote_fin {
+            return &[];
+        }
+
+        // Account for retransmitted segments that overlap with data we already received.
+        let offset = self.rcv_nxt.wrapping_sub(seq) as usize;
+        let Some(new_data) = payload.get(offset..) else {
+            return &[]; // Out-of-order or a full duplicate.
+        };
+        let new_data = &new_data[..new_data.len().min(window)];
+
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(new_data.len() as u32);
+
+        new_data
+    }
+
+    /// Processes the FIN of a segment.
+    ///
+    /// Returns `true` if the remote closed its side with this segment.
+    pub(crate) fn receive_fin(&mut self, seq: u32, payload_len: usize) -> bool {
+        if self.remote_fin || seq.wrapping_add(payload_len as u32) != self.rcv_nxt {
+            return false;
+        }
+
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        self.remote_fin = true;
+
+        true
+    }
+
+    /// Closes our side of the connection once all buffered data is sent.
+    pub(crate) fn close(&mut self) {
+        self.local_fin = true;
+    }
+
+    /// Sends all buffered data that fits into the remote's window `snd_wnd` and our FIN once we are closing.
+    pub(crate) fn transmit(
+        &mut self,
+        id: ConnectionId,
+        snd_wnd: usize,
+        rcv_wnd: u16,
+        now: Instant,
+        packets: &mut VecDeque<MutableIpPacket<'static>>,
+    ) {
+        if !self.established || self.local_fin_sent {
+            return;
+        }
+
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let window = snd_wnd.saturating_sub(sent);
+            let unsent = self.send_buffer.len().saturating_sub(sent);
+            let len = unsent.min(window).min(MAX_SEGMENT_SIZE);
+
+            if len == 0 {
+                if unsent > 0 {
+                    // The remote's window is closed, we need to probe it once it stays closed.
+                    self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+                }
+
+                break;
+            }
+
+            let payload = self
+                .send_buffer
+                .range(sent..sent + len)
+                .copied()
+                .collect::<Vec<_>>();
+
+            packets.push_back(segment(
+                id,
+                self.snd_nxt,
+                self.rcv_nxt,
+                TcpFlags::PSH | TcpFlags::ACK,
+                rcv_wnd,
+                &payload,
+            ));
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+        }
+
+        let all_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.send_buffer.len();
+
+        if self.local_fin && all_sent {
+            packets.push_back(segment(
+                id,
+                self.snd_nxt,
+                self.rcv_nxt,
+                TcpFlags::FIN | TcpFlags::ACK,
+                rcv_wnd,
+                &[],
+            ));
+
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.local_fin_sent = true;
+            self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+        }
+    }
+
+    /// Sends our SYN-ACK again or, once established, everything that hasn't been acknowledged.
+    ///
+    /// Also probes a closed window with a single byte.
+    pub(crate) fn retransmit(
+        &mut self,
+        id: ConnectionId,
+        snd_wnd: usize,
+        rcv_wnd: u16,
+        now: Instant,
+        packets: &mut VecDeque<MutableIpPacket<'static>>,
+    ) {
+        self.retransmits += 1;
+        self.retransmit_at = Some(now + RETRANSMIT_TIMEOUT);
+
+        if !self.established {
+            packets.push_back(self.syn_ack(id, rcv_wnd));
+            return;
+        }
+
+        // Go back to the oldest unacknowledged byte.
+        self.snd_nxt = self.snd_una;
+        self.local_fin_sent = false;
+        self.transmit(id, snd_wnd.max(1), rcv_wnd, now, packets);
+    }
+
+    pub(crate) fn syn_ack(&self, id: ConnectionId, rcv_wnd: u16) -> MutableIpPacket<'static> {
+        segment(
+            id,
+            self.snd_una,
+            self.rcv_nxt,
+            TcpFlags::SYN | TcpFlags::ACK,
+            rcv_wnd,
+            &[],
+        )
+    }
+
+    pub(crate) fn ack(&self, id: ConnectionId, rcv_wnd: u16) -> MutableIpPacket<'static> {
+        segment(id, self.snd_nxt, self.rcv_nxt, TcpFlags::ACK, rcv_wnd, &[])
+    }
+
+    pub(crate) fn rst(&self, id: ConnectionId) -> MutableIpPacket<'static> {
+        segment(
+            id,
+            self.snd_nxt,
+            self.rcv_nxt,
+            TcpFlags::RST | TcpFlags::ACK,
+            0,
+            &[],
+        )
+    }
+
+    /// Resets the connection before we sent our SYN-ACK, the remote doesn't know our sequence numbers yet.
+    pub(crate) fn refuse(&self, id: ConnectionId) -> MutableIpPacket<'static> {
+        segment(id, 0, self.rcv_nxt, TcpFlags::RST | TcpFlags::ACK, 0, &[])
+    }
+}
+
+/// RFC 9293: Any segment for a connection that doesn't exist gets a reset.
+pub(crate) fn reset_unknown(id: ConnectionId, tcp: &TcpPacket<'_>) -> MutableIpPacket<'static> {
+    let flags = tcp.get_flags();
+
+    if flags & TcpFlags::ACK != 0 {
+        return segment(id, tcp.get_acknowledgement(), 0, TcpFlags::RST, 0, &[]);
+    }
+
+    segment(
+        id,
+        0,
+        tcp.get_sequence().wrapping_add(segment_len(tcp)),
+        TcpFlags::RST | TcpFlags::ACK,
+        0,
+        &[],
+    )
+}
+
+/// Refuses a connection that was opened with a SYN carrying the given sequence number.
+pub(crate) fn refuse_syn(id: ConnectionId, seq: u32) -> MutableIpPacket<'static> {
+    segment(
+        id,
+        0,
+        seq.wrapping_add(1),
+        TcpFlags::RST | TcpFlags::ACK,
+        0,
+        &[],
+    )
+}
+
+/// The number of sequence numbers occupied by the given segment.
+fn segment_len(tcp: &TcpPacket<'_>) -> u32 {
+    let flags = tcp.get_flags();
+
+    tcp.payload().len() as u32
+        + u32::from(flags & TcpFlags::SYN != 0)
+        + u32::from(flags & TcpFlags::FIN != 0)
+}
+
+fn segment(
+    id: ConnectionId,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: &[u8],
+) -> MutableIpPacket<'static> {
+    ip_packet::make::tcp_segment(id.local, id.remote, seq, ack, flags, window, payload)
+}
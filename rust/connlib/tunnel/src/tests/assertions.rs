@@ -147,6 +147,7 @@ pub(crate) fn assert_dns_packets_properties(ref_client: &RefClient, sim_client:
 
         assert_correct_src_and_dst_ips(client_sent_query, client_received_response);
         assert_correct_src_and_dst_udp_ports(client_sent_query, client_received_response);
+        assert_dnssec_flags(client_sent_query, client_received_response);
     }
 }
 
@@ -199,6 +200,30 @@ fn assert_correct_src_and_dst_udp_ports(
     }
 }
 
+/// A validating stub resolver only trusts our responses if we mirror the DO bit of its query and never claim to have validated the answer.
+fn assert_dnssec_flags(client_sent_query: &IpPacket<'_>, client_received_response: &IpPacket<'_>) {
+    let query = client_sent_query.unwrap_as_dns();
+    let response = client_received_response.unwrap_as_dns();
+
+    let query_do = query.extensions().as_ref().is_some_and(|e| e.dnssec_ok());
+    let response_do = response
+        .extensions()
+        .as_ref()
+        .is_some_and(|e| e.dnssec_ok());
+
+    if query_do != response_do {
+        tracing::error!(target: "assertions", %query_do, %response_do, "❌ DO bit of response does not match query");
+    } else {
+        tracing::info!(target: "assertions", dnssec_ok = %query_do, "✅ DO bit of response matches query");
+    }
+
+    if response.authentic_data() {
+        tracing::error!(target: "assertions", "❌ Response claims to be authenticated");
+    } else {
+        tracing::info!(target: "assertions", "✅ Response does not claim to be authenticated");
+    }
+}
+
 fn assert_destination_is_cdir_resource(gateway_received_request: &IpPacket<'_>, expected: &IpAddr) {
     let actual = gateway_received_request.destination();
 
@@ -182,6 +182,12 @@ impl ReferenceStateMachine for ReferenceState {
             })
             .with(1, roam_client())
             .with(1, Just(Transition::ReconnectPortal))
+            .with(
+                1,
+                client_rate_limit().prop_map(Transition::SetClientRateLimit),
+            )
+            .with(1, Just(Transition::Idle))
+            .with(1, Just(Transition::ExpireAccess))
             .with_if_not_empty(
                 10,
                 state.client.inner().ipv4_cidr_resource_dsts(),
@@ -232,7 +238,26 @@ impl ReferenceStateMachine for ReferenceState {
                     state.client.ip4,
                 ),
                 |(domains, v4_dns_servers, _)| {
-                    dns_query(sample::select(domains), sample::select(v4_dns_servers))
+                    dns_query(
+                        sample::select(domains),
+                        sample::select(v4_dns_servers),
+                        address_record_type(),
+                    )
+                },
+            )
+            .with_if_not_empty(
+                2,
+                (
+                    state.dns_resource_domains(state.client.inner()),
+                    state.client.inner().v4_dns_servers(),
+                    state.client.ip4,
+                ),
+                |(domains, v4_dns_servers, _)| {
+                    dns_query(
+                        sample::select(domains),
+                        sample::select(v4_dns_servers),
+                        non_address_record_type(),
+                    )
                 },
             )
             .with_if_not_empty(
@@ -243,7 +268,26 @@ impl ReferenceStateMachine for ReferenceState {
                     state.client.ip6,
                 ),
                 |(domains, v6_dns_servers, _)| {
-                    dns_query(sample::select(domains), sample::select(v6_dns_servers))
+                    dns_query(
+                        sample::select(domains),
+                        sample::select(v6_dns_servers),
+                        address_record_type(),
+                    )
+                },
+            )
+            .with_if_not_empty(
+                2,
+                (
+                    state.dns_resource_domains(state.client.inner()),
+                    state.client.inner().v6_dns_servers(),
+                    state.client.ip6,
+                ),
+                |(domains, v6_dns_servers, _)| {
+                    dns_query(
+                        sample::select(domains),
+                        sample::select(v6_dns_servers),
+                        non_address_record_type(),
+                    )
                 },
             )
             .with_if_not_empty(
@@ -310,6 +354,16 @@ impl ReferenceStateMachine for ReferenceState {
                     client.connected_dns_resources.retain(|(r, _)| r != id);
                 });
             }
+            Transition::SendDnsQuery {
+                domain,
+                r_type: RecordType::TXT,
+                query_id,
+                ..
+            } => state.client.exec_mut(|client| {
+                client.on_dns_query_to_gateway(domain.clone(), *query_id, |r| {
+                    state.portal.gateway_for_resource(r).copied()
+                })
+            }),
             Transition::SendDnsQuery {
                 domain,
                 r_type,
@@ -330,6 +384,13 @@ impl ReferenceStateMachine for ReferenceState {
                         .client
                         .exec_mut(|client| client.connected_cidr_resources.insert(resource));
                 }
+                Some(_)
+                    if state.client.inner().exceeds_rate_limit()
+                        && !state.client.inner().upstream_dns_resolvers.is_empty()
+                        && !state.client.inner().is_known_host(&domain.to_string()) =>
+                {
+                    // The query is routed through the tunnel, where the gateway drops it.
+                }
                 Some(_) | None => {
                     state.client.exec_mut(|client| {
                         client
@@ -399,6 +460,33 @@ impl ReferenceStateMachine for ReferenceState {
             Transition::ReconnectPortal => {
                 // Reconnecting to the portal should have no noticeable impact on the data plane.
             }
+            Transition::SetClientRateLimit(limit) => {
+                state.client.exec_mut(|client| client.rate_limit = *limit);
+            }
+            Transition::Idle => {
+                state.now += idle_duration();
+                state.utc_now += idle_duration();
+
+                // Idle connections are closed, the next packet needs to establish a new one.
+                state
+                    .client
+                    .exec_mut(|client| client.connected_cidr_resources.clear());
+                state
+                    .client
+                    .exec_mut(|client| client.connected_dns_resources.clear());
+            }
+            Transition::ExpireAccess => {
+                state.now += access_duration();
+                state.utc_now += access_duration();
+
+                // Once the access expired, the next packet needs to request it again.
+                state
+                    .client
+                    .exec_mut(|client| client.connected_cidr_resources.clear());
+                state
+                    .client
+                    .exec_mut(|client| client.connected_dns_resources.clear());
+            }
         };
 
         state
@@ -495,6 +583,24 @@ impl ReferenceStateMachine for ReferenceState {
 
                 true
             }
+            Transition::SendDnsQuery {
+                domain,
+                r_type: RecordType::TXT,
+                dns_server,
+                ..
+            } => {
+                let ref_client = state.client.inner();
+                let Some(resource) = ref_client.dns_resource_by_domain(domain) else {
+                    return false;
+                };
+                let Some(gateway) = state.portal.gateway_for_resource(resource) else {
+                    return false;
+                };
+
+                state.global_dns_records.contains_key(domain)
+                    && ref_client.expected_dns_servers().contains(dns_server)
+                    && state.gateways.contains_key(gateway)
+            }
             Transition::SendDnsQuery {
                 domain, dns_server, ..
             } => {
@@ -532,7 +638,10 @@ impl ReferenceStateMachine for ReferenceState {
 
                 !is_assigned_ip4 && !is_assigned_ip6 && !is_previous_port
             }
-            Transition::ReconnectPortal => true,
+            Transition::ReconnectPortal
+            | Transition::SetClientRateLimit(_)
+            | Transition::Idle
+            | Transition::ExpireAccess => true,
             Transition::DeactivateResource(r) => {
                 state.client.inner().all_resource_ids().contains(r)
             }
@@ -555,6 +664,14 @@ impl ReferenceState {
             .collect()
     }
 
+    fn dns_resource_domains(&self, client: &RefClient) -> Vec<DomainName> {
+        self.global_dns_records
+            .keys()
+            .filter(|domain| client.dns_resource_by_domain(domain).is_some())
+            .cloned()
+            .collect()
+    }
+
     fn all_resources_not_known_to_client(&self) -> Vec<ResourceDescription> {
         let mut all_resources = self.portal.all_resources();
         all_resources.retain(|r| !self.client.inner().has_resource(r.id()));
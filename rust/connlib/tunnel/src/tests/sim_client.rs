@@ -10,13 +10,14 @@ use bimap::BiMap;
 use connlib_shared::{
     messages::{
         client::{ResourceDescription, ResourceDescriptionCidr, ResourceDescriptionDns},
+        gateway::RateLimit,
         ClientId, DnsServer, GatewayId, Interface, ResourceId,
     },
     proptest::{client_id, domain_name},
     DomainName,
 };
 use hickory_proto::{
-    op::MessageType,
+    op::{Edns, MessageType},
     rr::{rdata, RData, RecordType},
     serialize::binary::BinDecodable as _,
 };
@@ -82,6 +83,7 @@ impl SimClient {
         r_type: RecordType,
         query_id: u16,
         dns_server: SocketAddr,
+        dnssec_ok: bool,
         now: Instant,
     ) -> Option<Transmit<'static>> {
         let Some(dns_server) = self.dns_by_sentinel.get_by_right(&dns_server).copied() else {
@@ -98,12 +100,24 @@ impl SimClient {
             .tunnel_ip_for(dns_server)
             .expect("tunnel should be initialised");
 
-        let packet = ip_packet::make::dns_query(
-            name,
-            r_type,
-            SocketAddr::new(src, 9999), // An application would pick a random source port that is free.
-            SocketAddr::new(dns_server, 53),
-            query_id,
+        let src = SocketAddr::new(src, 9999); // An application would pick a random source port that is free.
+        let dst = SocketAddr::new(dns_server, 53);
+
+        let mut message = ip_packet::make::dns_query(name, r_type, src, dst, query_id)
+            .into_immutable()
+            .unwrap_as_dns();
+        if dnssec_ok {
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(true);
+            message.set_edns(edns);
+        }
+
+        let packet = ip_packet::make::udp_packet(
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port(),
+            message.to_vec().expect("to be able to serialize DNS query"),
         );
 
         self.encapsulate(packet, now)
@@ -189,6 +203,7 @@ impl SimClient {
                     let ip = match record.data() {
                         Some(RData::A(rdata::A(ip4))) => IpAddr::from(*ip4),
                         Some(RData::AAAA(rdata::AAAA(ip6))) => IpAddr::from(*ip6),
+                        Some(RData::TXT(_)) => continue,
                         unhandled => {
                             panic!("Unexpected record data: {unhandled:?}")
                         }
@@ -226,6 +241,8 @@ pub struct RefClient {
     pub(crate) system_dns_resolvers: Vec<IpAddr>,
     /// The upstream DNS resolvers configured in the portal.
     pub(crate) upstream_dns_resolvers: Vec<DnsServer>,
+    /// The rate limit of the client's traffic configured in the portal.
+    pub(crate) rate_limit: Option<RateLimit>,
 
     /// The CIDR resources the client is aware of.
     pub(crate) cidr_resources: IpNetworkTable<ResourceDescriptionCidr>,
@@ -263,6 +280,8 @@ impl RefClient {
             ipv4: self.tunnel_ip4,
             ipv6: self.tunnel_ip6,
             upstream_dns: self.upstream_dns_resolvers,
+            proxy_ip_lease: None,
+            dns_forwarding_rules: vec![],
         });
         let _ = client_state.update_system_resolvers(self.system_dns_resolvers);
 
@@ -307,6 +326,13 @@ impl RefClient {
         };
 
         if self.is_connected_to_cidr(resource.id) && self.is_tunnel_ip(src) {
+            if self.exceeds_rate_limit() {
+                tracing::debug!(
+                    "Connected to CIDR resource, expecting packet to be dropped by the rate limit"
+                );
+                return;
+            }
+
             tracing::debug!("Connected to CIDR resource, expecting packet to be routed");
             self.expected_icmp_handshakes
                 .entry(gateway)
@@ -348,6 +374,13 @@ impl RefClient {
             .contains(&(resource, dst.clone()))
             && self.is_tunnel_ip(src)
         {
+            if self.exceeds_rate_limit() {
+                tracing::debug!(
+                    "Connected to DNS resource, expecting packet to be dropped by the rate limit"
+                );
+                return;
+            }
+
             tracing::debug!("Connected to DNS resource, expecting packet to be routed");
             self.expected_icmp_handshakes
                 .entry(gateway)
@@ -365,6 +398,41 @@ impl RefClient {
         self.connected_dns_resources.insert((resource, dst));
     }
 
+    /// Queries for non-address records of DNS resources are forwarded to the gateway, just like any other packet to the resource.
+    #[tracing::instrument(level = "debug", skip_all, fields(dst, resource))]
+    pub(crate) fn on_dns_query_to_gateway(
+        &mut self,
+        dst: DomainName,
+        query_id: u16,
+        gateway_by_resource: impl Fn(ResourceId) -> Option<GatewayId>,
+    ) {
+        tracing::Span::current().record("dst", tracing::field::display(&dst));
+
+        let Some(resource) = self.dns_resource_by_domain(&dst) else {
+            tracing::error!("No resource corresponds to domain");
+            return;
+        };
+
+        tracing::Span::current().record("resource", tracing::field::display(resource));
+
+        if gateway_by_resource(resource).is_none() {
+            tracing::error!("No gateway for resource");
+            return;
+        }
+
+        if self
+            .connected_dns_resources
+            .contains(&(resource, dst.clone()))
+        {
+            tracing::debug!("Connected to DNS resource, expecting gateway to answer query");
+            self.expected_dns_handshakes.push_back(query_id);
+            return;
+        }
+
+        tracing::debug!("Not connected to resource, expecting to trigger connection intent");
+        self.connected_dns_resources.insert((resource, dst));
+    }
+
     pub(crate) fn ipv4_cidr_resource_dsts(&self) -> Vec<Ipv4Network> {
         self.cidr_resources
             .iter_ipv4()
@@ -383,6 +451,14 @@ impl RefClient {
         self.connected_cidr_resources.contains(&id)
     }
 
+    /// Whether the gateways drop all of the client's traffic to resources.
+    ///
+    /// [`client_rate_limit`](super::transition::client_rate_limit) only generates limits that either drop every packet or none.
+    pub(crate) fn exceeds_rate_limit(&self) -> bool {
+        self.rate_limit
+            .is_some_and(|l| l.burst_packets.or(l.packets_per_second) == Some(0))
+    }
+
     pub(crate) fn is_known_host(&self, name: &str) -> bool {
         self.known_hosts.contains_key(name)
     }
@@ -391,10 +467,8 @@ impl RefClient {
         self.dns_resources
             .values()
             .filter(|r| is_subdomain(&domain.to_string(), &r.address))
-            .sorted_by_key(|r| r.address.len())
-            .rev()
+            .max_by_key(|r| specificity(&r.address))
             .map(|r| r.id)
-            .next()
     }
 
     fn resolved_domains(&self) -> impl Iterator<Item = (DomainName, HashSet<RecordType>)> + '_ {
@@ -566,6 +640,15 @@ impl RefClient {
     }
 }
 
+/// The most specific resource wins: the one with the longest non-wildcard part and then exact matches over `?` over `*`.
+fn specificity(address: &str) -> (usize, u8) {
+    match address.split_once('.') {
+        Some(("*", rest)) => (rest.len(), 0),
+        Some(("?", rest)) => (rest.len(), 1),
+        Some(_) | None => (address.len(), 2),
+    }
+}
+
 fn is_subdomain(name: &str, record: &str) -> bool {
     if name == record {
         return true;
@@ -652,6 +735,7 @@ fn ref_client(
                 tunnel_ip6,
                 system_dns_resolvers,
                 upstream_dns_resolvers,
+                rate_limit: None,
                 cidr_resources: IpNetworkTable::new(),
                 dns_resources: Default::default(),
                 dns_records: Default::default(),
@@ -4,7 +4,7 @@ use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerS
 use proptest::prelude::*;
 use rand::rngs::StdRng;
 use secrecy::SecretString;
-use snownet::{RelaySocket, Transmit};
+use snownet::{RelaySocket, Transmit, Transport};
 use std::{
     borrow::Cow,
     collections::HashSet,
@@ -15,6 +15,8 @@ use std::{
 pub(crate) struct SimRelay {
     pub(crate) sut: firezone_relay::Server<StdRng>,
     pub(crate) allocations: HashSet<(AddressFamily, AllocationPort)>,
+    /// Transports on which packets from clients never reach us, e.g. because a firewall drops them.
+    blocked_transports: HashSet<Transport>,
     buffer: Vec<u8>,
 }
 
@@ -38,10 +40,34 @@ impl SimRelay {
         Self {
             sut,
             allocations: Default::default(),
+            blocked_transports: Default::default(),
             buffer: vec![0u8; (1 << 16) - 1],
         }
     }
 
+    pub(crate) fn block(&mut self, transport: Transport) {
+        self.blocked_transports.insert(transport);
+    }
+
+    /// The socket and transport on which we send to a client, i.e. the connection it opened to us.
+    pub(crate) fn sending_socket_to(
+        &self,
+        client: ClientSocket,
+    ) -> Option<(SocketAddr, Transport)> {
+        let transport = match client.transport() {
+            firezone_relay::Transport::Udp => Transport::Udp,
+            firezone_relay::Transport::Tcp => Transport::Tcp,
+            firezone_relay::Transport::Tls => Transport::Tls,
+        };
+        let socket =
+            self.matching_listen_socket(client.into_socket(), self.sut.public_address())?;
+
+        Some((
+            SocketAddr::new(socket.ip(), listen_port(transport)),
+            transport,
+        ))
+    }
+
     fn explode(
         &self,
         username: &str,
@@ -80,13 +106,36 @@ impl SimRelay {
         payload: &[u8],
         sender: SocketAddr,
         dst: SocketAddr,
+        transport: Transport,
         now: Instant,
     ) -> Option<Transmit<'static>> {
+        if self.blocked_transports.contains(&transport) {
+            tracing::debug!(%sender, ?transport, "Dropping packet blocked by firewall");
+
+            return None;
+        }
+
         if self
             .matching_listen_socket(dst, self.sut.public_address())
-            .is_some_and(|s| s == dst)
+            .is_some_and(|s| s.ip() == dst.ip() && listen_port(transport) == dst.port())
         {
-            return self.handle_client_input(payload, ClientSocket::new(sender), now);
+            let transport = match transport {
+                Transport::Udp => firezone_relay::Transport::Udp,
+                Transport::Tcp => firezone_relay::Transport::Tcp,
+                Transport::Tls => firezone_relay::Transport::Tls,
+            };
+
+            return self.handle_client_input(
+                payload,
+                ClientSocket::with_transport(sender, transport),
+                now,
+            );
+        }
+
+        if transport != Transport::Udp {
+            tracing::debug!(%dst, ?transport, "Dropping stream packet to unknown socket");
+
+            return None;
         }
 
         self.handle_peer_traffic(
@@ -140,6 +189,7 @@ impl SimRelay {
             src: Some(src),
             dst,
             payload: Cow::Owned(payload.to_vec()),
+            transport: Transport::Udp,
         })
     }
 
@@ -158,15 +208,13 @@ impl SimRelay {
         );
         self.buffer[4..full_length].copy_from_slice(payload);
 
-        let receiving_socket = client.into_socket();
-        let sending_socket = self
-            .matching_listen_socket(receiving_socket, self.sut.public_address())
-            .unwrap();
+        let (sending_socket, transport) = self.sending_socket_to(client).unwrap();
 
         Some(Transmit {
             src: Some(sending_socket),
-            dst: receiving_socket,
+            dst: client.into_socket(),
             payload: Cow::Owned(self.buffer[..full_length].to_vec()),
+            transport,
         })
     }
 
@@ -184,6 +232,14 @@ impl SimRelay {
     }
 }
 
+/// The port we accept clients' packets on for the given transport.
+fn listen_port(transport: Transport) -> u16 {
+    match transport {
+        Transport::Udp | Transport::Tcp => 3478,
+        Transport::Tls => 443,
+    }
+}
+
 pub(crate) fn relay_prototype() -> impl Strategy<Value = Host<u64>> {
     host(
         dual_ip_stack(), // For this test, our relays always run in dual-stack mode to ensure connectivity!
@@ -191,3 +247,89 @@ pub(crate) fn relay_prototype() -> impl Strategy<Value = Host<u64>> {
         any::<u64>(),
     )
 }
+
+#[test]
+fn allocation_falls_back_to_tcp_and_then_tls_if_relay_drops_udp() {
+    use connlib_shared::messages::GatewayId;
+    use rand::SeedableRng as _;
+    use std::net::Ipv4Addr;
+
+    let start = Instant::now();
+    let client = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 52625));
+
+    let mut relay = SimRelay::new(firezone_relay::Server::new(
+        IpStack::Ip4(Ipv4Addr::new(10, 0, 0, 1)),
+        StdRng::seed_from_u64(0),
+        3478,
+        49152..=65535,
+    ));
+    relay.block(Transport::Udp);
+    relay.block(Transport::Tcp);
+
+    let (socket, username, password, realm) = relay.explode(
+        "client",
+        relay.sut.auth_secret(),
+        relay.sut.public_address(),
+    );
+    let mut node = snownet::ClientNode::<GatewayId, RelayId>::new(
+        boringtun::x25519::StaticSecret::random_from_rng(rand::rngs::OsRng),
+    );
+    node.update_relays(
+        HashSet::default(),
+        &HashSet::from([(RelayId::from_u128(1), socket, username, password, realm)]),
+        start,
+    );
+
+    let mut now = start;
+    let mut transports = Vec::new();
+    let mut buffer = vec![0u8; (1 << 16) - 1];
+
+    while relay.allocations.is_empty() {
+        assert!(
+            now < start + Duration::from_secs(60),
+            "Failed to allocate via {transports:?}"
+        );
+
+        while let Some(transmit) = node.poll_transmit() {
+            if transports.last() != Some(&transmit.transport) {
+                transports.push(transmit.transport);
+            }
+
+            relay.handle_packet(
+                &transmit.payload,
+                client,
+                transmit.dst,
+                transmit.transport,
+                now,
+            );
+        }
+
+        while let Some(command) = relay.sut.next_command() {
+            match command {
+                firezone_relay::Command::SendMessage { payload, recipient } => {
+                    let (src, _) = relay.sending_socket_to(recipient).unwrap();
+
+                    node.decapsulate(client, src, &payload, now, &mut buffer)
+                        .unwrap();
+                }
+                firezone_relay::Command::CreateAllocation { port, family } => {
+                    relay.allocations.insert((family, port));
+                }
+                firezone_relay::Command::FreeAllocation { port, family } => {
+                    relay.allocations.remove(&(family, port));
+                }
+            }
+        }
+
+        let timeout = node
+            .poll_timeout()
+            .expect("to retry until we have an allocation");
+        now = now.max(timeout);
+        node.handle_timeout(now);
+    }
+
+    assert_eq!(
+        transports,
+        vec![Transport::Udp, Transport::Tcp, Transport::Tls]
+    );
+}
@@ -107,6 +107,8 @@ impl StubPortal {
                     address: r.address,
                     name: r.name.clone(),
                     filters: Vec::new(),
+                    rate_limit: None,
+                    port_mappings: Vec::new(),
                 },
             ))
         });
@@ -117,6 +119,10 @@ impl StubPortal {
                 filters: Vec::new(),
                 domain: r.address.clone(),
                 addresses: resolved_ips.clone(),
+                ttl: None,
+                rate_limit: None,
+                port_mappings: Vec::new(),
+                load_balancing: None,
             })
         });
 
@@ -7,10 +7,10 @@ use super::stub_portal::StubPortal;
 use crate::dns::is_subdomain;
 use crate::tests::assertions::*;
 use crate::tests::sim_relay::map_explode;
-use crate::tests::transition::Transition;
+use crate::tests::transition::{access_duration, Transition};
 use crate::{dns::DnsQuery, ClientEvent, GatewayEvent, Request};
 use chrono::{DateTime, Utc};
-use connlib_shared::messages::client::ResourceDescription;
+use connlib_shared::messages::{client::ResourceDescription, gateway::RateLimit};
 use connlib_shared::{
     messages::{ClientId, GatewayId, Interface, RelayId},
     DomainName,
@@ -18,7 +18,7 @@ use connlib_shared::{
 use firezone_relay::IpStack;
 use hickory_proto::{
     op::Query,
-    rr::{RData, Record, RecordType},
+    rr::{rdata::TXT, RData, Record, RecordType},
 };
 use hickory_resolver::lookup::Lookup;
 use proptest_state_machine::{ReferenceStateMachine, StateMachineTest};
@@ -234,9 +234,12 @@ impl StateMachineTest for TunnelTest {
                 r_type,
                 query_id,
                 dns_server,
+                dnssec_ok,
             } => {
                 let transmit = state.client.exec_mut(|sim| {
-                    sim.send_dns_query_for(domain, r_type, query_id, dns_server, state.now)
+                    sim.send_dns_query_for(
+                        domain, r_type, query_id, dns_server, dnssec_ok, state.now,
+                    )
                 });
 
                 buffered_transmits.push(transmit, &state.client);
@@ -252,6 +255,8 @@ impl StateMachineTest for TunnelTest {
                         ipv4: c.sut.tunnel_ip4().unwrap(),
                         ipv6: c.sut.tunnel_ip6().unwrap(),
                         upstream_dns: servers,
+                        proxy_ip_lease: None,
+                        dns_forwarding_rules: vec![],
                     })
                 });
             }
@@ -288,12 +293,27 @@ impl StateMachineTest for TunnelTest {
                         ipv4,
                         ipv6,
                         upstream_dns,
+                        proxy_ip_lease: None,
+                        dns_forwarding_rules: vec![],
                     });
                     c.sut
                         .update_relays(HashSet::default(), relays, ref_state.now);
                     c.sut.set_resources(all_resources);
                 });
             }
+            Transition::SetClientRateLimit(limit) => {
+                // In production, the portal sends the client's rate limit along with every access it grants.
+                // Applying it right away saves us from tracking which gateways already got the new one.
+                let client_id = state.client.inner().id;
+
+                for gateway in state.gateways.values_mut() {
+                    gateway.exec_mut(|g| g.sut.set_client_rate_limit(client_id, limit, state.now));
+                }
+            }
+            Transition::Idle | Transition::ExpireAccess => {
+                state.now = ref_state.now;
+                state.utc_now = ref_state.utc_now;
+            }
         };
         state.advance(ref_state, &mut buffered_transmits);
         assert!(buffered_transmits.is_empty()); // Sanity check to ensure we handled all packets.
@@ -365,6 +385,7 @@ impl TunnelTest {
                     event,
                     &ref_state.portal,
                     &ref_state.global_dns_records,
+                    ref_state.client.inner().rate_limit,
                 );
                 continue;
             }
@@ -390,7 +411,14 @@ impl TunnelTest {
                     continue;
                 };
 
-                on_gateway_event(*id, event, &mut self.client, self.now);
+                on_gateway_event(
+                    *id,
+                    event,
+                    gateway,
+                    &mut self.client,
+                    &ref_state.global_dns_records,
+                    self.now,
+                );
                 continue 'outer;
             }
 
@@ -402,8 +430,9 @@ impl TunnelTest {
                 match message {
                     firezone_relay::Command::SendMessage { payload, recipient } => {
                         let dst = recipient.into_socket();
-                        let src = relay
-                            .sending_socket_for(dst.ip())
+                        let (src, transport) = relay
+                            .inner()
+                            .sending_socket_to(recipient)
                             .expect("relay to never emit packets without a matching socket");
 
                         buffered_transmits.push(
@@ -411,6 +440,7 @@ impl TunnelTest {
                                 src: Some(src),
                                 dst,
                                 payload: payload.into(),
+                                transport,
                             },
                             relay,
                         );
@@ -494,6 +524,7 @@ impl TunnelTest {
             .src
             .expect("`src` should always be set in these tests");
         let dst = transmit.dst;
+        let transport = transmit.transport;
         let payload = &transmit.payload;
 
         let Some(host) = self.network.host_by_ip(dst.ip()) else {
@@ -526,7 +557,7 @@ impl TunnelTest {
                 let relay = self.relays.get_mut(&id).expect("unknown relay");
 
                 let Some(transmit) =
-                    relay.exec_mut(|r| r.handle_packet(payload, src, dst, self.now))
+                    relay.exec_mut(|r| r.handle_packet(payload, src, dst, transport, self.now))
                 else {
                     return;
                 };
@@ -545,6 +576,7 @@ impl TunnelTest {
         event: ClientEvent,
         portal: &StubPortal,
         global_dns_records: &BTreeMap<DomainName, HashSet<IpAddr>>,
+        rate_limit: Option<RateLimit>,
     ) {
         match event {
             ClientEvent::AddedIceCandidates {
@@ -633,12 +665,20 @@ impl TunnelTest {
                                         .client_payload
                                         .domain
                                         .map(|r| (r.name, r.proxy_ips)),
-                                    None, // TODO: How to generate expiry?
+                                    Some(self.utc_now + access_duration()),
                                     resource,
                                     self.now,
+                                    self.utc_now,
                                 )
                             })
                             .unwrap();
+                        gateway.exec_mut(|g| {
+                            g.sut.set_client_rate_limit(
+                                self.client.inner().id,
+                                rate_limit,
+                                self.now,
+                            )
+                        });
 
                         self.client
                             .exec_mut(|c| {
@@ -662,17 +702,23 @@ impl TunnelTest {
                             .get_mut(&reuse_connection.gateway_id)
                             .expect("unknown gateway");
 
-                        gateway
-                            .exec_mut(|g| {
-                                g.sut.allow_access(
+                        gateway.exec_mut(|g| {
+                            g.sut
+                                .allow_access(
                                     resource,
                                     self.client.inner().id,
-                                    None,
+                                    Some(self.utc_now + access_duration()),
                                     reuse_connection.payload.map(|r| (r.name, r.proxy_ips)),
                                     self.now,
+                                    self.utc_now,
                                 )
-                            })
-                            .unwrap();
+                                .unwrap();
+                            g.sut.set_client_rate_limit(
+                                self.client.inner().id,
+                                rate_limit,
+                                self.now,
+                            );
+                        });
                     }
                 };
             }
@@ -698,17 +744,20 @@ impl TunnelTest {
                         reuse_connection.resource_id,
                     );
 
-                    gateway
-                        .exec_mut(|g| {
-                            g.sut.allow_access(
+                    gateway.exec_mut(|g| {
+                        g.sut
+                            .allow_access(
                                 resource,
                                 self.client.inner().id,
-                                None,
+                                Some(self.utc_now + access_duration()),
                                 reuse_connection.payload.map(|r| (r.name, r.proxy_ips)),
                                 self.now,
+                                self.utc_now,
                             )
-                        })
-                        .unwrap();
+                            .unwrap();
+                        g.sut
+                            .set_client_rate_limit(self.client.inner().id, rate_limit, self.now);
+                    });
                 }
             }
             ClientEvent::ResourcesChanged { .. } => {
@@ -721,6 +770,7 @@ impl TunnelTest {
                     .exec_mut(|c| c.dns_by_sentinel = dns_by_sentinel);
             }
             ClientEvent::TunRoutesUpdated { .. } => {}
+            ClientEvent::DnsQueryLogged { .. } => {}
         }
     }
 
@@ -729,15 +779,29 @@ impl TunnelTest {
     // - hickory error?
     // - TTL?
     fn on_forwarded_dns_query(&mut self, query: DnsQuery<'static>, ref_state: &ReferenceState) {
-        let all_ips = &ref_state
-            .global_dns_records
-            .get(&query.name)
-            .expect("Forwarded DNS query to be for known domain");
+        let lookup = lookup(&query, &ref_state.global_dns_records);
 
-        let name = domain_to_hickory_name(query.name.clone());
-        let requested_type = query.record_type;
+        self.client
+            .exec_mut(|c| c.sut.on_dns_result(query, Ok(Ok(Ok(lookup))), self.now))
+    }
+}
 
-        let record_data = all_ips
+/// Resolves a DNS query against the global DNS records.
+fn lookup(
+    query: &DnsQuery<'static>,
+    global_dns_records: &BTreeMap<DomainName, HashSet<IpAddr>>,
+) -> Lookup {
+    let all_ips = global_dns_records
+        .get(&query.name)
+        .expect("Forwarded DNS query to be for known domain");
+
+    let name = domain_to_hickory_name(query.name.clone());
+    let requested_type = query.record_type;
+
+    let record_data = if requested_type == RecordType::TXT {
+        vec![RData::TXT(TXT::new(vec![query.name.to_string()]))]
+    } else {
+        all_ips
             .iter()
             .filter_map(|ip| match (requested_type, ip) {
                 (RecordType::A, IpAddr::V4(v4)) => Some(RData::A((*v4).into())),
@@ -745,25 +809,23 @@ impl TunnelTest {
                 (RecordType::A, IpAddr::V6(_)) | (RecordType::AAAA, IpAddr::V4(_)) => None,
                 _ => unreachable!(),
             })
-            .map(|rdata| Record::from_rdata(name.clone(), 86400_u32, rdata))
-            .collect::<Arc<_>>();
-
-        self.client.exec_mut(|c| {
-            c.sut.on_dns_result(
-                query,
-                Ok(Ok(Ok(Lookup::new_with_max_ttl(
-                    Query::query(name, requested_type),
-                    record_data,
-                )))),
-            )
-        })
-    }
+            .collect()
+    };
+
+    let records = record_data
+        .into_iter()
+        .map(|rdata| Record::from_rdata(name.clone(), 86400_u32, rdata))
+        .collect::<Arc<_>>();
+
+    Lookup::new_with_max_ttl(Query::query(name, requested_type), records)
 }
 
 fn on_gateway_event(
     src: GatewayId,
     event: GatewayEvent,
+    gateway: &mut Host<SimGateway>,
     client: &mut Host<SimClient>,
+    global_dns_records: &BTreeMap<DomainName, HashSet<IpAddr>>,
     now: Instant,
 ) {
     match event {
@@ -778,6 +840,33 @@ fn on_gateway_event(
             }
         }),
         GatewayEvent::RefreshDns { .. } => todo!(),
+        GatewayEvent::ResolveDnsQuery { conn_id, query } => {
+            let lookup = lookup(&query, global_dns_records);
+
+            gateway.exec_mut(|g| g.sut.on_dns_result(conn_id, query, Ok(lookup), now))
+        }
+        GatewayEvent::RateLimited { .. } => {
+            // Only reported to the portal, the dropped packets are covered by the reference state.
+        }
+        GatewayEvent::NatExhaustion { .. } => {
+            // Only reported to the operator, our tests don't come close to the default limit of NAT sessions.
+        }
+        GatewayEvent::FlowLogged { .. } => {
+            // Flow records are only exported, they don't affect the data plane.
+        }
+        GatewayEvent::AccessExpired { resource_id, .. } => {
+            client.exec_mut(|c| c.sut.on_access_expired(src, resource_id))
+        }
+        GatewayEvent::ProbeBackend { ip, probe, .. } => {
+            // Our resources don't go down, thus every backend is healthy.
+            gateway.exec_mut(|g| g.sut.on_probe_result(ip, probe, true))
+        }
+        GatewayEvent::EgressProxyConnect { .. }
+        | GatewayEvent::EgressProxyData { .. }
+        | GatewayEvent::EgressProxyShutdown { .. }
+        | GatewayEvent::EgressProxyClosed { .. } => {
+            // Only TCP connections are relayed through the egress proxy and our tests only send ICMP packets.
+        }
     }
 }
 
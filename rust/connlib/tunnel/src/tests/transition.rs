@@ -1,11 +1,14 @@
 use super::sim_net::{any_ip_stack, any_port};
 use connlib_shared::{
-    messages::{client::ResourceDescription, DnsServer, ResourceId},
+    messages::{client::ResourceDescription, gateway::RateLimit, DnsServer, ResourceId},
     DomainName,
 };
 use hickory_proto::rr::RecordType;
 use proptest::{prelude::*, sample};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 /// The possible transitions of the state machine.
 #[derive(Clone, derivative::Derivative)]
@@ -49,6 +52,8 @@ pub(crate) enum Transition {
         /// The DNS query ID.
         query_id: u16,
         dns_server: SocketAddr,
+        /// Whether the query has the DNSSEC OK (DO) bit set in its EDNS record.
+        dnssec_ok: bool,
     },
 
     /// The system's DNS servers changed.
@@ -65,6 +70,28 @@ pub(crate) enum Transition {
 
     /// Reconnect to the portal.
     ReconnectPortal,
+
+    /// The portal changed the rate limit of the client's traffic through the gateways.
+    SetClientRateLimit(Option<RateLimit>),
+
+    /// Let time pass without any traffic until all connections are idle.
+    Idle,
+
+    /// Let time pass until the client's access to all resources expired.
+    ExpireAccess,
+}
+
+/// How much time passes in [`Transition::Idle`].
+pub(crate) fn idle_duration() -> Duration {
+    snownet::ConnectionConfig::default().idle_timeout + Duration::from_secs(1)
+}
+
+/// For how long the portal grants access to a resource.
+///
+/// This is also how much time passes in [`Transition::ExpireAccess`].
+/// It needs to be shorter than [`idle_duration`] so that connections stay alive while their access expires.
+pub(crate) fn access_duration() -> Duration {
+    Duration::from_secs(60)
 }
 
 pub(crate) fn ping_random_ip<I>(
@@ -141,6 +168,7 @@ where
 pub(crate) fn dns_query<S>(
     domain: impl Strategy<Value = DomainName>,
     dns_server: impl Strategy<Value = S>,
+    r_type: impl Strategy<Value = RecordType>,
 ) -> impl Strategy<Value = Transition>
 where
     S: Into<SocketAddr>,
@@ -148,17 +176,47 @@ where
     (
         domain,
         dns_server.prop_map_into(),
-        prop_oneof![Just(RecordType::A), Just(RecordType::AAAA)],
+        r_type,
         any::<u16>(),
+        any::<bool>(),
     )
-        .prop_map(
-            move |(domain, dns_server, r_type, query_id)| Transition::SendDnsQuery {
+        .prop_map(move |(domain, dns_server, r_type, query_id, dnssec_ok)| {
+            Transition::SendDnsQuery {
                 domain,
                 r_type,
                 query_id,
                 dns_server,
-            },
-        )
+                dnssec_ok,
+            }
+        })
+}
+
+pub(crate) fn address_record_type() -> impl Strategy<Value = RecordType> {
+    prop_oneof![Just(RecordType::A), Just(RecordType::AAAA)]
+}
+
+/// Records that connlib cannot answer itself for DNS resources and instead forwards to the gateway.
+pub(crate) fn non_address_record_type() -> impl Strategy<Value = RecordType> {
+    Just(RecordType::TXT)
+}
+
+/// A client rate limit that is either far above or far below what our tests send.
+///
+/// A limit of zero packets drops all of them.
+/// We can't use a tiny byte limit for that because the byte bucket always fits one full-size packet.
+/// None of our tests come close to sending a million packets, so the generous limit never drops any.
+pub(crate) fn client_rate_limit() -> impl Strategy<Value = Option<RateLimit>> {
+    prop_oneof![
+        Just(None),
+        Just(Some(RateLimit {
+            packets_per_second: Some(0),
+            ..Default::default()
+        })),
+        Just(Some(RateLimit {
+            packets_per_second: Some(1_000_000),
+            ..Default::default()
+        })),
+    ]
 }
 
 pub(crate) fn roam_client() -> impl Strategy<Value = Transition> {
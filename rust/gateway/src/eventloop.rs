@@ -1,30 +1,44 @@
+use crate::egress_proxy::{self, EgressProxy};
+use crate::flow_log::{FlowExporter, FlowLogRecord};
 use crate::messages::{
-    AllowAccess, ClientIceCandidates, ClientsIceCandidates, ConnectionReady, EgressMessages,
-    IngressMessages, RejectAccess, RequestConnection,
+    AccessExpired, AllowAccess, ClientIceCandidates, ClientsIceCandidates, ConnectionReady,
+    EgressMessages, IngressMessages, RejectAccess, RequestConnection,
 };
+use crate::metrics::Metrics;
 use anyhow::Result;
 use boringtun::x25519::PublicKey;
 use connlib_shared::messages::{
-    ClientId, ConnectionAccepted, Interface, RelaysPresence, ResourceAccepted, ResourceId,
+    gateway::HealthProbe, ClientId, ConnectionAccepted, DnsResolutionError, DomainResponse,
+    Interface, RelaysPresence, ResourceAccepted, ResourceId,
 };
 use connlib_shared::{messages::GatewayResponse, DomainName};
-#[cfg(not(target_os = "windows"))]
-use dns_lookup::{AddrInfoHints, AddrInfoIter, LookupError};
-use firezone_tunnel::GatewayTunnel;
+use firezone_tunnel::{DnsQuery, EgressStreamId, GatewayTunnel};
 use futures::channel::mpsc;
 use futures_bounded::Timeout;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::lookup::Lookup;
+use hickory_resolver::proto::error::ProtoErrorKind;
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::TokioAsyncResolver;
 use phoenix_channel::PhoenixChannel;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 pub const PHOENIX_TOPIC: &str = "gateway";
 
-/// How long we allow a DNS resolution via `libc::get_addr_info`.
+/// How long we allow the resolution of a DNS resource's domain.
 const DNS_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How often we refresh the snapshot of the tunnel's stats that `/metrics` reports.
+const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound for the timeout of health checks, each probe is limited to the timeout configured for its resource.
+const MAX_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
 // DNS resolution happens as part of every connection setup.
 // For a connection to succeed, DNS resolution must be less than `snownet`'s handshake timeout.
 static_assertions::const_assert!(
@@ -43,7 +57,28 @@ pub struct Eventloop {
     portal: PhoenixChannel<(), IngressMessages, ()>,
     tun_device_channel: mpsc::Sender<Interface>,
 
-    resolve_tasks: futures_bounded::FuturesTupleSet<Vec<IpAddr>, ResolveTrigger>,
+    resolve_tasks: futures_bounded::FuturesTupleSet<Resolution, ResolveTrigger>,
+
+    /// Resolves the domains of DNS resources and the DNS queries that clients forward to us for non-address records of DNS resources.
+    resolver: TokioAsyncResolver,
+    dns_queries: futures_bounded::FuturesTupleSet<
+        std::result::Result<Lookup, ResolveError>,
+        (ClientId, DnsQuery<'static>),
+    >,
+
+    /// Exports the flows between clients and resources, if the flow log is enabled.
+    flow_exporter: Option<FlowExporter>,
+
+    /// Health checks of the backends of load-balanced resources.
+    health_checks: futures_bounded::FuturesTupleSet<bool, (IpAddr, HealthProbe)>,
+
+    /// Relays the TCP connections of selected resources through an upstream proxy, if configured.
+    egress_proxy: Option<EgressProxy>,
+    /// Data of the upstream proxy that the tunnel couldn't accept yet.
+    pending_egress_data: HashMap<EgressStreamId, Vec<u8>>,
+
+    metrics: Arc<Metrics>,
+    metrics_refresh: tokio::time::Interval,
 }
 
 impl Eventloop {
@@ -51,12 +86,24 @@ impl Eventloop {
         tunnel: GatewayTunnel,
         portal: PhoenixChannel<(), IngressMessages, ()>,
         tun_device_channel: mpsc::Sender<Interface>,
+        resolver: TokioAsyncResolver,
+        flow_exporter: Option<FlowExporter>,
+        egress_proxy: Option<EgressProxy>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             tunnel,
             portal,
             resolve_tasks: futures_bounded::FuturesTupleSet::new(DNS_RESOLUTION_TIMEOUT, 100),
             tun_device_channel,
+            resolver,
+            dns_queries: futures_bounded::FuturesTupleSet::new(DNS_RESOLUTION_TIMEOUT, 100),
+            flow_exporter,
+            health_checks: futures_bounded::FuturesTupleSet::new(MAX_HEALTH_CHECK_TIMEOUT, 1000),
+            egress_proxy,
+            pending_egress_data: HashMap::default(),
+            metrics,
+            metrics_refresh: tokio::time::interval(METRICS_REFRESH_INTERVAL),
         }
     }
 }
@@ -64,6 +111,8 @@ impl Eventloop {
 impl Eventloop {
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<Infallible>> {
         loop {
+            self.flush_egress_proxy_data();
+
             match self.tunnel.poll_next_event(cx) {
                 Poll::Ready(Ok(event)) => {
                     self.handle_tunnel_event(event);
@@ -77,21 +126,42 @@ impl Eventloop {
             }
 
             match self.resolve_tasks.poll_unpin(cx) {
-                Poll::Ready((result, ResolveTrigger::RequestConnection(req))) => {
-                    self.accept_connection(result, req);
+                Poll::Ready((result, trigger)) => {
+                    let resolution = result.unwrap_or_else(|_: Timeout| {
+                        Resolution::failed(DnsResolutionError::Timeout)
+                    });
+
+                    self.handle_resolution(resolution, trigger);
                     continue;
                 }
-                Poll::Ready((result, ResolveTrigger::AllowAccess(req))) => {
-                    self.allow_access(result, req);
+                Poll::Pending => {}
+            }
+
+            match self.dns_queries.poll_unpin(cx) {
+                Poll::Ready((result, (conn_id, query))) => {
+                    let response = result.unwrap_or_else(|_| Err(ResolveErrorKind::Timeout.into()));
+
+                    self.tunnel.on_dns_result(conn_id, query, response);
                     continue;
                 }
-                Poll::Ready((result, ResolveTrigger::Refresh(name, conn_id, resource_id))) => {
-                    self.refresh_translation(result, conn_id, resource_id, name);
+                Poll::Pending => {}
+            }
+
+            match self.health_checks.poll_unpin(cx) {
+                Poll::Ready((result, (ip, probe))) => {
+                    let healthy = result.unwrap_or(false);
+
+                    self.tunnel.on_probe_result(ip, probe, healthy);
                     continue;
                 }
                 Poll::Pending => {}
             }
 
+            if let Some(Poll::Ready(event)) = self.egress_proxy.as_mut().map(|p| p.poll_event(cx)) {
+                self.handle_egress_proxy_event(event);
+                continue;
+            }
+
             match self.portal.poll(cx)? {
                 Poll::Ready(event) => {
                     self.handle_portal_event(event);
@@ -100,6 +170,13 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            if self.metrics_refresh.poll_tick(cx).is_ready() {
+                self.metrics.set_stats(self.tunnel.stats());
+                self.metrics
+                    .set_portal_connected(self.portal.is_connected());
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
@@ -138,7 +215,7 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(Some(name.clone())),
+                        resolve(self.resolver.clone(), Some(name.clone())),
                         ResolveTrigger::Refresh(name, conn_id, resource_id),
                     )
                     .is_err()
@@ -146,9 +223,142 @@ impl Eventloop {
                     tracing::warn!("Too many dns resolution requests, dropping existing one");
                 };
             }
+            firezone_tunnel::GatewayEvent::ResolveDnsQuery { conn_id, query } => {
+                let resolver = self.resolver.clone();
+                let name = format!("{}.", query.name); // Make it fully-qualified so no search domains get appended.
+                let record_type = query.record_type;
+
+                if self
+                    .dns_queries
+                    .try_push(
+                        async move { resolver.lookup(name, record_type).await },
+                        (conn_id, query),
+                    )
+                    .is_err()
+                {
+                    tracing::warn!("Too many DNS queries, dropping existing one");
+                };
+            }
+            firezone_tunnel::GatewayEvent::RateLimited {
+                conn_id,
+                resource_id,
+                dropped_packets,
+                dropped_bytes,
+            } => {
+                tracing::info!(client = %conn_id, resource = ?resource_id, %dropped_packets, %dropped_bytes, "Dropped traffic exceeding rate limit");
+            }
+            firezone_tunnel::GatewayEvent::NatExhaustion {
+                conn_id,
+                sessions,
+                max_sessions,
+                failed_translations,
+            } => {
+                tracing::warn!(client = %conn_id, %sessions, %max_sessions, %failed_translations, "Client is exhausting its NAT sessions");
+            }
+            firezone_tunnel::GatewayEvent::FlowLogged { conn_id, record } => {
+                let Some(exporter) = self.flow_exporter.as_mut() else {
+                    return;
+                };
+
+                exporter.export(FlowLogRecord::new(
+                    conn_id,
+                    record,
+                    Instant::now(),
+                    SystemTime::now(),
+                ));
+            }
+            firezone_tunnel::GatewayEvent::AccessExpired {
+                conn_id: client_id,
+                resource_id,
+            } => {
+                self.portal.send(
+                    PHOENIX_TOPIC,
+                    EgressMessages::AccessExpired(AccessExpired {
+                        client_id,
+                        resource_id,
+                    }),
+                );
+            }
+            firezone_tunnel::GatewayEvent::ProbeBackend { ip, probe, timeout } => {
+                if self
+                    .health_checks
+                    .try_push(crate::health_check::probe(ip, probe, timeout), (ip, probe))
+                    .is_err()
+                {
+                    tracing::warn!(%ip, "Too many health checks, dropping existing one");
+                };
+            }
+            firezone_tunnel::GatewayEvent::EgressProxyConnect { stream, dst } => {
+                let Some(proxy) = self.egress_proxy.as_mut() else {
+                    self.tunnel.reset_egress_proxy_stream(stream);
+                    return;
+                };
+
+                proxy.connect(stream, dst);
+            }
+            firezone_tunnel::GatewayEvent::EgressProxyData { stream, data } => {
+                if let Some(proxy) = self.egress_proxy.as_mut() {
+                    proxy.write(stream, data);
+                }
+            }
+            firezone_tunnel::GatewayEvent::EgressProxyShutdown { stream } => {
+                if let Some(proxy) = self.egress_proxy.as_mut() {
+                    proxy.shutdown(stream);
+                }
+            }
+            firezone_tunnel::GatewayEvent::EgressProxyClosed { stream } => {
+                if let Some(proxy) = self.egress_proxy.as_mut() {
+                    proxy.close(stream);
+                }
+                self.pending_egress_data.remove(&stream);
+            }
         }
     }
 
+    fn handle_egress_proxy_event(&mut self, event: egress_proxy::Event) {
+        match event {
+            egress_proxy::Event::Connected(stream) => {
+                self.tunnel.on_egress_proxy_connected(stream);
+            }
+            egress_proxy::Event::Data(stream, data) => {
+                self.pending_egress_data.insert(stream, data);
+                self.flush_egress_proxy_data();
+            }
+            egress_proxy::Event::Written(stream, len) => {
+                self.tunnel.on_egress_proxy_written(stream, len);
+            }
+            egress_proxy::Event::Eof(stream) => {
+                self.tunnel.on_egress_proxy_eof(stream);
+            }
+            egress_proxy::Event::Failed(stream, e) => {
+                tracing::debug!(?stream, "Proxied connection failed: {e:#}");
+
+                self.tunnel.reset_egress_proxy_stream(stream);
+                self.pending_egress_data.remove(&stream);
+            }
+        }
+    }
+
+    /// Passes data of the upstream proxy to the tunnel, as much as the clients' windows allow.
+    fn flush_egress_proxy_data(&mut self) {
+        let Some(proxy) = self.egress_proxy.as_mut() else {
+            return;
+        };
+
+        self.pending_egress_data.retain(|stream, data| {
+            let len = self.tunnel.send_egress_proxy_data(*stream, data);
+            data.drain(..len);
+
+            if !data.is_empty() {
+                return true;
+            }
+
+            proxy.on_data_consumed(*stream);
+
+            false
+        });
+    }
+
     fn handle_portal_event(&mut self, event: phoenix_channel::Event<IngressMessages, ()>) {
         match event {
             phoenix_channel::Event::InboundMessage {
@@ -158,7 +368,10 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(req.client.payload.domain.as_ref().map(|r| r.name())),
+                        resolve(
+                            self.resolver.clone(),
+                            req.client.payload.domain.as_ref().map(|r| r.name()),
+                        ),
                         ResolveTrigger::RequestConnection(req),
                     )
                     .is_err()
@@ -173,7 +386,10 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(req.payload.as_ref().map(|r| r.name())),
+                        resolve(
+                            self.resolver.clone(),
+                            req.payload.as_ref().map(|r| r.name()),
+                        ),
                         ResolveTrigger::AllowAccess(req),
                     )
                     .is_err()
@@ -256,14 +472,28 @@ impl Eventloop {
         }
     }
 
-    pub fn accept_connection(
-        &mut self,
-        result: Result<Vec<IpAddr>, Timeout>,
-        req: RequestConnection,
-    ) {
-        let addresses = result
-            .inspect_err(|e| tracing::debug!(client = %req.client.id, reference = %req.reference, "DNS resolution timed out as part of connection request: {e}"))
-            .unwrap_or_default();
+    fn handle_resolution(&mut self, resolution: Resolution, trigger: ResolveTrigger) {
+        match trigger {
+            ResolveTrigger::RequestConnection(req) => self.accept_connection(resolution, req),
+            ResolveTrigger::AllowAccess(req) => self.allow_access(resolution, req),
+            ResolveTrigger::Refresh(name, conn_id, resource_id) => {
+                self.refresh_translation(resolution, conn_id, resource_id, name)
+            }
+        }
+    }
+
+    pub fn accept_connection(&mut self, resolution: Resolution, req: RequestConnection) {
+        if let Some(e) = resolution.error {
+            tracing::debug!(client = %req.client.id, reference = %req.reference, "DNS resolution failed as part of connection request: {e}");
+        }
+
+        // The portal only sends a keep-alive if an admin overrode it for this client.
+        if let Some(keepalive) = req.client.peer.persistent_keepalive {
+            // Like in wireguard, `0` disables keep-alives.
+            let keepalive = (keepalive != 0).then(|| Duration::from_secs(keepalive.into()));
+
+            self.tunnel.set_client_keepalive(req.client.id, keepalive);
+        }
 
         match self.tunnel.accept(
             req.client.id,
@@ -274,20 +504,22 @@ impl Eventloop {
             req.client.peer.ipv6,
             req.client.payload.domain.as_ref().map(|r| r.as_tuple()),
             req.expires_at,
-            req.resource.into_resolved(addresses.clone()),
+            req.resource
+                .into_resolved(resolution.addresses.clone(), resolution.ttl),
         ) {
             Ok(accepted) => {
+                self.tunnel
+                    .set_client_rate_limit(req.client.id, req.client_rate_limit);
                 self.portal.send(
                     PHOENIX_TOPIC,
                     EgressMessages::ConnectionReady(ConnectionReady {
                         reference: req.reference,
                         gateway_payload: GatewayResponse::ConnectionAccepted(ConnectionAccepted {
                             ice_parameters: accepted,
-                            domain_response: req.client.payload.domain.map(|r| {
-                                connlib_shared::messages::DomainResponse {
-                                    domain: r.name(),
-                                    address: addresses,
-                                }
+                            domain_response: req.client.payload.domain.map(|r| DomainResponse {
+                                domain: r.name(),
+                                address: resolution.addresses,
+                                error: resolution.error,
                             }),
                         }),
                     }),
@@ -304,28 +536,34 @@ impl Eventloop {
         }
     }
 
-    pub fn allow_access(&mut self, result: Result<Vec<IpAddr>, Timeout>, req: AllowAccess) {
-        let addresses = result
-            .inspect_err(|e| tracing::debug!(client = %req.client_id, reference = %req.reference, "DNS resolution timed out as part of allow access request: {e}"))
-            .unwrap_or_default();
-
-        if let (Ok(()), Some(resolve_request)) = (
-            self.tunnel.allow_access(
-                req.resource.into_resolved(addresses.clone()),
-                req.client_id,
-                req.expires_at,
-                req.payload.as_ref().map(|r| r.as_tuple()),
-            ),
-            req.payload,
-        ) {
+    pub fn allow_access(&mut self, resolution: Resolution, req: AllowAccess) {
+        if let Some(e) = resolution.error {
+            tracing::debug!(client = %req.client_id, reference = %req.reference, "DNS resolution failed as part of allow access request: {e}");
+        }
+
+        let result = self.tunnel.allow_access(
+            req.resource
+                .into_resolved(resolution.addresses.clone(), resolution.ttl),
+            req.client_id,
+            req.expires_at,
+            req.payload.as_ref().map(|r| r.as_tuple()),
+        );
+
+        if result.is_ok() {
+            self.tunnel
+                .set_client_rate_limit(req.client_id, req.client_rate_limit);
+        }
+
+        if let (Ok(()), Some(resolve_request)) = (result, req.payload) {
             self.portal.send(
                 PHOENIX_TOPIC,
                 EgressMessages::ConnectionReady(ConnectionReady {
                     reference: req.reference,
                     gateway_payload: GatewayResponse::ResourceAccepted(ResourceAccepted {
-                        domain_response: connlib_shared::messages::DomainResponse {
+                        domain_response: DomainResponse {
                             domain: resolve_request.name(),
-                            address: addresses,
+                            address: resolution.addresses,
+                            error: resolution.error,
                         },
                     }),
                 }),
@@ -335,82 +573,91 @@ impl Eventloop {
 
     pub fn refresh_translation(
         &mut self,
-        result: Result<Vec<IpAddr>, Timeout>,
+        resolution: Resolution,
         conn_id: ClientId,
         resource_id: ResourceId,
         name: DomainName,
     ) {
-        let addresses = result
-            .inspect_err(|e| tracing::debug!(%conn_id, "DNS resolution timed out as part of allow access request: {e}"))
-            .unwrap_or_default();
+        if let Some(e) = resolution.error {
+            // Keep the existing translations, the tunnel will retry the refresh later.
+            tracing::debug!(%conn_id, %name, "DNS resolution failed as part of refresh: {e}");
+            return;
+        }
 
-        self.tunnel
-            .refresh_translation(conn_id, resource_id, name, addresses);
+        self.tunnel.refresh_translation(
+            conn_id,
+            resource_id,
+            name,
+            resolution.addresses,
+            resolution.ttl,
+        );
     }
 }
 
-async fn resolve(domain: Option<DomainName>) -> Vec<IpAddr> {
-    let Some(domain) = domain.clone() else {
-        return vec![];
-    };
+/// The outcome of resolving the domain of a DNS resource.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    addresses: Vec<IpAddr>,
+    /// How long the addresses are valid for, based on the lowest TTL of the records.
+    ttl: Option<Duration>,
+    error: Option<DnsResolutionError>,
+}
 
-    let dname = domain.to_string();
+impl Resolution {
+    fn failed(error: DnsResolutionError) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
 
-    match tokio::task::spawn_blocking(move || resolve_addresses(&dname)).await {
-        Ok(Ok(addresses)) => addresses,
-        Ok(Err(e)) => {
-            tracing::warn!("Failed to resolve '{domain}': {e}");
+async fn resolve(resolver: TokioAsyncResolver, domain: Option<DomainName>) -> Resolution {
+    let Some(domain) = domain else {
+        return Resolution::default();
+    };
 
-            vec![]
-        }
+    // Make it fully-qualified so no search domains get appended.
+    match resolver.lookup_ip(format!("{domain}.")).await {
+        Ok(lookup) => Resolution {
+            addresses: lookup.iter().collect(),
+            ttl: Some(
+                lookup
+                    .valid_until()
+                    .saturating_duration_since(Instant::now()),
+            ),
+            error: None,
+        },
         Err(e) => {
             tracing::warn!("Failed to resolve '{domain}': {e}");
 
-            vec![]
+            Resolution::failed(resolution_error(&e))
         }
     }
 }
 
-#[cfg(target_os = "windows")]
-fn resolve_addresses(_: &str) -> std::io::Result<Vec<IpAddr>> {
-    unimplemented!()
-}
+fn resolution_error(e: &ResolveError) -> DnsResolutionError {
+    if matches!(e.kind(), ResolveErrorKind::Timeout) {
+        return DnsResolutionError::Timeout;
+    }
+
+    let ResolveErrorKind::Proto(proto) = e.kind() else {
+        return DnsResolutionError::ServerFailure;
+    };
 
-#[cfg(not(target_os = "windows"))]
-fn resolve_addresses(addr: &str) -> std::io::Result<Vec<IpAddr>> {
-    use libc::{AF_INET, AF_INET6};
-    let addr_v4: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET)
-        .map_err(|e| e.into())
-        .and_then(|a| a.collect());
-    let addr_v6: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET6)
-        .map_err(|e| e.into())
-        .and_then(|a| a.collect());
-    match (addr_v4, addr_v6) {
-        (Ok(v4), Ok(v6)) => Ok(v6
-            .iter()
-            .map(|a| a.sockaddr.ip())
-            .chain(v4.iter().map(|a| a.sockaddr.ip()))
-            .collect()),
-        (Ok(v4), Err(_)) => Ok(v4.iter().map(|a| a.sockaddr.ip()).collect()),
-        (Err(_), Ok(v6)) => Ok(v6.iter().map(|a| a.sockaddr.ip()).collect()),
-        (Err(e), Err(_)) => Err(e),
+    if matches!(proto.kind(), ProtoErrorKind::Timeout) {
+        return DnsResolutionError::Timeout;
     }
-}
 
-#[cfg(not(target_os = "windows"))]
-fn resolve_address_family(
-    addr: &str,
-    family: i32,
-) -> std::result::Result<AddrInfoIter, LookupError> {
-    use libc::SOCK_STREAM;
-
-    dns_lookup::getaddrinfo(
-        Some(addr),
-        None,
-        Some(AddrInfoHints {
-            socktype: SOCK_STREAM,
-            address: family,
-            ..Default::default()
-        }),
-    )
+    if let ProtoErrorKind::NoRecordsFound { response_code, .. } = proto.kind() {
+        if *response_code == ResponseCode::NXDomain {
+            return DnsResolutionError::NxDomain;
+        }
+
+        if *response_code == ResponseCode::NoError {
+            return DnsResolutionError::NoRecords;
+        }
+    }
+
+    DnsResolutionError::ServerFailure
 }
@@ -0,0 +1,464 @@
+//! Exports the flows between clients and resources, e.g. to answer which clients accessed a resource.
+//!
+//! connlib reports each flow once it ended, we turn these into [`FlowLogRecord`]s and hand them to all configured [`FlowSink`]s.
+//! The sinks perform blocking IO, thus the [`FlowExporter`] runs them on a blocking task.
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{ClientId, ResourceId};
+use firezone_tunnel::{FlowRecord, FlowVerdict};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write as _};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+/// How many records we queue for the sinks before we start dropping new ones.
+const MAX_QUEUED_RECORDS: usize = 1000;
+
+/// How often we re-send our templates to the IPFIX collector, see <https://www.rfc-editor.org/rfc/rfc7011#section-8.4>.
+const IPFIX_TEMPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+const IPFIX_VERSION: u16 = 10;
+const IPFIX_TEMPLATE_SET_ID: u16 = 2;
+const IPFIX_IPV4_TEMPLATE_ID: u16 = 256;
+const IPFIX_IPV6_TEMPLATE_ID: u16 = 257;
+/// Marks an information element as variable-length in a template.
+const IPFIX_VARIABLE_LENGTH: u16 = u16::MAX;
+
+/// The information elements of our templates, see <https://www.iana.org/assignments/ipfix/ipfix.xhtml>.
+///
+/// The source and destination addresses come first and depend on the IP version of the flow.
+const IPFIX_FIELDS: [(u16, u16); 12] = [
+    (4, 1),                       // protocolIdentifier
+    (7, 2),                       // sourceTransportPort
+    (11, 2),                      // destinationTransportPort
+    (231, 8),                     // initiatorOctets
+    (298, 8),                     // initiatorPackets
+    (232, 8),                     // responderOctets
+    (299, 8),                     // responderPackets
+    (152, 8),                     // flowStartMilliseconds
+    (153, 8),                     // flowEndMilliseconds
+    (233, 1),                     // firewallEvent
+    (371, IPFIX_VARIABLE_LENGTH), // userName, the client ID
+    (96, IPFIX_VARIABLE_LENGTH),  // applicationName, the resource ID
+];
+const IPFIX_IPV4_FIELDS: [(u16, u16); 2] = [
+    (8, 4),  // sourceIPv4Address
+    (12, 4), // destinationIPv4Address
+];
+const IPFIX_IPV6_FIELDS: [(u16, u16); 2] = [
+    (27, 16), // sourceIPv6Address
+    (28, 16), // destinationIPv6Address
+];
+
+/// Values of the `firewallEvent` information element.
+const IPFIX_FLOW_DELETED: u8 = 2;
+const IPFIX_FLOW_DENIED: u8 = 3;
+
+/// A destination for flow records.
+pub trait FlowSink: Send {
+    fn write(&mut self, record: &FlowLogRecord) -> Result<()>;
+}
+
+/// Hands flow records to the sinks without blocking the caller.
+pub struct FlowExporter {
+    records: mpsc::Sender<FlowLogRecord>,
+
+    /// How many records we dropped because the queue was full, reported once it has room again.
+    dropped: u64,
+}
+
+impl FlowExporter {
+    /// Spawns a blocking task that writes the exported records to all `sinks`.
+    pub fn spawn(mut sinks: Vec<Box<dyn FlowSink>>) -> Self {
+        let (records, mut receiver) = mpsc::channel::<FlowLogRecord>(MAX_QUEUED_RECORDS);
+
+        tokio::task::spawn_blocking(move || {
+            while let Some(record) = receiver.blocking_recv() {
+                for sink in &mut sinks {
+                    if let Err(e) = sink.write(&record) {
+                        tracing::warn!("Failed to export flow: {e:#}");
+                    }
+                }
+            }
+        });
+
+        Self {
+            records,
+            dropped: 0,
+        }
+    }
+
+    /// Queues a record for the sinks, dropping it if they can't keep up.
+    pub fn export(&mut self, record: FlowLogRecord) {
+        match self.records.try_send(record) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    tracing::warn!(dropped = %self.dropped, "Flow sinks couldn't keep up, dropped records");
+
+                    self.dropped = 0;
+                }
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.dropped += 1;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!("Flow export task is gone, dropping record");
+            }
+        }
+    }
+}
+
+/// A flow between a client and a resource that ended.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowLogRecord {
+    pub client_id: ClientId,
+    pub resource_id: Option<ResourceId>,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// The IANA protocol number, e.g. 6 for TCP.
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    /// Packets and bytes from the client to the resource.
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    /// Packets and bytes from the resource to the client.
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub verdict: Verdict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Allowed,
+    Denied,
+}
+
+impl FlowLogRecord {
+    /// Converts a record from connlib, using `now` to translate its [`Instant`]s into wall-clock time.
+    pub fn new(
+        client_id: ClientId,
+        record: FlowRecord,
+        now: Instant,
+        system_now: SystemTime,
+    ) -> Self {
+        let to_utc = |instant: Instant| {
+            DateTime::<Utc>::from(system_now - now.saturating_duration_since(instant))
+        };
+
+        Self {
+            client_id,
+            resource_id: record.resource_id,
+            src: record.src,
+            dst: record.dst,
+            protocol: record.protocol,
+            src_port: record.src_port,
+            dst_port: record.dst_port,
+            packets_out: record.packets_out,
+            bytes_out: record.bytes_out,
+            packets_in: record.packets_in,
+            bytes_in: record.bytes_in,
+            start: to_utc(record.start),
+            end: to_utc(record.end),
+            verdict: match record.verdict {
+                FlowVerdict::Allowed => Verdict::Allowed,
+                FlowVerdict::Denied => Verdict::Denied,
+            },
+        }
+    }
+}
+
+/// Appends a JSON object per flow to a file.
+pub struct JsonLinesSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open flow log `{}`", path.display()))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl FlowSink for JsonLinesSink {
+    fn write(&mut self, record: &FlowLogRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Exports a data record per flow to an IPFIX collector via UDP, see <https://www.rfc-editor.org/rfc/rfc7011>.
+pub struct IpfixSink {
+    socket: UdpSocket,
+
+    /// The number of data records we sent so far.
+    sequence_number: u32,
+    templates_sent_at: Option<Instant>,
+}
+
+impl IpfixSink {
+    pub fn connect(collector: SocketAddr) -> Result<Self> {
+        let local = match collector {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+
+        let socket = UdpSocket::bind(local).context("Failed to bind IPFIX socket")?;
+        socket
+            .connect(collector)
+            .with_context(|| format!("Failed to connect to IPFIX collector {collector}"))?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            sequence_number: 0,
+            templates_sent_at: None,
+        })
+    }
+}
+
+impl FlowSink for IpfixSink {
+    fn write(&mut self, record: &FlowLogRecord) -> Result<()> {
+        let now = Instant::now();
+        let export_time = Utc::now();
+
+        if self.templates_sent_at.map_or(true, |sent_at| {
+            now.duration_since(sent_at) >= IPFIX_TEMPLATE_REFRESH_INTERVAL
+        }) {
+            self.socket
+                .send(&ipfix_templates(self.sequence_number, export_time))
+                .context("Failed to send IPFIX templates")?;
+            self.templates_sent_at = Some(now);
+        }
+
+        let Some(message) = ipfix_data(record, self.sequence_number, export_time) else {
+            tracing::debug!(
+                ?record,
+                "Cannot export flow with mixed IP versions via IPFIX"
+            );
+            return Ok(());
+        };
+
+        self.socket
+            .send(&message)
+            .context("Failed to send IPFIX data record")?;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        Ok(())
+    }
+}
+
+fn ipfix_templates(sequence_number: u32, export_time: DateTime<Utc>) -> Vec<u8> {
+    let mut set = Vec::new();
+
+    for (id, addresses) in [
+        (IPFIX_IPV4_TEMPLATE_ID, IPFIX_IPV4_FIELDS),
+        (IPFIX_IPV6_TEMPLATE_ID, IPFIX_IPV6_FIELDS),
+    ] {
+        let fields = addresses.iter().chain(IPFIX_FIELDS.iter());
+
+        set.extend_from_slice(&id.to_be_bytes());
+        set.extend_from_slice(&(fields.clone().count() as u16).to_be_bytes());
+
+        for (element, length) in fields {
+            set.extend_from_slice(&element.to_be_bytes());
+            set.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+
+    ipfix_message(IPFIX_TEMPLATE_SET_ID, &set, sequence_number, export_time)
+}
+
+/// Encodes a flow as data record of the template matching its IP version.
+fn ipfix_data(
+    record: &FlowLogRecord,
+    sequence_number: u32,
+    export_time: DateTime<Utc>,
+) -> Option<Vec<u8>> {
+    let mut set = Vec::new();
+
+    let template = match (record.src, record.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            set.extend_from_slice(&src.octets());
+            set.extend_from_slice(&dst.octets());
+
+            IPFIX_IPV4_TEMPLATE_ID
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            set.extend_from_slice(&src.octets());
+            set.extend_from_slice(&dst.octets());
+
+            IPFIX_IPV6_TEMPLATE_ID
+        }
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => return None,
+    };
+
+    set.push(record.protocol);
+    set.extend_from_slice(&record.src_port.unwrap_or_default().to_be_bytes());
+    set.extend_from_slice(&record.dst_port.unwrap_or_default().to_be_bytes());
+    set.extend_from_slice(&record.bytes_out.to_be_bytes());
+    set.extend_from_slice(&record.packets_out.to_be_bytes());
+    set.extend_from_slice(&record.bytes_in.to_be_bytes());
+    set.extend_from_slice(&record.packets_in.to_be_bytes());
+    set.extend_from_slice(&(record.start.timestamp_millis() as u64).to_be_bytes());
+    set.extend_from_slice(&(record.end.timestamp_millis() as u64).to_be_bytes());
+    set.push(match record.verdict {
+        Verdict::Allowed => IPFIX_FLOW_DELETED,
+        Verdict::Denied => IPFIX_FLOW_DENIED,
+    });
+    push_variable_length(&mut set, record.client_id.to_string().as_bytes());
+    push_variable_length(
+        &mut set,
+        record
+            .resource_id
+            .map(|id| id.to_string())
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+
+    Some(ipfix_message(template, &set, sequence_number, export_time))
+}
+
+/// Wraps a single set into an IPFIX message.
+fn ipfix_message(
+    set_id: u16,
+    set: &[u8],
+    sequence_number: u32,
+    export_time: DateTime<Utc>,
+) -> Vec<u8> {
+    const MESSAGE_HEADER_LEN: usize = 16;
+    const SET_HEADER_LEN: usize = 4;
+
+    let set_len = SET_HEADER_LEN + set.len();
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_LEN + set_len);
+
+    message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+    message.extend_from_slice(&((MESSAGE_HEADER_LEN + set_len) as u16).to_be_bytes());
+    message.extend_from_slice(&(export_time.timestamp() as u32).to_be_bytes());
+    message.extend_from_slice(&sequence_number.to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes()); // Observation domain ID
+    message.extend_from_slice(&set_id.to_be_bytes());
+    message.extend_from_slice(&(set_len as u16).to_be_bytes());
+    message.extend_from_slice(set);
+
+    message
+}
+
+/// Our variable-length values are IDs and thus always shorter than 255 bytes, i.e. fit the short length encoding.
+fn push_variable_length(buf: &mut Vec<u8>, value: &[u8]) {
+    let value = &value[..value.len().min(254)];
+
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_instants_to_wall_clock_time() {
+        let now = Instant::now();
+        let system_now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let record = FlowLogRecord::new(
+            client_id(),
+            flow_record(now - Duration::from_secs(10), now - Duration::from_secs(2)),
+            now,
+            system_now,
+        );
+
+        assert_eq!(record.start.timestamp(), 1_699_999_990);
+        assert_eq!(record.end.timestamp(), 1_699_999_998);
+    }
+
+    #[test]
+    fn serializes_record_as_json() {
+        let now = Instant::now();
+        let record = FlowLogRecord::new(
+            client_id(),
+            flow_record(now, now),
+            now,
+            SystemTime::UNIX_EPOCH,
+        );
+
+        let json = serde_json::to_value(&record).unwrap();
+
+        assert_eq!(json["client_id"], "00000000-0000-0000-0000-000000000001");
+        assert_eq!(json["resource_id"], "00000000-0000-0000-0000-000000000002");
+        assert_eq!(json["dst"], "10.0.0.1");
+        assert_eq!(json["dst_port"], 443);
+        assert_eq!(json["bytes_in"], 1000);
+        assert_eq!(json["start"], "1970-01-01T00:00:00Z");
+        assert_eq!(json["verdict"], "allowed");
+    }
+
+    #[test]
+    fn ipfix_data_record_matches_template() {
+        let now = Instant::now();
+        let record = FlowLogRecord::new(
+            client_id(),
+            flow_record(now, now),
+            now,
+            SystemTime::UNIX_EPOCH,
+        );
+
+        let templates = ipfix_templates(0, Utc::now());
+        let data = ipfix_data(&record, 0, Utc::now()).unwrap();
+
+        for message in [&templates, &data] {
+            assert_eq!(&message[0..2], &IPFIX_VERSION.to_be_bytes());
+            assert_eq!(&message[2..4], &(message.len() as u16).to_be_bytes());
+            assert_eq!(&message[18..20], &(message.len() as u16 - 16).to_be_bytes());
+        }
+        assert_eq!(&data[16..18], &IPFIX_IPV4_TEMPLATE_ID.to_be_bytes());
+
+        let fixed_len: usize = IPFIX_IPV4_FIELDS
+            .iter()
+            .chain(IPFIX_FIELDS.iter())
+            .filter(|(_, len)| *len != IPFIX_VARIABLE_LENGTH)
+            .map(|(_, len)| *len as usize)
+            .sum();
+
+        // Both IDs are 36 characters plus a length byte.
+        assert_eq!(data.len() - 20, fixed_len + 2 * 37);
+    }
+
+    fn client_id() -> ClientId {
+        "00000000-0000-0000-0000-000000000001".parse().unwrap()
+    }
+
+    fn flow_record(start: Instant, end: Instant) -> FlowRecord {
+        FlowRecord {
+            resource_id: Some(ResourceId::from_u128(2)),
+            src: "100.64.0.1".parse().unwrap(),
+            dst: "10.0.0.1".parse().unwrap(),
+            protocol: 6,
+            src_port: Some(50000),
+            dst_port: Some(443),
+            packets_out: 10,
+            bytes_out: 500,
+            packets_in: 8,
+            bytes_in: 1000,
+            start,
+            end,
+            verdict: FlowVerdict::Allowed,
+        }
+    }
+}
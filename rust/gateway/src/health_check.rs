@@ -0,0 +1,91 @@
+//! Probes the backends of load-balanced resources on behalf of the tunnel.
+use connlib_shared::messages::gateway::HealthProbe;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// The sequence number of the next ICMP echo request we send.
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+/// Returns whether the backend passed the probe within the timeout.
+pub async fn probe(ip: IpAddr, probe: HealthProbe, timeout: Duration) -> bool {
+    let result = tokio::time::timeout(timeout, async {
+        match probe {
+            HealthProbe::Tcp { port } => tcp(ip, port).await,
+            HealthProbe::Icmp => icmp(ip).await,
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            tracing::debug!(%ip, ?probe, "Health check failed: {e}");
+
+            false
+        }
+        Err(_) => {
+            tracing::debug!(%ip, ?probe, "Health check timed out");
+
+            false
+        }
+    }
+}
+
+async fn tcp(ip: IpAddr, port: u16) -> io::Result<()> {
+    TcpStream::connect((ip, port)).await?;
+
+    Ok(())
+}
+
+/// Pings the backend via an unprivileged ICMP socket.
+///
+/// The kernel sets the identifier and the checksum of our requests and only hands us the replies to them.
+async fn icmp(ip: IpAddr) -> io::Result<()> {
+    let (domain, protocol, echo_request, echo_reply) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4, 8, 0),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6, 128, 129),
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(std::net::UdpSocket::from(socket))?;
+    socket.connect(SocketAddr::new(ip, 0)).await?;
+
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+    let request = [echo_request, 0, 0, 0, 0, 0, sequence[0], sequence[1]];
+    socket.send(&request).await?;
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let reply = &buf[..len];
+
+        if reply.first() == Some(&echo_reply) && reply.get(6..8) == Some(&sequence[..]) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn tcp_probe_succeeds_only_while_port_is_open() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = HealthProbe::Tcp { port };
+
+        assert!(super::probe(Ipv4Addr::LOCALHOST.into(), probe, Duration::from_secs(1)).await);
+
+        drop(listener);
+
+        assert!(!super::probe(Ipv4Addr::LOCALHOST.into(), probe, Duration::from_secs(1)).await);
+    }
+}
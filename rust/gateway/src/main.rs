@@ -1,27 +1,45 @@
+use crate::egress_proxy::{EgressProxy, ProxyConfig};
 use crate::eventloop::{Eventloop, PHOENIX_TOPIC};
+use crate::flow_log::{FlowExporter, FlowSink, IpfixSink, JsonLinesSink};
+use crate::metrics::Metrics;
 use anyhow::{Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use connlib_shared::{get_user_agent, keypair, messages::Interface, LoginUrl, StaticSecret};
+use connlib_shared::{
+    get_user_agent, keypair,
+    messages::{Interface, ResourceId},
+    LoginUrl, StaticSecret,
+};
 use firezone_bin_shared::{setup_global_subscriber, CommonArgs, TunDeviceManager};
-use firezone_tunnel::{GatewayTunnel, Tun};
+use firezone_tunnel::{ConnectionConfig, GatewayTunnel, NatConfig, Tun};
 
 use futures::channel::mpsc;
 use futures::{future, StreamExt, TryFutureExt};
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
 use ip_network::{Ipv4Network, Ipv6Network};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
 use std::convert::Infallible;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::signal::ctrl_c;
 use tracing_subscriber::layer;
 use uuid::Uuid;
 
+mod egress_proxy;
 mod eventloop;
+mod flow_log;
+mod health_check;
 mod messages;
+mod metrics;
 
 const ID_PATH: &str = "/var/lib/firezone/gateway_id";
 const PEERS_IPV4: &str = "100.64.0.0/11";
@@ -56,13 +74,35 @@ async fn try_main() -> Result<()> {
         public_key.to_bytes(),
     )?;
 
-    let task = tokio::spawn(run(login, private_key)).err_into();
+    let flow_sinks = make_flow_sinks(cli.flow_log_file, cli.flow_log_ipfix_collector)?;
+
+    let nat_config = cli.nat.config();
+    let metrics = Arc::new(Metrics::new(nat_config.max_sessions));
+
+    let task = tokio::spawn(run(
+        login,
+        private_key,
+        cli.dns_servers,
+        flow_sinks,
+        TunnelConfig {
+            nat: nat_config,
+            connection: cli.connection.config(),
+            egress_proxy_resources: cli.egress_proxy_resources,
+        },
+        cli.egress_proxy,
+        metrics.clone(),
+    ))
+    .err_into();
 
     let ctrl_c = pin!(ctrl_c().map_err(anyhow::Error::new));
 
-    tokio::spawn(http_health_check::serve(
+    tokio::spawn(http_health_check::serve_with_metrics(
         cli.health_check.health_check_addr,
-        || true,
+        {
+            let metrics = metrics.clone();
+            move || metrics.is_healthy()
+        },
+        move || metrics.render(),
     ));
 
     match future::try_select(task, ctrl_c)
@@ -99,8 +139,36 @@ async fn get_firezone_id(env_id: Option<String>) -> Result<String> {
     Ok(id)
 }
 
-async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
+/// How the tunnel handles the traffic of clients.
+struct TunnelConfig {
+    nat: NatConfig,
+    connection: ConnectionConfig,
+    /// The resources whose TCP connections are relayed through the egress proxy.
+    egress_proxy_resources: Vec<ResourceId>,
+}
+
+async fn run(
+    login: LoginUrl,
+    private_key: StaticSecret,
+    dns_servers: Vec<SocketAddr>,
+    flow_sinks: Vec<Box<dyn FlowSink>>,
+    config: TunnelConfig,
+    egress_proxy: Option<ProxyConfig>,
+    metrics: Arc<Metrics>,
+) -> Result<Infallible> {
+    let flow_exporter = (!flow_sinks.is_empty()).then(|| FlowExporter::spawn(flow_sinks));
+
     let mut tunnel = GatewayTunnel::new(private_key)?;
+    tunnel.set_flow_log(flow_exporter.is_some());
+    tunnel.set_nat_config(config.nat);
+    tunnel.set_connection_config(config.connection);
+
+    if let Some(proxy) = &egress_proxy {
+        tracing::info!(%proxy, resources = ?config.egress_proxy_resources, "Relaying TCP connections through upstream proxy");
+
+        tunnel.set_egress_proxy_resources(config.egress_proxy_resources.into_iter().collect());
+    }
+
     let portal = PhoenixChannel::connect(
         Secret::new(login),
         get_user_agent(None, env!("CARGO_PKG_VERSION")),
@@ -116,9 +184,19 @@ async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
     let tun_device_manager = TunDeviceManager::new()?;
     tunnel.set_tun(Tun::new()?);
 
-    let update_device_task = update_device_task(tun_device_manager, receiver);
+    let update_device_task = update_device_task(tun_device_manager, receiver, metrics.clone());
+
+    let resolver = make_resolver(dns_servers)?;
 
-    let mut eventloop = Eventloop::new(tunnel, portal, sender);
+    let mut eventloop = Eventloop::new(
+        tunnel,
+        portal,
+        sender,
+        resolver,
+        flow_exporter,
+        egress_proxy.map(EgressProxy::new),
+        metrics,
+    );
     let eventloop_task = future::poll_fn(move |cx| eventloop.poll(cx));
 
     let ((), result) = futures::join!(update_device_task, eventloop_task);
@@ -128,16 +206,99 @@ async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
     unreachable!()
 }
 
+/// Creates the resolver for DNS resources and the DNS queries forwarded to us by clients.
+///
+/// Uses the system's configuration unless upstream servers are given explicitly.
+fn make_resolver(dns_servers: Vec<SocketAddr>) -> Result<TokioAsyncResolver> {
+    let (config, mut opts) = if dns_servers.is_empty() {
+        hickory_resolver::system_conf::read_system_conf()
+            .context("Failed to read system DNS configuration")?
+    } else {
+        let mut config = ResolverConfig::new();
+        for server in dns_servers {
+            config.add_name_server(NameServerConfig::new(server, Protocol::Udp));
+            config.add_name_server(NameServerConfig::new(server, Protocol::Tcp));
+        }
+
+        (config, ResolverOpts::default())
+    };
+
+    // We always want both, the client decides which ones it needs.
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+    tracing::info!(servers = ?config.name_servers().iter().map(|s| s.socket_addr).collect::<Vec<_>>(), "Resolving DNS resources");
+
+    Ok(TokioAsyncResolver::tokio(config, opts))
+}
+
+fn make_flow_sinks(
+    file: Option<PathBuf>,
+    ipfix_collector: Option<SocketAddr>,
+) -> Result<Vec<Box<dyn FlowSink>>> {
+    let mut sinks = Vec::<Box<dyn FlowSink>>::new();
+
+    if let Some(path) = file {
+        tracing::info!(path = %path.display(), "Writing flow log");
+
+        sinks.push(Box::new(JsonLinesSink::open(&path)?));
+    }
+
+    if let Some(collector) = ipfix_collector {
+        tracing::info!(%collector, "Exporting flows via IPFIX");
+
+        sinks.push(Box::new(IpfixSink::connect(collector)?));
+    }
+
+    Ok(sinks)
+}
+
+/// Parses a port range given as `START-END`.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("`{s}` is not a range of the form `START-END`"))?;
+
+    let start = start
+        .parse::<u16>()
+        .map_err(|e| format!("Invalid start of port range: {e}"))?;
+    let end = end
+        .parse::<u16>()
+        .map_err(|e| format!("Invalid end of port range: {e}"))?;
+
+    if start == 0 || start > end {
+        return Err(format!("`{s}` is not a valid port range"));
+    }
+
+    Ok(start..=end)
+}
+
+/// Parses a DNS server given as `IP` or `IP:port`, defaulting to port 53.
+fn parse_dns_server(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(socket) = s.parse::<SocketAddr>() {
+        return Ok(socket);
+    }
+
+    let ip = s
+        .parse::<IpAddr>()
+        .map_err(|_| format!("`{s}` is neither an IP nor an IP:port"))?;
+
+    Ok(SocketAddr::new(ip, 53))
+}
+
 async fn update_device_task(
     mut tun_device: TunDeviceManager,
     mut receiver: mpsc::Receiver<Interface>,
+    metrics: Arc<Metrics>,
 ) {
     while let Some(next_interface) = receiver.next().await {
+        let mut tun_up = true;
+
         if let Err(e) = tun_device
             .set_ips(next_interface.ipv4, next_interface.ipv6)
             .await
         {
             tracing::warn!("Failed to set interface: {e:#}");
+            tun_up = false;
         }
 
         if let Err(e) = tun_device
@@ -148,7 +309,10 @@ async fn update_device_task(
             .await
         {
             tracing::warn!("Failed to set routes: {e:#}");
+            tun_up = false;
         };
+
+        metrics.set_tun_up(tun_up);
     }
 }
 
@@ -164,4 +328,120 @@ struct Cli {
     /// Identifier generated by the portal to identify and display the device.
     #[arg(short = 'i', long, env = "FIREZONE_ID")]
     pub firezone_id: Option<String>,
+
+    /// Upstream DNS servers for resolving DNS resources, as `IP` or `IP:port`.
+    ///
+    /// Can be given multiple times or as a comma-separated list.
+    /// Defaults to the system's resolvers.
+    #[arg(
+        long = "dns-server",
+        env = "FIREZONE_DNS_SERVERS",
+        value_delimiter = ',',
+        value_parser = parse_dns_server
+    )]
+    pub dns_servers: Vec<SocketAddr>,
+
+    #[command(flatten)]
+    nat: NatArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Appends a JSON line for every flow between a client and a resource to this file.
+    #[arg(long, env = "FIREZONE_FLOW_LOG_FILE")]
+    pub flow_log_file: Option<PathBuf>,
+
+    /// Exports every flow between a client and a resource to this IPFIX collector, e.g. `127.0.0.1:4739`.
+    #[arg(long, env = "FIREZONE_FLOW_LOG_IPFIX_COLLECTOR")]
+    pub flow_log_ipfix_collector: Option<SocketAddr>,
+
+    /// Relays TCP connections to the resources in `--egress-proxy-resources` through this proxy.
+    ///
+    /// Either `socks5://[user:pass@]host[:port]` or `http://[user:pass@]host[:port]` for HTTP CONNECT.
+    #[arg(long, env = "FIREZONE_EGRESS_PROXY")]
+    pub egress_proxy: Option<ProxyConfig>,
+
+    /// The IDs of the resources whose TCP connections are relayed through `--egress-proxy`.
+    ///
+    /// Can be given multiple times or as a comma-separated list.
+    #[arg(
+        long = "egress-proxy-resource",
+        env = "FIREZONE_EGRESS_PROXY_RESOURCES",
+        value_delimiter = ',',
+        requires = "egress_proxy"
+    )]
+    pub egress_proxy_resources: Vec<ResourceId>,
+}
+
+/// Configuration of the connections to clients, unset values use the defaults of [`ConnectionConfig`].
+#[derive(clap::Args)]
+struct ConnectionArgs {
+    /// How long a connection to a client may be idle before we close it, e.g. `30m`.
+    #[arg(long, env = "FIREZONE_CONNECTION_IDLE_TIMEOUT")]
+    connection_idle_timeout: Option<humantime::Duration>,
+
+    /// How often we send a keep-alive to clients if there is no other traffic, e.g. `25s`.
+    ///
+    /// `0s` disables keep-alives.
+    /// The portal can override this per client.
+    #[arg(long, env = "FIREZONE_CONNECTION_KEEPALIVE")]
+    connection_keepalive: Option<humantime::Duration>,
+}
+
+impl ConnectionArgs {
+    fn config(self) -> ConnectionConfig {
+        let default = ConnectionConfig::default();
+
+        ConnectionConfig {
+            idle_timeout: self
+                .connection_idle_timeout
+                .map_or(default.idle_timeout, Into::into),
+            keepalive: self.connection_keepalive.map_or(default.keepalive, |k| {
+                Some(Duration::from(k)).filter(|k| !k.is_zero())
+            }),
+        }
+    }
+}
+
+/// Configuration of the NAT for DNS resources, unset values use the defaults of [`NatConfig`].
+#[derive(clap::Args)]
+struct NatArgs {
+    /// How long an established TCP session through the NAT may be idle, e.g. `2h`.
+    #[arg(long, env = "FIREZONE_NAT_TCP_TIMEOUT")]
+    nat_tcp_timeout: Option<humantime::Duration>,
+
+    /// How long a UDP session through the NAT may be idle, e.g. `2m`.
+    #[arg(long, env = "FIREZONE_NAT_UDP_TIMEOUT")]
+    nat_udp_timeout: Option<humantime::Duration>,
+
+    /// How long an ICMP session through the NAT may be idle, e.g. `1m`.
+    #[arg(long, env = "FIREZONE_NAT_ICMP_TIMEOUT")]
+    nat_icmp_timeout: Option<humantime::Duration>,
+
+    /// How many NAT sessions a single client may have at once.
+    #[arg(long, env = "FIREZONE_NAT_MAX_SESSIONS")]
+    nat_max_sessions: Option<usize>,
+
+    /// The ports the NAT maps sessions to, as `START-END`.
+    #[arg(long, env = "FIREZONE_NAT_PORTS", value_parser = parse_port_range)]
+    nat_ports: Option<RangeInclusive<u16>>,
+}
+
+impl NatArgs {
+    fn config(self) -> NatConfig {
+        let default = NatConfig::default();
+
+        NatConfig {
+            tcp_established_timeout: self
+                .nat_tcp_timeout
+                .map_or(default.tcp_established_timeout, Into::into),
+            udp_timeout: self.nat_udp_timeout.map_or(default.udp_timeout, Into::into),
+            icmp_timeout: self
+                .nat_icmp_timeout
+                .map_or(default.icmp_timeout, Into::into),
+            max_sessions: self.nat_max_sessions.unwrap_or(default.max_sessions),
+            ports: self.nat_ports.unwrap_or(default.ports),
+            ..default
+        }
+    }
 }
@@ -3,8 +3,8 @@ use std::{collections::HashSet, net::IpAddr};
 use chrono::{serde::ts_seconds_option, DateTime, Utc};
 use connlib_shared::{
     messages::{
-        gateway::ResourceDescription, ClientId, GatewayResponse, Interface, Offer, Peer, Relay,
-        RelaysPresence, ResourceId,
+        gateway::{RateLimit, ResourceDescription},
+        ClientId, GatewayResponse, Interface, Offer, Peer, Relay, RelaysPresence, ResourceId,
     },
     DomainName,
 };
@@ -46,6 +46,9 @@ pub struct RequestConnection {
     pub reference: String,
     #[serde(with = "ts_seconds_option")]
     pub expires_at: Option<DateTime<Utc>>,
+    /// Limits all traffic of the client, see [`AllowAccess::client_rate_limit`].
+    #[serde(default)]
+    pub client_rate_limit: Option<RateLimit>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -92,6 +95,11 @@ pub struct AllowAccess {
     pub payload: Option<ResolveRequest>,
     #[serde(rename = "ref")]
     pub reference: String,
+    /// Limits all traffic of the client.
+    ///
+    /// Replaces the limit of previous messages, absent means unlimited.
+    #[serde(default)]
+    pub client_rate_limit: Option<RateLimit>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -141,6 +149,7 @@ pub enum EgressMessages {
     ConnectionReady(ConnectionReady),
     BroadcastIceCandidates(ClientsIceCandidates),
     BroadcastInvalidatedIceCandidates(ClientsIceCandidates),
+    AccessExpired(AccessExpired),
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -150,10 +159,18 @@ pub struct ConnectionReady {
     pub gateway_payload: GatewayResponse,
 }
 
+/// The access of a client to a resource expired and we no longer route its traffic.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct AccessExpired {
+    pub client_id: ClientId,
+    pub resource_id: ResourceId,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use connlib_shared::messages::gateway::Filter;
+    use connlib_shared::messages::gateway::IcmpFilter;
     use connlib_shared::messages::gateway::PortRange;
     use connlib_shared::messages::gateway::ResourceDescriptionDns;
     use connlib_shared::messages::Turn;
@@ -228,7 +245,7 @@ mod test {
         "ipv6": "fd00:2021:1111::4:4616",
         "public_key": "zHtdIFPDm8QQkqjbmAc1r8O1WegviA6UeUTP6rpminA=",
         "ipv4": "100.87.247.184",
-        "persistent_keepalive": 25,
+        "persistent_keepalive": null,
         "preshared_key": "BzPiNE9qszKczZcZzGsyieLYeJ2EQfkfdibls/l3beM="
       },
       "payload": {
@@ -319,7 +336,7 @@ mod test {
         "ipv6": "fd00:2021:1111::4:4616",
         "public_key": "zHtdIFPDm8QQkqjbmAc1r8O1WegviA6UeUTP6rpminA=",
         "ipv4": "100.87.247.184",
-        "persistent_keepalive": 25,
+        "persistent_keepalive": null,
         "preshared_key": "BzPiNE9qszKczZcZzGsyieLYeJ2EQfkfdibls/l3beM="
       },
       "payload": {
@@ -410,6 +427,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -435,6 +454,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -460,6 +481,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -485,6 +508,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -510,6 +535,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -535,6 +562,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -560,6 +589,8 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    proxy_ip_lease: None,
+                    dns_forwarding_rules: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -585,12 +616,18 @@ mod test {
                 address: "?.httpbin".to_string(),
                 name: "?.httpbin".to_string(),
                 filters: vec![
-                    Filter::Icmp,
-                    Filter::Tcp(PortRange {
-                        port_range_end: 65535,
-                        port_range_start: 0,
-                    }),
+                    Filter::Icmp(IcmpFilter::default()),
+                    Filter::Tcp(
+                        PortRange {
+                            port_range_end: 65535,
+                            port_range_start: 0,
+                        }
+                        .into(),
+                    ),
                 ],
+                rate_limit: None,
+                port_mappings: vec![],
+                load_balancing: None,
             }));
         let ingress_message = serde_json::from_str::<IngressMessages>(message).unwrap();
         assert_eq!(m, ingress_message);
@@ -639,4 +676,21 @@ mod test {
 
         assert_eq!(ingress_message, expected);
     }
+
+    #[test]
+    fn serialize_access_expired_message() {
+        let message = EgressMessages::AccessExpired(AccessExpired {
+            client_id: "3a25ff38-f8d7-47de-9b30-c7c40c206083".parse().unwrap(),
+            resource_id: "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b".parse().unwrap(),
+        });
+        let expected = serde_json::json!({
+            "event": "access_expired",
+            "payload": {
+                "client_id": "3a25ff38-f8d7-47de-9b30-c7c40c206083",
+                "resource_id": "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b"
+            }
+        });
+
+        assert_eq!(serde_json::to_value(message).unwrap(), expected);
+    }
 }
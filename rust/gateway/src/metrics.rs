@@ -0,0 +1,423 @@
+//! The state that the gateway reports via `/healthz` and `/metrics`.
+use connlib_shared::messages::ClientId;
+use firezone_tunnel::{CandidateType, ConnectionStats, GatewayStats, TrafficStats};
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Shared between the eventloop, which updates it, and the HTTP server, which reports it.
+///
+/// The stats of the tunnel are a snapshot that the eventloop refreshes periodically.
+pub struct Metrics {
+    portal_connected: AtomicBool,
+    tun_up: AtomicBool,
+    /// How many NAT sessions a single client may have at once.
+    nat_max_sessions: usize,
+    stats: Mutex<GatewayStats>,
+}
+
+impl Metrics {
+    pub fn new(nat_max_sessions: usize) -> Self {
+        Self {
+            portal_connected: AtomicBool::new(false),
+            tun_up: AtomicBool::new(false),
+            nat_max_sessions,
+            stats: Mutex::new(GatewayStats::default()),
+        }
+    }
+
+    /// We are only healthy if we can receive new connections from the portal and route traffic.
+    pub fn is_healthy(&self) -> bool {
+        self.portal_connected.load(Ordering::Relaxed) && self.tun_up.load(Ordering::Relaxed)
+    }
+
+    pub fn set_portal_connected(&self, connected: bool) {
+        self.portal_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_tun_up(&self, up: bool) {
+        self.tun_up.store(up, Ordering::Relaxed);
+    }
+
+    pub fn set_stats(&self, stats: GatewayStats) {
+        *self.stats.lock().expect("not poisoned") = stats;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let stats = self.stats.lock().expect("not poisoned").clone();
+
+        let mut out = String::new();
+        self.write(&mut out, &stats)
+            .expect("writing to a string never fails");
+
+        out
+    }
+
+    fn write(&self, out: &mut String, stats: &GatewayStats) -> fmt::Result {
+        let mut clients = stats.clients.iter().collect::<Vec<_>>();
+        clients.sort_by_key(|(id, _)| **id);
+        let mut resources = stats.resources.iter().collect::<Vec<_>>();
+        resources.sort_by_key(|(id, _)| **id);
+
+        gauge(
+            out,
+            "firezone_gateway_portal_connected",
+            "Whether the gateway is connected to the portal.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_portal_connected {}",
+            u8::from(self.portal_connected.load(Ordering::Relaxed))
+        )?;
+
+        gauge(
+            out,
+            "firezone_gateway_tun_up",
+            "Whether the TUN device is configured.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_tun_up {}",
+            u8::from(self.tun_up.load(Ordering::Relaxed))
+        )?;
+
+        gauge(
+            out,
+            "firezone_gateway_connected_clients",
+            "How many clients are connected.",
+        )?;
+        writeln!(out, "firezone_gateway_connected_clients {}", clients.len())?;
+
+        gauge(
+            out,
+            "firezone_gateway_relayed_clients",
+            "How many clients are connected via a relay.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_relayed_clients {}",
+            clients.iter().filter(|(_, c)| c.relayed).count()
+        )?;
+
+        gauge(
+            out,
+            "firezone_gateway_active_resources",
+            "How many resources at least one client has access to.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_active_resources {}",
+            stats.active_resources
+        )?;
+
+        counter(
+            out,
+            "firezone_gateway_handshake_failures_total",
+            "How many connections to clients failed.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_handshake_failures_total {}",
+            stats.handshake_failures
+        )?;
+
+        counter(
+            out,
+            "firezone_gateway_relay_control_bytes_total",
+            "How many bytes we sent to relays for maintaining our allocations.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_relay_control_bytes_total {}",
+            stats.relay_control_bytes
+        )?;
+
+        gauge(
+            out,
+            "firezone_gateway_nat_max_sessions",
+            "How many NAT sessions a single client may have.",
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_nat_max_sessions {}",
+            self.nat_max_sessions
+        )?;
+
+        gauge(
+            out,
+            "firezone_gateway_nat_sessions",
+            "How many NAT sessions for DNS resources a client has.",
+        )?;
+        for (id, client) in &clients {
+            writeln!(
+                out,
+                "firezone_gateway_nat_sessions{{client_id=\"{id}\"}} {}",
+                client.nat_sessions
+            )?;
+        }
+
+        gauge(
+            out,
+            "firezone_gateway_client_rtt_seconds",
+            "The round-trip time to a client, as measured during the last handshake.",
+        )?;
+        for (id, rtt) in clients
+            .iter()
+            .filter_map(|(id, c)| Some((id, c.connection?.rtt?)))
+        {
+            writeln!(
+                out,
+                "firezone_gateway_client_rtt_seconds{{client_id=\"{id}\"}} {}",
+                rtt.as_secs_f64()
+            )?;
+        }
+
+        let connections = clients
+            .iter()
+            .filter_map(|(id, c)| Some((**id, c.connection?)))
+            .collect::<Vec<_>>();
+
+        gauge(
+            out,
+            "firezone_gateway_client_candidate_pair",
+            "The kind of candidates that ICE nominated for the connection to a client.",
+        )?;
+        for (id, pair) in connections
+            .iter()
+            .filter_map(|(id, c)| Some((id, c.candidate_pair?)))
+        {
+            writeln!(
+                out,
+                "firezone_gateway_client_candidate_pair{{client_id=\"{id}\",local=\"{}\",remote=\"{}\"}} 1",
+                candidate_type(pair.local),
+                candidate_type(pair.remote)
+            )?;
+        }
+
+        counter(
+            out,
+            "firezone_gateway_client_handshakes_total",
+            "How many wireguard handshakes completed on the connection to a client.",
+        )?;
+        for (id, connection) in &connections {
+            writeln!(
+                out,
+                "firezone_gateway_client_handshakes_total{{client_id=\"{id}\"}} {}",
+                connection.handshakes
+            )?;
+        }
+
+        tunnel_traffic(out, &connections)?;
+
+        let client_traffic = clients
+            .iter()
+            .map(|(id, c)| (format!("client_id=\"{id}\""), c.traffic))
+            .collect::<Vec<_>>();
+        traffic(out, "client", &client_traffic)?;
+
+        let resource_traffic = resources
+            .iter()
+            .map(|(id, t)| (format!("resource_id=\"{id}\""), **t))
+            .collect::<Vec<_>>();
+        traffic(out, "resource", &resource_traffic)?;
+
+        Ok(())
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} gauge")
+}
+
+fn counter(out: &mut String, name: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} counter")
+}
+
+/// Writes the packet and byte counters of the wireguard traffic on the connections to clients.
+fn tunnel_traffic(out: &mut String, connections: &[(ClientId, ConnectionStats)]) -> fmt::Result {
+    counter(
+        out,
+        "firezone_gateway_client_tunnel_packets_total",
+        "How many wireguard data packets we exchanged with a client.",
+    )?;
+    for (id, c) in connections {
+        writeln!(
+            out,
+            "firezone_gateway_client_tunnel_packets_total{{client_id=\"{id}\",direction=\"in\"}} {}",
+            c.packets_in
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_client_tunnel_packets_total{{client_id=\"{id}\",direction=\"out\"}} {}",
+            c.packets_out
+        )?;
+    }
+
+    counter(
+        out,
+        "firezone_gateway_client_tunnel_bytes_total",
+        "How many bytes of wireguard data packets we exchanged with a client.",
+    )?;
+    for (id, c) in connections {
+        writeln!(
+            out,
+            "firezone_gateway_client_tunnel_bytes_total{{client_id=\"{id}\",direction=\"in\"}} {}",
+            c.bytes_in.0
+        )?;
+        writeln!(
+            out,
+            "firezone_gateway_client_tunnel_bytes_total{{client_id=\"{id}\",direction=\"out\"}} {}",
+            c.bytes_out.0
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The abbreviations of the candidate types from RFC 8445.
+fn candidate_type(kind: CandidateType) -> &'static str {
+    match kind {
+        CandidateType::Host => "host",
+        CandidateType::ServerReflexive => "srflx",
+        CandidateType::PeerReflexive => "prflx",
+        CandidateType::Relayed => "relay",
+    }
+}
+
+/// Writes the packet and byte counters of the given clients or resources, labelled by direction.
+fn traffic(out: &mut String, subject: &str, traffic: &[(String, TrafficStats)]) -> fmt::Result {
+    let packets = format!("firezone_gateway_{subject}_packets_total");
+    let bytes = format!("firezone_gateway_{subject}_bytes_total");
+
+    counter(
+        out,
+        &packets,
+        &format!("How many packets we forwarded for a {subject}."),
+    )?;
+    for (labels, t) in traffic {
+        writeln!(
+            out,
+            "{packets}{{{labels},direction=\"to_resource\"}} {}",
+            t.packets_to_resource
+        )?;
+        writeln!(
+            out,
+            "{packets}{{{labels},direction=\"from_resource\"}} {}",
+            t.packets_from_resource
+        )?;
+    }
+
+    counter(
+        out,
+        &bytes,
+        &format!("How many bytes we forwarded for a {subject}."),
+    )?;
+    for (labels, t) in traffic {
+        writeln!(
+            out,
+            "{bytes}{{{labels},direction=\"to_resource\"}} {}",
+            t.bytes_to_resource
+        )?;
+        writeln!(
+            out,
+            "{bytes}{{{labels},direction=\"from_resource\"}} {}",
+            t.bytes_from_resource
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::ResourceId;
+    use firezone_tunnel::{CandidatePair, ClientStats};
+    use std::time::Duration;
+
+    #[test]
+    fn is_only_healthy_when_connected_to_portal_and_tun_is_up() {
+        let metrics = Metrics::new(16_384);
+        assert!(!metrics.is_healthy());
+
+        metrics.set_portal_connected(true);
+        assert!(!metrics.is_healthy());
+
+        metrics.set_tun_up(true);
+        assert!(metrics.is_healthy());
+
+        metrics.set_portal_connected(false);
+        assert!(!metrics.is_healthy());
+    }
+
+    #[test]
+    fn renders_labelled_counters() {
+        let metrics = Metrics::new(16_384);
+        let client = "9d4b2ad2-3b2b-4ae7-8e4a-5e1b2f2c8f11"
+            .parse::<ClientId>()
+            .unwrap();
+        let resource = "56bc8a1f-9a44-4cd7-a4ac-1c7a5b0e0a0e"
+            .parse::<ResourceId>()
+            .unwrap();
+        let traffic = TrafficStats {
+            packets_to_resource: 3,
+            bytes_to_resource: 300,
+            packets_from_resource: 2,
+            bytes_from_resource: 2000,
+        };
+
+        metrics.set_stats(GatewayStats {
+            clients: [(
+                client,
+                ClientStats {
+                    traffic,
+                    nat_sessions: 5,
+                    relayed: true,
+                    connection: Some(ConnectionStats {
+                        relayed: true,
+                        rtt: Some(Duration::from_millis(25)),
+                        candidate_pair: Some(CandidatePair {
+                            local: CandidateType::Host,
+                            remote: CandidateType::Relayed,
+                        }),
+                        packets_in: 7,
+                        handshakes: 2,
+                        ..Default::default()
+                    }),
+                },
+            )]
+            .into(),
+            resources: [(resource, traffic)].into(),
+            active_resources: 1,
+            handshake_failures: 4,
+            relay_control_bytes: 0,
+        });
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("firezone_gateway_connected_clients 1\n"));
+        assert!(rendered.contains("firezone_gateway_relayed_clients 1\n"));
+        assert!(rendered.contains("firezone_gateway_handshake_failures_total 4\n"));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_client_rtt_seconds{{client_id=\"{client}\"}} 0.025\n"
+        )));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_client_candidate_pair{{client_id=\"{client}\",local=\"host\",remote=\"relay\"}} 1\n"
+        )));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_client_handshakes_total{{client_id=\"{client}\"}} 2\n"
+        )));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_client_tunnel_packets_total{{client_id=\"{client}\",direction=\"in\"}} 7\n"
+        )));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_nat_sessions{{client_id=\"{client}\"}} 5\n"
+        )));
+        assert!(rendered.contains(&format!(
+            "firezone_gateway_resource_bytes_total{{resource_id=\"{resource}\",direction=\"from_resource\"}} 2000\n"
+        )));
+    }
+}
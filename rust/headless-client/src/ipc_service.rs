@@ -6,7 +6,9 @@ use crate::{
 };
 use anyhow::{Context as _, Result};
 use clap::Parser;
-use connlib_client_shared::{file_logger, keypair, ConnectArgs, LoginUrl, Session};
+use connlib_client_shared::{
+    file_logger, keypair, ConnectArgs, ConnectionConfig, LoginUrl, Session,
+};
 use futures::{
     future::poll_fn,
     task::{Context, Poll},
@@ -355,6 +357,8 @@ impl<'a> Handler<'a> {
                     udp_socket_factory: Arc::new(crate::udp_socket_factory),
                     private_key,
                     callbacks: self.callback_handler.clone(),
+                    connection_config: ConnectionConfig::default(),
+                    session_cache: None,
                 };
                 let portal = PhoenixChannel::connect(
                     Secret::new(url),
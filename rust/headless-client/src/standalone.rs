@@ -1,13 +1,15 @@
 //! AKA "Headless"
 
 use crate::{
-    default_token_path, device_id, dns_control, platform, signals, CallbackHandler, CliCommon,
-    DnsController, InternalServerMsg, IpcServerMsg, TOKEN_ENV_KEY,
+    default_token_path, device_id, dns_control, known_dirs, platform, signals, CallbackHandler,
+    CliCommon, DnsController, InternalServerMsg, IpcServerMsg, TOKEN_ENV_KEY,
 };
 use anyhow::{anyhow, Context as _, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use connlib_client_shared::{file_logger, keypair, ConnectArgs, LoginUrl, Session};
+use connlib_client_shared::{
+    file_logger, keypair, ConnectArgs, ConnectionConfig, LoginUrl, Session, SessionCache,
+};
 use connlib_shared::get_user_agent;
 use firezone_bin_shared::{setup_global_subscriber, TunDeviceManager};
 use futures::{FutureExt as _, StreamExt as _};
@@ -17,6 +19,7 @@ use std::{
     path::{Path, PathBuf},
     pin::pin,
     sync::Arc,
+    time::Duration,
 };
 use tokio::{sync::mpsc, time::Instant};
 use tokio_stream::wrappers::ReceiverStream;
@@ -66,6 +69,23 @@ struct Cli {
     #[arg(short = 'i', long, env = "FIREZONE_ID")]
     firezone_id: Option<String>,
 
+    /// Remember this device's key, how to reach gateways and the relays across restarts.
+    ///
+    /// Connections to gateways are re-established faster after a restart.
+    /// The cache is stored next to the device ID and contains secrets, thus it is only supported on Unix.
+    #[arg(long, env = "FIREZONE_SESSION_CACHE")]
+    session_cache: bool,
+
+    /// How long a connection to a gateway may be idle before we close it, e.g. `30m`.
+    #[arg(long, env = "FIREZONE_CONNECTION_IDLE_TIMEOUT")]
+    connection_idle_timeout: Option<humantime::Duration>,
+
+    /// How often we send a keep-alive to gateways if there is no other traffic, e.g. `25s`.
+    ///
+    /// `0s` disables keep-alives.
+    #[arg(long, env = "FIREZONE_CONNECTION_KEEPALIVE")]
+    connection_keepalive: Option<humantime::Duration>,
+
     /// Token generated by the portal to authorize websocket connection.
     // systemd recommends against passing secrets through env vars:
     // <https://www.freedesktop.org/software/systemd/man/latest/systemd.exec.html#Environment=>
@@ -82,6 +102,22 @@ struct Cli {
     token_path: PathBuf,
 }
 
+impl Cli {
+    /// The [`ConnectionConfig`] for our connections to gateways, unset values use its defaults.
+    fn connection_config(&self) -> ConnectionConfig {
+        let default = ConnectionConfig::default();
+
+        ConnectionConfig {
+            idle_timeout: self
+                .connection_idle_timeout
+                .map_or(default.idle_timeout, Into::into),
+            keepalive: self.connection_keepalive.map_or(default.keepalive, |k| {
+                Some(Duration::from(k)).filter(|k| !k.is_zero())
+            }),
+        }
+    }
+}
+
 #[derive(clap::Subcommand, Clone, Copy)]
 enum Cmd {
     #[command(hide = true)]
@@ -138,6 +174,7 @@ pub fn run_only_headless_client() -> Result<()> {
     })?;
     // TODO: Should this default to 30 days?
     let max_partition_time = cli.common.max_partition_time.map(|d| d.into());
+    let connection_config = cli.connection_config();
 
     // AKA "Device ID", not the Firezone slug
     let firezone_id = match cli.firezone_id {
@@ -145,7 +182,15 @@ pub fn run_only_headless_client() -> Result<()> {
         None => device_id::get_or_create().context("Could not get `firezone_id` from CLI, could not read it from disk, could not generate it and save it to disk")?.id,
     };
 
-    let (private_key, public_key) = keypair();
+    let session_cache = if cli.session_cache {
+        Some(SessionCache::load(session_cache_path()?).context("Failed to enable session cache")?)
+    } else {
+        None
+    };
+    let (private_key, public_key) = match &session_cache {
+        Some(cache) => (cache.private_key(), cache.public_key()),
+        None => keypair(),
+    };
     let url = LoginUrl::client(
         cli.api_url,
         &token,
@@ -170,6 +215,8 @@ pub fn run_only_headless_client() -> Result<()> {
         tcp_socket_factory: Arc::new(crate::tcp_socket_factory),
         private_key,
         callbacks,
+        connection_config,
+        session_cache,
     };
     let _guard = rt.enter(); // Constructing `PhoenixChannel` requires a runtime context.
     let portal = PhoenixChannel::connect(
@@ -268,6 +315,16 @@ fn get_token(
     read_token_file(token_path)
 }
 
+/// Returns the path of the session cache
+///
+/// e.g. `/var/lib/dev.firezone.client/config/session-cache.json`
+fn session_cache_path() -> Result<PathBuf> {
+    let path = known_dirs::ipc_service_config()
+        .context("Failed to compute path for session cache")?
+        .join("session-cache.json");
+    Ok(path)
+}
+
 /// Try to retrieve the token from disk
 ///
 /// Sync because we do blocking file I/O
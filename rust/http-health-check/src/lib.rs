@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::routing::get;
 use axum::Router;
 use std::net::SocketAddr;
@@ -8,22 +8,49 @@ pub async fn serve(
     addr: impl Into<SocketAddr>,
     is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
 ) -> std::io::Result<()> {
-    let addr = addr.into();
+    run(addr.into(), health_router(is_healthy)).await
+}
+
+/// Like [`serve`] but additionally responds to `GET /metrics` with the output of `render_metrics`.
+///
+/// `render_metrics` must return metrics in the Prometheus text exposition format.
+pub async fn serve_with_metrics(
+    addr: impl Into<SocketAddr>,
+    is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    render_metrics: impl Fn() -> String + Clone + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let router = health_router(is_healthy).route(
+        "/metrics",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                render_metrics(),
+            )
+        }),
+    );
+
+    run(addr.into(), router).await
+}
 
-    let service = Router::new()
-        .route(
-            "/healthz",
-            get(move || async move {
-                if is_healthy() {
-                    StatusCode::OK
-                } else {
-                    StatusCode::BAD_REQUEST
-                }
-            }),
-        )
-        .into_make_service();
+fn health_router(is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static) -> Router {
+    Router::new().route(
+        "/healthz",
+        get(move || async move {
+            if is_healthy() {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_REQUEST
+            }
+        }),
+    )
+}
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+async fn run(addr: SocketAddr, router: Router) -> std::io::Result<()> {
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        router.into_make_service(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -1280,7 +1280,7 @@ impl<'a> MutableIpPacket<'a> {
 
 impl<'a> IpPacket<'a> {
     pub fn new(buf: &'a [u8]) -> Option<Self> {
-        match buf[0] >> 4 {
+        match buf.first()? >> 4 {
             4 => Some(IpPacket::Ipv4(Ipv4Packet::new(buf)?)),
             6 => Some(IpPacket::Ipv6(Ipv6Packet::new(buf)?)),
             _ => None,
@@ -1441,6 +1441,21 @@ impl<'a> IpPacket<'a> {
         }
     }
 
+    /// If this is an ICMP error, returns the packet that caused it.
+    ///
+    /// ICMP errors only embed the IP header and the first 8 bytes of the payload of the offending packet.
+    /// That is enough to read the ports of TCP, UDP and SCTP and the identifier of ICMP echo requests but the transport header is usually incomplete.
+    pub fn icmp_error_packet(&self) -> Option<IpPacket<'_>> {
+        let icmp = self.as_icmp()?;
+
+        if !icmp.is_error() {
+            return None;
+        }
+
+        // The ICMP header is 4 bytes, followed by 4 unused bytes (or the MTU / pointer).
+        IpPacket::new(self.payload().get(8..)?)
+    }
+
     pub fn udp_checksum(&self, dgm: &UdpPacket<'_>) -> u16 {
         match self {
             Self::Ipv4(p) => udp::ipv4_checksum(dgm, &p.get_source(), &p.get_destination()),
@@ -1501,6 +1516,27 @@ impl<'a> IcmpPacket<'a> {
         self.as_echo_request().is_some()
     }
 
+    /// Whether this ICMP message reports an error about another packet.
+    pub fn is_error(&self) -> bool {
+        match self {
+            IcmpPacket::Ipv4(v4) => matches!(
+                v4.get_icmp_type(),
+                IcmpTypes::DestinationUnreachable
+                    | IcmpTypes::SourceQuench
+                    | IcmpTypes::RedirectMessage
+                    | IcmpTypes::TimeExceeded
+                    | IcmpTypes::ParameterProblem
+            ),
+            IcmpPacket::Ipv6(v6) => matches!(
+                v6.get_icmpv6_type(),
+                Icmpv6Types::DestinationUnreachable
+                    | Icmpv6Types::PacketTooBig
+                    | Icmpv6Types::TimeExceeded
+                    | Icmpv6Types::ParameterProblem
+            ),
+        }
+    }
+
     pub fn checksum(&self) -> u16 {
         match self {
             IcmpPacket::Ipv4(p) => p.get_checksum(),
@@ -47,6 +47,61 @@ pub fn icmp_response_packet(packet: IpPacket<'static>) -> MutableIpPacket<'stati
     )
 }
 
+/// Makes an ICMP "destination unreachable" error from `src` for the given packet.
+///
+/// Like a router would, this embeds the IP header and the first 8 bytes of the payload of the original packet.
+pub fn icmp_destination_unreachable(
+    src: IpAddr,
+    original: &IpPacket<'_>,
+) -> MutableIpPacket<'static> {
+    use crate::{
+        icmp::{IcmpCode, IcmpTypes, MutableIcmpPacket},
+        icmpv6::{Icmpv6Code, Icmpv6Types, MutableIcmpv6Packet},
+        ip::IpNextHeaderProtocols,
+        Packet as _,
+    };
+
+    let header_len = match original {
+        IpPacket::Ipv4(p) => p.get_header_length() as usize * 4,
+        IpPacket::Ipv6(_) => 40,
+    };
+    let embedded = &original.packet()[..(header_len + 8).min(original.packet().len())];
+
+    match (src, original.source()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut buf = vec![0u8; 20 + 20 + 8 + embedded.len()];
+
+            ipv4_header(src, dst, IpNextHeaderProtocols::Icmp, 5, &mut buf[20..]);
+
+            let mut icmp_packet = MutableIcmpPacket::new(&mut buf[40..]).unwrap();
+            icmp_packet.set_icmp_type(IcmpTypes::DestinationUnreachable);
+            icmp_packet.set_icmp_code(IcmpCode(1)); // Host unreachable.
+            buf[48..].copy_from_slice(embedded);
+
+            let mut result = MutableIpPacket::owned(buf).unwrap();
+            result.update_checksum();
+            result
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut buf = vec![0u8; 20 + 40 + 8 + embedded.len()];
+
+            ipv6_header(src, dst, IpNextHeaderProtocols::Icmpv6, &mut buf[20..]);
+
+            let mut icmp_packet = MutableIcmpv6Packet::new(&mut buf[60..]).unwrap();
+            icmp_packet.set_icmpv6_type(Icmpv6Types::DestinationUnreachable);
+            icmp_packet.set_icmpv6_code(Icmpv6Code(3)); // Address unreachable.
+            buf[68..].copy_from_slice(embedded);
+
+            let mut result = MutableIpPacket::owned(buf).unwrap();
+            result.update_checksum();
+            result
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
+            panic!("IPs must be of the same version")
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, test_strategy::Arbitrary))]
 pub(crate) enum IcmpKind {
     Request,
@@ -183,7 +238,18 @@ where
 
             ipv4_header(src, dst, IpNextHeaderProtocols::Tcp, 5, &mut buf[20..]);
 
-            tcp_header(saddr, daddr, sport, dport, &payload, &mut buf[40..]);
+            tcp_header(
+                saddr,
+                daddr,
+                sport,
+                dport,
+                0,
+                0,
+                0,
+                128,
+                &payload,
+                &mut buf[40..],
+            );
             MutableIpPacket::owned(buf).unwrap()
         }
         (IpAddr::V6(src), IpAddr::V6(dst)) => {
@@ -193,7 +259,75 @@ where
 
             ipv6_header(src, dst, IpNextHeaderProtocols::Tcp, &mut buf[20..]);
 
-            tcp_header(saddr, daddr, sport, dport, &payload, &mut buf[60..]);
+            tcp_header(
+                saddr,
+                daddr,
+                sport,
+                dport,
+                0,
+                0,
+                0,
+                128,
+                &payload,
+                &mut buf[60..],
+            );
+            MutableIpPacket::owned(buf).unwrap()
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
+            panic!("IPs must be of the same version")
+        }
+    }
+}
+
+/// Makes a TCP segment with the given sequence & acknowledgement number, flags and window.
+pub fn tcp_segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: &[u8],
+) -> MutableIpPacket<'static> {
+    use crate::ip::IpNextHeaderProtocols;
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(saddr), IpAddr::V4(daddr)) => {
+            let mut buf = vec![0u8; 20 + 20 + payload.len() + 20];
+
+            ipv4_header(saddr, daddr, IpNextHeaderProtocols::Tcp, 5, &mut buf[20..]);
+
+            tcp_header(
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port(),
+                seq,
+                ack,
+                flags,
+                window,
+                payload,
+                &mut buf[40..],
+            );
+            MutableIpPacket::owned(buf).unwrap()
+        }
+        (IpAddr::V6(saddr), IpAddr::V6(daddr)) => {
+            let mut buf = vec![0u8; 40 + 20 + payload.len() + 20];
+
+            ipv6_header(saddr, daddr, IpNextHeaderProtocols::Tcp, &mut buf[20..]);
+
+            tcp_header(
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port(),
+                seq,
+                ack,
+                flags,
+                window,
+                payload,
+                &mut buf[60..],
+            );
             MutableIpPacket::owned(buf).unwrap()
         }
         (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
@@ -243,6 +377,42 @@ where
     }
 }
 
+/// Makes an IP packet with an arbitrary payload, e.g. for protocols that we don't have a dedicated function for.
+///
+/// The payload is used as is, only the checksums of ICMP, TCP and UDP are updated.
+pub fn ip_packet(
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> MutableIpPacket<'static> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut buf = vec![0u8; 20 + 20 + payload.len()];
+
+            ipv4_header(src, dst, protocol, 5, &mut buf[20..]);
+            buf[40..].copy_from_slice(payload);
+
+            let mut result = MutableIpPacket::owned(buf).unwrap();
+            result.update_checksum();
+            result
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut buf = vec![0u8; 20 + 40 + payload.len()];
+
+            ipv6_header(src, dst, protocol, &mut buf[20..]);
+            buf[60..].copy_from_slice(payload);
+
+            let mut result = MutableIpPacket::owned(buf).unwrap();
+            result.update_checksum();
+            result
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
+            panic!("IPs must be of the same version")
+        }
+    }
+}
+
 pub fn dns_query(
     domain: Name,
     kind: RecordType,
@@ -280,6 +450,9 @@ where
     let mut response = Message::new();
     response.set_id(query.id());
     response.set_message_type(hickory_proto::op::MessageType::Response);
+    if let Some(edns) = query.extensions().clone() {
+        response.set_edns(edns);
+    }
 
     for query in query.take_queries() {
         response.add_query(query.clone());
@@ -320,7 +493,10 @@ pub fn dns_err_response(packet: IpPacket<'static>, code: ResponseCode) -> Mutabl
 
     debug_assert_ne!(code, ResponseCode::NoError);
 
-    let response = Message::error_msg(query.id(), query.op_code(), code);
+    let mut response = Message::error_msg(query.id(), query.op_code(), code);
+    if let Some(edns) = query.extensions().clone() {
+        response.set_edns(edns);
+    }
 
     let payload = response.to_vec().unwrap();
 
@@ -372,22 +548,27 @@ fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, proto: IpNextHeaderProtocol, buf: &
     ipv6_packet.set_destination(dst);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn tcp_header(
     saddr: IpAddr,
     daddr: IpAddr,
     sport: u16,
     dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
     payload: &[u8],
     buf: &mut [u8],
 ) {
     let mut tcp_packet = MutableTcpPacket::new(buf).unwrap();
     tcp_packet.set_source(sport);
     tcp_packet.set_destination(dport);
-    tcp_packet.set_sequence(0);
-    tcp_packet.set_acknowledgement(0);
+    tcp_packet.set_sequence(seq);
+    tcp_packet.set_acknowledgement(ack);
     tcp_packet.set_data_offset(5);
-    tcp_packet.set_flags(0);
-    tcp_packet.set_window(128);
+    tcp_packet.set_flags(flags);
+    tcp_packet.set_window(window);
     tcp_packet.set_payload(payload);
     match (saddr, daddr) {
         (IpAddr::V4(src), IpAddr::V4(dst)) => {
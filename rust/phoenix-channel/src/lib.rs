@@ -278,6 +278,11 @@ where
         self.url.expose_secret().host()
     }
 
+    /// Whether we currently have a websocket connection to the portal.
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
     /// Join the provided room.
     ///
     /// If successful, a [`Event::JoinedRoom`] event will be emitted.
@@ -6,6 +6,7 @@ pub mod auth;
 #[cfg(feature = "proptest")]
 pub mod proptest;
 pub mod sockets;
+pub mod streams;
 
 pub use net_ext::IpAddrExt;
 pub use server::{
@@ -89,20 +90,43 @@ impl From<(Option<Ipv4Addr>, Option<Ipv6Addr>)> for IpStack {
 /// From the [spec](https://www.rfc-editor.org/rfc/rfc8656#section-2-4.4):
 ///
 /// > A STUN client that implements this specification.
+///
+/// Clients that talk to us via UDP and via TCP or TLS are different clients, even if they use the same address,
+/// e.g. two devices behind the same carrier-grade NAT.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-pub struct ClientSocket(SocketAddr);
+pub struct ClientSocket {
+    addr: SocketAddr,
+    transport: Transport,
+}
+
+/// How a client talks to us.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
 
 impl ClientSocket {
+    /// A client that talks to us via UDP.
     pub fn new(addr: SocketAddr) -> Self {
-        Self(addr)
+        Self::with_transport(addr, Transport::Udp)
+    }
+
+    pub fn with_transport(addr: SocketAddr, transport: Transport) -> Self {
+        Self { addr, transport }
     }
 
     pub fn into_socket(self) -> SocketAddr {
-        self.0
+        self.addr
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
     }
 
     pub fn family(&self) -> AddressFamily {
-        match self.0 {
+        match self.addr {
             SocketAddr::V4(_) => AddressFamily::V4,
             SocketAddr::V6(_) => AddressFamily::V6,
         }
@@ -111,7 +135,11 @@ impl ClientSocket {
 
 impl fmt::Display for ClientSocket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        match self.transport {
+            Transport::Udp => write!(f, "{}", self.addr),
+            Transport::Tcp => write!(f, "tcp://{}", self.addr),
+            Transport::Tls => write!(f, "tls://{}", self.addr),
+        }
     }
 }
 
@@ -2,9 +2,10 @@ use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
 use firezone_relay::sockets::Sockets;
+use firezone_relay::streams::{self, Streams};
 use firezone_relay::{
     sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack,
-    PeerSocket, Server, Sleep,
+    PeerSocket, Server, Sleep, Transport,
 };
 use futures::{future, FutureExt};
 use opentelemetry::KeyValue;
@@ -13,7 +14,9 @@ use phoenix_channel::{Event, LoginUrl, PhoenixChannel};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{Secret, SecretString};
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::Poll;
@@ -27,6 +30,14 @@ use url::Url;
 
 const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
+/// The port we listen on for TURN over TLS.
+///
+/// Clients always connect to this port for TLS, thus it is not configurable.
+const TLS_LISTEN_PORT: u16 = 443;
+
+/// How often we close the streams of clients that connected but never allocated.
+const CLOSE_IDLE_STREAMS_INTERVAL: Duration = Duration::from_secs(5);
+
 const MAX_PARTITION_TIME: Duration = Duration::from_secs(60 * 15);
 
 #[derive(Parser, Debug)]
@@ -38,8 +49,18 @@ struct Args {
     #[arg(long, env)]
     public_ip6_addr: Option<Ipv6Addr>,
     /// The port to listen on for STUN messages.
+    ///
+    /// We listen on this port for both UDP and TCP.
     #[arg(long, env, hide = true, default_value = "3478")]
     listen_port: u16,
+    /// A PEM file with the certificate chain to use for TURN over TLS.
+    ///
+    /// If omitted, we don't listen for TLS on port 443.
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// A PEM file with the private key of the certificate.
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "49152")]
@@ -154,9 +175,19 @@ async fn main() -> Result<()> {
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr, last_heartbeat_sent)?;
+    let tls = match (args.tls_cert_file.as_deref(), args.tls_key_file.as_deref()) {
+        (Some(cert_file), Some(key_file)) => Some(streams::tls_acceptor(cert_file, key_file)?),
+        _ => None,
+    };
+    let listens_for_tls = tls.is_some();
 
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {0}", args.listen_port);
+    let mut eventloop = Eventloop::new(server, channel, public_addr, tls, last_heartbeat_sent)?;
+
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP and TCP port {0}", args.listen_port);
+
+    if listens_for_tls {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TLS port {TLS_LISTEN_PORT}");
+    }
 
     future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -296,6 +327,9 @@ const MAX_UDP_SIZE: usize = 65536;
 
 struct Eventloop<R> {
     sockets: Sockets,
+    /// TCP and TLS connections of clients that can't reach us via UDP.
+    streams: Streams,
+    close_idle_streams_interval: tokio::time::Interval,
 
     server: Server<R>,
     channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
@@ -320,9 +354,11 @@ where
         server: Server<R>,
         channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
         public_address: IpStack,
+        tls: Option<tokio_rustls::TlsAcceptor>,
         last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
     ) -> Result<Self> {
         let mut sockets = Sockets::new();
+        let mut streams = Streams::new();
 
         if public_address.as_v4().is_some() {
             sockets
@@ -345,8 +381,36 @@ where
                 })?;
         }
 
+        let families = [
+            public_address.as_v4().map(|_| AddressFamily::V4),
+            public_address.as_v6().map(|_| AddressFamily::V6),
+        ];
+
+        for family in families.into_iter().flatten() {
+            streams
+                .listen(server.listen_port(), family, None)
+                .with_context(|| {
+                    format!(
+                        "Failed to listen on TCP port {} on {family} interfaces",
+                        server.listen_port()
+                    )
+                })?;
+
+            if let Some(acceptor) = tls.as_ref() {
+                streams
+                    .listen(TLS_LISTEN_PORT, family, Some(acceptor.clone()))
+                    .with_context(|| {
+                        format!(
+                            "Failed to listen on TCP port {TLS_LISTEN_PORT} on {family} interfaces"
+                        )
+                    })?;
+            }
+        }
+
         Ok(Self {
             server,
+            streams,
+            close_idle_streams_interval: tokio::time::interval(CLOSE_IDLE_STREAMS_INTERVAL),
             channel,
             sleep: Sleep::default(),
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
@@ -369,9 +433,11 @@ where
             if let Some(next_command) = self.server.next_command() {
                 match next_command {
                     Command::SendMessage { payload, recipient } => {
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &self.sockets,
+                            &self.streams,
                             self.server.listen_port(),
-                            recipient.into_socket(),
+                            recipient,
                             &payload,
                         ) {
                             tracing::warn!(target: "relay", %recipient, "Failed to send message: {e}");
@@ -465,9 +531,11 @@ where
                             header,
                         );
 
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &self.sockets,
+                            &self.streams,
                             self.server.listen_port(), // Packets coming in from peers always go out on the TURN port
-                            client.into_socket(),
+                            client,
                             &self.buffer[..total_length],
                         ) {
                             tracing::warn!(target: "relay", %client, "Failed to relay data to client: {e}");
@@ -483,6 +551,43 @@ where
                 Poll::Pending => {}
             }
 
+            // Priority 2b: Read from the TCP and TLS connections of our clients.
+            match self.streams.poll_event(cx) {
+                Poll::Ready(streams::Event::Received { from, message }) => {
+                    if let Some((port, peer)) =
+                        self.server
+                            .handle_client_input(&message, from, Instant::now())
+                    {
+                        let payload = ChannelData::parse(&message)
+                            .expect("valid ChannelData if we should relay it")
+                            .data();
+
+                        if let Err(e) =
+                            self.sockets
+                                .try_send(port.value(), peer.into_socket(), payload)
+                        {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+                        }
+                    };
+                    continue;
+                }
+                Poll::Ready(streams::Event::Closed { from }) => {
+                    self.server.handle_client_disconnected(from);
+                    continue;
+                }
+                Poll::Ready(streams::Event::Connected { .. }) => {
+                    unreachable!("`Streams` handles new connections internally")
+                }
+                Poll::Pending => {}
+            }
+
+            if self.close_idle_streams_interval.poll_tick(cx).is_ready() {
+                let server = &self.server;
+                self.streams
+                    .close_idle(Instant::now(), |client| server.has_allocation(client));
+                continue;
+            }
+
             // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
             if let Some(timeout) = self.server.poll_timeout() {
                 Pin::new(&mut self.sleep).reset(timeout);
@@ -576,6 +681,20 @@ where
     }
 }
 
+/// Sends a message to a client, via its TCP or TLS connection if it is not using UDP.
+fn send_to_client(
+    sockets: &Sockets,
+    streams: &Streams,
+    listen_port: u16,
+    client: ClientSocket,
+    msg: &[u8],
+) -> io::Result<()> {
+    match client.transport() {
+        Transport::Udp => sockets.try_send(listen_port, client.into_socket(), msg),
+        Transport::Tcp | Transport::Tls => streams.try_send(client, msg),
+    }
+}
+
 fn fmt_human_throughput(mut throughput: f64) -> String {
     let units = ["B/s", "kB/s", "MB/s", "GB/s", "TB/s"];
 
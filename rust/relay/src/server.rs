@@ -47,6 +47,9 @@ use uuid::Uuid;
 /// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
 /// we can index data simply by the sender's [`SocketAddr`].
 ///
+/// Clients may also talk to us via TCP or TLS, in which case the sender is the remote address of their connection.
+/// Allocations always relay via UDP, only the transport between client and relay differs.
+///
 /// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
 #[derive(Debug)]
 pub struct Server<R> {
@@ -235,6 +238,10 @@ where
         self.allocations.len()
     }
 
+    pub fn has_allocation(&self, client: ClientSocket) -> bool {
+        self.allocations.contains_key(&client)
+    }
+
     pub fn num_active_channels(&self) -> usize {
         self.channels_by_client_and_number
             .iter()
@@ -355,6 +362,18 @@ where
         self.delete_allocation(allocation)
     }
 
+    /// The TCP or TLS connection of a client was closed.
+    ///
+    /// An allocation is bound to the connection it was made on, thus it can no longer be used.
+    #[tracing::instrument(level = "debug", skip(self), fields(%client))]
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        let Some(port) = self.allocations.get(&client).map(|a| a.port) else {
+            return;
+        };
+
+        self.delete_allocation(port)
+    }
+
     /// Return the next command to be executed.
     pub fn next_command(&mut self) -> Option<Command> {
         self.pending_commands.pop_front()
@@ -0,0 +1,461 @@
+//! TCP and TLS connections from clients that cannot reach us via UDP.
+//!
+//! STUN and channel-data messages carry their own length, thus clients send them back-to-back on the stream.
+//! Channel-data messages are padded to a multiple of 4 bytes, see <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+//!
+//! Each connection is served by its own task which forwards the messages to the main task and writes the messages we send to the client.
+
+use crate::{ClientSocket, Transport};
+use anyhow::{Context as _, Result};
+use bytes::{Buf as _, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// How many messages we buffer per client before we start dropping them.
+///
+/// Like with UDP, it is up to the protocols on top to deal with packet loss.
+const MAX_BUFFERED_MESSAGES: usize = 256;
+
+/// How many TCP and TLS connections we serve at once, further ones are closed right away.
+const MAX_STREAMS: usize = 10_000;
+
+/// How long a client may take to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client without an allocation may stay connected without sending us a message.
+const IDLE_TIMEOUT_WITHOUT_ALLOCATION: Duration = Duration::from_secs(30);
+
+/// All TCP and TLS connections of clients.
+pub struct Streams {
+    clients: HashMap<ClientSocket, Client>,
+    /// Limits the number of connections across all listeners to [`MAX_STREAMS`].
+    permits: Arc<Semaphore>,
+
+    event_tx: mpsc::Sender<Event>,
+    event_rx: mpsc::Receiver<Event>,
+}
+
+struct Client {
+    /// The sender for the messages we write to the client.
+    ///
+    /// Dropping it closes the connection.
+    tx: mpsc::Sender<Vec<u8>>,
+    last_received: Instant,
+}
+
+/// Something that happened on one of the [`Streams`].
+#[derive(Debug)]
+pub enum Event {
+    Connected {
+        from: ClientSocket,
+        tx: mpsc::Sender<Vec<u8>>,
+    },
+    /// A complete STUN or channel-data message from a client, without padding.
+    Received { from: ClientSocket, message: Bytes },
+    /// The connection of a client was closed.
+    Closed { from: ClientSocket },
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(1_024);
+
+        Self {
+            clients: Default::default(),
+            permits: Arc::new(Semaphore::new(MAX_STREAMS)),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Accepts connections on the given port and address family, optionally wrapped in TLS.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn listen(
+        &mut self,
+        port: u16,
+        family: AddressFamily,
+        tls: Option<TlsAcceptor>,
+    ) -> io::Result<()> {
+        let listener = TcpListener::from_std(make_wildcard_listener(family, port)?)?;
+
+        tokio::spawn(accept(
+            listener,
+            tls,
+            self.permits.clone(),
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Closes the connections of clients that have no allocation and didn't send us anything for a while.
+    ///
+    /// Otherwise, anyone could keep connections open without ever authenticating.
+    pub fn close_idle(&mut self, now: Instant, has_allocation: impl Fn(ClientSocket) -> bool) {
+        self.clients.retain(|client, c| {
+            if has_allocation(*client)
+                || now.duration_since(c.last_received) < IDLE_TIMEOUT_WITHOUT_ALLOCATION
+            {
+                return true;
+            }
+
+            tracing::debug!(target: "relay", %client, "Closing idle stream without allocation");
+
+            false
+        });
+    }
+
+    pub fn try_send(&self, client: ClientSocket, msg: &[u8]) -> io::Result<()> {
+        let tx = self.clients.get(&client).map(|c| &c.tx).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("No stream from {client}"),
+            )
+        })?;
+
+        let mut frame = Vec::with_capacity(msg.len() + 3);
+        frame.extend_from_slice(msg);
+        frame.extend_from_slice(padding(msg));
+
+        tx.try_send(frame).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => io::Error::from(io::ErrorKind::WouldBlock),
+            mpsc::error::TrySendError::Closed(_) => io::Error::from(io::ErrorKind::BrokenPipe),
+        })
+    }
+
+    pub fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Event> {
+        loop {
+            let event = ready!(self.event_rx.poll_recv(cx)).expect("we hold a sender ourselves");
+
+            match event {
+                Event::Connected { from, tx } => {
+                    tracing::debug!(target: "relay", %from, "New stream");
+
+                    self.clients.insert(
+                        from,
+                        Client {
+                            tx,
+                            last_received: Instant::now(),
+                        },
+                    );
+                    continue;
+                }
+                Event::Closed { from } => {
+                    tracing::debug!(target: "relay", %from, "Stream closed");
+
+                    self.clients.remove(&from);
+                    return Poll::Ready(Event::Closed { from });
+                }
+                Event::Received { from, .. } => {
+                    if let Some(client) = self.clients.get_mut(&from) {
+                        client.last_received = Instant::now();
+                    }
+
+                    return Poll::Ready(event);
+                }
+            }
+        }
+    }
+}
+
+/// Loads the certificate chain and private key for TURN over TLS from the given PEM files.
+pub fn tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file).with_context(|| format!("Failed to open {}", cert_file.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Failed to parse certificates")?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_file).with_context(|| format!("Failed to open {}", key_file.display()))?,
+    ))
+    .context("Failed to parse private key")?
+    .context("No private key found")?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn accept(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    permits: Arc<Semaphore>,
+    event_tx: mpsc::Sender<Event>,
+) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!(target: "relay", "Failed to accept connection: {e}");
+
+                // Errors like running out of file descriptors persist for a while, don't spin on them.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            tracing::debug!(target: "relay", %from, "Refusing stream; too many open streams");
+            continue;
+        };
+
+        let transport = match tls {
+            Some(_) => Transport::Tls,
+            None => Transport::Tcp,
+        };
+
+        tokio::spawn(serve(
+            stream,
+            ClientSocket::with_transport(from, transport),
+            tls.clone(),
+            event_tx.clone(),
+            permit,
+        ));
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    from: ClientSocket,
+    tls: Option<TlsAcceptor>,
+    event_tx: mpsc::Sender<Event>,
+    _permit: OwnedSemaphorePermit,
+) {
+    let _ = stream.set_nodelay(true);
+
+    let result = match tls {
+        Some(tls) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+            Ok(Ok(stream)) => relay_messages(stream, from, &event_tx).await,
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            )),
+        },
+        None => relay_messages(stream, from, &event_tx).await,
+    };
+
+    if let Err(e) = result {
+        tracing::debug!(target: "relay", %from, "Stream failed: {e}");
+    }
+
+    let _ = event_tx.send(Event::Closed { from }).await;
+}
+
+/// Forwards the messages read from the stream to the main task and writes the ones it sends us until either side is closed.
+async fn relay_messages(
+    stream: impl AsyncRead + AsyncWrite,
+    from: ClientSocket,
+    event_tx: &mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_BUFFERED_MESSAGES);
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    event_tx
+        .send(Event::Connected { from, tx })
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+    let read = async {
+        let mut buffer = BytesMut::with_capacity(16 * 1024);
+
+        loop {
+            while let Some(message) = decode(&mut buffer)? {
+                event_tx
+                    .send(Event::Received { from, message })
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            }
+
+            if reader.read_buf(&mut buffer).await? == 0 {
+                break;
+            }
+        }
+
+        Ok::<_, io::Error>(())
+    };
+    let write = async {
+        while let Some(frame) = rx.recv().await {
+            writer.write_all(&frame).await?;
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    tokio::select! {
+        result = read => result,
+        result = write => result,
+    }
+}
+
+/// Splits the next complete message off the buffer.
+fn decode(buffer: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    let (Some(first), Some(length)) = (buffer.first(), buffer.get(2..4)) else {
+        return Ok(None);
+    };
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+    // The first two bits distinguish STUN from channel-data messages, see <https://www.rfc-editor.org/rfc/rfc7983#section-7>.
+    let (message_len, frame_len) = match first >> 6 {
+        0b00 => (STUN_HEADER_LEN + length, STUN_HEADER_LEN + length),
+        0b01 => {
+            let message_len = CHANNEL_DATA_HEADER_LEN + length;
+
+            (message_len, message_len.next_multiple_of(4))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "neither a STUN nor a channel-data message",
+            ))
+        }
+    };
+
+    if buffer.len() < frame_len {
+        return Ok(None);
+    }
+
+    let message = buffer.split_to(message_len).freeze();
+    buffer.advance(frame_len - message_len);
+
+    Ok(Some(message))
+}
+
+fn padding(message: &[u8]) -> &'static [u8] {
+    const ZEROS: [u8; 3] = [0; 3];
+
+    let is_channel_data = message.first().is_some_and(|b| b >> 6 == 0b01);
+
+    if !is_channel_data {
+        return &[];
+    }
+
+    &ZEROS[..(4 - message.len() % 4) % 4]
+}
+
+/// Creates a [std::net::TcpListener] via the [socket2] library that is configured like our UDP sockets.
+///
+/// Most importantly, this sets the `IPV6_V6ONLY` flag so we can bind to IP4 and IP6 addresses on the same port.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> io::Result<std::net::TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_padded_channel_data_followed_by_stun_message() {
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]);
+        let mut stun = [0u8; STUN_HEADER_LEN + 4];
+        stun[3] = 4;
+        buffer.extend_from_slice(&stun);
+
+        assert_eq!(
+            decode(&mut buffer).unwrap().unwrap(),
+            Bytes::from_static(&[0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5])
+        );
+        assert_eq!(
+            decode(&mut buffer).unwrap().unwrap(),
+            Bytes::copy_from_slice(&stun)
+        );
+        assert_eq!(decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn waits_for_complete_message() {
+        let mut buffer = BytesMut::from(&[0x40, 0x00, 0x00, 0x05, 1, 2][..]);
+
+        assert_eq!(decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn closes_idle_streams_without_allocation() {
+        let mut streams = Streams::new();
+        let now = Instant::now();
+        let with_allocation = client(1);
+        let without_allocation = client(2);
+        let (tx, _rx) = mpsc::channel(1);
+        for client in [with_allocation, without_allocation] {
+            streams.clients.insert(
+                client,
+                Client {
+                    tx: tx.clone(),
+                    last_received: now,
+                },
+            );
+        }
+
+        streams.close_idle(now + Duration::from_secs(1), |_| false);
+        assert_eq!(streams.clients.len(), 2);
+
+        streams.close_idle(now + IDLE_TIMEOUT_WITHOUT_ALLOCATION, |c| {
+            c == with_allocation
+        });
+        assert!(streams.clients.contains_key(&with_allocation));
+        assert!(!streams.clients.contains_key(&without_allocation));
+    }
+
+    fn client(port: u16) -> ClientSocket {
+        ClientSocket::with_transport(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            Transport::Tcp,
+        )
+    }
+
+    #[test]
+    fn pads_only_channel_data() {
+        assert_eq!(padding(&[0x40, 0x00, 0x00, 0x01, 1]), &[0, 0, 0]);
+        assert_eq!(padding(&[0x00, 0x01, 0x00, 0x00]), &[]);
+    }
+}
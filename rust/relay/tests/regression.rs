@@ -1,9 +1,11 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
     AddressFamily, Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server,
+    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server, Transport,
 };
 use rand::rngs::mock::StepRng;
+use rand::rngs::StdRng;
+use rand::SeedableRng as _;
 use secrecy::SecretString;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
@@ -294,6 +296,109 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
     server.assert_commands(forward_time_to(first_wake + Duration::from_secs(1)), []);
 }
 
+#[proptest]
+fn when_stream_of_client_is_closed_then_delete_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let client = tcp_client(source);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        Input::Client(
+            client,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .into(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            Output::SendMessage((
+                client,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            )),
+        ],
+    );
+
+    server.assert_commands(
+        Input::Disconnected(client),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+    assert_eq!(server.server.poll_timeout(), None);
+}
+
+#[proptest]
+fn udp_and_stream_client_with_same_address_have_separate_allocations(
+    #[strategy(firezone_relay::proptest::transaction_id())] udp_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] tcp_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let udp_client = ClientSocket::new(source.into());
+    let tcp_client = tcp_client(source);
+
+    // The `StepRng` of `TestServer` would pick the same port for the second allocation forever.
+    let mut server = Server::new(
+        public_relay_addr,
+        StdRng::seed_from_u64(0),
+        3478,
+        49152..=65535,
+    );
+    server.add_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let allocate = |transaction_id| {
+        Allocate::new_authenticated_udp_implicit_ip4(
+            transaction_id,
+            Some(lifetime.clone()),
+            valid_username(&username_salt),
+            &secret,
+            nonce,
+        )
+    };
+
+    server.handle_client_message(allocate(udp_transaction_id).into(), udp_client, now);
+    server.handle_client_message(allocate(tcp_transaction_id).into(), tcp_client, now);
+
+    let mut recipients = Vec::new();
+    let mut allocations = Vec::new();
+    while let Some(command) = server.next_command() {
+        match command {
+            Command::SendMessage { recipient, .. } => recipients.push(recipient),
+            Command::CreateAllocation { port, .. } => allocations.push(port),
+            Command::FreeAllocation { .. } => panic!("Unexpected command: {command:?}"),
+        }
+    }
+
+    assert_eq!(recipients, vec![udp_client, tcp_client]);
+    assert_eq!(allocations.len(), 2);
+
+    server.handle_client_disconnected(tcp_client);
+
+    assert!(matches!(
+        server.next_command(),
+        Some(Command::FreeAllocation { port, .. }) if port == allocations[1]
+    ));
+    assert!(server.next_command().is_none());
+    assert_eq!(server.num_allocations(), 1);
+}
+
 #[proptest]
 fn freeing_allocation_clears_all_channels(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -715,6 +820,9 @@ impl TestServer {
             Input::Client(sender, message, now) => {
                 self.server.handle_client_message(message, sender, now);
             }
+            Input::Disconnected(client) => {
+                self.server.handle_client_disconnected(client);
+            }
             Input::Time(now) => {
                 self.server.handle_timeout(now);
             }
@@ -860,6 +968,7 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, Instant),
+    Disconnected(ClientSocket),
     Time(Instant),
 }
 
@@ -871,6 +980,10 @@ fn from_client<'a>(
     Input::Client(ClientSocket::new(from.into()), message.into(), now)
 }
 
+fn disconnect<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnected(ClientSocket::new(client.into()))
+}
+
 fn forward_time_to<'a>(when: Instant) -> Input<'a> {
     Input::Time(when)
 }
@@ -890,6 +1003,10 @@ fn free_allocation(port: u16, fam: AddressFamily) -> Output {
     Output::FreeAllocation(AllocationPort::new(port), fam)
 }
 
+fn tcp_client(source: impl Into<SocketAddr>) -> ClientSocket {
+    ClientSocket::with_transport(source.into(), Transport::Tcp)
+}
+
 fn send_message(source: impl Into<SocketAddr>, message: Message<Attribute>) -> Output {
     Output::SendMessage((ClientSocket::new(source.into()), message))
 }
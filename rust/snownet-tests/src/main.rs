@@ -404,7 +404,8 @@ impl<T> Eventloop<T> {
             }
             Some(
                 snownet::Event::InvalidateIceCandidate { .. }
-                | snownet::Event::ConnectionClosed { .. },
+                | snownet::Event::ConnectionClosed { .. }
+                | snownet::Event::ConnectionPathChanged { .. },
             )
             | None => {}
         }
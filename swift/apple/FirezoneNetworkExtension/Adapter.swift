@@ -55,6 +55,9 @@ class Adapter {
   /// Used to avoid needlessly sending reconnects to connlib
   private var primaryInterfaceName: String?
 
+  /// How often connlib sends a keep-alive to gateways on idle connections, 0 disables them.
+  private static let keepaliveSecs: UInt16 = 10
+
   /// Private queue used to ensure consistent ordering among path update and connlib callbacks
   /// This is the primary async primitive used in this class.
   private let workQueue = DispatchQueue(label: "FirezoneAdapterWorkQueue")
@@ -134,6 +137,7 @@ class Adapter {
           DeviceMetadata.getOSVersion(),
           connlibLogFolderPath,
           logFilter,
+          Adapter.keepaliveSecs,
           callbackHandler
         )
       // Update our internal state